@@ -0,0 +1,421 @@
+use std::collections::BTreeMap;
+
+use rpled_vm::builder::{encode_push, short_jump};
+use rpled_vm::vm::MAX_CODE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Label(usize);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AssembleError {
+    UnboundLabel(Label),
+    LabelBoundTwice(Label),
+    // The jump at `offset` can't reach `label` even with an i16 offset
+    JumpTooFar {
+        offset: usize,
+        label: Label,
+        distance: isize,
+    },
+    // More code than the VM can address
+    CodeTooLarge {
+        len: usize,
+        max: usize,
+    },
+}
+
+type Result<T> = core::result::Result<T, AssembleError>;
+
+enum Item {
+    Bytes(Vec<u8>),
+    Jump { opcode: u8, label: Label },
+    Bind(Label),
+}
+
+// A jump operand patched with a label's address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    // Offset of the operand in the code
+    pub offset: usize,
+    pub label: Label,
+    // 1 for an i8 operand, 2 for i16
+    pub size: usize,
+}
+
+pub struct Assembled {
+    pub code: Vec<u8>,
+    // Code offset of each label, indexed by label
+    pub labels: Vec<usize>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Assembled {
+    pub fn label_offset(&self, label: Label) -> usize {
+        self.labels[label.0]
+    }
+}
+
+// Code emitter with labels, so that jumps and calls can refer to code that
+// hasn't been emitted yet. Jumps are laid out in a second pass: each starts
+// in its short form (if it has one) and is widened only if its target turns
+// out to be out of range, repeating until the layout is stable.
+//
+// Code is emitted in sections, one per function, which can be reordered
+// before assembly (see order_functions). The first section is the program
+// entry point.
+pub struct Assembler {
+    sections: Vec<Vec<Item>>,
+    bound: Vec<bool>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Assembler {
+            sections: vec![Vec::new()],
+            bound: Vec::new(),
+        }
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn items(&mut self) -> &mut Vec<Item> {
+        self.sections.last_mut().unwrap()
+    }
+
+    pub fn label(&mut self) -> Label {
+        self.bound.push(false);
+        Label(self.bound.len() - 1)
+    }
+
+    // Places `label` at the next byte to be emitted
+    pub fn bind(&mut self, label: Label) -> Result<()> {
+        if std::mem::replace(&mut self.bound[label.0], true) {
+            return Err(AssembleError::LabelBoundTwice(label));
+        }
+        self.items().push(Item::Bind(label));
+        Ok(())
+    }
+
+    // Starts a new function section, with `label` at its start
+    pub fn function(&mut self, label: Label) -> Result<()> {
+        self.sections.push(Vec::new());
+        self.bind(label)
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        match self.items().last_mut() {
+            Some(Item::Bytes(bytes)) => bytes.extend_from_slice(data),
+            _ => self.items().push(Item::Bytes(data.to_vec())),
+        }
+    }
+
+    pub fn op(&mut self, opcode: u8) {
+        self.bytes(&[opcode]);
+    }
+
+    pub fn op_u8(&mut self, opcode: u8, arg: u8) {
+        self.bytes(&[opcode, arg]);
+    }
+
+    pub fn op_u16(&mut self, opcode: u8, arg: u16) {
+        self.op(opcode);
+        self.bytes(&arg.to_le_bytes());
+    }
+
+    pub fn push(&mut self, value: i16) {
+        let (bytes, len) = encode_push(value);
+        self.bytes(&bytes[..len]);
+    }
+
+    // See ProgramBuilder::module_call
+    pub fn module_call(&mut self, module: u8, func: u8, n_args: u8) {
+        match n_args {
+            0..=2 => self.op_u8(module + n_args, func),
+            _ => self.bytes(&[module + 3, func, n_args]),
+        }
+    }
+
+    // A relative jump or call (JMP, JZ, CALL, TRY...) to `label`
+    pub fn jump(&mut self, opcode: u8, label: Label) {
+        self.items().push(Item::Jump { opcode, label });
+    }
+
+    // Reorders function sections so that each function follows the caller
+    // that calls it most, keeping relative offsets small enough for short
+    // jumps. Functions are placed depth-first from the entry point, visiting
+    // callees in order of decreasing call count; ties, and functions never
+    // called, keep their emission order so builds are reproducible.
+    pub fn order_functions(&mut self) {
+        let mut section_of = BTreeMap::new();
+        for (index, section) in self.sections.iter().enumerate() {
+            for item in section {
+                if let Item::Bind(label) = item {
+                    section_of.insert(*label, index);
+                }
+            }
+        }
+        let callees: Vec<Vec<usize>> = self
+            .sections
+            .iter()
+            .enumerate()
+            .map(|(index, section)| {
+                let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+                for item in section {
+                    if let Item::Jump { label, .. } = item
+                        && let Some(&target) = section_of.get(label)
+                        && target != index
+                    {
+                        *counts.entry(target).or_default() += 1;
+                    }
+                }
+                let mut callees: Vec<(usize, usize)> = counts.into_iter().collect();
+                callees.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+                callees.into_iter().map(|(target, _)| target).collect()
+            })
+            .collect();
+
+        let mut order = Vec::with_capacity(self.sections.len());
+        let mut placed = vec![false; self.sections.len()];
+        for root in 0..self.sections.len() {
+            let mut stack = vec![root];
+            while let Some(index) = stack.pop() {
+                if std::mem::replace(&mut placed[index], true) {
+                    continue;
+                }
+                order.push(index);
+                stack.extend(callees[index].iter().rev());
+            }
+        }
+
+        let mut sections: Vec<Option<Vec<Item>>> = std::mem::take(&mut self.sections)
+            .into_iter()
+            .map(Some)
+            .collect();
+        self.sections = order
+            .into_iter()
+            .map(|index| sections[index].take().unwrap())
+            .collect();
+    }
+
+    fn layout(&self, items: &[&Item], sizes: &[usize]) -> (Vec<usize>, Vec<usize>) {
+        let mut offsets = Vec::with_capacity(items.len());
+        let mut labels = vec![0; self.bound.len()];
+        let mut offset = 0;
+        for (item, size) in items.iter().zip(sizes) {
+            offsets.push(offset);
+            if let Item::Bind(label) = item {
+                labels[label.0] = offset;
+            }
+            offset += size;
+        }
+        (offsets, labels)
+    }
+
+    pub fn assemble(&self) -> Result<Assembled> {
+        let items: Vec<&Item> = self.sections.iter().flatten().collect();
+        for item in &items {
+            if let Item::Jump { label, .. } = item
+                && !self.bound[label.0]
+            {
+                return Err(AssembleError::UnboundLabel(*label));
+            }
+        }
+
+        let mut sizes: Vec<usize> = items
+            .iter()
+            .map(|item| match item {
+                Item::Bytes(bytes) => bytes.len(),
+                Item::Jump { opcode, .. } if short_jump(*opcode).is_some() => 2,
+                Item::Jump { .. } => 3,
+                Item::Bind(_) => 0,
+            })
+            .collect();
+        // Widening a jump only moves other targets further away, so this
+        // terminates
+        let (offsets, labels) = loop {
+            let (offsets, labels) = self.layout(&items, &sizes);
+            let mut changed = false;
+            for (i, item) in items.iter().enumerate() {
+                if let Item::Jump { label, .. } = item
+                    && sizes[i] == 2
+                {
+                    let distance = labels[label.0] as isize - (offsets[i] + 2) as isize;
+                    if i8::try_from(distance).is_err() {
+                        sizes[i] = 3;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break (offsets, labels);
+            }
+        };
+
+        let mut code = Vec::new();
+        let mut relocations = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+            match item {
+                Item::Bytes(bytes) => code.extend_from_slice(bytes),
+                Item::Bind(_) => {}
+                Item::Jump { opcode, label } => {
+                    let distance = labels[label.0] as isize - (offsets[i] + sizes[i]) as isize;
+                    if sizes[i] == 2 {
+                        code.push(short_jump(*opcode).unwrap());
+                        code.push(distance as i8 as u8);
+                    } else {
+                        let distance =
+                            i16::try_from(distance).map_err(|_| AssembleError::JumpTooFar {
+                                offset: offsets[i],
+                                label: *label,
+                                distance,
+                            })?;
+                        code.push(*opcode);
+                        code.extend_from_slice(&distance.to_le_bytes());
+                    }
+                    relocations.push(Relocation {
+                        offset: offsets[i] + 1,
+                        label: *label,
+                        size: sizes[i] - 1,
+                    });
+                }
+            }
+        }
+        if code.len() > MAX_CODE_SIZE {
+            return Err(AssembleError::CodeTooLarge {
+                len: code.len(),
+                max: MAX_CODE_SIZE,
+            });
+        }
+        Ok(Assembled {
+            code,
+            labels,
+            relocations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpled_vm::vm::opcodes;
+
+    #[test]
+    fn test_forward_and_backward() {
+        let mut asm = Assembler::new();
+        let function = asm.label();
+        let top = asm.label();
+        asm.bind(top).unwrap();
+        asm.jump(opcodes::CALL, function);
+        asm.jump(opcodes::JMP, top);
+        asm.bind(function).unwrap();
+        asm.push(1);
+        asm.op(opcodes::RET);
+
+        let assembled = asm.assemble().unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            opcodes::CALL, 2, 0,
+            opcodes::JMP8, 0xfb,
+            opcodes::PUSH1,
+            opcodes::RET,
+        ];
+        assert_eq!(assembled.code, expected);
+        assert_eq!(assembled.label_offset(function), 5);
+        assert_eq!(
+            assembled.relocations,
+            [
+                Relocation {
+                    offset: 1,
+                    label: function,
+                    size: 2
+                },
+                Relocation {
+                    offset: 4,
+                    label: top,
+                    size: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_widening() {
+        // The JZ only needs widening once the JMP is widened
+        let mut asm = Assembler::new();
+        let middle = asm.label();
+        let end = asm.label();
+        asm.jump(opcodes::JZ, middle);
+        asm.bytes(&[opcodes::HALT; 125]);
+        asm.jump(opcodes::JMP, end);
+        asm.bind(middle).unwrap();
+        asm.bytes(&[opcodes::HALT; 200]);
+        asm.bind(end).unwrap();
+
+        let assembled = asm.assemble().unwrap();
+        assert_eq!(assembled.code[0], opcodes::JZ);
+        assert_eq!(assembled.code[128], opcodes::JMP);
+        assert_eq!(assembled.label_offset(middle), 131);
+        assert_eq!(assembled.label_offset(end), 331);
+    }
+
+    #[test]
+    fn test_order_functions() {
+        let mut asm = Assembler::new();
+        let rare = asm.label();
+        let helper = asm.label();
+        let hot = asm.label();
+        asm.jump(opcodes::CALL, rare);
+        asm.jump(opcodes::CALL, hot);
+        asm.jump(opcodes::CALL, hot);
+        asm.op(opcodes::HALT);
+        asm.function(rare).unwrap();
+        asm.bytes(&[opcodes::ZERO; 200]);
+        asm.op(opcodes::RET);
+        asm.function(helper).unwrap();
+        asm.op(opcodes::RET);
+        asm.function(hot).unwrap();
+        asm.jump(opcodes::CALL, helper);
+        asm.op(opcodes::RET);
+
+        asm.order_functions();
+        let assembled = asm.assemble().unwrap();
+        assert_eq!(assembled.label_offset(hot), 10);
+        assert_eq!(assembled.label_offset(helper), 14);
+        assert_eq!(assembled.label_offset(rare), 15);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut asm = Assembler::new();
+        let label = asm.label();
+        asm.jump(opcodes::JMP, label);
+        assert_eq!(
+            asm.assemble().err(),
+            Some(AssembleError::UnboundLabel(label))
+        );
+        asm.bytes(&[opcodes::HALT; 40000]);
+        asm.bind(label).unwrap();
+        assert_eq!(asm.bind(label), Err(AssembleError::LabelBoundTwice(label)));
+        assert_eq!(
+            asm.assemble().err(),
+            Some(AssembleError::JumpTooFar {
+                offset: 0,
+                label,
+                distance: 40000
+            })
+        );
+
+        let mut asm = Assembler::new();
+        asm.bytes(&vec![opcodes::HALT; MAX_CODE_SIZE + 1]);
+        assert_eq!(
+            asm.assemble().err(),
+            Some(AssembleError::CodeTooLarge {
+                len: MAX_CODE_SIZE + 1,
+                max: MAX_CODE_SIZE
+            })
+        );
+    }
+}
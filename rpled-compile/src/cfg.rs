@@ -0,0 +1,545 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use rpled_vm::disasm::{DecodeError, Instruction, decode};
+use rpled_vm::vm::opcodes;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    Undecodable {
+        offset: usize,
+        error: DecodeError,
+    },
+    JumpOutOfBounds {
+        offset: usize,
+    },
+    // `target` is reached, but lies inside the instruction at `offset`
+    JumpIntoInstruction {
+        offset: usize,
+        target: usize,
+    },
+    FallsOffEnd {
+        offset: usize,
+    },
+    // Execution runs on into another function, usually a missing RET
+    FallsIntoFunction {
+        offset: usize,
+        function: usize,
+    },
+    StackMismatch {
+        offset: usize,
+        expected: i32,
+        found: i32,
+    },
+    StackUnderflow {
+        offset: usize,
+    },
+    UnknownStackEffect {
+        offset: usize,
+    },
+    // RETs in one function leave the stack at different depths
+    InconsistentReturn {
+        offset: usize,
+        expected: i32,
+        found: i32,
+    },
+    // A CALLZ, CALLNZ or TRY of a function that changes the stack depth
+    UnbalancedCall {
+        offset: usize,
+        function: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Next,
+    Jump(usize),
+    Branch(usize),
+    Call(usize),
+    Stop,
+}
+
+fn flow(instruction: &Instruction) -> Flow {
+    let target = instruction.jump_target().unwrap_or(usize::MAX);
+    match instruction.opcode {
+        opcodes::JMP | opcodes::JMP8 => Flow::Jump(target),
+        opcodes::JZ | opcodes::JNZ | opcodes::JZ8 | opcodes::JNZ8 => Flow::Branch(target),
+        opcodes::CALL | opcodes::CALLZ | opcodes::CALLNZ | opcodes::TRY => Flow::Call(target),
+        opcodes::RET | opcodes::HALT | opcodes::HALTWITH => Flow::Stop,
+        _ => Flow::Next,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    // Blocks control can pass to within the same function
+    pub successors: Vec<usize>,
+}
+
+// Stack use of a function, relative to the stack on entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FunctionInfo {
+    pub entry: usize,
+    // Depth at RET, which the caller sees as the call's effect. None if the
+    // function never returns.
+    pub net_effect: Option<i32>,
+    pub max_depth: i32,
+}
+
+// Control-flow graph over the instructions reachable from the program
+// entry point. Calls are edges to the next instruction, with the callee
+// recorded as a separate function.
+pub struct Cfg<'a> {
+    pub instructions: BTreeMap<usize, Instruction<'a>>,
+    pub blocks: BTreeMap<usize, BasicBlock>,
+    // Offset 0 and every CALL/TRY target
+    pub functions: BTreeSet<usize>,
+}
+
+impl<'a> Cfg<'a> {
+    // Successors of an instruction within its function
+    pub fn successors(&self, instruction: &Instruction) -> Vec<usize> {
+        let next = instruction.end();
+        match flow(instruction) {
+            Flow::Next | Flow::Call(_) => vec![next],
+            Flow::Jump(target) => vec![target],
+            Flow::Branch(target) => vec![target, next],
+            Flow::Stop => vec![],
+        }
+        .into_iter()
+        .filter(|offset| self.instructions.contains_key(offset))
+        .collect()
+    }
+
+    pub fn build(code: &'a [u8]) -> (Self, Vec<VerifyError>) {
+        let mut errors = Vec::new();
+        let mut cfg = Cfg {
+            instructions: BTreeMap::new(),
+            blocks: BTreeMap::new(),
+            functions: BTreeSet::from([0]),
+        };
+        let mut leaders = BTreeSet::from([0]);
+        let mut undecodable = BTreeSet::new();
+        let mut work = vec![0];
+        while let Some(offset) = work.pop() {
+            if cfg.instructions.contains_key(&offset) || undecodable.contains(&offset) {
+                continue;
+            }
+            let instruction = match decode(code, offset) {
+                Ok(instruction) => instruction,
+                Err(error) => {
+                    errors.push(VerifyError::Undecodable { offset, error });
+                    undecodable.insert(offset);
+                    continue;
+                }
+            };
+            cfg.instructions.insert(offset, instruction);
+
+            let flow = flow(&instruction);
+            if let Flow::Jump(target) | Flow::Branch(target) | Flow::Call(target) = flow {
+                if target >= code.len() {
+                    errors.push(VerifyError::JumpOutOfBounds { offset });
+                } else {
+                    leaders.insert(target);
+                    work.push(target);
+                    if let Flow::Call(_) = flow {
+                        cfg.functions.insert(target);
+                    }
+                }
+            }
+            match flow {
+                Flow::Jump(_) | Flow::Stop => {}
+                _ if instruction.end() >= code.len() => {
+                    errors.push(VerifyError::FallsOffEnd { offset });
+                }
+                _ => {
+                    if let Flow::Branch(_) = flow {
+                        leaders.insert(instruction.end());
+                    }
+                    work.push(instruction.end());
+                }
+            }
+        }
+
+        // Decoding from a target inside another instruction gives overlapping
+        // instructions
+        let mut previous: Option<&Instruction> = None;
+        for instruction in cfg.instructions.values() {
+            if let Some(previous) = previous
+                && previous.end() > instruction.offset
+            {
+                errors.push(VerifyError::JumpIntoInstruction {
+                    offset: previous.offset,
+                    target: instruction.offset,
+                });
+            }
+            previous = Some(instruction);
+        }
+
+        for instruction in cfg.instructions.values() {
+            let next = instruction.end();
+            let falls_through = matches!(
+                flow(instruction),
+                Flow::Next | Flow::Branch(_) | Flow::Call(_)
+            );
+            if falls_through && cfg.functions.contains(&next) {
+                errors.push(VerifyError::FallsIntoFunction {
+                    offset: instruction.offset,
+                    function: next,
+                });
+            }
+        }
+
+        cfg.build_blocks(&leaders);
+        (cfg, errors)
+    }
+
+    fn build_blocks(&mut self, leaders: &BTreeSet<usize>) {
+        let mut start = None;
+        let mut iter = self.instructions.values().peekable();
+        while let Some(instruction) = iter.next() {
+            let block_start = *start.get_or_insert(instruction.offset);
+            let ends_block = !matches!(flow(instruction), Flow::Next | Flow::Call(_))
+                || iter.peek().is_none_or(|next| {
+                    next.offset != instruction.end() || leaders.contains(&next.offset)
+                });
+            if ends_block {
+                self.blocks.insert(
+                    block_start,
+                    BasicBlock {
+                        start: block_start,
+                        end: instruction.end(),
+                        successors: Vec::new(),
+                    },
+                );
+                start = None;
+            }
+        }
+        let successors: Vec<(usize, Vec<usize>)> = self
+            .blocks
+            .values()
+            .map(|block| {
+                let (_, last) = self.instructions.range(..block.end).next_back().unwrap();
+                (block.start, self.successors(last))
+            })
+            .collect();
+        for (start, successors) in successors {
+            self.blocks.get_mut(&start).unwrap().successors = successors;
+        }
+    }
+
+    // Call targets reachable from `function` without following calls
+    fn callees(&self, function: usize) -> BTreeSet<usize> {
+        let mut seen = BTreeSet::from([function]);
+        let mut callees = BTreeSet::new();
+        let mut work = vec![function];
+        while let Some(offset) = work.pop() {
+            let Some(instruction) = self.instructions.get(&offset) else {
+                continue;
+            };
+            if let Flow::Call(target) = flow(instruction) {
+                callees.insert(target);
+            }
+            for next in self.successors(instruction) {
+                if seen.insert(next) {
+                    work.push(next);
+                }
+            }
+        }
+        callees
+    }
+
+    // Stack depth before each instruction of `function`, relative to its
+    // entry. Depths must agree
+    // wherever paths join. Calls use the callee's net effect from `infos`,
+    // assuming 0 for callees not yet analysed (recursion).
+    pub fn stack_depths(
+        &self,
+        function: usize,
+        infos: &BTreeMap<usize, FunctionInfo>,
+        errors: &mut Vec<VerifyError>,
+    ) -> BTreeMap<usize, i32> {
+        let mut depths = BTreeMap::from([(function, 0)]);
+        let mut work = vec![function];
+        while let Some(offset) = work.pop() {
+            let Some(instruction) = self.instructions.get(&offset) else {
+                continue;
+            };
+            let depth = depths[&offset];
+            let Some((pops, pushes)) = instruction.stack_effect() else {
+                errors.push(VerifyError::UnknownStackEffect { offset });
+                continue;
+            };
+            // Functions take their arguments from the caller's stack, so only
+            // the entry point can underflow
+            if function == 0 && depth < pops as i32 {
+                errors.push(VerifyError::StackUnderflow { offset });
+                continue;
+            }
+            let mut after = depth - pops as i32 + pushes as i32;
+            if let Flow::Call(target) = flow(instruction) {
+                let effect = infos
+                    .get(&target)
+                    .and_then(|info| info.net_effect)
+                    .unwrap_or(0);
+                // A conditional call, or a TRY that unwinds, leaves the stack
+                // as it was, so only CALL may change its depth
+                if effect != 0 && instruction.opcode != opcodes::CALL {
+                    errors.push(VerifyError::UnbalancedCall {
+                        offset,
+                        function: target,
+                    });
+                } else {
+                    after += effect;
+                }
+            }
+            for next in self.successors(instruction) {
+                match depths.get(&next) {
+                    None => {
+                        depths.insert(next, after);
+                        work.push(next);
+                    }
+                    Some(&expected) if expected != after => {
+                        errors.push(VerifyError::StackMismatch {
+                            offset: next,
+                            expected,
+                            found: after,
+                        });
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        depths
+    }
+
+    fn function_info(
+        &self,
+        function: usize,
+        infos: &mut BTreeMap<usize, FunctionInfo>,
+        in_progress: &mut BTreeSet<usize>,
+        errors: &mut Vec<VerifyError>,
+    ) {
+        if infos.contains_key(&function) || !in_progress.insert(function) {
+            return;
+        }
+        for callee in self.callees(function) {
+            self.function_info(callee, infos, in_progress, errors);
+        }
+        let depths = self.stack_depths(function, infos, errors);
+        let mut info = FunctionInfo {
+            entry: function,
+            net_effect: None,
+            max_depth: 0,
+        };
+        for (&offset, &depth) in &depths {
+            let instruction = &self.instructions[&offset];
+            let (pops, pushes) = instruction.stack_effect().unwrap_or((0, 0));
+            info.max_depth = info.max_depth.max(depth - pops as i32 + pushes as i32);
+            if instruction.opcode != opcodes::RET {
+                continue;
+            }
+            match info.net_effect {
+                None => info.net_effect = Some(depth),
+                Some(expected) if expected != depth => {
+                    errors.push(VerifyError::InconsistentReturn {
+                        offset,
+                        expected,
+                        found: depth,
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+        in_progress.remove(&function);
+        infos.insert(function, info);
+    }
+
+    // Stack use of every function, analysing callees before their callers
+    pub fn function_infos(&self, errors: &mut Vec<VerifyError>) -> BTreeMap<usize, FunctionInfo> {
+        let mut infos = BTreeMap::new();
+        for &function in &self.functions {
+            self.function_info(function, &mut infos, &mut BTreeSet::new(), errors);
+        }
+        infos
+    }
+}
+
+// Checks that `code` only jumps to instruction boundaries, that every path
+// through a function ends in RET, HALT or a jump, and that the stack depth
+// is the same whichever way an instruction is reached
+pub fn verify(code: &[u8]) -> Vec<VerifyError> {
+    let (cfg, mut errors) = Cfg::build(code);
+    cfg.function_infos(&mut errors);
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::decompressed_code;
+    use rpled_vm::builder::ProgramBuilder;
+
+    fn code(build: impl FnOnce(&mut ProgramBuilder)) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Cfg").unwrap();
+        build(&mut builder);
+        decompressed_code(builder.finish()).unwrap()
+    }
+
+    #[test]
+    fn test_valid_program() {
+        let code = code(|b| {
+            b.op_i16(opcodes::CALL, 1).unwrap();
+            b.op(opcodes::HALT).unwrap();
+            // 4: function
+            b.op(opcodes::ZERO).unwrap();
+            b.op_i16(opcodes::JZ, 1).unwrap();
+            b.op(opcodes::RET).unwrap();
+            b.op(opcodes::RET).unwrap();
+        });
+        assert_eq!(verify(&code), vec![]);
+        let (cfg, _) = Cfg::build(&code);
+        assert_eq!(cfg.functions, BTreeSet::from([0, 4]));
+        assert_eq!(cfg.blocks.keys().copied().collect::<Vec<_>>(), [0, 4, 8, 9]);
+        assert_eq!(cfg.blocks[&4].successors, [9, 8]);
+    }
+
+    #[test]
+    fn test_jump_into_instruction() {
+        let code = code(|b| {
+            b.op_u16(opcodes::LOAD, opcodes::HALT as u16).unwrap();
+            b.op_i16(opcodes::JMP, -5).unwrap();
+        });
+        assert_eq!(
+            verify(&code),
+            vec![VerifyError::JumpIntoInstruction {
+                offset: 0,
+                target: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_stack_mismatch() {
+        let code = code(|b| {
+            b.op(opcodes::ZERO).unwrap();
+            b.op_i16(opcodes::JZ, 1).unwrap();
+            b.op(opcodes::ZERO).unwrap();
+            b.op(opcodes::HALT).unwrap();
+        });
+        assert_eq!(
+            verify(&code),
+            vec![VerifyError::StackMismatch {
+                offset: 5,
+                expected: 0,
+                found: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_missing_return() {
+        let falls_off = code(|b| {
+            b.op_i16(opcodes::CALL, 1).unwrap();
+            b.op(opcodes::HALT).unwrap();
+            b.op(opcodes::ZERO).unwrap();
+        });
+        assert_eq!(
+            verify(&falls_off),
+            vec![VerifyError::FallsOffEnd { offset: 4 }]
+        );
+
+        let falls_into = code(|b| {
+            b.op_i16(opcodes::CALL, 1).unwrap();
+            b.op(opcodes::ZERO).unwrap();
+            b.op(opcodes::RET).unwrap();
+        });
+        assert_eq!(
+            verify(&falls_into),
+            vec![VerifyError::FallsIntoFunction {
+                offset: 3,
+                function: 4
+            }]
+        );
+    }
+
+    #[test]
+    fn test_function_infos() {
+        // The function consumes its argument
+        let build = |call| {
+            code(|b| {
+                b.op(opcodes::ZERO).unwrap();
+                b.op_i16(call, 1).unwrap();
+                b.op(opcodes::HALT).unwrap();
+                b.op(opcodes::POP).unwrap();
+                b.op(opcodes::RET).unwrap();
+            })
+        };
+        let code = build(opcodes::CALL);
+        let (cfg, mut errors) = Cfg::build(&code);
+        let infos = cfg.function_infos(&mut errors);
+        assert_eq!(errors, vec![]);
+        assert_eq!(
+            infos[&0],
+            FunctionInfo {
+                entry: 0,
+                net_effect: None,
+                max_depth: 1
+            }
+        );
+        assert_eq!(
+            infos[&5],
+            FunctionInfo {
+                entry: 5,
+                net_effect: Some(-1),
+                max_depth: 0
+            }
+        );
+
+        assert_eq!(
+            verify(&build(opcodes::CALLNZ)),
+            vec![VerifyError::UnbalancedCall {
+                offset: 1,
+                function: 5
+            }]
+        );
+    }
+
+    // Diagnostics for the programs in testprogs/diagnostics: fixture
+    // assembler errors, header errors, then verifier errors
+    fn diagnostics(source: &str) -> Vec<String> {
+        let program = match rpled_vm::fixture_parse::decode_fixture(source) {
+            Ok(program) => program,
+            Err(err) => return vec![err.to_string()],
+        };
+        match decompressed_code(&program) {
+            Ok(code) => verify(&code)
+                .iter()
+                .map(|err| format!("{:?}", err))
+                .collect(),
+            Err(err) => vec![format!("{:?}", err)],
+        }
+    }
+
+    // Each program in the corpus lists the diagnostics it should produce,
+    // in order, after a `=== DIAGNOSTICS ===` line
+    #[test]
+    fn test_diagnostics_corpus() {
+        let mut paths: Vec<_> = std::fs::read_dir("../testprogs/diagnostics")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        assert!(!paths.is_empty());
+        for path in paths {
+            let fixture = std::fs::read_to_string(&path).unwrap();
+            let (source, expected) = fixture
+                .split_once("=== DIAGNOSTICS ===")
+                .unwrap_or_else(|| panic!("{}: no diagnostics section", path.display()));
+            let expected: Vec<_> = expected.lines().filter(|line| !line.is_empty()).collect();
+            assert_eq!(diagnostics(source), expected, "{}", path.display());
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use rpled_vm::lz4;
+use rpled_vm::program::{FLAGS_OFFSET, Program, ProgramError, ProgramFlags};
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+// The LZ4 block format requires the last 5 bytes to be literals, and the
+// last match to start at least 12 bytes before the end
+const LAST_LITERALS: usize = 5;
+const MATCH_LIMIT: usize = 12;
+
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_code = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) << 4) | match_code.min(15)) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_code >= 15 {
+            write_length(out, match_code - 15);
+        }
+    }
+}
+
+// Greedy single-pass LZ4 block compressor
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut out = Vec::new();
+    let mut anchor = 0;
+    let mut pos = 0;
+    let match_limit = input.len().saturating_sub(MATCH_LIMIT);
+    while pos < match_limit {
+        let sequence = u32::from_le_bytes(input[pos..pos + 4].try_into().unwrap());
+        let hash = (sequence.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+        let candidate = std::mem::replace(&mut table[hash], pos);
+        if candidate == usize::MAX
+            || pos - candidate > MAX_OFFSET
+            || input[candidate..candidate + MIN_MATCH] != input[pos..pos + MIN_MATCH]
+        {
+            pos += 1;
+            continue;
+        }
+
+        let mut len = MIN_MATCH;
+        while pos + len < input.len() - LAST_LITERALS && input[candidate + len] == input[pos + len]
+        {
+            len += 1;
+        }
+        write_sequence(&mut out, &input[anchor..pos], Some((pos - candidate, len)));
+        pos += len;
+        anchor = pos;
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+// Returns a copy of `program` with its code compressed and the COMPRESSED
+// flag set. Any signature is dropped, as it wouldn't match the new body.
+pub fn compress_program(program: &[u8]) -> Result<Vec<u8>, ProgramError> {
+    program.validate_program()?;
+    // Version 0 headers have no flags byte to set
+    if program.version()? == 0 {
+        return Err(ProgramError::UnexpectedVersion(0));
+    }
+    let start = program.program_start()? as usize;
+    let body = decompressed_code(program)?;
+
+    let mut compressed = program[..start].to_vec();
+    let flags = (program.flags()? | ProgramFlags::COMPRESSED) - ProgramFlags::SIGNED;
+    compressed[FLAGS_OFFSET] = flags.bits();
+    compressed.extend(compress(&body));
+    Ok(compressed)
+}
+
+// The code section of a program, decompressed if needed
+pub fn decompressed_code(program: &[u8]) -> Result<Vec<u8>, ProgramError> {
+    let code = &program[program.program_start()? as usize..program.program_end()?];
+    if !program.flags()?.contains(ProgramFlags::COMPRESSED) {
+        return Ok(code.to_vec());
+    }
+    let mut out = vec![0; u16::MAX as usize];
+    let len = lz4::decompress(code, &mut out).map_err(|_| ProgramError::CorruptBody)?;
+    out.truncate(len);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpled_vm::builder::ProgramBuilder;
+    use rpled_vm::vm::opcodes;
+
+    #[test]
+    fn test_round_trip() {
+        let inputs: [&[u8]; 4] = [
+            b"",
+            b"short",
+            &[7; 1000],
+            b"the quick brown fox jumps over the lazy dog, the quick brown fox!",
+        ];
+        for input in inputs {
+            let compressed = compress(input);
+            let mut out = vec![0; input.len()];
+            assert_eq!(lz4::decompress(&compressed, &mut out), Ok(input.len()));
+            assert_eq!(out, input);
+        }
+        assert!(compress(&[7; 1000]).len() < 20);
+    }
+
+    #[test]
+    fn test_compress_program() {
+        let mut buf = [0u8; 128];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Zip").unwrap();
+        for _ in 0..20 {
+            builder.push(1).unwrap();
+            builder.op(opcodes::POP).unwrap();
+        }
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        let compressed = compress_program(program).unwrap();
+        assert!(compressed.len() < program.len());
+        let flags = compressed.as_slice().flags().unwrap();
+        assert_eq!(flags, ProgramFlags::COMPRESSED);
+        assert_eq!(
+            decompressed_code(&compressed).unwrap(),
+            decompressed_code(program).unwrap()
+        );
+    }
+}
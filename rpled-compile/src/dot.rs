@@ -0,0 +1,94 @@
+use std::fmt::Write;
+
+use crate::cfg::Cfg;
+use crate::compress::decompressed_code;
+use rpled_vm::program::{Program, ProgramError};
+use rpled_vm::vm::opcodes;
+
+// Produces a Graphviz DOT graph of a compiled program's control flow: one
+// node per basic block listing its ops and estimated cycles, with solid
+// edges for jumps and fallthrough and dashed edges for calls. Function
+// entries are drawn with a double border.
+pub fn dot(program: &[u8]) -> Result<String, ProgramError> {
+    program.validate_program()?;
+    let code = &decompressed_code(program)?;
+    let (cfg, _) = Cfg::build(code);
+
+    let mut out = String::new();
+    writeln!(out, "digraph \"{}\" {{", program.program_name()?).unwrap();
+    writeln!(out, "  node [shape=box, fontname=monospace];").unwrap();
+    for block in cfg.blocks.values() {
+        let mut label = String::new();
+        let mut cycles = 0;
+        let mut calls = Vec::new();
+        for instruction in cfg
+            .instructions
+            .range(block.start..block.end)
+            .map(|(_, i)| i)
+        {
+            write!(label, "{:04x}  {}\\l", instruction.offset, instruction).unwrap();
+            cycles += opcodes::cycle_cost(instruction.opcode).unwrap_or(0) as u32;
+            if let Some(target) = instruction.jump_target()
+                && cfg.functions.contains(&target)
+                && !block.successors.contains(&target)
+            {
+                calls.push(target);
+            }
+        }
+        write!(label, "{} cycles\\l", cycles).unwrap();
+        let peripheries = if cfg.functions.contains(&block.start) {
+            2
+        } else {
+            1
+        };
+        writeln!(
+            out,
+            "  b{:04x} [label=\"{}\", peripheries={}];",
+            block.start, label, peripheries
+        )
+        .unwrap();
+        for successor in &block.successors {
+            writeln!(out, "  b{:04x} -> b{:04x};", block.start, successor).unwrap();
+        }
+        for target in calls {
+            writeln!(
+                out,
+                "  b{:04x} -> b{:04x} [style=dashed];",
+                block.start, target
+            )
+            .unwrap();
+        }
+    }
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpled_vm::builder::ProgramBuilder;
+
+    #[test]
+    fn test_dot() {
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Dot").unwrap();
+        builder.op_i16(opcodes::CALL, 1).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        builder.op(opcodes::ZERO).unwrap();
+        builder.op_i16(opcodes::JNZ, -4).unwrap();
+        builder.op(opcodes::RET).unwrap();
+
+        let expected = "\
+digraph \"Dot\" {
+  node [shape=box, fontname=monospace];
+  b0000 [label=\"0000  CALL 1\\l0003  HALT\\l55 cycles\\l\", peripheries=2];
+  b0000 -> b0004 [style=dashed];
+  b0004 [label=\"0004  ZERO\\l0005  JNZ -4\\l60 cycles\\l\", peripheries=2];
+  b0004 -> b0004;
+  b0004 -> b0008;
+  b0008 [label=\"0008  RET\\l35 cycles\\l\", peripheries=1];
+}
+";
+        assert_eq!(dot(builder.finish()).unwrap(), expected);
+    }
+}
@@ -1,13 +1,9 @@
-
-
-
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-
-//     // #[test]
-//     // fn it_works() {
-//     //     let result = add(2, 2);
-//     //     assert_eq!(result, 4);
-//     // }
-// }
+pub mod assembler;
+pub mod cfg;
+pub mod compress;
+pub mod dot;
+pub mod listing;
+pub mod package;
+pub mod pixel_map;
+pub mod signing;
+pub mod stats;
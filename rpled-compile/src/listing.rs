@@ -0,0 +1,156 @@
+use std::fmt::Write;
+
+use crate::cfg::Cfg;
+use crate::compress::decompressed_code;
+use rpled_vm::disasm::Instructions;
+use rpled_vm::program::{Program, ProgramError};
+
+// Width of the byte column: enough for the longest instruction (3 bytes)
+const BYTES_COLUMN: usize = 12;
+
+// Produces an assembler-style listing of a compiled program: a summary of
+// the header, then one line per instruction with its address, encoding and
+// disassembly. Bytes that don't decode (e.g. embedded data) are listed as
+// `.byte` directives. Each function is preceded by its stack use.
+pub fn listing(program: &[u8]) -> Result<String, ProgramError> {
+    program.validate_program()?;
+    let code = &decompressed_code(program)?;
+
+    let mut out = String::new();
+    writeln!(out, "; name:    {}", program.program_name()?).unwrap();
+    writeln!(out, "; modules: {:?}", program.required_modules()?).unwrap();
+    writeln!(out, "; heap:    {} bytes", program.heap_size()?).unwrap();
+    writeln!(out, "; flags:   {:?}", program.flags()?).unwrap();
+    writeln!(out, "; code:    {} bytes", code.len()).unwrap();
+    writeln!(out).unwrap();
+
+    let (cfg, mut errors) = Cfg::build(code);
+    let functions = cfg.function_infos(&mut errors);
+    for (offset, decoded) in Instructions::new(code) {
+        if let Some(function) = functions.get(&offset) {
+            let effect = match function.net_effect {
+                Some(effect) => format!("{:+}", effect),
+                None => "no return".to_string(),
+            };
+            writeln!(
+                out,
+                "; function: stack {}, max depth {}",
+                effect, function.max_depth
+            )
+            .unwrap();
+        }
+        let (bytes, text) = match decoded {
+            Ok(instruction) => {
+                let mut text = instruction.to_string();
+                if let Some(target) = instruction.jump_target() {
+                    write!(text, "  ; -> {:04x}", target).unwrap();
+                }
+                (instruction.bytes, text)
+            }
+            Err(_) => (
+                &code[offset..offset + 1],
+                format!(".byte 0x{:02x}", code[offset]),
+            ),
+        };
+        let hex = bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(
+            out,
+            "{:04x}  {:<width$}{}",
+            offset,
+            hex,
+            text,
+            width = BYTES_COLUMN
+        )
+        .unwrap();
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpled_vm::builder::ProgramBuilder;
+    use rpled_vm::fixture_parse::decode_fixture;
+    use rpled_vm::vm::opcodes;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_listing() {
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 4, &[], "List").unwrap();
+        builder.push(-2).unwrap();
+        builder.op(opcodes::INC).unwrap();
+        builder.op_i16(opcodes::JNZ, -4).unwrap();
+        builder.u8(0).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+
+        let expected = "\
+; name:    List
+; modules: ModuleFlags(0x0)
+; heap:    4 bytes
+; flags:   ProgramFlags(0x0)
+; code:    8 bytes
+
+; function: stack no return, max depth 1
+0000  29 fe       PUSH8 -2
+0002  1a          INC
+0003  21 fc ff    JNZ -4  ; -> 0002
+0006  00          .byte 0x00
+0007  26          HALT
+";
+        assert_eq!(listing(builder.finish()).unwrap(), expected);
+    }
+
+    // The fixtures under `dir`, leaving out the listings themselves and the
+    // diagnostics corpus, which is made of broken programs
+    fn fixtures(dir: &Path, paths: &mut Vec<PathBuf>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                if !path.ends_with("listings") && !path.ends_with("diagnostics") {
+                    fixtures(&path, paths);
+                }
+            } else if path.to_string_lossy().ends_with(".pxs.txt") {
+                paths.push(path);
+            }
+        }
+    }
+
+    // Compares the listing of each fixture with its checked-in copy in
+    // testprogs/listings, so changes to encodings show up as readable
+    // diffs. Run with BLESS=1 to update the listings.
+    #[test]
+    fn test_listings() {
+        let testprogs = Path::new("../testprogs");
+        let mut paths = Vec::new();
+        fixtures(testprogs, &mut paths);
+        paths.sort();
+        assert!(!paths.is_empty());
+        for path in paths {
+            let fixture = std::fs::read_to_string(&path).unwrap();
+            // The program, without the expected output or frames
+            let source = fixture.split("\n===").next().unwrap();
+            let program = decode_fixture(source).unwrap();
+            let actual =
+                listing(&program).unwrap_or_else(|err| format!("; load error: {:?}\n", err));
+
+            let name = path.strip_prefix(testprogs).unwrap().to_string_lossy();
+            let expected_path = testprogs
+                .join("listings")
+                .join(name.replace(".pxs.txt", ".lst"));
+            if std::env::var_os("BLESS").is_some() {
+                std::fs::create_dir_all(expected_path.parent().unwrap()).unwrap();
+                std::fs::write(&expected_path, &actual).unwrap();
+                continue;
+            }
+            let expected = std::fs::read_to_string(&expected_path).unwrap_or_else(|err| {
+                panic!("{}: {} (run with BLESS=1)", expected_path.display(), err)
+            });
+            assert_eq!(actual, expected, "Listing changed for {}", path.display());
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use rpled_vm::program::{Program, ProgramError};
+
+// A .pxpkg bundles a compiled program with everything needed to share and
+// install it. The layout is a magic/version prelude followed by sections,
+// each a tag byte, a little-endian u32 length and the section data.
+// Unknown sections are skipped so newer packages can add to the format.
+pub const MAGIC: &[u8; 4] = b"PXPK";
+pub const VERSION: u8 = 0;
+
+const TAG_PROGRAM: u8 = 1;
+const TAG_PARAMS: u8 = 2;
+const TAG_DESCRIPTION: u8 = 3;
+const TAG_PREVIEW: u8 = 4;
+
+#[derive(Debug)]
+pub enum PackageError {
+    InvalidMagic,
+    UnexpectedVersion(u8),
+    Truncated,
+    MissingProgram,
+    DuplicateSection(u8),
+    InvalidDescription,
+    InvalidProgram(ProgramError),
+}
+
+impl From<ProgramError> for PackageError {
+    fn from(err: ProgramError) -> Self {
+        PackageError::InvalidProgram(err)
+    }
+}
+
+type Result<T> = std::result::Result<T, PackageError>;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Package {
+    pub program: Vec<u8>,
+    // Parameter schema, stored as-is until programs carry metadata
+    pub params: Option<Vec<u8>>,
+    pub description: Option<String>,
+    // Preview animation (GIF)
+    pub preview: Option<Vec<u8>>,
+}
+
+impl Package {
+    pub fn new(program: Vec<u8>) -> Result<Self> {
+        program.as_slice().validate_program()?;
+        Ok(Package {
+            program,
+            ..Default::default()
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        let mut section = |tag: u8, data: &[u8]| {
+            out.push(tag);
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(data);
+        };
+        section(TAG_PROGRAM, &self.program);
+        if let Some(params) = &self.params {
+            section(TAG_PARAMS, params);
+        }
+        if let Some(description) = &self.description {
+            section(TAG_DESCRIPTION, description.as_bytes());
+        }
+        if let Some(preview) = &self.preview {
+            section(TAG_PREVIEW, preview);
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let rest = data
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or(PackageError::InvalidMagic)?;
+        let (&version, mut rest) = rest.split_first().ok_or(PackageError::Truncated)?;
+        if version != VERSION {
+            return Err(PackageError::UnexpectedVersion(version));
+        }
+
+        let mut program = None;
+        let mut description = None;
+        let mut package = Package::default();
+        while let Some((&tag, tail)) = rest.split_first() {
+            let len_bytes = tail.get(..4).ok_or(PackageError::Truncated)?;
+            let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+            let section = tail.get(4..4 + len).ok_or(PackageError::Truncated)?;
+            rest = &tail[4 + len..];
+
+            let slot = match tag {
+                TAG_PROGRAM => &mut program,
+                TAG_PARAMS => &mut package.params,
+                TAG_DESCRIPTION => &mut description,
+                TAG_PREVIEW => &mut package.preview,
+                _ => continue,
+            };
+            if slot.replace(section.to_vec()).is_some() {
+                return Err(PackageError::DuplicateSection(tag));
+            }
+        }
+
+        package.program = program.ok_or(PackageError::MissingProgram)?;
+        package.description = description
+            .map(String::from_utf8)
+            .transpose()
+            .map_err(|_| PackageError::InvalidDescription)?;
+        package.program.as_slice().validate_program()?;
+        Ok(package)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpled_vm::builder::ProgramBuilder;
+    use rpled_vm::vm::opcodes;
+
+    #[test]
+    fn test_round_trip() {
+        let mut buf = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Pkg").unwrap();
+        builder.op(opcodes::HALT).unwrap();
+
+        let mut package = Package::new(builder.finish().to_vec()).unwrap();
+        package.description = Some("Halts".to_string());
+        package.preview = Some(b"GIF89a".to_vec());
+        let mut bytes = package.to_bytes();
+        // An unknown trailing section is ignored
+        bytes.extend_from_slice(&[99, 1, 0, 0, 0, 0]);
+        assert_eq!(Package::from_bytes(&bytes).unwrap(), package);
+
+        assert!(matches!(
+            Package::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(PackageError::Truncated)
+        ));
+        assert!(matches!(
+            Package::from_bytes(b"PXPK\0"),
+            Err(PackageError::MissingProgram)
+        ));
+    }
+}
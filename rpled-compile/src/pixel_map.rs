@@ -0,0 +1,81 @@
+use rpled_vm::modules::led::pixel_map::{MappedPixel, PixelMap};
+
+// Pixel maps are written as CSV, one logical pixel per line in order, each
+// `physical index, x, y`. Blank lines, lines starting with # and a header
+// line starting with a letter are skipped.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PixelMapError {
+    pub line: usize,
+    pub message: String,
+}
+
+pub fn parse_csv(text: &str) -> Result<PixelMap, PixelMapError> {
+    let mut map = PixelMap::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(char::is_alphabetic) {
+            continue;
+        }
+        let error = |message: String| PixelMapError {
+            line: i + 1,
+            message,
+        };
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [index, x, y] = fields[..] else {
+            return Err(error(format!("Expected 3 fields, found {}", fields.len())));
+        };
+        let parse = |field: &str| {
+            field
+                .parse::<i16>()
+                .map_err(|_| error(format!("Invalid number '{}'", field)))
+        };
+        let index = index
+            .parse::<u16>()
+            .map_err(|_| error(format!("Invalid pixel index '{}'", index)))?;
+        map.pixels.push(MappedPixel {
+            index,
+            x: parse(x)?,
+            y: parse(y)?,
+        });
+    }
+    if map.pixels.len() > u16::MAX as usize {
+        return Err(PixelMapError {
+            line: text.lines().count(),
+            message: "Too many pixels".to_string(),
+        });
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let map = parse_csv("index,x,y\n# Trunk\n2, 0, -1\n\n0,5,3\n").unwrap();
+        assert_eq!(
+            map.pixels,
+            [
+                MappedPixel {
+                    index: 2,
+                    x: 0,
+                    y: -1
+                },
+                MappedPixel {
+                    index: 0,
+                    x: 5,
+                    y: 3
+                },
+            ]
+        );
+        assert_eq!(
+            parse_csv("1,2,3\n1,2\n"),
+            Err(PixelMapError {
+                line: 2,
+                message: "Expected 3 fields, found 2".to_string()
+            })
+        );
+        assert_eq!(parse_csv("-1,0,0").unwrap_err().line, 1);
+    }
+}
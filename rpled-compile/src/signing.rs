@@ -0,0 +1,73 @@
+use ed25519_compact::{KeyPair, Seed};
+use rpled_vm::program::{FLAGS_OFFSET, Program, ProgramError, ProgramFlags};
+
+// Signing keys are stored as their 32-byte seed
+pub type SecretSeed = [u8; 32];
+
+pub fn public_key(seed: &SecretSeed) -> [u8; 32] {
+    *KeyPair::from_seed(Seed::new(*seed)).pk
+}
+
+// Returns a copy of `program` with the SIGNED flag set and an ed25519
+// signature over the result appended. Already-signed programs are re-signed.
+pub fn sign(program: &[u8], seed: &SecretSeed) -> Result<Vec<u8>, ProgramError> {
+    program.validate_program()?;
+    // Version 0 headers have no flags byte to set
+    if program.version()? == 0 {
+        return Err(ProgramError::UnexpectedVersion(0));
+    }
+    let mut signed = program[..program.program_end()?].to_vec();
+    signed[FLAGS_OFFSET] |= ProgramFlags::SIGNED.bits();
+
+    let signature = KeyPair::from_seed(Seed::new(*seed)).sk.sign(&signed, None);
+    signed.extend_from_slice(signature.as_ref());
+    Ok(signed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpled_vm::builder::ProgramBuilder;
+    use rpled_vm::program::verify_signature;
+    use rpled_vm::vm::opcodes;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let mut buf = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Signed").unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+        let seed = [7; 32];
+
+        assert!(matches!(
+            verify_signature(program, &public_key(&seed)),
+            Err(ProgramError::Unsigned)
+        ));
+
+        let mut signed = sign(program, &seed).unwrap();
+        signed.as_slice().validate_program().unwrap();
+        assert_eq!(signed.as_slice().program_end().unwrap(), program.len());
+        verify_signature(&signed, &public_key(&seed)).unwrap();
+        assert!(matches!(
+            verify_signature(&signed, &public_key(&[8; 32])),
+            Err(ProgramError::InvalidSignature)
+        ));
+
+        let code = signed.as_slice().program_start().unwrap() as usize;
+        signed[code] = opcodes::POP;
+        assert!(matches!(
+            verify_signature(&signed, &public_key(&seed)),
+            Err(ProgramError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn test_sign_v0() {
+        let program: &[u8] = &[b'P', b'X', b'S', 0, 0, 0, 3, 0, b'V', b'0', opcodes::HALT];
+        program.validate_program().unwrap();
+        assert!(matches!(
+            sign(program, &[7; 32]),
+            Err(ProgramError::UnexpectedVersion(0))
+        ));
+    }
+}
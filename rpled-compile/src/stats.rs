@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::compress::decompressed_code;
+use rpled_vm::disasm::Instructions;
+use rpled_vm::modules;
+use rpled_vm::program::ProgramError;
+use rpled_vm::vm::opcodes;
+
+// Buckets for the first operand of an op: values that a compact encoding
+// could hold inline, ones that fit in a byte, and the rest
+const OPERAND_BUCKETS: [&str; 3] = ["0-3", "i8", "wider"];
+
+fn operand_bucket(value: i32) -> usize {
+    match value {
+        0..=3 => 0,
+        -128..=127 => 1,
+        _ => 2,
+    }
+}
+
+// Opcode, operand and module usage totals over a corpus of compiled
+// programs, to guide encoding and dispatch optimisation
+#[derive(Default)]
+pub struct Stats {
+    pub programs: usize,
+    pub code_bytes: usize,
+    pub opcodes: BTreeMap<u8, usize>,
+    // Counts of each op's first operand in each of OPERAND_BUCKETS
+    pub operands: BTreeMap<u8, [usize; 3]>,
+    // Keyed by (module opcode offset, function)
+    pub module_calls: BTreeMap<(u8, u8), usize>,
+}
+
+impl Stats {
+    pub fn add(&mut self, program: &[u8]) -> Result<(), ProgramError> {
+        let code = decompressed_code(program)?;
+        self.programs += 1;
+        self.code_bytes += code.len();
+        for instruction in Instructions::new(&code).filter_map(|(_, i)| i.ok()) {
+            *self.opcodes.entry(instruction.opcode).or_default() += 1;
+            let Some(operand) = instruction.operand(0) else {
+                continue;
+            };
+            self.operands.entry(instruction.opcode).or_default()[operand_bucket(operand)] += 1;
+            let module = instruction.opcode & !3;
+            if modules::function_name(module, operand as u8).is_some() {
+                *self
+                    .module_calls
+                    .entry((module, operand as u8))
+                    .or_default() += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{} programs, {} bytes of code",
+            self.programs, self.code_bytes
+        )
+        .unwrap();
+        writeln!(out).unwrap();
+
+        let total: usize = self.opcodes.values().sum();
+        let mut by_count: Vec<_> = self.opcodes.iter().collect();
+        by_count.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(
+            out,
+            "{:<8}{:>8}{:>8}  operands {}",
+            "op",
+            "count",
+            "%",
+            OPERAND_BUCKETS.join("/")
+        )
+        .unwrap();
+        for (&opcode, &count) in by_count {
+            let name = opcodes::name(opcode).unwrap_or("?");
+            let percent = count as f64 * 100.0 / total as f64;
+            write!(out, "{:<8}{:>8}{:>7.1}%", name, count, percent).unwrap();
+            if let Some(buckets) = self.operands.get(&opcode) {
+                let buckets: Vec<String> = buckets.iter().map(usize::to_string).collect();
+                write!(out, "  {}", buckets.join("/")).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+
+        if !self.module_calls.is_empty() {
+            writeln!(out).unwrap();
+            writeln!(out, "module calls:").unwrap();
+            for (&(module, func), &count) in &self.module_calls {
+                let module_name = opcodes::name(module).unwrap_or("?");
+                let module_name = module_name.trim_end_matches('0').to_lowercase();
+                let func_name = modules::function_name(module, func).unwrap_or("?");
+                writeln!(out, "  {}.{:<20}{:>8}", module_name, func_name, count).unwrap();
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpled_vm::builder::ProgramBuilder;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::default();
+        for value in [1, 100, 1000] {
+            let mut buf = [0u8; 64];
+            let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Stats").unwrap();
+            // Wide pushes, as from an encoder without compact forms
+            builder.op_i16(opcodes::PUSH, value).unwrap();
+            builder.op_i16(opcodes::PUSH, 2).unwrap();
+            builder.module_call(opcodes::MATH0, 1, 1).unwrap();
+            builder.op(opcodes::HALT).unwrap();
+            stats.add(builder.finish()).unwrap();
+        }
+
+        assert_eq!(stats.programs, 3);
+        assert_eq!(stats.opcodes[&opcodes::PUSH], 6);
+        assert_eq!(stats.operands[&opcodes::PUSH], [4, 1, 1]);
+        assert_eq!(stats.module_calls[&(opcodes::MATH0, 1)], 3);
+
+        let report = stats.report();
+        assert!(report.starts_with("3 programs, 27 bytes of code\n"));
+        assert!(report.contains("PUSH           6   50.0%  4/1/1\n"));
+        assert!(report.contains("  math.ease_in"));
+    }
+}
@@ -1,3 +1,205 @@
-fn main() {
-    println!("Hello, world!");
+use std::path::Path;
+use std::process::ExitCode;
+
+use rpled_compile::package::Package;
+use rpled_compile::signing::{self, SecretSeed};
+use rpled_vm::vm::MemoryMap;
+
+// Bytes of VM memory on the target board
+const DEFAULT_MEMORY_SIZE: usize = 4096;
+
+const USAGE: &str = "\
+Usage: rpled-compiler <command> [args]
+
+Commands:
+  listing <program>    Print an assembler-style listing of a compiled program
+  verify <program>     Check a compiled program's control flow and stack use
+  dot <program>        Print a Graphviz graph of a compiled program's control flow
+  stats <dir>          Print opcode, operand and module usage across the programs in <dir>
+  map <program> [--memory <bytes>]
+                       Print where a compiled program's code, heap and stack sit in VM memory
+  pack <program> <package> [--description <file>] [--params <file>] [--preview <gif>]
+                       Bundle a compiled program into a .pxpkg package
+  unpack <package> <dir>
+                       Extract the contents of a .pxpkg package into <dir>
+  sign <program> <key> <output>
+                       Sign a compiled program with a 32-byte ed25519 key seed
+  compress <program> <output>
+                       Compress the code of a compiled program, to be decompressed on load
+  public-key <key> <output>
+                       Write the public key for a key seed, for embedding in firmware
+  pixel-map <csv> <output>
+                       Convert a CSV pixel map (index, x, y per pixel) to the table led.load_map reads";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["listing", path] => listing(path),
+        ["verify", path] => verify(path),
+        ["dot", path] => dot(path),
+        ["stats", dir] => stats(dir),
+        ["map", path] => map(path, DEFAULT_MEMORY_SIZE),
+        ["map", path, "--memory", size] => match size.parse() {
+            Ok(size) => map(path, size),
+            Err(_) => Err(format!("Invalid memory size {}", size)),
+        },
+        ["pack", program, package, ref options @ ..] => pack(program, package, options),
+        ["unpack", package, dir] => unpack(package, dir),
+        ["compress", program, output] => compress(program, output),
+        ["sign", program, key, output] => sign(program, key, output),
+        ["public-key", key, output] => public_key(key, output),
+        ["pixel-map", csv, output] => pixel_map(csv, output),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+}
+
+fn read_key(path: &str) -> Result<SecretSeed, String> {
+    read_file(path)?
+        .try_into()
+        .map_err(|_| format!("Key {} must be exactly 32 bytes", path))
+}
+
+fn listing(path: &str) -> Result<(), String> {
+    let program = read_file(path)?;
+    let text = rpled_compile::listing::listing(&program)
+        .map_err(|err| format!("Invalid program {}: {:?}", path, err))?;
+    print!("{}", text);
+    Ok(())
+}
+
+fn verify(path: &str) -> Result<(), String> {
+    let program = read_file(path)?;
+    let code = rpled_compile::compress::decompressed_code(&program)
+        .map_err(|err| format!("Invalid program {}: {:?}", path, err))?;
+    let errors = rpled_compile::cfg::verify(&code);
+    for error in &errors {
+        println!("{:?}", error);
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{}: {} problems found", path, errors.len()))
+    }
+}
+
+fn dot(path: &str) -> Result<(), String> {
+    let program = read_file(path)?;
+    let text = rpled_compile::dot::dot(&program)
+        .map_err(|err| format!("Invalid program {}: {:?}", path, err))?;
+    print!("{}", text);
+    Ok(())
+}
+
+fn stats(dir: &str) -> Result<(), String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|err| format!("Failed to read {}: {}", dir, err))?;
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+    let mut stats = rpled_compile::stats::Stats::default();
+    for path in paths {
+        let program = read_file(&path.to_string_lossy())?;
+        if let Err(err) = stats.add(&program) {
+            eprintln!("Skipping {}: {:?}", path.display(), err);
+        }
+    }
+    print!("{}", stats.report());
+    Ok(())
+}
+
+fn map(path: &str, memory_size: usize) -> Result<(), String> {
+    let program = read_file(path)?;
+    let map = MemoryMap::plan(&program, memory_size)
+        .map_err(|err| format!("Can't load {}: {:?}", path, err))?;
+    print!("{}", map);
+    Ok(())
+}
+
+fn pack(program_path: &str, package_path: &str, options: &[&str]) -> Result<(), String> {
+    let program = read_file(program_path)?;
+    let mut package = Package::new(program)
+        .map_err(|err| format!("Invalid program {}: {:?}", program_path, err))?;
+    for option in options.chunks(2) {
+        let [name, path] = option else {
+            return Err(USAGE.to_string());
+        };
+        let data = read_file(path)?;
+        match *name {
+            "--description" => {
+                let text = String::from_utf8(data)
+                    .map_err(|_| format!("Description {} is not valid UTF-8", path))?;
+                package.description = Some(text);
+            }
+            "--params" => package.params = Some(data),
+            "--preview" => package.preview = Some(data),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    write_file(Path::new(package_path), &package.to_bytes())
+}
+
+fn unpack(package_path: &str, dir: &str) -> Result<(), String> {
+    let data = read_file(package_path)?;
+    let package = Package::from_bytes(&data)
+        .map_err(|err| format!("Invalid package {}: {:?}", package_path, err))?;
+    let dir = Path::new(dir);
+    std::fs::create_dir_all(dir)
+        .map_err(|err| format!("Failed to create {}: {}", dir.display(), err))?;
+    write_file(&dir.join("program.bin"), &package.program)?;
+    if let Some(params) = &package.params {
+        write_file(&dir.join("params.bin"), params)?;
+    }
+    if let Some(description) = &package.description {
+        write_file(&dir.join("description.txt"), description.as_bytes())?;
+    }
+    if let Some(preview) = &package.preview {
+        write_file(&dir.join("preview.gif"), preview)?;
+    }
+    Ok(())
+}
+
+fn compress(program_path: &str, output: &str) -> Result<(), String> {
+    let program = read_file(program_path)?;
+    let compressed = rpled_compile::compress::compress_program(&program)
+        .map_err(|err| format!("Invalid program {}: {:?}", program_path, err))?;
+    write_file(Path::new(output), &compressed)
+}
+
+fn sign(program_path: &str, key_path: &str, output: &str) -> Result<(), String> {
+    let program = read_file(program_path)?;
+    let signed = signing::sign(&program, &read_key(key_path)?)
+        .map_err(|err| format!("Invalid program {}: {:?}", program_path, err))?;
+    write_file(Path::new(output), &signed)
+}
+
+fn public_key(key_path: &str, output: &str) -> Result<(), String> {
+    write_file(
+        Path::new(output),
+        &signing::public_key(&read_key(key_path)?),
+    )
+}
+
+fn pixel_map(csv_path: &str, output: &str) -> Result<(), String> {
+    let text = std::fs::read_to_string(csv_path)
+        .map_err(|err| format!("Failed to read {}: {}", csv_path, err))?;
+    let map = rpled_compile::pixel_map::parse_csv(&text)
+        .map_err(|err| format!("{} line {}: {}", csv_path, err.line, err.message))?;
+    write_file(Path::new(output), &map.to_bytes())
 }
@@ -0,0 +1,47 @@
+/* The C interface to the rpled VM (see rpled-ffi/src/lib.rs).
+ * Generated by `cargo xtask ffi-header`: don't edit.
+ *
+ * Create a VM with rpled_vm_create(), load a compiled program with
+ * rpled_vm_load() and call rpled_vm_run_for() regularly, reading the
+ * shown frame with rpled_vm_get_frame(). Functions returning int32_t give
+ * RPLED_OK, RPLED_INVALID_ARGUMENT or the VM's error code. */
+
+#ifndef RPLED_H
+#define RPLED_H
+
+#include <stdint.h>
+#include <stddef.h>
+
+#define RPLED_OK 0
+
+#define RPLED_HALTED 10
+
+#define RPLED_INVALID_ARGUMENT -1
+
+typedef struct RpledVm RpledVm;
+
+typedef uint64_t (*RpledClock)(void);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+void rpled_set_clock(RpledClock clock);
+
+struct RpledVm *rpled_vm_create(uint16_t num_pixels);
+
+void rpled_vm_destroy(struct RpledVm *vm);
+
+int32_t rpled_vm_load(struct RpledVm *vm, const uint8_t *program, size_t len);
+
+int32_t rpled_vm_run_for(struct RpledVm *vm, uint32_t ops);
+
+int32_t rpled_vm_set_param(struct RpledVm *vm, uint16_t index, int16_t value);
+
+size_t rpled_vm_get_frame(const struct RpledVm *vm, uint8_t *out, size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* RPLED_H */
@@ -0,0 +1,269 @@
+// A C interface to the VM, so firmware written in C or C++ (ESP-IDF
+// projects, say) can embed the interpreter. The firmware creates a VM,
+// loads a compiled program into it, and then calls rpled_vm_run_for()
+// regularly (e.g. from its main loop), reading the shown frame with
+// rpled_vm_get_frame() and sending it to its own LED driver.
+//
+// The VM's waits don't wait here: SLEEP and frame pacing are up to how
+// often the firmware calls rpled_vm_run_for(). The time module reads the
+// clock set with rpled_set_clock(), and msg calls find no other VMs.
+
+// The safety requirements are in each function's comment
+#![allow(clippy::missing_safety_doc)]
+
+use core::future::{Future, ready};
+use core::pin::pin;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use std::sync::Mutex;
+
+use rpled_vm::sync::{Mailbox, Signal, Sync};
+use rpled_vm::vm::{POLL_INTERVAL, VMError};
+use rpled_vm::vm_builder::{Standard4K, VmBuilder};
+
+// Returned by the functions below that can fail; other failures return
+// the VM's error code (see VMError::code), e.g. RPLED_HALTED once the
+// program has stopped
+pub const RPLED_OK: i32 = 0;
+pub const RPLED_HALTED: i32 = 10;
+// A null pointer was passed
+pub const RPLED_INVALID_ARGUMENT: i32 = -1;
+
+pub struct FfiSignal(AtomicBool);
+
+impl Signal for FfiSignal {
+    fn signal(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    fn wait_signal(&self) -> impl Future<Output = ()> {
+        ready(())
+    }
+
+    fn wait_reset(&self) -> impl Future<Output = ()> {
+        ready(())
+    }
+
+    fn is_signaled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub struct NoMailbox;
+
+impl Mailbox for NoMailbox {
+    fn send(&self, _channel: u8, _value: i16) -> bool {
+        false
+    }
+
+    fn recv(&self, _channel: u8) -> Option<i16> {
+        None
+    }
+
+    fn pending(&self, _channel: u8) -> usize {
+        0
+    }
+}
+
+static MAILBOX: NoMailbox = NoMailbox;
+
+// The firmware's clock, in microseconds. Null reads as 0.
+pub type RpledClock = Option<extern "C" fn() -> u64>;
+
+static CLOCK: Mutex<RpledClock> = Mutex::new(None);
+
+pub struct FfiSync;
+
+impl Sync for FfiSync {
+    type Signal = FfiSignal;
+    type Mailbox = NoMailbox;
+
+    fn create_signal() -> FfiSignal {
+        FfiSignal(AtomicBool::new(false))
+    }
+
+    fn delay(_us: u16) -> impl Future<Output = ()> {
+        ready(())
+    }
+
+    fn now_us() -> u64 {
+        let clock = CLOCK.lock().map_or(None, |clock| *clock);
+        clock.map_or(0, |clock| clock())
+    }
+
+    fn mailbox() -> &'static NoMailbox {
+        &MAILBOX
+    }
+}
+
+// FfiSync's futures are always ready, so the VM's never have to wait
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+fn status(result: Result<(), VMError>) -> i32 {
+    match result {
+        Ok(()) => RPLED_OK,
+        Err(err) => err.code() as i32,
+    }
+}
+
+// A VM, opaque to C
+pub struct RpledVm {
+    vm: Standard4K<FfiSync>,
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn rpled_set_clock(clock: RpledClock) {
+    if let Ok(mut current) = CLOCK.lock() {
+        *current = clock;
+    }
+}
+
+// A VM with 4K of memory, driving `num_pixels` LEDs. Free it with
+// rpled_vm_destroy().
+#[unsafe(no_mangle)]
+pub extern "C" fn rpled_vm_create(num_pixels: u16) -> *mut RpledVm {
+    let vm = block_on(
+        VmBuilder::standard_4k()
+            .num_pixels(num_pixels as usize)
+            .build(),
+    );
+    Box::into_raw(Box::new(RpledVm { vm }))
+}
+
+// Safety: `vm` must have come from rpled_vm_create(), and not be used
+// again
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rpled_vm_destroy(vm: *mut RpledVm) {
+    if !vm.is_null() {
+        drop(unsafe { Box::from_raw(vm) });
+    }
+}
+
+// Loads the `len` byte program at `program`, which can be freed once this
+// returns
+//
+// Safety: `vm` must be a live VM and `program` must point to `len` bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rpled_vm_load(vm: *mut RpledVm, program: *const u8, len: usize) -> i32 {
+    let Some(vm) = (unsafe { vm.as_mut() }) else {
+        return RPLED_INVALID_ARGUMENT;
+    };
+    if program.is_null() {
+        return RPLED_INVALID_ARGUMENT;
+    }
+    let program = unsafe { slice::from_raw_parts(program, len) };
+    status(vm.vm.load(program))
+}
+
+// Runs up to `ops` ops, returning RPLED_OK if the program is still
+// running, or the error it stopped with
+//
+// Safety: `vm` must be a live VM
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rpled_vm_run_for(vm: *mut RpledVm, ops: u32) -> i32 {
+    let Some(vm) = (unsafe { vm.as_mut() }) else {
+        return RPLED_INVALID_ARGUMENT;
+    };
+    let vm = &mut vm.vm;
+    status(block_on(async {
+        for op in 0..ops {
+            if op % POLL_INTERVAL == 0 {
+                vm.modules.poll();
+            }
+            vm.step().await?;
+        }
+        Ok(())
+    }))
+}
+
+// Sets parameter `index`, which scripts read as the i16 at heap address
+// index * 2, e.g. a speed or color picked in the firmware's UI
+//
+// Safety: `vm` must be a live VM
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rpled_vm_set_param(vm: *mut RpledVm, index: u16, value: i16) -> i32 {
+    let Some(vm) = (unsafe { vm.as_mut() }) else {
+        return RPLED_INVALID_ARGUMENT;
+    };
+    status(vm.vm.write_heap(index as usize * 2, value))
+}
+
+// Copies the last frame the program showed into `out`, as r, g, b bytes
+// per pixel, up to `len` bytes. Returns the number of pixels in the frame.
+//
+// Safety: `vm` must be a live VM and `out` must point to `len` writable
+// bytes
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rpled_vm_get_frame(vm: *const RpledVm, out: *mut u8, len: usize) -> usize {
+    let Some(vm) = (unsafe { vm.as_ref() }) else {
+        return 0;
+    };
+    let frame = vm.vm.modules.led.output();
+    if !out.is_null() {
+        let out = unsafe { slice::from_raw_parts_mut(out, len) };
+        for (out, channel) in out.iter_mut().zip(frame.iter().flatten()) {
+            *out = *channel;
+        }
+    }
+    frame.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ptr;
+    use rpled_vm::builder::ProgramBuilder;
+    use rpled_vm::vm::opcodes;
+
+    extern "C" fn clock() -> u64 {
+        2_000_000
+    }
+
+    #[test]
+    fn test_ffi() {
+        // Sets pixel 1 to (param 0, 2, 3), shows it and halts
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 2, &[], "Ffi").unwrap();
+        builder.push(3).unwrap();
+        builder.push(2).unwrap();
+        builder.op_u16(opcodes::LOAD, 0).unwrap();
+        builder.push(1).unwrap();
+        builder.module_call(opcodes::LED0, 4, 4).unwrap();
+        builder.module_call(opcodes::LED0, 2, 0).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        unsafe {
+            let vm = rpled_vm_create(2);
+            assert_eq!(rpled_vm_load(vm, program.as_ptr(), program.len()), RPLED_OK);
+            assert_eq!(rpled_vm_set_param(vm, 0, 200), RPLED_OK);
+            assert_eq!(rpled_vm_run_for(vm, 3), RPLED_OK);
+            assert_eq!(rpled_vm_run_for(vm, 100), RPLED_HALTED);
+
+            let mut frame = [0xffu8; 5];
+            assert_eq!(rpled_vm_get_frame(vm, frame.as_mut_ptr(), frame.len()), 2);
+            assert_eq!(frame, [0, 0, 0, 200, 2]);
+            assert_eq!(rpled_vm_set_param(vm, 100, 1), 7);
+            assert_eq!(rpled_vm_load(vm, ptr::null(), 0), RPLED_INVALID_ARGUMENT);
+            rpled_vm_destroy(vm);
+        }
+
+        assert_eq!(FfiSync::now_us(), 0);
+        rpled_set_clock(Some(clock));
+        assert_eq!(FfiSync::now_us(), 2_000_000);
+        rpled_set_clock(None);
+    }
+}
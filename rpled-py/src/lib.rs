@@ -0,0 +1,241 @@
+// Python bindings, for developing effects in notebooks and checking their
+// output in visual regression tests:
+//   frames = rpled.run(program, 100, num_pixels=60)
+// gives a (frames, pixels, 3) uint8 numpy array of the frames the program
+// showed. There's no compile(src) yet, as there's no pixelscript compiler
+// to wrap; programs come from rpled-compile's tools.
+//
+// Runs use a virtual clock that SLEEP moves forward rather than waiting,
+// so they go as fast as the host can run them and give the same frames
+// every time. Each run starts with its own clock and an empty mailbox, and
+// releases the GIL, so other Python threads can run programs alongside it.
+
+use core::cell::{Cell, RefCell};
+use core::future::{Future, ready};
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+
+use numpy::ndarray::Array3;
+use numpy::{IntoPyArray, PyArray3};
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use rpled_vm::modules::led::{MAX_PIXELS, Rgb};
+use rpled_vm::sync::tokio_sync::AsyncSignal;
+use rpled_vm::sync::{Mailbox, Sync, TokioMailbox};
+use rpled_vm::vm::{HaltReason, VMError};
+use rpled_vm::vm_builder::{Standard4K, VmBuilder};
+
+// Runs are the only users of the thread they're on until they finish, so
+// the clock and mailbox are per thread, and reset by each run
+thread_local! {
+    static CLOCK_US: Cell<u64> = const { Cell::new(0) };
+    static MAILBOX: RefCell<TokioMailbox> = const { RefCell::new(TokioMailbox::new()) };
+}
+
+// The run's ops budget when none is given
+pub const DEFAULT_OPS_PER_FRAME: u64 = 100_000;
+
+pub struct SimMailbox;
+
+impl Mailbox for SimMailbox {
+    fn send(&self, channel: u8, value: i16) -> bool {
+        MAILBOX.with_borrow(|mailbox| mailbox.send(channel, value))
+    }
+
+    fn recv(&self, channel: u8) -> Option<i16> {
+        MAILBOX.with_borrow(|mailbox| mailbox.recv(channel))
+    }
+
+    fn pending(&self, channel: u8) -> usize {
+        MAILBOX.with_borrow(|mailbox| mailbox.pending(channel))
+    }
+}
+
+pub struct SimSync;
+
+impl SimSync {
+    // Sets the clock back to 0 and empties the mailbox
+    fn reset() {
+        CLOCK_US.with(|clock| clock.set(0));
+        MAILBOX.set(TokioMailbox::new());
+    }
+}
+
+impl Sync for SimSync {
+    type Signal = AsyncSignal;
+    type Mailbox = SimMailbox;
+
+    fn create_signal() -> AsyncSignal {
+        AsyncSignal::new()
+    }
+
+    fn delay(us: u16) -> impl Future<Output = ()> {
+        CLOCK_US.with(|clock| clock.set(clock.get() + us as u64));
+        ready(())
+    }
+
+    fn now_us() -> u64 {
+        CLOCK_US.with(Cell::get)
+    }
+
+    fn mailbox() -> &'static SimMailbox {
+        &SimMailbox
+    }
+}
+
+// SimSync's futures are always ready, so the VM's never have to wait
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    Vm(VMError),
+    // The program ran this many ops without showing all the frames
+    OutOfOps(u64),
+}
+
+impl From<VMError> for RunError {
+    fn from(err: VMError) -> Self {
+        RunError::Vm(err)
+    }
+}
+
+// Runs `program` until it has shown `frames` frames, or fewer if it halts,
+// failing if that takes more than `max_ops` ops
+pub fn run_frames(
+    program: &[u8],
+    frames: usize,
+    num_pixels: usize,
+    max_ops: u64,
+) -> Result<Vec<Vec<Rgb>>, RunError> {
+    SimSync::reset();
+    block_on(async {
+        let mut vm: Standard4K<SimSync> = VmBuilder::standard_4k()
+            .num_pixels(num_pixels)
+            .capture_frames()
+            .build()
+            .await;
+        vm.load(program)?;
+        let shown =
+            |vm: &Standard4K<SimSync>| vm.modules.led.captured_frames.as_ref().map_or(0, Vec::len);
+        let mut ops = 0;
+        while shown(&vm) < frames {
+            if ops == max_ops {
+                return Err(RunError::OutOfOps(ops));
+            }
+            ops += 1;
+            match vm.step().await {
+                Ok(()) => {}
+                Err(VMError::Halt(HaltReason::HaltOp | HaltReason::Exit(_))) => break,
+                Err(err) => return Err(err.into()),
+            }
+        }
+        let mut captured = vm.modules.led.take_captured_frames();
+        captured.truncate(frames);
+        Ok(captured)
+    })
+}
+
+// max_ops defaults to DEFAULT_OPS_PER_FRAME for each frame, so programs
+// that stop showing frames fail rather than hanging
+#[pyfunction]
+#[pyo3(signature = (program, frames, num_pixels = MAX_PIXELS, max_ops = None))]
+fn run<'py>(
+    py: Python<'py>,
+    program: &[u8],
+    frames: usize,
+    num_pixels: usize,
+    max_ops: Option<u64>,
+) -> PyResult<Bound<'py, PyArray3<u8>>> {
+    let max_ops = max_ops.unwrap_or((frames as u64).saturating_mul(DEFAULT_OPS_PER_FRAME));
+    let captured = py
+        .detach(|| run_frames(program, frames, num_pixels, max_ops))
+        .map_err(|err| match err {
+            RunError::Vm(err) => PyRuntimeError::new_err(format!("Program failed: {:?}", err)),
+            RunError::OutOfOps(ops) => PyRuntimeError::new_err(format!(
+                "Program ran {} ops without showing {} frames",
+                ops, frames
+            )),
+        })?;
+    let pixels = num_pixels.min(MAX_PIXELS);
+    let shape = (captured.len(), pixels, 3);
+    let values = captured.into_iter().flatten().flatten().collect();
+    let array = Array3::from_shape_vec(shape, values)
+        .map_err(|err| PyRuntimeError::new_err(err.to_string()))?;
+    Ok(array.into_pyarray(py))
+}
+
+#[pymodule]
+fn rpled(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(run, module)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rpled_vm::builder::ProgramBuilder;
+    use rpled_vm::vm::opcodes;
+
+    #[test]
+    fn test_run_frames() {
+        // Lights pixel n red, shows it and sleeps 33ms, for n = 0, 1...
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Chase").unwrap();
+        builder.push(0).unwrap();
+        let top = builder.pc();
+        builder.module_call(opcodes::LED0, 1, 0).unwrap();
+        builder.op(opcodes::DUP).unwrap();
+        for value in [0, 0, 255] {
+            builder.push(value).unwrap();
+            builder.op(opcodes::SWAP).unwrap();
+        }
+        builder.module_call(opcodes::LED0, 4, 4).unwrap();
+        builder.module_call(opcodes::LED0, 2, 0).unwrap();
+        builder.push(i16::MAX).unwrap();
+        builder.op(opcodes::SLEEP).unwrap();
+        builder.op(opcodes::INC).unwrap();
+        builder.jump_to(opcodes::JMP, top).unwrap();
+        let program = builder.finish();
+
+        let frames = run_frames(program, 3, 2, 1000).unwrap();
+        assert_eq!(
+            frames,
+            [
+                [[255, 0, 0], [0, 0, 0]],
+                [[0, 0, 0], [255, 0, 0]],
+                [[0, 0, 0], [0, 0, 0]],
+            ]
+        );
+        assert!(SimSync::now_us() >= 2 * i16::MAX as u64);
+
+        assert!(matches!(
+            run_frames(program, 3, 2, 20),
+            Err(RunError::OutOfOps(20))
+        ));
+        assert!(SimSync::now_us() < 2 * i16::MAX as u64);
+    }
+
+    #[test]
+    fn test_run_mailbox_is_per_run() {
+        // Sends 1 on channel 0, then halts
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[72], "Send").unwrap();
+        builder.push(1).unwrap();
+        builder.push(0).unwrap();
+        builder.module_call(opcodes::MSG0, 1, 2).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        run_frames(program, 1, 1, 100).unwrap();
+        assert_eq!(SimSync::mailbox().pending(0), 1);
+        run_frames(program, 1, 1, 100).unwrap();
+        assert_eq!(SimSync::mailbox().pending(0), 1);
+    }
+}
@@ -1,4 +1,29 @@
+use std::env;
+use std::process::Command;
+
 pub fn main() {
     println!("cargo::rerun-if-changed=../testprogs");
     println!("cargo::rerun-if-env-changed=BASE_TEST_DIR");
+
+    // Nightly compilers get the nightly-only language features (the `!`
+    // type), as `cfg(nightly)`
+    println!("cargo::rustc-check-cfg=cfg(nightly)");
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let version = Command::new(rustc).arg("--version").output();
+    if let Ok(version) = version
+        && String::from_utf8_lossy(&version.stdout).contains("nightly")
+    {
+        println!("cargo::rustc-cfg=nightly");
+    }
+
+    // `cfg(modules)` when any module written with define_module! (all but
+    // host) is built in, for the code they share
+    println!("cargo::rustc-check-cfg=cfg(modules)");
+    let modules = ["LED", "MATH", "MSG", "DBG", "TIME", "RAND", "FIXTURES"];
+    if modules
+        .iter()
+        .any(|module| env::var_os(format!("CARGO_FEATURE_{}", module)).is_some())
+    {
+        println!("cargo::rustc-cfg=modules");
+    }
 }
@@ -0,0 +1,167 @@
+use core::ops::Range;
+
+use crate::vm::{VMError, VmDebug};
+
+// A VmDebug hook for debuggers, that watches for conditions that don't
+// depend on knowing addresses in the program: any op from a set (e.g. every
+// LED call), a write to a range of heap addresses, or any error, including
+// ones a TRY goes on to catch. Hooks can't stop the VM, so the debugger
+// checks take_hit() after each op; the hit records the pc of the op that
+// caused it.
+#[derive(Default)]
+pub struct BreakConditions {
+    opcodes: [u8; 32],
+    heap_writes: Option<Range<usize>>,
+    errors: bool,
+    pc: usize,
+    hit: Option<BreakHit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakHit {
+    Opcode { pc: usize, opcode: u8 },
+    HeapWrite { pc: usize, addr: usize },
+    Error { pc: usize, code: u8 },
+}
+
+impl BreakConditions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn break_on_opcode(&mut self, opcode: u8) {
+        if let Some(byte) = self.opcodes.get_mut(opcode as usize / 8) {
+            *byte |= 1 << (opcode % 8);
+        }
+    }
+
+    // Breaks on every call into the module at `module_offset` (e.g.
+    // LED_OPCODE_OFFSET), whatever the number of arguments
+    pub fn break_on_module(&mut self, module_offset: u8) {
+        for opcode in module_offset..module_offset.saturating_add(4) {
+            self.break_on_opcode(opcode);
+        }
+    }
+
+    // Breaks on writes touching heap addresses in `range`
+    pub fn break_on_heap_write(&mut self, range: Range<usize>) {
+        self.heap_writes = Some(range);
+    }
+
+    pub fn break_on_errors(&mut self) {
+        self.errors = true;
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    // The first condition hit since the last call
+    pub fn take_hit(&mut self) -> Option<BreakHit> {
+        self.hit.take()
+    }
+
+    fn record(&mut self, hit: BreakHit) {
+        self.hit.get_or_insert(hit);
+    }
+}
+
+impl VmDebug for BreakConditions {
+    async fn will_run_op(&mut self, pc: usize, opcode: u8) {
+        self.pc = pc;
+        let watched = self
+            .opcodes
+            .get(opcode as usize / 8)
+            .is_some_and(|byte| byte & (1 << (opcode % 8)) != 0);
+        if watched {
+            self.record(BreakHit::Opcode { pc, opcode });
+        }
+    }
+
+    async fn did_run_op(&mut self) {}
+
+    fn will_write_heap(&mut self, addr: usize, len: usize) {
+        if let Some(range) = &self.heap_writes
+            && addr < range.end
+            && addr + len > range.start
+        {
+            self.record(BreakHit::HeapWrite { pc: self.pc, addr });
+        }
+    }
+
+    fn op_failed(&mut self, err: &VMError) {
+        if self.errors && !matches!(err, VMError::Halt(_)) {
+            self.record(BreakHit::Error {
+                pc: self.pc,
+                code: err.code(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::modules::TEST_OPCODE_OFFSET;
+    use crate::sync::TokioSync;
+    use crate::vm::{VM, opcodes};
+    use crate::vm_builder::VmBuilder;
+
+    // Runs until a break condition is hit, returning it
+    async fn run_to_break(vm: &mut VM<256, TokioSync, BreakConditions>) -> Option<BreakHit> {
+        while vm.step().await.is_ok() {
+            if let Some(hit) = vm.debug.take_hit() {
+                return Some(hit);
+            }
+        }
+        vm.debug.take_hit()
+    }
+
+    #[tokio::test]
+    async fn test_break_conditions() {
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Break").unwrap();
+        builder.push(5).unwrap();
+        builder.op_u16(opcodes::STORE, 0).unwrap();
+        builder.push(6).unwrap();
+        builder.op_u16(opcodes::STORE, 4).unwrap();
+        builder.push(1).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.op_i16(opcodes::TRY, 1).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        builder.op(opcodes::POP).unwrap();
+        let program = builder.finish();
+
+        let mut breaks = BreakConditions::new();
+        breaks.break_on_heap_write(3..8);
+        breaks.break_on_module(TEST_OPCODE_OFFSET);
+        breaks.break_on_errors();
+        let mut vm = VmBuilder::<256>::new()
+            .debug(breaks)
+            .load::<TokioSync>(program)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            run_to_break(&mut vm).await,
+            Some(BreakHit::HeapWrite { pc: 7, addr: 4 })
+        );
+        assert_eq!(
+            run_to_break(&mut vm).await,
+            Some(BreakHit::Opcode {
+                pc: 11,
+                opcode: opcodes::TEST1
+            })
+        );
+        // The POP underflows inside the TRY, which catches it
+        assert_eq!(
+            run_to_break(&mut vm).await,
+            Some(BreakHit::Error {
+                pc: 17,
+                code: VMError::StackUnderflow.code()
+            })
+        );
+        assert_eq!(run_to_break(&mut vm).await, None);
+    }
+}
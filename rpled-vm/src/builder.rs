@@ -0,0 +1,260 @@
+use bytemuck::bytes_of;
+
+use crate::program::{
+    CURRENT_VERSION, HEADER_LEN_OFFSET, HeaderPrelude, MAGIC, MAX_STRIPS, PRELUDE_SIZE,
+    ProgramFlags, STRIP_ENTRY_SIZE,
+};
+use crate::vm::opcodes;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BuildError {
+    BufferFull,
+    HeaderTooLong,
+    JumpTooFar,
+}
+
+type Result<T> = core::result::Result<T, BuildError>;
+
+// Writes a complete program (header + code) into a caller-supplied buffer,
+// so that small programs can be generated without an allocator.
+pub struct ProgramBuilder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    code_start: usize,
+}
+
+impl<'a> ProgramBuilder<'a> {
+    pub fn new(buf: &'a mut [u8], heap_size: u16, modules: &[u8], name: &str) -> Result<Self> {
+        Self::with_strips(buf, heap_size, modules, &[], name)
+    }
+
+    // A program driving several strips, each given as (length, color order)
+    pub fn with_strips(
+        buf: &'a mut [u8],
+        heap_size: u16,
+        modules: &[u8],
+        strips: &[(u16, u8)],
+        name: &str,
+    ) -> Result<Self> {
+        let strips_len = match strips.len() {
+            0 => 0,
+            n => 1 + n * STRIP_ENTRY_SIZE,
+        };
+        let header_len = 1 + modules.len() + strips_len + name.len();
+        if header_len > u8::MAX as usize || strips.len() > MAX_STRIPS {
+            return Err(BuildError::HeaderTooLong);
+        }
+        let prelude = HeaderPrelude {
+            magic: *MAGIC,
+            version: CURRENT_VERSION,
+            heap_size,
+            flags: if strips.is_empty() {
+                0
+            } else {
+                ProgramFlags::STRIPS.bits()
+            },
+            header_len: header_len as u8,
+            n_modules: modules.len() as u8,
+        };
+
+        let mut builder = ProgramBuilder {
+            buf,
+            len: 0,
+            code_start: 0,
+        };
+        builder.bytes(bytes_of(&prelude))?;
+        builder.bytes(modules)?;
+        if !strips.is_empty() {
+            builder.bytes(&[strips.len() as u8])?;
+            for (len, color_order) in strips {
+                builder.bytes(&len.to_le_bytes())?;
+                builder.bytes(&[*color_order])?;
+            }
+        }
+        builder.bytes(name.as_bytes())?;
+        debug_assert_eq!(builder.len, header_len + HEADER_LEN_OFFSET as usize);
+        debug_assert!(builder.len >= PRELUDE_SIZE);
+        builder.code_start = builder.len;
+        Ok(builder)
+    }
+
+    // Offset of the next byte to be written, relative to the start of the code
+    // (i.e. the value the VM's pc will have when executing it)
+    pub fn pc(&self) -> usize {
+        self.len - self.code_start
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) -> Result<()> {
+        let end = self.len + data.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(BuildError::BufferFull)?
+            .copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    pub fn u8(&mut self, value: u8) -> Result<()> {
+        self.bytes(&[value])
+    }
+
+    pub fn i16(&mut self, value: i16) -> Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u16(&mut self, value: u16) -> Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn op(&mut self, opcode: u8) -> Result<()> {
+        self.u8(opcode)
+    }
+
+    pub fn op_u8(&mut self, opcode: u8, arg: u8) -> Result<()> {
+        self.u8(opcode)?;
+        self.u8(arg)
+    }
+
+    pub fn op_i16(&mut self, opcode: u8, arg: i16) -> Result<()> {
+        self.u8(opcode)?;
+        self.i16(arg)
+    }
+
+    pub fn op_u16(&mut self, opcode: u8, arg: u16) -> Result<()> {
+        self.u8(opcode)?;
+        self.u16(arg)
+    }
+
+    pub fn op_i8(&mut self, opcode: u8, arg: i8) -> Result<()> {
+        self.u8(opcode)?;
+        self.bytes(&arg.to_le_bytes())
+    }
+
+    // Pushes a constant using the smallest encoding that holds it
+    pub fn push(&mut self, value: i16) -> Result<()> {
+        let (bytes, len) = encode_push(value);
+        bytes.iter().take(len).try_for_each(|byte| self.u8(*byte))
+    }
+
+    // Emits a relative jump or call to `target` (a pc, see pc()), using the
+    // short form of JMP, JZ or JNZ when the target is close enough
+    pub fn jump_to(&mut self, opcode: u8, target: usize) -> Result<()> {
+        let relative = |len: usize| target as isize - (self.pc() + len) as isize;
+        if let Some(short) = short_jump(opcode)
+            && let Ok(offset) = i8::try_from(relative(2))
+        {
+            return self.op_i8(short, offset);
+        }
+        let offset = i16::try_from(relative(3)).map_err(|_| BuildError::JumpTooFar)?;
+        self.op_i16(opcode, offset)
+    }
+
+    // Emits a module call, picking the 0/1/2/N variant from the argument count.
+    // `module` is the module's base opcode (e.g. opcodes::LED0).
+    pub fn module_call(&mut self, module: u8, func: u8, n_args: u8) -> Result<()> {
+        match n_args {
+            0..=2 => self.op_u8(module + n_args, func),
+            _ => {
+                self.op_u8(module + 3, func)?;
+                self.u8(n_args)
+            }
+        }
+    }
+
+    pub fn finish(self) -> &'a [u8] {
+        self.buf.get(..self.len).unwrap_or_default()
+    }
+}
+
+// The smallest encoding of a push of `value`, as (bytes, length)
+pub fn encode_push(value: i16) -> ([u8; 3], usize) {
+    let [lo, hi] = value.to_le_bytes();
+    match value {
+        0 => ([opcodes::ZERO, 0, 0], 1),
+        1 => ([opcodes::PUSH1, 0, 0], 1),
+        2 => ([opcodes::PUSH2, 0, 0], 1),
+        3 => ([opcodes::PUSH3, 0, 0], 1),
+        -128..=127 => ([opcodes::PUSH8, lo, 0], 2),
+        _ => ([opcodes::PUSH, lo, hi], 3),
+    }
+}
+
+// The i8-offset form of a relative jump, if it has one
+pub fn short_jump(opcode: u8) -> Option<u8> {
+    match opcode {
+        opcodes::JMP => Some(opcodes::JMP8),
+        opcodes::JZ => Some(opcodes::JZ8),
+        opcodes::JNZ => Some(opcodes::JNZ8),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::TEST_OPCODE_OFFSET;
+    use crate::program::Program;
+    use crate::sync::TokioSync;
+    use crate::vm::{HaltReason, VMError, make_vm};
+
+    #[tokio::test]
+    async fn test_build_and_run() {
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[TEST_OPCODE_OFFSET], "Built").unwrap();
+        builder.push(7).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.push(1).unwrap();
+        builder.push(2).unwrap();
+        builder.push(3).unwrap();
+        builder.module_call(opcodes::TEST0, 3, 2).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        assert_eq!(program.program_name().unwrap(), "Built");
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load(program).unwrap();
+        let result = vm.run().await;
+        assert!(matches!(result, Err(VMError::Halt(HaltReason::HaltOp))));
+        assert_eq!(
+            vm.modules.test.messages,
+            ["TEST_ONE_ARG: 7", "TEST_TWO_ARGS: 3, 2"]
+        );
+    }
+
+    #[test]
+    fn test_buffer_full() {
+        let mut buf = [0u8; 13];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Tiny").unwrap();
+        assert_eq!(builder.pc(), 0);
+        assert_eq!(builder.push(1), Err(BuildError::BufferFull));
+    }
+
+    #[test]
+    fn test_compact_encodings() {
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Compact").unwrap();
+        builder.push(0).unwrap();
+        builder.push(3).unwrap();
+        builder.push(-100).unwrap();
+        builder.push(1000).unwrap();
+        builder.jump_to(opcodes::JNZ, 0).unwrap();
+        builder.jump_to(opcodes::CALL, 0).unwrap();
+        assert_eq!(
+            builder.jump_to(opcodes::JMP, 40000),
+            Err(BuildError::JumpTooFar)
+        );
+        let program = builder.finish();
+
+        let code = &program[program.program_start().unwrap() as usize..];
+        #[rustfmt::skip]
+        let expected = [
+            opcodes::ZERO,
+            opcodes::PUSH3,
+            opcodes::PUSH8, 0x9c,
+            opcodes::PUSH, 0xe8, 0x03,
+            opcodes::JNZ8, 0xf7,
+            opcodes::CALL, 0xf4, 0xff,
+        ];
+        assert_eq!(code, expected);
+    }
+}
@@ -0,0 +1,206 @@
+// Host only, so free to panic (see lib.rs)
+#![allow(clippy::unwrap_used)]
+
+extern crate std;
+
+use std::fmt::Write;
+use std::string::String;
+use std::time::Instant;
+use std::vec::Vec;
+
+use crate::modules::{self, LED_OPCODE_OFFSET};
+use crate::vm::{VmDebug, opcodes};
+
+// Threads of the trace timeline
+const OPS_TID: u8 = 1;
+const FRAMES_TID: u8 = 2;
+
+struct Event {
+    name: &'static str,
+    cat: &'static str,
+    // Microseconds since the trace started
+    ts: f64,
+    dur: f64,
+    tid: u8,
+    pc: Option<usize>,
+}
+
+// A VmDebug hook recording what the VM spends its time on as Chrome Trace
+// Event JSON, for chrome://tracing or Perfetto. Every op (or with
+// calls_only(), just module calls and sleeps) is a slice on one thread,
+// with module calls named after the function; frames, from one led.show
+// to the next, are slices on a second thread.
+pub struct ChromeTrace {
+    start: Instant,
+    include_ops: bool,
+    events: Vec<Event>,
+    // The op being run: (start, pc, opcode)
+    current: Option<(Instant, usize, u8)>,
+    module_call: Option<(u8, u8)>,
+    frame_start: Option<Instant>,
+}
+
+impl Default for ChromeTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChromeTrace {
+    pub fn new() -> Self {
+        ChromeTrace {
+            start: Instant::now(),
+            include_ops: true,
+            events: Vec::new(),
+            current: None,
+            module_call: None,
+            frame_start: None,
+        }
+    }
+
+    // Leaves out plain ops, which keeps long traces to a manageable size
+    pub fn calls_only() -> Self {
+        ChromeTrace {
+            include_ops: false,
+            ..Self::new()
+        }
+    }
+
+    fn micros(&self, at: Instant) -> f64 {
+        at.duration_since(self.start).as_secs_f64() * 1e6
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"traceEvents\":[\n");
+        for (i, event) in self.events.iter().enumerate() {
+            if i > 0 {
+                out.push_str(",\n");
+            }
+            write!(
+                out,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":1,\"tid\":{}",
+                event.name, event.cat, event.ts, event.dur, event.tid
+            )
+            .unwrap();
+            if let Some(pc) = event.pc {
+                write!(out, ",\"args\":{{\"pc\":{}}}", pc).unwrap();
+            }
+            out.push('}');
+        }
+        out.push_str("\n]}\n");
+        out
+    }
+}
+
+impl VmDebug for ChromeTrace {
+    async fn will_run_op(&mut self, pc: usize, opcode: u8) {
+        self.current = Some((Instant::now(), pc, opcode));
+        self.module_call = None;
+    }
+
+    async fn did_run_op(&mut self) {
+        let end = Instant::now();
+        let Some((start, pc, opcode)) = self.current.take() else {
+            return;
+        };
+        let (name, cat) = match self.module_call.take() {
+            Some((module, func)) => {
+                let name = modules::function_name(module, func).unwrap_or("?");
+                if module == LED_OPCODE_OFFSET && name == "show" {
+                    if let Some(frame_start) = self.frame_start {
+                        self.events.push(Event {
+                            name: "frame",
+                            cat: "frame",
+                            ts: self.micros(frame_start),
+                            dur: self.micros(end) - self.micros(frame_start),
+                            tid: FRAMES_TID,
+                            pc: None,
+                        });
+                    }
+                    self.frame_start = Some(end);
+                }
+                (name, "module")
+            }
+            None if opcode == opcodes::SLEEP => ("SLEEP", "sleep"),
+            None if self.include_ops => (opcodes::name(opcode).unwrap_or("?"), "op"),
+            None => return,
+        };
+        self.events.push(Event {
+            name,
+            cat,
+            ts: self.micros(start),
+            dur: self.micros(end) - self.micros(start),
+            tid: OPS_TID,
+            pc: Some(pc),
+        });
+    }
+
+    fn will_call_module(&mut self, module: u8, func: u8) {
+        self.module_call = Some((module, func));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::sync::TokioSync;
+    use crate::vm_builder::VmBuilder;
+
+    #[tokio::test]
+    async fn test_chrome_trace() {
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Trace").unwrap();
+        builder.push(7).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.push(1).unwrap();
+        builder.op(opcodes::SLEEP).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        let mut vm = VmBuilder::<256>::new()
+            .debug(ChromeTrace::calls_only())
+            .load::<TokioSync>(program)
+            .await
+            .unwrap();
+        let _ = vm.run().await;
+        let names: Vec<_> = vm.debug.events.iter().map(|e| (e.name, e.cat)).collect();
+        assert_eq!(names, [("test_one_arg", "module"), ("SLEEP", "sleep")]);
+
+        let json = vm.debug.to_json();
+        assert!(json.starts_with(
+            "{\"traceEvents\":[\n{\"name\":\"test_one_arg\",\"cat\":\"module\",\"ph\":\"X\",\"ts\":"
+        ));
+        assert!(json.contains("\"tid\":1,\"args\":{\"pc\":5}}\n]}\n"));
+    }
+
+    #[cfg(feature = "led")]
+    #[tokio::test]
+    async fn test_chrome_trace_frames() {
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Frames").unwrap();
+        for _ in 0..3 {
+            builder.module_call(opcodes::LED0, 2, 0).unwrap();
+        }
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        let mut vm = VmBuilder::<256>::new()
+            .debug(ChromeTrace::new())
+            .load::<TokioSync>(program)
+            .await
+            .unwrap();
+        let _ = vm.run().await;
+        let events: Vec<_> = vm.debug.events.iter().map(|e| (e.name, e.tid)).collect();
+        assert_eq!(
+            events,
+            [
+                ("show", OPS_TID),
+                ("frame", FRAMES_TID),
+                ("show", OPS_TID),
+                ("frame", FRAMES_TID),
+                ("show", OPS_TID),
+            ]
+        );
+    }
+}
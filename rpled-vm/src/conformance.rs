@@ -0,0 +1,281 @@
+// Host only, so free to panic (see lib.rs)
+#![allow(clippy::unwrap_used, clippy::expect_used, clippy::indexing_slicing)]
+
+extern crate std;
+
+use crate::builder::ProgramBuilder;
+use crate::fixture_parse::{FixtureError, Result, decode_fixture, error_kind};
+use crate::sync::Sync;
+use crate::vm::{HaltReason, VMError, make_vm, opcodes};
+use std::string::{String, ToString};
+use std::vec::Vec;
+use std::{format, vec};
+
+// The opcode semantics, as data that other implementations of the VM (a C
+// or JS port) can run too, in testprogs/conformance/*.txt. Each case starts
+// the VM with the given stack and heap, runs its ops and then a HALT, and
+// checks what's left. Cases are made of these lines, '#' starting a comment:
+//   case <name>             starts a case
+//   stack <values>...       the stack before the ops, bottom first, as
+//                           i16s or hex u16s
+//   heap <bytes>            the heap, in the fixture's numeric line syntax
+//                           (e.g. '0x05 0 7i16'); empty if not given
+//   op <OPNAME> [args]      an op, as an OP: line in a fixture
+//   expect stack <values>   the whole stack after the ops, bottom first
+//   expect heap <bytes>     the whole heap, including ALLOC'd blocks
+//   expect error <kind>     the error the ops fail with (see error_kind)
+// The heap is exactly the bytes given, so ALLOC's first block is at the
+// heap's length, and any access past it overflows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Case {
+    pub name: String,
+    pub line: usize,
+    pub stack: Vec<i16>,
+    pub heap: Vec<u8>,
+    pub code: Vec<u8>,
+    pub expected: Expected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expected {
+    Halted {
+        stack: Option<Vec<i16>>,
+        heap: Option<Vec<u8>>,
+    },
+    Error(String),
+}
+
+const MEMORY_SIZE: usize = 4096;
+
+fn error<T>(line: usize, message: impl ToString) -> Result<T> {
+    Err(FixtureError {
+        line,
+        message: message.to_string(),
+    })
+}
+
+// Decimal i16s, or hex given as their u16 bits (e.g. 0xffff for -1)
+fn parse_values(values: &str, line: usize) -> Result<Vec<i16>> {
+    values
+        .split_whitespace()
+        .map(|value| {
+            let parsed = match value.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16).map(|bits| bits as i16),
+                None => value.parse(),
+            };
+            parsed.or_else(|_| error(line, format!("Invalid value: {}", value)))
+        })
+        .collect()
+}
+
+// decode_fixture() for a single line, with errors at `line`
+fn decode_line(data: &str, line: usize) -> Result<Vec<u8>> {
+    decode_fixture(data).map_err(|err| FixtureError { line, ..err })
+}
+
+struct PartialCase {
+    name: String,
+    line: usize,
+    stack: Vec<i16>,
+    heap: Vec<u8>,
+    code: Vec<u8>,
+    expected_stack: Option<Vec<i16>>,
+    expected_heap: Option<Vec<u8>>,
+    expected_error: Option<String>,
+}
+
+impl PartialCase {
+    fn finish(self) -> Result<Case> {
+        let expected = match (self.expected_error, self.expected_stack, self.expected_heap) {
+            (Some(kind), None, None) => Expected::Error(kind),
+            (Some(_), _, _) => {
+                return error(self.line, "A case can't expect both an error and a state");
+            }
+            (None, None, None) => return error(self.line, "Case has no expectations"),
+            (None, stack, heap) => Expected::Halted { stack, heap },
+        };
+        Ok(Case {
+            name: self.name,
+            line: self.line,
+            stack: self.stack,
+            heap: self.heap,
+            code: self.code,
+            expected,
+        })
+    }
+}
+
+pub fn parse_suite(data: &str) -> Result<Vec<Case>> {
+    let mut cases = vec![];
+    let mut current: Option<PartialCase> = None;
+    for (index, line) in data.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        if keyword == "case" {
+            if let Some(case) = current.take() {
+                cases.push(case.finish()?);
+            }
+            current = Some(PartialCase {
+                name: rest.to_string(),
+                line: line_number,
+                stack: vec![],
+                heap: vec![],
+                code: vec![],
+                expected_stack: None,
+                expected_heap: None,
+                expected_error: None,
+            });
+            continue;
+        }
+        let Some(case) = current.as_mut() else {
+            return error(line_number, "Expected 'case <name>'");
+        };
+        let expectation = match keyword {
+            "expect" => rest.split_once(' ').unwrap_or((rest, "")),
+            _ => ("", ""),
+        };
+        match (keyword, expectation) {
+            ("stack", _) => case.stack = parse_values(rest, line_number)?,
+            ("heap", _) => case.heap = decode_line(rest, line_number)?,
+            ("op", _) => {
+                let mut op = decode_line(&format!("OP:{}", rest), line_number)?;
+                case.code.append(&mut op);
+            }
+            (_, ("stack", values)) => {
+                case.expected_stack = Some(parse_values(values, line_number)?);
+            }
+            (_, ("heap", bytes)) => case.expected_heap = Some(decode_line(bytes, line_number)?),
+            (_, ("error", kind)) if !kind.trim().is_empty() => {
+                case.expected_error = Some(kind.trim().to_string());
+            }
+            _ => return error(line_number, format!("Unknown line: {}", line)),
+        }
+    }
+    if let Some(case) = current {
+        cases.push(case.finish()?);
+    }
+    Ok(cases)
+}
+
+// Runs `case`, describing how it went wrong if it did
+pub async fn run_case<S: Sync>(case: &Case) -> core::result::Result<(), String> {
+    let mut buf = vec![0u8; case.code.len() + 64];
+    let mut builder = ProgramBuilder::new(&mut buf, case.heap.len() as u16, &[], "Conformance")
+        .map_err(|err| format!("Failed to build program: {:?}", err))?;
+    builder
+        .bytes(&case.code)
+        .and_then(|()| builder.op(opcodes::HALT))
+        .map_err(|err| format!("Failed to build program: {:?}", err))?;
+    let program = builder.finish();
+
+    let mut vm = make_vm::<MEMORY_SIZE, S>().await;
+    vm.load(program)
+        .map_err(|err| format!("Failed to load: {:?}", err))?;
+    // The VM sizes the heap to fit the program, but cases get exactly the
+    // heap they give
+    vm.heap_end = vm.heap_start + case.heap.len();
+    vm.arena_start = vm.heap_end;
+    vm.memory[vm.heap_start..vm.heap_end].copy_from_slice(&case.heap);
+    for value in &case.stack {
+        vm.stack_push(*value)
+            .map_err(|err| format!("Failed to set up the stack: {:?}", err))?;
+    }
+
+    let Err(err) = vm.run().await;
+    let (expected_stack, expected_heap) = match (&case.expected, &err) {
+        (Expected::Error(kind), VMError::Halt(HaltReason::HaltOp)) => {
+            return Err(format!("Expected {}, but halted", kind));
+        }
+        (Expected::Error(kind), err) if error_kind(err) != *kind => {
+            return Err(format!("Expected {}, but failed with {:?}", kind, err));
+        }
+        (Expected::Error(_), _) => return Ok(()),
+        (Expected::Halted { stack, heap }, VMError::Halt(HaltReason::HaltOp)) => (stack, heap),
+        (Expected::Halted { .. }, err) => return Err(format!("Failed with {:?}", err)),
+    };
+
+    // The stack grows down from the byte below the top of memory
+    let stack_bytes = &vm.memory[vm.sp..MEMORY_SIZE - 1];
+    if stack_bytes.len() % 2 != 0 {
+        return Err(format!(
+            "Stack holds an odd number of bytes: {:?}",
+            stack_bytes
+        ));
+    }
+    let stack: Vec<i16> = stack_bytes
+        .chunks_exact(2)
+        .rev()
+        .map(|value| i16::from_le_bytes([value[0], value[1]]))
+        .collect();
+    if let Some(expected) = expected_stack
+        && stack != *expected
+    {
+        return Err(format!("Expected stack {:?}, got {:?}", expected, stack));
+    }
+    let heap = &vm.memory[vm.heap_start..vm.heap_end];
+    if let Some(expected) = expected_heap
+        && heap != expected.as_slice()
+    {
+        return Err(format!("Expected heap {:?}, got {:?}", expected, heap));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::TokioSync;
+    use rstest::*;
+    use std::path::PathBuf;
+
+    #[rstest]
+    #[tokio::test]
+    async fn test_conformance(#[files("../testprogs/conformance/*.txt")] path: PathBuf) {
+        let cases = parse_suite(&std::fs::read_to_string(&path).unwrap())
+            .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        let mut failures = vec![];
+        for case in &cases {
+            if let Err(message) = run_case::<TokioSync>(case).await {
+                failures.push(format!("{} (line {}): {}", case.name, case.line, message));
+            }
+        }
+        assert!(
+            failures.is_empty(),
+            "{}:\n{}",
+            path.display(),
+            failures.join("\n")
+        );
+    }
+
+    #[test]
+    fn test_parse_suite() {
+        let cases =
+            parse_suite("# Adding\ncase add\nstack 1 -2\nop ADD\nexpect stack -1\n").unwrap();
+        assert_eq!(
+            cases,
+            [Case {
+                name: "add".to_string(),
+                line: 2,
+                stack: vec![1, -2],
+                heap: vec![],
+                code: vec![opcodes::ADD],
+                expected: Expected::Halted {
+                    stack: Some(vec![-1]),
+                    heap: None
+                },
+            }]
+        );
+
+        let err = parse_suite("case a\nop NOPE\nexpect stack\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: Unknown opcode: NOPE");
+        let err = parse_suite("case a\nop ADD\ncase b\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: Case has no expectations");
+        let err = parse_suite("stack 1\n").unwrap_err();
+        assert_eq!(err.line, 1);
+    }
+}
@@ -0,0 +1,76 @@
+use crate::vm::{VmDebug, opcodes};
+
+pub const RP2040_CLOCK_HZ: u32 = 125_000_000;
+
+// Cost charged for bytes that aren't a known opcode (the VM will error on them)
+const UNKNOWN_OP_CYCLES: u16 = 10;
+
+// Number of cycles available per frame at the given clock and frame rate
+pub const fn frame_budget_cycles(clock_hz: u32, fps: u32) -> u32 {
+    if fps == 0 {
+        return u32::MAX;
+    }
+    clock_hz / fps
+}
+
+// A VmDebug hook that totals the estimated cost of every op executed, so
+// hosts can compare per-frame cost against frame_budget_cycles().
+#[derive(Default)]
+pub struct CycleCounter {
+    pub total_cycles: u64,
+    pub frame_cycles: u32,
+}
+
+impl CycleCounter {
+    pub const fn new() -> Self {
+        CycleCounter {
+            total_cycles: 0,
+            frame_cycles: 0,
+        }
+    }
+
+    // Returns the cycles spent since the last call, starting a new frame
+    pub fn take_frame_cycles(&mut self) -> u32 {
+        core::mem::take(&mut self.frame_cycles)
+    }
+}
+
+impl VmDebug for CycleCounter {
+    async fn will_run_op(&mut self, _pc: usize, opcode: u8) {
+        let cycles = opcodes::cycle_cost(opcode).unwrap_or(UNKNOWN_OP_CYCLES);
+        self.total_cycles += cycles as u64;
+        self.frame_cycles = self.frame_cycles.saturating_add(cycles as u32);
+    }
+
+    async fn did_run_op(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::sync::TokioSync;
+    use crate::vm_builder::VmBuilder;
+
+    #[tokio::test]
+    async fn test_cycle_counter() {
+        let mut buf = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Cost").unwrap();
+        builder.push(1).unwrap();
+        builder.push(2).unwrap();
+        builder.op(opcodes::ADD).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        let mut vm = VmBuilder::<256>::new()
+            .debug(CycleCounter::new())
+            .load::<TokioSync>(program)
+            .await
+            .unwrap();
+        let _ = vm.run().await;
+        assert_eq!(vm.debug.total_cycles, 20 + 20 + 35 + 10);
+        assert_eq!(vm.debug.take_frame_cycles(), 85);
+        assert_eq!(vm.debug.frame_cycles, 0);
+        assert_eq!(frame_budget_cycles(RP2040_CLOCK_HZ, 50), 2_500_000);
+    }
+}
@@ -0,0 +1,140 @@
+use core::iter::Peekable;
+use core::ops::Range;
+
+use crate::disasm::Instructions;
+use crate::vm::VmDebug;
+
+// A VmDebug hook recording which instruction offsets and which opcodes have
+// been executed. Covers programs of up to BYTES * 8 bytes; ops beyond that
+// are counted by opcode only.
+pub struct Coverage<const BYTES: usize> {
+    offsets: [u8; BYTES],
+    opcodes: [u8; 32],
+}
+
+impl<const BYTES: usize> Default for Coverage<BYTES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn is_set(bits: &[u8], index: usize) -> bool {
+    bits.get(index / 8)
+        .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+}
+
+fn set(bits: &mut [u8], index: usize) {
+    if let Some(byte) = bits.get_mut(index / 8) {
+        *byte |= 1 << (index % 8);
+    }
+}
+
+impl<const BYTES: usize> Coverage<BYTES> {
+    pub const fn new() -> Self {
+        Coverage {
+            offsets: [0; BYTES],
+            opcodes: [0; 32],
+        }
+    }
+
+    pub fn is_executed(&self, offset: usize) -> bool {
+        is_set(&self.offsets, offset)
+    }
+
+    pub fn opcode_executed(&self, opcode: u8) -> bool {
+        is_set(&self.opcodes, opcode as usize)
+    }
+
+    // Adds everything executed under `other`, e.g. to aggregate the coverage
+    // of several runs of one program
+    pub fn merge(&mut self, other: &Self) {
+        for (bits, other) in self.offsets.iter_mut().zip(&other.offsets) {
+            *bits |= other;
+        }
+        for (bits, other) in self.opcodes.iter_mut().zip(&other.opcodes) {
+            *bits |= other;
+        }
+    }
+
+    // Byte ranges of `code` made up of instructions that never ran. Bytes
+    // that don't decode are assumed to be data, and aren't reported.
+    pub fn uncovered<'a>(&'a self, code: &'a [u8]) -> Uncovered<'a, BYTES> {
+        Uncovered {
+            coverage: self,
+            instructions: Instructions::new(code).peekable(),
+        }
+    }
+}
+
+impl<const BYTES: usize> VmDebug for Coverage<BYTES> {
+    async fn will_run_op(&mut self, pc: usize, opcode: u8) {
+        set(&mut self.offsets, pc);
+        set(&mut self.opcodes, opcode as usize);
+    }
+
+    async fn did_run_op(&mut self) {}
+}
+
+pub struct Uncovered<'a, const BYTES: usize> {
+    coverage: &'a Coverage<BYTES>,
+    instructions: Peekable<Instructions<'a>>,
+}
+
+impl<const BYTES: usize> Iterator for Uncovered<'_, BYTES> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        let start = loop {
+            let (offset, result) = self.instructions.next()?;
+            if let Ok(instruction) = result
+                && !self.coverage.is_executed(offset)
+            {
+                break instruction;
+            }
+        };
+        let mut end = start.end();
+        while let Some((offset, Ok(instruction))) = self.instructions.peek() {
+            if self.coverage.is_executed(*offset) {
+                break;
+            }
+            end = instruction.end();
+            self.instructions.next();
+        }
+        Some(start.offset..end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::sync::TokioSync;
+    use crate::vm::opcodes;
+    use crate::vm_builder::VmBuilder;
+
+    #[tokio::test]
+    async fn test_coverage() {
+        let mut buf = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Cover").unwrap();
+        builder.op(opcodes::ZERO).unwrap();
+        builder.op_i16(opcodes::JZ, 2).unwrap();
+        builder.push(1).unwrap();
+        builder.op(opcodes::POP).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        let mut vm = VmBuilder::<256>::new()
+            .debug(Coverage::<4>::new())
+            .load::<TokioSync>(program)
+            .await
+            .unwrap();
+        let _ = vm.run().await;
+        let code = vm.code();
+        assert!(vm.debug.is_executed(0));
+        assert!(vm.debug.opcode_executed(opcodes::JZ));
+        assert!(!vm.debug.opcode_executed(opcodes::PUSH1));
+        let mut uncovered = vm.debug.uncovered(code);
+        assert_eq!(uncovered.next(), Some(4..6));
+        assert_eq!(uncovered.next(), None);
+    }
+}
@@ -0,0 +1,208 @@
+use bytemuck::{Pod, Zeroable, bytes_of, pod_read_unaligned};
+use core::mem::size_of;
+
+use crate::storage::{ProgramStorage, StorageError};
+use crate::sync::Sync;
+use crate::vm::{VM, VMError, VmDebug};
+
+pub const TRACE_LEN: usize = 16;
+pub const HEAP_WINDOW: usize = 32;
+pub const STACK_WINDOW: usize = 16;
+
+const CRASH_MAGIC: [u8; 4] = *b"PXCR";
+
+// A VmDebug hook that remembers the last TRACE_LEN ops executed
+#[derive(Default)]
+pub struct TraceTail {
+    pcs: [u16; TRACE_LEN],
+    opcodes: [u8; TRACE_LEN],
+    next: usize,
+    len: usize,
+}
+
+impl TraceTail {
+    pub const fn new() -> Self {
+        TraceTail {
+            pcs: [0; TRACE_LEN],
+            opcodes: [0; TRACE_LEN],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    // (pc, opcode) pairs, oldest first
+    pub fn entries(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        let start = (self.next + TRACE_LEN - self.len) % TRACE_LEN;
+        self.pcs
+            .iter()
+            .zip(&self.opcodes)
+            .cycle()
+            .skip(start)
+            .take(self.len)
+            .map(|(pc, opcode)| (*pc, *opcode))
+    }
+}
+
+impl VmDebug for TraceTail {
+    async fn will_run_op(&mut self, pc: usize, opcode: u8) {
+        if let Some(slot) = self.pcs.get_mut(self.next) {
+            *slot = pc as u16;
+        }
+        if let Some(slot) = self.opcodes.get_mut(self.next) {
+            *slot = opcode;
+        }
+        self.next = (self.next + 1) % TRACE_LEN;
+        self.len = (self.len + 1).min(TRACE_LEN);
+    }
+
+    async fn did_run_op(&mut self) {}
+}
+
+// A compact, fixed-size description of a VM failure, small enough to be
+// written to storage on device and read back later for diagnosis
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C, packed)]
+pub struct CrashRecord {
+    magic: [u8; 4],
+    // See error_code()
+    pub error: u8,
+    pub error_arg: u16,
+    pub pc: u16,
+    pub sp: u16,
+    pub trace_len: u8,
+    // Oldest first
+    pub trace_pcs: [u16; TRACE_LEN],
+    pub trace_opcodes: [u8; TRACE_LEN],
+    pub heap_start: u16,
+    // The start of the heap, where globals live
+    pub heap: [u8; HEAP_WINDOW],
+    // The top STACK_WINDOW bytes of the stack, starting at sp
+    pub stack: [u8; STACK_WINDOW],
+}
+
+pub const RECORD_SIZE: usize = size_of::<CrashRecord>();
+
+// Error number (VMError::code()) and argument stored in a crash record
+pub fn error_code(err: &VMError) -> (u8, u16) {
+    let arg = match err {
+        VMError::PCOverflow(pc) => *pc,
+        VMError::InvalidOpcode(opcode, _) | VMError::ModuleNotEnabled(opcode) => *opcode as u16,
+        _ => 0,
+    };
+    (err.code(), arg)
+}
+
+impl CrashRecord {
+    pub fn capture<const N: usize, S: Sync, D: VmDebug>(
+        vm: &VM<N, S, D>,
+        err: &VMError,
+        trace: &TraceTail,
+    ) -> Self {
+        let (error, error_arg) = error_code(err);
+        let mut trace_pcs = [0; TRACE_LEN];
+        let mut trace_opcodes = [0; TRACE_LEN];
+        for ((pc_slot, opcode_slot), (pc, opcode)) in trace_pcs
+            .iter_mut()
+            .zip(&mut trace_opcodes)
+            .zip(trace.entries())
+        {
+            *pc_slot = pc;
+            *opcode_slot = opcode;
+        }
+        let mut record = CrashRecord {
+            magic: CRASH_MAGIC,
+            error,
+            error_arg,
+            pc: vm.pc as u16,
+            sp: vm.sp as u16,
+            trace_len: trace.len as u8,
+            trace_pcs,
+            trace_opcodes,
+            heap_start: vm.heap_start as u16,
+            heap: [0; HEAP_WINDOW],
+            stack: [0; STACK_WINDOW],
+        };
+        copy_window(&mut record.heap, &vm.memory, vm.heap_start);
+        copy_window(&mut record.stack, &vm.memory, vm.sp);
+        record
+    }
+
+    pub async fn save<P: ProgramStorage>(
+        &self,
+        storage: &mut P,
+        slot: usize,
+    ) -> Result<(), StorageError> {
+        storage.erase(slot).await?;
+        storage.write(slot, 0, bytes_of(self)).await
+    }
+
+    // The record saved in `slot`, if there is one
+    pub async fn load<P: ProgramStorage>(
+        storage: &mut P,
+        slot: usize,
+    ) -> Result<Option<Self>, StorageError> {
+        let mut bytes = [0u8; RECORD_SIZE];
+        storage.read(slot, 0, &mut bytes).await?;
+        let record: CrashRecord = pod_read_unaligned(&bytes);
+        Ok((record.magic == CRASH_MAGIC).then_some(record))
+    }
+}
+
+fn copy_window(window: &mut [u8], memory: &[u8], start: usize) {
+    let available = memory.get(start..).unwrap_or(&[]);
+    for (byte, value) in window.iter_mut().zip(available) {
+        *byte = *value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::storage::MemoryStorage;
+    use crate::sync::TokioSync;
+    use crate::vm::opcodes;
+    use crate::vm_builder::VmBuilder;
+
+    #[tokio::test]
+    async fn test_capture_and_save() {
+        let mut buf = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Crash").unwrap();
+        builder.push(5).unwrap();
+        builder.op_u16(opcodes::STORE, 0).unwrap();
+        builder.push(1).unwrap();
+        builder.op(opcodes::ZERO).unwrap();
+        builder.op(opcodes::DIV).unwrap();
+        let program = builder.finish();
+
+        let mut vm = VmBuilder::<256>::new()
+            .trace()
+            .load::<TokioSync>(program)
+            .await
+            .unwrap();
+        let Err(err) = vm.run().await;
+        let record = CrashRecord::capture(&vm, &err, &vm.debug);
+
+        let mut storage = MemoryStorage::<1, 256>::default();
+        assert!(CrashRecord::load(&mut storage, 0).await.unwrap().is_none());
+        record.save(&mut storage, 0).await.unwrap();
+        let loaded = CrashRecord::load(&mut storage, 0).await.unwrap().unwrap();
+
+        assert_eq!(loaded.error, 8);
+        assert_eq!(loaded.trace_len, 5);
+        let trace_opcodes = loaded.trace_opcodes;
+        assert_eq!(
+            trace_opcodes[..5],
+            [
+                opcodes::PUSH8,
+                opcodes::STORE,
+                opcodes::PUSH1,
+                opcodes::ZERO,
+                opcodes::DIV
+            ]
+        );
+        let trace_pcs = loaded.trace_pcs;
+        assert_eq!(trace_pcs[..5], [0, 2, 5, 6, 7]);
+        assert_eq!(loaded.heap[..2], 5i16.to_le_bytes());
+    }
+}
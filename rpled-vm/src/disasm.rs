@@ -0,0 +1,153 @@
+use core::fmt;
+
+use crate::modules;
+use crate::vm::opcodes::{self, Operand};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownOpcode(u8),
+    Truncated,
+}
+
+type Result<T> = core::result::Result<T, DecodeError>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Instruction<'a> {
+    pub offset: usize,
+    pub opcode: u8,
+    pub name: &'static str,
+    pub operands: &'static [Operand],
+    pub bytes: &'a [u8],
+}
+
+impl Instruction<'_> {
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Offset of the instruction that follows this one
+    pub fn end(&self) -> usize {
+        self.offset + self.size()
+    }
+
+    pub fn operand(&self, index: usize) -> Option<i32> {
+        let kind = *self.operands.get(index)?;
+        let start = 1 + self
+            .operands
+            .iter()
+            .take(index)
+            .map(|o| o.size())
+            .sum::<usize>();
+        Some(match (kind, self.bytes.get(start..start + kind.size())?) {
+            (Operand::U8, [byte]) => *byte as i32,
+            (Operand::I8, [byte]) => *byte as i8 as i32,
+            (Operand::U16, [lo, hi]) => u16::from_le_bytes([*lo, *hi]) as i32,
+            (Operand::I16, [lo, hi]) => i16::from_le_bytes([*lo, *hi]) as i32,
+            _ => return None,
+        })
+    }
+
+    pub fn is_relative_jump(&self) -> bool {
+        matches!(
+            self.opcode,
+            opcodes::JMP
+                | opcodes::JZ
+                | opcodes::JNZ
+                | opcodes::JMP8
+                | opcodes::JZ8
+                | opcodes::JNZ8
+                | opcodes::CALL
+                | opcodes::CALLZ
+                | opcodes::CALLNZ
+                | opcodes::TRY
+        )
+    }
+
+    // Values (popped, pushed), including ops whose effect depends on their
+    // operands. None for calls to module functions that aren't known.
+    pub fn stack_effect(&self) -> Option<(u8, u8)> {
+        if self.opcode == opcodes::POPN {
+            return Some((self.operand(0)? as u8, 0));
+        }
+        if let Some(effect) = opcodes::stack_effect(self.opcode) {
+            return Some(effect);
+        }
+        // A module call: the variant is the argument count, apart from N,
+        // which has it as a second operand
+        let n_args = match self.opcode & 3 {
+            3 => self.operand(1)? as u8,
+            variant => variant,
+        };
+        let results = modules::call_results(self.opcode & !3, self.operand(0)? as u8)?;
+        Some((n_args, results))
+    }
+
+    // Destination of a relative jump or call, which is relative to the next
+    // instruction. None for other ops or targets before the program start.
+    pub fn jump_target(&self) -> Option<usize> {
+        if !self.is_relative_jump() {
+            return None;
+        }
+        let target = self.end() as isize + self.operand(0)? as isize;
+        usize::try_from(target).ok()
+    }
+}
+
+impl fmt::Display for Instruction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)?;
+        for index in 0..self.operands.len() {
+            let separator = if index == 0 { " " } else { ", " };
+            let operand = self.operand(index).ok_or(fmt::Error)?;
+            write!(f, "{}{}", separator, operand)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn decode(code: &[u8], offset: usize) -> Result<Instruction<'_>> {
+    let opcode = *code.get(offset).ok_or(DecodeError::Truncated)?;
+    let name = opcodes::name(opcode).ok_or(DecodeError::UnknownOpcode(opcode))?;
+    let operands = opcodes::operands(opcode).unwrap_or(&[]);
+    let len = 1 + operands.iter().map(|o| o.size()).sum::<usize>();
+    let bytes = code
+        .get(offset..offset + len)
+        .ok_or(DecodeError::Truncated)?;
+    Ok(Instruction {
+        offset,
+        opcode,
+        name,
+        operands,
+        bytes,
+    })
+}
+
+// Linear sweep over a code section. Undecodable bytes are yielded as errors
+// and skipped one at a time, so embedded data doesn't stop the sweep.
+pub struct Instructions<'a> {
+    code: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Instructions<'a> {
+    pub fn new(code: &'a [u8]) -> Self {
+        Instructions { code, offset: 0 }
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = (usize, Result<Instruction<'a>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.code.len() {
+            return None;
+        }
+        let offset = self.offset;
+        let result = decode(self.code, offset);
+        self.offset = match &result {
+            Ok(instruction) => instruction.end(),
+            Err(_) => offset + 1,
+        };
+        Some((offset, result))
+    }
+}
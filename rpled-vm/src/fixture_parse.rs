@@ -1,31 +1,253 @@
-use crate::sync::Sync;
-use crate::vm::{NoVmDebug, VM};
+// Host only, so free to panic (see lib.rs)
+#![allow(clippy::unwrap_used, clippy::expect_used, clippy::indexing_slicing)]
+
+extern crate std;
+
+use crate::builder::ProgramBuilder;
+use crate::modules::TEST_OPCODE_OFFSET;
+use crate::vm::opcodes;
+use core::fmt;
 use regex::{Regex, RegexSet};
+use std::collections::BTreeMap;
+use std::string::{String, ToString};
 use std::vec::Vec;
+use std::{format, vec};
 
 const OUTPUT_SEPARATOR: &str = "=== OUTPUT ===";
+const CHANNELS_SEPARATOR: &str = "=== CHANNELS ===";
+const ERROR_SEPARATOR: &str = "=== EXPECT ERROR ===";
+const FRAMES_SEPARATOR_RE: &str = r"(?m)^=== FRAMES(?: tolerance=(?<tolerance>\d+))? ===$";
+
+// A problem with a fixture file, at a 1-based line number
+#[derive(Debug, PartialEq, Eq)]
+pub struct FixtureError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, FixtureError>;
+
+fn error<T>(line: usize, message: impl ToString) -> Result<T> {
+    Err(FixtureError {
+        line,
+        message: message.to_string(),
+    })
+}
 
+// 1-based line number of the line that `offset` (a byte offset into `data`) is on
+fn line_at(data: &str, offset: usize) -> usize {
+    data[..offset].lines().count() + 1
+}
+
+// Fixtures have the program, then any of these sections, in this order:
+//   === CHANNELS ===      values expected on each test_emit channel
+//   === EXPECT ERROR ===  the error the program should fail with
+//   === OUTPUT ===        test module messages, then how the run ended
+// Every fixture needs an OUTPUT or EXPECT ERROR section.
 pub struct ParsedFixture {
     pub program: Vec<u8>,
-    pub expected_output: String,
+    pub expected_output: Option<String>,
+    pub expected_channels: Option<BTreeMap<u8, Vec<i16>>>,
+    pub expected_error: Option<ExpectedError>,
+}
+
+// An EXPECT ERROR section is a single line: the error's kind (see
+// error_kind), optionally followed by where it happened, either '@load'
+// or '@' and the pc of the failing op, e.g. 'StackUnderflow@0x12'
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExpectedError {
+    pub kind: String,
+    pub location: Option<ErrorLocation>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorLocation {
+    Load,
+    Pc(usize),
+}
+
+// The variant name of an error, and of the error or reason it wraps, if
+// any: 'StackUnderflow', 'ProgramError::TooShort', 'Halt::Exit'
+pub fn error_kind(err: &impl fmt::Debug) -> String {
+    let debug = format!("{:?}", err);
+    let name_end = |s: &str| s.find(|c: char| !c.is_alphanumeric()).unwrap_or(s.len());
+    let name = &debug[..name_end(&debug)];
+    match debug[name.len()..].strip_prefix('(') {
+        Some(inner) if inner.starts_with(char::is_uppercase) => {
+            format!("{}::{}", name, &inner[..name_end(inner)])
+        }
+        _ => name.to_string(),
+    }
+}
+
+fn parse_expected_error(section: &str, first_line: usize) -> Result<ExpectedError> {
+    let lines: Vec<(usize, &str)> = section
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (first_line + index, line.split('#').next().unwrap().trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect();
+    let [(line_number, line)] = lines[..] else {
+        return error(first_line, "Expected one line in '=== EXPECT ERROR ==='");
+    };
+    let (kind, location) = match line.split_once('@') {
+        Some((kind, "load")) => (kind, Some(ErrorLocation::Load)),
+        Some((kind, pc)) => {
+            let parsed = match pc.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => pc.parse(),
+            };
+            let Ok(pc) = parsed else {
+                return error(line_number, format!("Invalid error location: {}", pc));
+            };
+            (kind, Some(ErrorLocation::Pc(pc)))
+        }
+        None => (line, None),
+    };
+    Ok(ExpectedError {
+        kind: kind.trim().to_string(),
+        location,
+    })
+}
+
+pub struct ParsedFrameFixture {
+    pub program: Vec<u8>,
+    pub expected_frames: Vec<Vec<[u8; 3]>>,
+    pub tolerance: u8,
 }
 
-pub fn parse_fixture_with_output(data: &str) -> ParsedFixture {
-    let (program_section, output_section) = data
-        .rsplit_once(OUTPUT_SEPARATOR)
-        .expect("Fixture must contain '=== OUTPUT ===' separator");
+pub fn parse_fixture_with_output(data: &str) -> Result<ParsedFixture> {
+    let (program_section, output_section) = match data.rsplit_once(OUTPUT_SEPARATOR) {
+        Some((program_section, output_section)) => (program_section, Some(output_section)),
+        None => (data, None),
+    };
+    let (program_section, expected_error) = match program_section.split_once(ERROR_SEPARATOR) {
+        Some((program_section, error_section)) => {
+            let first_line = line_at(data, program_section.len());
+            (
+                program_section,
+                Some(parse_expected_error(error_section, first_line)?),
+            )
+        }
+        None => (program_section, None),
+    };
+    if output_section.is_none() && expected_error.is_none() {
+        return error(
+            data.lines().count(),
+            "Fixture must contain '=== OUTPUT ===' or '=== EXPECT ERROR ===' separator",
+        );
+    }
+
+    let (program_section, expected_channels) = match program_section.split_once(CHANNELS_SEPARATOR)
+    {
+        Some((program_section, channels_section)) => {
+            let first_line = line_at(data, program_section.len());
+            (
+                program_section,
+                Some(parse_channels(channels_section, first_line)?),
+            )
+        }
+        None => (program_section, None),
+    };
 
-    ParsedFixture {
-        program: decode_fixture(program_section),
+    Ok(ParsedFixture {
+        program: decode_fixture(program_section)?,
+        expected_channels,
+        expected_error,
         expected_output: output_section
-            .trim()
-            .lines()
-            .collect::<Vec<&str>>()
-            .join("\n"),
+            .map(|output| output.trim().lines().collect::<Vec<&str>>().join("\n")),
+    })
+}
+
+// One line per channel, e.g. '1: 0 -5 12'. `first_line` is the line number
+// of the separator.
+fn parse_channels(section: &str, first_line: usize) -> Result<BTreeMap<u8, Vec<i16>>> {
+    let mut channels = BTreeMap::new();
+    for (index, line) in section.lines().enumerate() {
+        let line_number = first_line + index;
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some((channel, values)) = line.split_once(':') else {
+            return error(line_number, "Expected '<channel>: <values>'");
+        };
+        let Ok(channel) = channel.trim().parse() else {
+            return error(line_number, format!("Invalid channel: {}", channel.trim()));
+        };
+        let values = values
+            .split_whitespace()
+            .map(|value| {
+                value
+                    .parse()
+                    .or_else(|_| error(line_number, format!("Invalid value: {}", value)))
+            })
+            .collect::<Result<_>>()?;
+        channels.insert(channel, values);
     }
+    Ok(channels)
 }
 
-pub fn decode_fixture(data: &str) -> Vec<u8> {
+pub fn parse_fixture_with_frames(data: &str) -> Result<ParsedFrameFixture> {
+    // Frame fixtures end with a '=== FRAMES ===' section containing one line per
+    // expected frame, each a space-separated list of RRGGBB hex pixel colors.
+    let separator = Regex::new(FRAMES_SEPARATOR_RE).unwrap();
+    let Some(captures) = separator.captures(data) else {
+        return error(
+            data.lines().count(),
+            "Fixture must contain '=== FRAMES ===' separator",
+        );
+    };
+    let separator_match = captures.get(0).unwrap();
+    let separator_line = line_at(data, separator_match.start());
+    let tolerance = match captures.name("tolerance") {
+        Some(t) => match t.as_str().parse() {
+            Ok(tolerance) => tolerance,
+            Err(_) => return error(separator_line, "Failed to parse frame tolerance"),
+        },
+        None => 0,
+    };
+
+    let expected_frames = data[separator_match.end()..]
+        .lines()
+        .enumerate()
+        .map(|(index, line)| {
+            (
+                separator_line + index,
+                line.split('#').next().unwrap().trim(),
+            )
+        })
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(line_number, line)| {
+            line.split_whitespace()
+                .map(|token| parse_rgb(token).or_else(|message| error(line_number, message)))
+                .collect()
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(ParsedFrameFixture {
+        program: decode_fixture(&data[..separator_match.start()])?,
+        expected_frames,
+        tolerance,
+    })
+}
+
+fn parse_rgb(token: &str) -> core::result::Result<[u8; 3], String> {
+    let value = u32::from_str_radix(token, 16)
+        .ok()
+        .filter(|_| token.len() == 6)
+        .ok_or_else(|| format!("Failed to parse RRGGBB color: {}", token))?;
+    let [_, r, g, b] = value.to_be_bytes();
+    Ok([r, g, b])
+}
+
+pub fn decode_fixture(data: &str) -> Result<Vec<u8>> {
     // Each line is either:
     // - A blank line
     // - A double quote followed by characters (utf-8), ending with a double quote
@@ -33,7 +255,7 @@ pub fn decode_fixture(data: &str) -> Vec<u8> {
     //   - Hex values start with '0x' or '0X'
     //   - Hex values default to u8, or i16 if exactly 4 hex digits
     //   - Decimal values default to u8
-    //   - Each value can have 'u8', 'u16', or 'i16' suffixes to indicate size and signedness
+    //   - Each value can have 'u8', 'i8', 'u16', or 'i16' suffixes to indicate size and signedness
     // - OP:<OPNAME> [comma-separated arguments] - an opcode by name with optional arguments
     //   - Arguments can be hex (0xNN) or decimal numbers
     // - HEADER(XX) where XX is the heap size - expands to a valid header section
@@ -44,7 +266,7 @@ pub fn decode_fixture(data: &str) -> Vec<u8> {
     let mut result: Vec<u8> = Vec::new();
 
     let quote_line_re = r#"^\s*"(?<quote>.*)"\s*(#.*)?$"#;
-    let num_line_re = r"^(?<num>((0x|0X)?-?[0-9a-fA-F]+(u8|u16|i16)?\s*)+)(#.*)?$";
+    let num_line_re = r"^(?<num>((0x|0X)?-?[0-9a-fA-F]+(u8|i8|u16|i16)?\s*)+)(#.*)?$";
     let header_line_re = r"^\s*HEADER\((?<heap>\d+)\)\s*(#.*)?$";
     let op_line_re = r"^\s*OP:(?<opname>[A-Z0-9]+)\s*(?<args>[^#]*)(#.*)?$";
     let blank_line_re = r"^\s*(#.*)?$";
@@ -64,14 +286,22 @@ pub fn decode_fixture(data: &str) -> Vec<u8> {
 
     let line_set = RegexSet::new(patterns).unwrap();
 
-    for line in data.lines() {
+    for (index, line) in data.lines().enumerate() {
+        let line_number = index + 1;
         let matches = line_set.matches(line);
         let match_idx = match matches.iter().next() {
             Some(idx) => idx,
             None => {
-                panic!("Line did not match any known pattern: {}", line);
+                return error(
+                    line_number,
+                    format!("Line did not match any known pattern: {}", line),
+                );
             }
         };
+        let at_line = |message| FixtureError {
+            line: line_number,
+            message,
+        };
         let capture = res[match_idx].captures(line).unwrap();
         if let Some(quote) = capture.name("quote") {
             let s = quote.as_str();
@@ -79,61 +309,49 @@ pub fn decode_fixture(data: &str) -> Vec<u8> {
         }
         if let Some(num) = capture.name("num") {
             let s = num.as_str();
-            let mut num_bytes = num_line_to_vec(s);
+            let mut num_bytes = num_line_to_vec(s).map_err(at_line)?;
             result.append(&mut num_bytes);
         }
         if let Some(heap) = capture.name("heap") {
-            let heap_size: u16 = heap.as_str().parse().expect("Failed to parse heap size");
+            let Ok(heap_size) = heap.as_str().parse() else {
+                return error(line_number, "Failed to parse heap size");
+            };
             let mut header_bytes = generate_header(heap_size);
             result.append(&mut header_bytes);
         }
         if let Some(opname) = capture.name("opname") {
             let op_str = opname.as_str();
-            let opcode = opcode_by_name::<crate::sync::TokioSync>(op_str)
-                .unwrap_or_else(|| panic!("Unknown opcode: {}", op_str));
+            let Some(opcode) = opcodes::by_name(op_str) else {
+                return error(line_number, format!("Unknown opcode: {}", op_str));
+            };
             result.push(opcode);
 
             if let Some(args) = capture.name("args") {
                 let args_str = args.as_str().trim();
                 if !args_str.is_empty() {
-                    let mut arg_bytes = parse_op_args(args_str);
+                    let mut arg_bytes = parse_op_args(args_str).map_err(at_line)?;
                     result.append(&mut arg_bytes);
                 }
             }
         }
     }
-    result
+    Ok(result)
 }
 
 fn generate_header(heap_size: u16) -> Vec<u8> {
-    let mut result = Vec::new();
-
-    // Magic bytes: "PXS"
-    result.extend_from_slice(b"PXS");
-
-    // Version: 0
-    result.push(0);
-
-    // Heap size (u16, little-endian)
-    result.extend_from_slice(&heap_size.to_le_bytes());
-
-    // Remaining header length: 1 (num_modules) + 1 (module id) + 2 ("T1")
-    result.push(4);
-
-    // Number of modules: 1
-    result.push(1);
-
-    // Module: Test (60) with name "T1"
-    result.push(60);
-    result.extend_from_slice(b"T1");
-
-    result
+    // A header requiring just the Test module, with the name "T1"
+    let mut buf = [0u8; 16];
+    let builder = ProgramBuilder::new(&mut buf, heap_size, &[TEST_OPCODE_OFFSET], "T1")
+        .expect("Failed to build fixture header");
+    builder.finish().to_vec()
 }
 
-fn parse_number(token: &str) -> Vec<u8> {
+fn parse_number(token: &str) -> core::result::Result<Vec<u8>, String> {
     // Extract suffix if present
     let (num_str, suffix) = if let Some(stripped) = token.strip_suffix("u8") {
         (stripped, Some("u8"))
+    } else if let Some(stripped) = token.strip_suffix("i8") {
+        (stripped, Some("i8"))
     } else if let Some(stripped) = token.strip_suffix("u16") {
         (stripped, Some("u16"))
     } else if let Some(stripped) = token.strip_suffix("i16") {
@@ -144,6 +362,7 @@ fn parse_number(token: &str) -> Vec<u8> {
 
     // Determine if hex or decimal
     let is_hex = num_str.starts_with("0x") || num_str.starts_with("0X");
+    let fail = |kind: &str| format!("Failed to parse {}: {}", kind, num_str);
 
     if is_hex {
         let hex_str = &num_str[2..];
@@ -154,21 +373,22 @@ fn parse_number(token: &str) -> Vec<u8> {
 
         match actual_type {
             "u8" => {
-                let value = u8::from_str_radix(hex_str, 16)
-                    .unwrap_or_else(|_| panic!("Failed to parse hex u8: {}", num_str));
-                vec![value]
+                let value = u8::from_str_radix(hex_str, 16).map_err(|_| fail("hex u8"))?;
+                Ok(vec![value])
+            }
+            "i8" => {
+                let value = i8::from_str_radix(hex_str, 16).map_err(|_| fail("hex i8"))?;
+                Ok(value.to_le_bytes().to_vec())
             }
             "u16" => {
-                let value = u16::from_str_radix(hex_str, 16)
-                    .unwrap_or_else(|_| panic!("Failed to parse hex u16: {}", num_str));
-                value.to_le_bytes().to_vec()
+                let value = u16::from_str_radix(hex_str, 16).map_err(|_| fail("hex u16"))?;
+                Ok(value.to_le_bytes().to_vec())
             }
             "i16" => {
-                let value = i16::from_str_radix(hex_str, 16)
-                    .unwrap_or_else(|_| panic!("Failed to parse hex i16: {}", num_str));
-                value.to_le_bytes().to_vec()
+                let value = i16::from_str_radix(hex_str, 16).map_err(|_| fail("hex i16"))?;
+                Ok(value.to_le_bytes().to_vec())
             }
-            _ => panic!("Unknown suffix: {}", actual_type),
+            _ => Err(format!("Unknown suffix: {}", actual_type)),
         }
     } else {
         // Decimal parsing
@@ -176,48 +396,36 @@ fn parse_number(token: &str) -> Vec<u8> {
 
         match actual_type {
             "u8" => {
-                let value: u8 = num_str
-                    .parse()
-                    .unwrap_or_else(|_| panic!("Failed to parse decimal u8: {}", num_str));
-                vec![value]
+                let value: u8 = num_str.parse().map_err(|_| fail("decimal u8"))?;
+                Ok(vec![value])
+            }
+            "i8" => {
+                let value: i8 = num_str.parse().map_err(|_| fail("decimal i8"))?;
+                Ok(value.to_le_bytes().to_vec())
             }
             "u16" => {
-                let value: u16 = num_str
-                    .parse()
-                    .unwrap_or_else(|_| panic!("Failed to parse decimal u16: {}", num_str));
-                value.to_le_bytes().to_vec()
+                let value: u16 = num_str.parse().map_err(|_| fail("decimal u16"))?;
+                Ok(value.to_le_bytes().to_vec())
             }
             "i16" => {
-                let value: i16 = num_str
-                    .parse()
-                    .unwrap_or_else(|_| panic!("Failed to parse decimal i16: {}", num_str));
-                value.to_le_bytes().to_vec()
+                let value: i16 = num_str.parse().map_err(|_| fail("decimal i16"))?;
+                Ok(value.to_le_bytes().to_vec())
             }
-            _ => panic!("Unknown suffix: {}", actual_type),
+            _ => Err(format!("Unknown suffix: {}", actual_type)),
         }
     }
 }
 
-fn num_line_to_vec(line: &str) -> Vec<u8> {
+fn num_line_to_vec(line: &str) -> core::result::Result<Vec<u8>, String> {
     let mut result: Vec<u8> = Vec::new();
     let tokens = line.split_whitespace();
     for token in tokens {
-        result.extend_from_slice(&parse_number(token));
-    }
-    result
-}
-
-fn opcode_by_name<S: Sync>(name: &str) -> Option<u8> {
-    let opcodes = VM::<0, S, NoVmDebug>::opcode_names();
-    for (code, op_name) in opcodes.iter() {
-        if *op_name == name {
-            return Some(*code);
-        }
+        result.extend_from_slice(&parse_number(token)?);
     }
-    None
+    Ok(result)
 }
 
-fn parse_op_args(args: &str) -> Vec<u8> {
+fn parse_op_args(args: &str) -> core::result::Result<Vec<u8>, String> {
     let mut result: Vec<u8> = Vec::new();
 
     // Split by comma and process each argument
@@ -227,8 +435,69 @@ fn parse_op_args(args: &str) -> Vec<u8> {
             continue;
         }
 
-        result.extend_from_slice(&parse_number(arg));
+        result.extend_from_slice(&parse_number(arg)?);
     }
 
-    result
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixture_errors() {
+        let parsed = parse_fixture_with_output("HEADER(0)\nOP:PUSH1\n=== OUTPUT ===\n1").unwrap();
+        assert_eq!(parsed.program.last(), Some(&opcodes::PUSH1));
+
+        let err = parse_fixture_with_output("HEADER(0)\n\nOP:NOPE\n=== OUTPUT ===").err();
+        assert_eq!(err.unwrap().to_string(), "line 3: Unknown opcode: NOPE");
+        let err = parse_fixture_with_output("HEADER(0)\nOP:PUSH 99999i16\n=== OUTPUT ===").err();
+        assert_eq!(err.unwrap().line, 2);
+        assert!(parse_fixture_with_output("HEADER(0)").is_err());
+
+        let parsed =
+            parse_fixture_with_output("HEADER(0)\n=== CHANNELS ===\n2: 1 -2\n0:\n=== OUTPUT ===")
+                .unwrap();
+        assert_eq!(
+            parsed.expected_channels,
+            Some(BTreeMap::from([(0, vec![]), (2, vec![1, -2])]))
+        );
+        let err =
+            parse_fixture_with_output("HEADER(0)\n=== CHANNELS ===\n1: x\n=== OUTPUT ===").err();
+        assert_eq!(err.unwrap().to_string(), "line 3: Invalid value: x");
+
+        let parsed = parse_fixture_with_output(
+            "HEADER(0)\n=== EXPECT ERROR ===\n# Comment\nHalt::Exit@0x1f\n",
+        )
+        .unwrap();
+        assert_eq!(parsed.expected_output, None);
+        assert_eq!(
+            parsed.expected_error,
+            Some(ExpectedError {
+                kind: "Halt::Exit".to_string(),
+                location: Some(ErrorLocation::Pc(0x1f))
+            })
+        );
+        let err = parse_fixture_with_output("HEADER(0)\n=== EXPECT ERROR ===\nA@x\n").err();
+        assert_eq!(
+            err.unwrap().to_string(),
+            "line 3: Invalid error location: x"
+        );
+        assert_eq!(
+            error_kind(&crate::vm::VMError::InvalidOpcode(1, 2)),
+            "InvalidOpcode"
+        );
+        assert_eq!(
+            error_kind(&crate::vm::VMError::Halt(crate::vm::HaltReason::Exit(3))),
+            "Halt::Exit"
+        );
+
+        let err =
+            parse_fixture_with_frames("HEADER(0)\n=== FRAMES ===\n# first\n000000 12345\n").err();
+        assert_eq!(
+            err.unwrap().to_string(),
+            "line 4: Failed to parse RRGGBB color: 12345"
+        );
+    }
 }
@@ -0,0 +1,135 @@
+use core::fmt::Write;
+
+use crate::modules::ModuleError;
+use crate::sync::Sync;
+use crate::vm::{Result, VM, VmDebug};
+
+// printf-style formatting for debug output. A template is a string in VM
+// memory, where each %d (signed), %u (unsigned), %x (hex) or %c (character)
+// takes the next value off the stack, and %% is a literal %. The compiler
+// lowers print("x=%d", x) to pushing the values in reverse, then the
+// template's length and address.
+pub const MAX_ARGS: usize = 8;
+
+// The number of values a template takes
+pub fn arg_count(template: &[u8]) -> Result<usize> {
+    let mut count = 0;
+    let mut bytes = template.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'%' {
+            match bytes.next() {
+                Some(b'%') => {}
+                Some(b'd' | b'u' | b'x' | b'c') => count += 1,
+                _ => return Err(ModuleError::BadFormat.into()),
+            }
+        }
+    }
+    Ok(count)
+}
+
+pub fn format(template: &[u8], args: &[i16], out: &mut impl Write) -> Result<()> {
+    let mut args = args.iter();
+    let mut bytes = template.iter();
+    let write = |result: core::fmt::Result| result.map_err(|_| ModuleError::OutputFailed);
+    while let Some(&byte) = bytes.next() {
+        if byte != b'%' {
+            write(out.write_char(byte as char))?;
+            continue;
+        }
+        let spec = bytes.next().ok_or(ModuleError::BadFormat)?;
+        if *spec == b'%' {
+            write(out.write_char('%'))?;
+            continue;
+        }
+        let arg = *args.next().ok_or(ModuleError::BadFormat)?;
+        match spec {
+            b'd' => write(write!(out, "{}", arg))?,
+            b'u' => write(write!(out, "{}", arg as u16))?,
+            b'x' => write(write!(out, "{:x}", arg as u16))?,
+            b'c' => write(out.write_char(arg as u8 as char))?,
+            _ => return Err(ModuleError::BadFormat.into()),
+        }
+    }
+    Ok(())
+}
+
+// Formats the template at `ptr` in VM memory, popping its values
+pub fn format_from_vm<const N: usize, S: Sync, D: VmDebug>(
+    vm: &mut VM<N, S, D>,
+    ptr: u16,
+    len: u16,
+    out: &mut impl Write,
+) -> Result<()> {
+    let range = ptr as usize..ptr as usize + len as usize;
+    let template = vm
+        .memory
+        .get(range.clone())
+        .ok_or(ModuleError::OutOfBounds)?;
+    let count = arg_count(template)?;
+    let mut args = [0i16; MAX_ARGS];
+    for arg in args.get_mut(..count).ok_or(ModuleError::BadFormat)? {
+        *arg = vm.stack_pop()?;
+    }
+    let template = vm.memory.get(range).ok_or(ModuleError::OutOfBounds)?;
+    format(template, args.get(..count).unwrap_or_default(), out)
+}
+
+// A fixed-size Write target for devices, which keeps what fits
+pub struct FormatBuffer<const M: usize> {
+    bytes: [u8; M],
+    len: usize,
+}
+
+impl<const M: usize> Default for FormatBuffer<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const M: usize> FormatBuffer<M> {
+    pub const fn new() -> Self {
+        FormatBuffer {
+            bytes: [0; M],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.bytes.get(..self.len).unwrap_or_default()
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const M: usize> Write for FormatBuffer<M> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            if let Some(slot) = self.bytes.get_mut(self.len) {
+                *slot = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let mut out = std::string::String::new();
+        format(b"x=%d y=%u %x%% %c", &[-1, -1, 255, 65], &mut out).unwrap();
+        assert_eq!(out, "x=-1 y=65535 ff% A");
+        assert_eq!(arg_count(b"%d%%%c").unwrap(), 2);
+        assert!(arg_count(b"%q").is_err());
+        assert!(format(b"%d", &[], &mut out).is_err());
+
+        let mut buffer = FormatBuffer::<4>::new();
+        format(b"%d", &[12345], &mut buffer).unwrap();
+        assert_eq!(buffer.as_bytes(), b"1234");
+    }
+}
@@ -0,0 +1,125 @@
+extern crate std;
+
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::sync::tokio_sync::AsyncSignal;
+use crate::sync::{Signal, TokioSync};
+use crate::vm::{Never, Result, VM, VmDebug};
+
+struct Shared<const N: usize, D: VmDebug> {
+    vm: Mutex<VM<N, TokioSync, D>>,
+    paused: AsyncSignal,
+}
+
+// A cloneable handle to a VM for host applications (simulator, bridges,
+// APIs) that need to inspect or change a VM that's running on another
+// task. One task drives the VM with run(), which holds the lock for a
+// slice of ops at a time; lock() waits for the current slice to finish, so
+// the VM is only ever seen between ops.
+pub struct VmHandle<const N: usize, D: VmDebug> {
+    shared: Arc<Shared<N, D>>,
+}
+
+impl<const N: usize, D: VmDebug> Clone for VmHandle<N, D> {
+    fn clone(&self) -> Self {
+        VmHandle {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<const N: usize, D: VmDebug> VmHandle<N, D> {
+    pub fn new(vm: VM<N, TokioSync, D>) -> Self {
+        VmHandle {
+            shared: Arc::new(Shared {
+                vm: Mutex::new(vm),
+                paused: AsyncSignal::new(),
+            }),
+        }
+    }
+
+    // Runs the VM until it halts or fails, as VM::run does, idling while
+    // paused
+    pub async fn run(&self) -> Result<Never> {
+        self.shared.vm.lock().await.halt_signal.reset();
+        loop {
+            self.shared.paused.wait_reset().await;
+            let mut vm = self.shared.vm.lock().await;
+            // pause() may have got the lock first
+            if !self.is_paused() {
+                vm.run_slice().await?;
+            }
+            drop(vm);
+            tokio::task::yield_now().await;
+        }
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, VM<N, TokioSync, D>> {
+        self.shared.vm.lock().await
+    }
+
+    // Stops run() after its current slice, returning once it has
+    pub async fn pause(&self) {
+        self.shared.paused.signal();
+        drop(self.shared.vm.lock().await);
+    }
+
+    pub fn resume(&self) {
+        self.shared.paused.reset();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.shared.paused.is_signaled()
+    }
+
+    // Makes run() return a Halt(Signal) error at the start of its next slice
+    pub async fn halt(&self) {
+        self.shared.vm.lock().await.signal_halt();
+        self.resume();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::vm::{HaltReason, VMError, make_vm, opcodes};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle() {
+        // Counts up in heap address 0 forever
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Count").unwrap();
+        builder.op_u16(opcodes::LOAD, 0).unwrap();
+        builder.op(opcodes::INC).unwrap();
+        builder.op_u16(opcodes::STORE, 0).unwrap();
+        builder.op_i8(opcodes::JMP8, -9).unwrap();
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load(builder.finish()).unwrap();
+
+        let handle = VmHandle::new(vm);
+        let runner = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.run().await }
+        });
+
+        handle.pause().await;
+        assert!(handle.is_paused());
+        let count: i16 = handle.lock().await.read_heap(0).unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(handle.lock().await.read_heap::<i16>(0).unwrap(), count);
+
+        handle.lock().await.write_heap(0, -1000i16).unwrap();
+        handle.resume();
+        while handle.lock().await.read_heap::<i16>(0).unwrap() < 0 {
+            tokio::task::yield_now().await;
+        }
+
+        handle.halt().await;
+        assert!(matches!(
+            runner.await.unwrap(),
+            Err(VMError::Halt(HaltReason::Signal))
+        ));
+    }
+}
@@ -1,16 +1,49 @@
-#![cfg_attr(not(test), no_std)]
-// TODO: remove this when generic_const_exprs is stable
-#![allow(incomplete_features)]
-#![feature(f16)]
-#![feature(generic_const_exprs)]
-#![feature(never_type)]
+// Fixture builds, like tests, only run on a host, and the test module they
+// include needs std
+#![cfg_attr(not(any(test, feature = "fixtures")), no_std)]
+#![cfg_attr(nightly, feature(never_type))]
+// A panic on device aborts the whole controller, so failures have to come
+// back as errors instead. Tests, and modules that only run on a host, may
+// allow these again.
+#![cfg_attr(
+    not(test),
+    deny(
+        clippy::unwrap_used,
+        clippy::expect_used,
+        clippy::panic,
+        clippy::unreachable,
+        clippy::todo,
+        clippy::unimplemented,
+        clippy::indexing_slicing
+    )
+)]
 
-mod modules;
+pub mod breaks;
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod chrome_trace;
+pub mod cost;
+pub mod coverage;
+pub mod crash;
+pub mod disasm;
+pub mod format;
+#[cfg(feature = "tokio")]
+pub mod handle;
+pub mod lz4;
+pub mod modules;
 pub mod ops;
+pub mod ota;
 pub mod program;
 mod read;
+pub mod storage;
+pub mod supervisor;
 pub mod sync;
 pub mod vm;
+pub mod vm_builder;
 
+#[cfg(any(test, feature = "fixtures"))]
+pub mod conformance;
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixture_parse;
 #[cfg(test)]
-mod fixture_parse;
+mod mutation;
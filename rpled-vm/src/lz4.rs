@@ -0,0 +1,122 @@
+// Decompressor for LZ4 block-format data, used for compressed program
+// bodies. Works in place on a caller-supplied buffer so it needs no
+// allocator.
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecompressError {
+    Corrupt,
+    OutputFull,
+}
+
+type Result<T> = core::result::Result<T, DecompressError>;
+
+fn read_length(input: &[u8], pos: &mut usize, base: usize) -> Result<usize> {
+    let mut len = base;
+    if base == 15 {
+        loop {
+            let byte = *input.get(*pos).ok_or(DecompressError::Corrupt)?;
+            *pos += 1;
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+// Length `input` decompresses to, without decompressing it
+pub fn decompressed_len(input: &[u8]) -> Result<usize> {
+    let mut ip = 0;
+    let mut len = 0;
+    loop {
+        let token = *input.get(ip).ok_or(DecompressError::Corrupt)?;
+        ip += 1;
+        let literals = read_length(input, &mut ip, (token >> 4) as usize)?;
+        ip += literals;
+        len += literals;
+        if ip == input.len() {
+            return Ok(len);
+        }
+        // Skip the match offset
+        ip += 2;
+        if ip > input.len() {
+            return Err(DecompressError::Corrupt);
+        }
+        len += read_length(input, &mut ip, (token & 0xf) as usize)? + 4;
+    }
+}
+
+// Decompresses `input` into the start of `output`, returning the
+// decompressed length
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize> {
+    let mut ip = 0;
+    let mut op = 0;
+    loop {
+        let token = *input.get(ip).ok_or(DecompressError::Corrupt)?;
+        ip += 1;
+
+        let literals = read_length(input, &mut ip, (token >> 4) as usize)?;
+        let src = input
+            .get(ip..ip + literals)
+            .ok_or(DecompressError::Corrupt)?;
+        output
+            .get_mut(op..op + literals)
+            .ok_or(DecompressError::OutputFull)?
+            .copy_from_slice(src);
+        ip += literals;
+        op += literals;
+        // The last sequence is literals only
+        if ip == input.len() {
+            return Ok(op);
+        }
+
+        let offset = input
+            .get(ip..ip + 2)
+            .and_then(|b| b.try_into().ok())
+            .map(|b| u16::from_le_bytes(b) as usize)
+            .ok_or(DecompressError::Corrupt)?;
+        ip += 2;
+        if offset == 0 || offset > op {
+            return Err(DecompressError::Corrupt);
+        }
+        let len = read_length(input, &mut ip, (token & 0xf) as usize)? + 4;
+        if op + len > output.len() {
+            return Err(DecompressError::OutputFull);
+        }
+        // Matches may overlap the bytes they produce, so copy forwards
+        for i in op..op + len {
+            let byte = *output.get(i - offset).ok_or(DecompressError::Corrupt)?;
+            *output.get_mut(i).ok_or(DecompressError::OutputFull)? = byte;
+        }
+        op += len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress() {
+        // "abc" literals, then an overlapping 7-byte match at offset 3
+        let input = [0x33, b'a', b'b', b'c', 3, 0, 0x10, b'!'];
+        let mut output = [0u8; 16];
+        assert_eq!(decompressed_len(&input), Ok(11));
+        assert_eq!(decompress(&input, &mut output), Ok(11));
+        assert_eq!(&output[..11], b"abcabcabca!");
+
+        assert_eq!(
+            decompress(&input, &mut [0u8; 8]),
+            Err(DecompressError::OutputFull)
+        );
+        assert_eq!(
+            decompress(&input[..5], &mut output),
+            Err(DecompressError::Corrupt)
+        );
+        assert_eq!(
+            decompress(&[0x10, b'x', 9, 0], &mut output),
+            Err(DecompressError::Corrupt)
+        );
+    }
+}
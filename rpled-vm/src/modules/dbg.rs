@@ -0,0 +1,152 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+
+use crate::vm::Result;
+use paste::paste;
+
+// Debug logging: log(template) formats its values (see format) and passes
+// the message to a sink the host sets up. Messages are rate limited, so a
+// script logging in a tight loop can't eat into frame timing; ones over
+// the limit are dropped and counted.
+
+// Where messages go: defmt or a UART on a device, stdout on a host. write()
+// is called from inside the frame loop, so must not block.
+pub trait DebugSink: Send {
+    fn write(&mut self, message: &[u8]);
+}
+
+pub type BoxedSink = Box<dyn DebugSink>;
+
+#[cfg(feature = "std")]
+pub struct StdoutSink;
+
+#[cfg(feature = "std")]
+impl DebugSink for StdoutSink {
+    fn write(&mut self, message: &[u8]) {
+        extern crate std;
+        std::println!("{}", std::string::String::from_utf8_lossy(message));
+    }
+}
+
+// Messages allowed between two polls of the modules (every POLL_INTERVAL
+// ops)
+pub const MESSAGES_PER_POLL: u8 = 4;
+
+// Longer messages are cut short
+pub const MAX_MESSAGE_LEN: usize = 80;
+
+pub struct DbgModule {
+    sink: Option<BoxedSink>,
+    budget: u8,
+    pub dropped_messages: u16,
+}
+
+impl super::ModuleInit for DbgModule {
+    async fn init() -> Self {
+        DbgModule {
+            sink: None,
+            budget: MESSAGES_PER_POLL,
+            dropped_messages: 0,
+        }
+    }
+
+    async fn reset(&mut self) -> Result<()> {
+        self.budget = MESSAGES_PER_POLL;
+        self.dropped_messages = 0;
+        Ok(())
+    }
+
+    fn poll(&mut self) {
+        self.budget = MESSAGES_PER_POLL;
+    }
+}
+
+impl DbgModule {
+    pub fn set_sink(&mut self, sink: BoxedSink) {
+        self.sink = Some(sink);
+    }
+
+    fn write(&mut self, message: &[u8]) {
+        let Some(sink) = &mut self.sink else {
+            return;
+        };
+        if self.budget == 0 {
+            self.dropped_messages = self.dropped_messages.saturating_add(1);
+            return;
+        }
+        self.budget -= 1;
+        sink.write(message);
+    }
+}
+
+define_module! {
+    dbg (vm) {
+        1 => async fn log(&mut vm, fmt_ptr: u16, fmt_len: u16) -> Result<()> {
+            use crate::modules::dbg::MAX_MESSAGE_LEN;
+            let mut message = crate::format::FormatBuffer::<MAX_MESSAGE_LEN>::new();
+            crate::format::format_from_vm(vm, fmt_ptr, fmt_len, &mut message)?;
+            vm.modules.dbg.write(message.as_bytes());
+            Ok(())
+        },
+        // Pushes the number of messages dropped by the rate limit
+        2 => async fn dropped(&mut vm) -> Result<i16> {
+            Ok(vm.modules.dbg.dropped_messages as i16)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::sync::TokioSync;
+    use crate::vm::{make_vm, opcodes};
+    use std::string::String;
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    struct RecordingSink(Arc<Mutex<Vec<String>>>);
+
+    impl DebugSink for RecordingSink {
+        fn write(&mut self, message: &[u8]) {
+            let message = String::from_utf8_lossy(message).to_string();
+            self.0.lock().unwrap().push(message);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        // Logs "n=%d" for n = 6..1, then the number dropped
+        let template = b"n=%d";
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Log").unwrap();
+        builder.push(6).unwrap();
+        let top = builder.pc();
+        builder.op(opcodes::DUP).unwrap();
+        builder.push(template.len() as i16).unwrap();
+        builder.op_u16(opcodes::LOAD, 0).unwrap();
+        builder.module_call(opcodes::DBG0, 1, 2).unwrap();
+        builder.op(opcodes::DEC).unwrap();
+        builder.op(opcodes::DUP).unwrap();
+        builder.jump_to(opcodes::JNZ, top).unwrap();
+        builder.module_call(opcodes::DBG0, 2, 0).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load(builder.finish()).unwrap();
+
+        // The template goes after heap address 0, which holds its address
+        let addr = vm.heap_start + 2;
+        vm.memory[addr..addr + template.len()].copy_from_slice(template);
+        vm.write_heap(0, addr as u16).unwrap();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+        vm.modules
+            .dbg
+            .set_sink(Box::new(RecordingSink(messages.clone())));
+
+        let _ = vm.run().await;
+        assert_eq!(*messages.lock().unwrap(), ["n=6", "n=5", "n=4", "n=3"]);
+        assert_eq!(vm.modules.test.messages, ["TEST_ONE_ARG: 2"]);
+    }
+}
@@ -3,26 +3,32 @@ macro_rules! define_module {
     (
         $mod_name:ident ( $vm_ident:ident ) {
             $(
-                $opcode:literal => async fn $name:ident $args:tt -> Result<()> $body:block
+                $opcode:literal => async fn $name:ident $args:tt -> Result<$ret:ty> $body:block
             ),* $(,)?
         }
     ) => {
         paste! {
-            // Arg structs for each opcode
-            mod args {
-                use paste::paste;
+            // Implementations as functions
+            mod impls {
                 $(
-                    define_module!(@args_struct $name, $args);
+                    define_module!(@fn_impl $name, $args, $ret, $body);
                 )*
             }
 
-            // Implementations as functions
-            mod impls {
+            // Number of results each function pushes, from its return type
+            pub const RESULTS: &[(u8, u8)] = &[
                 $(
-                    define_module!(@fn_impl $name, $args $body);
+                    ($opcode, <$ret as crate::modules::marshal::IntoStack>::VALUES),
                 )*
-            }
+            ];
 
+            pub const FUNCTION_NAMES: &[(u8, &str)] = &[
+                $(
+                    ($opcode, stringify!($name)),
+                )*
+            ];
+
+            #[allow(unused_variables)]
             pub(crate) async fn call0<const N: usize, S: crate::sync::Sync, D: crate::vm::VmDebug>(
                 $vm_ident: &mut crate::vm::VM<N, S, D>,
                 opcode: u8
@@ -77,29 +83,20 @@ macro_rules! define_module {
         }
     };
 
-    (@args_struct $name:ident, (&mut  $vm_name:ident $(, $arg:ident : $arg_ty:ty )* ) ) => {
-        paste! {
-            #[repr(C)]
-            #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-            pub struct [<$name:camel>] {
-                $( pub $arg : $arg_ty, )*
-            }
-        }
-    };
-
-    (@fn_impl $name:ident, ( &mut $vm_name:ident $(, $arg:ident : $arg_ty:ty )* ) $body:block) => {
+    (@fn_impl $name:ident, ( &mut $vm_name:ident $(, $arg:ident : $arg_ty:ty )* ), $ret:ty, $body:block) => {
         #[allow(unused_variables)]
         pub async fn $name<const N: usize, S: crate::sync::Sync, D: crate::vm::VmDebug>(
             $vm_name: &mut crate::vm::VM<N, S, D>,
             $( $arg : $arg_ty ),*
-        ) -> crate::vm::Result<()> {
+        ) -> crate::vm::Result<$ret> {
             $body
         }
     };
 
     (@call0 $vm_ident:ident, $name:ident, (&mut  $vm_name:ident) ) => {
         {
-            impls::$name( $vm_ident ).await
+            let result = impls::$name( $vm_ident ).await?;
+            crate::modules::marshal::IntoStack::push(result, $vm_ident)
         }
     };
 
@@ -115,18 +112,29 @@ macro_rules! define_module {
         }
     };
 
+    // Parameters are converted from stack values by their FromStack impls
+    // (see marshal), so a parameter type without one fails to compile
     (@calln $vm_ident:ident, $num:expr, $name:ident, (&mut $vm_name:ident , $( $arg:ident : $arg_ty:ty ),+ ) ) => {
-        paste! {
-            {
-                if (size_of::<i16>() * ($num as usize)) != size_of::<args::[<$name:camel>]>() {
-                    return Err(crate::modules::ModuleError::IncorrectCallVariant.into());
-                }
-                let args: args::[<$name:camel>] = $vm_name.stack_pop()?;
-                impls::$name(
-                    $vm_ident,
-                    $( args.$arg ),*
-                ).await
+        {
+            const SLOTS: usize = 0 $( + <$arg_ty as crate::modules::marshal::FromStack>::SLOTS )+;
+            const { assert!(SLOTS <= u8::MAX as usize, "A module function can take at most 255 stack values") };
+            if $num as usize != SLOTS {
+                return Err(crate::modules::ModuleError::IncorrectCallVariant.into());
             }
+            let bytes = $vm_name.stack_pop_raw(SLOTS * size_of::<i16>())?;
+            let mut values = bytes.chunks_exact(2).map(|value| match *value {
+                [low, high] => i16::from_le_bytes([low, high]),
+                _ => 0,
+            });
+            $(
+                let $arg = <$arg_ty as crate::modules::marshal::FromStack>::from_stack(&mut values);
+            )+
+            let result = impls::$name(
+                $vm_ident,
+                $( $arg ),*
+            ).await?;
+            // Results are pushed by their IntoStack impls (see marshal)
+            crate::modules::marshal::IntoStack::push(result, $vm_ident)
         }
     };
 
@@ -0,0 +1,166 @@
+extern crate alloc;
+extern crate std;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::pin::Pin;
+use std::collections::HashMap;
+
+use super::ModuleError;
+use crate::sync::Sync;
+use crate::vm::{Result, VM, VmDebug};
+
+// Calls forwarded to async closures the host application registers, so
+// desktop hosts can give scripts their own capabilities (a weather lookup,
+// a game's state) without writing a module. A call pops its arguments,
+// awaits the closure registered for its function code with them, first
+// argument first, and pushes the value it returns; None fails the call
+// with HostCallFailed. The VM waits for the closure, so anything slow
+// should hand out tickets, as requests does.
+pub type HostFuture = Pin<Box<dyn Future<Output = Option<i16>> + Send>>;
+pub type HostFn = Box<dyn Fn(Vec<i16>) -> HostFuture + Send>;
+
+// Every host function pushes one value, for code analysis
+pub const RESULTS: u8 = 1;
+
+pub fn boxed<F, Fut>(function: F) -> HostFn
+where
+    F: Fn(Vec<i16>) -> Fut + Send + 'static,
+    Fut: Future<Output = Option<i16>> + Send + 'static,
+{
+    Box::new(move |args| Box::pin(function(args)))
+}
+
+pub struct HostModule {
+    functions: HashMap<u8, HostFn>,
+}
+
+impl super::ModuleInit for HostModule {
+    async fn init() -> Self {
+        HostModule {
+            functions: HashMap::new(),
+        }
+    }
+
+    // Functions stay registered from one program to the next
+    async fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl HostModule {
+    // Replaces any function already registered as `code`
+    pub fn register<F, Fut>(&mut self, code: u8, function: F)
+    where
+        F: Fn(Vec<i16>) -> Fut + Send + 'static,
+        Fut: Future<Output = Option<i16>> + Send + 'static,
+    {
+        self.functions.insert(code, boxed(function));
+    }
+
+    pub fn register_boxed(&mut self, code: u8, function: HostFn) {
+        self.functions.insert(code, function);
+    }
+
+    pub fn unregister(&mut self, code: u8) -> bool {
+        self.functions.remove(&code).is_some()
+    }
+}
+
+async fn call<const N: usize, S: Sync, D: VmDebug>(
+    vm: &mut VM<N, S, D>,
+    code: u8,
+    n_args: u8,
+) -> Result<()> {
+    let args = (0..n_args)
+        .map(|_| vm.stack_pop::<i16>())
+        .collect::<Result<Vec<_>>>()?;
+    let function = vm
+        .modules
+        .host
+        .functions
+        .get(&code)
+        .ok_or(ModuleError::InvalidModuleOpcode)?;
+    let result = function(args).await.ok_or(ModuleError::HostCallFailed)?;
+    vm.stack_push(result)
+}
+
+pub(crate) async fn call0<const N: usize, S: Sync, D: VmDebug>(
+    vm: &mut VM<N, S, D>,
+    code: u8,
+) -> Result<()> {
+    call(vm, code, 0).await
+}
+
+pub(crate) async fn call1<const N: usize, S: Sync, D: VmDebug>(
+    vm: &mut VM<N, S, D>,
+    code: u8,
+) -> Result<()> {
+    call(vm, code, 1).await
+}
+
+pub(crate) async fn call2<const N: usize, S: Sync, D: VmDebug>(
+    vm: &mut VM<N, S, D>,
+    code: u8,
+) -> Result<()> {
+    call(vm, code, 2).await
+}
+
+pub(crate) async fn calln<const N: usize, S: Sync, D: VmDebug>(
+    vm: &mut VM<N, S, D>,
+    code: u8,
+) -> Result<()> {
+    let n_args: u8 = vm.read_pc()?;
+    call(vm, code, n_args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::sync::TokioSync;
+    use crate::vm::{HaltReason, NoVmDebug, VMError, opcodes};
+    use crate::vm_builder::VmBuilder;
+
+    #[tokio::test]
+    async fn test_host_calls() {
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Host").unwrap();
+        builder.push(2).unwrap();
+        builder.push(40).unwrap();
+        builder.module_call(opcodes::HOST0, 1, 2).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.module_call(opcodes::HOST0, 2, 0).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        let mut vm: VM<256, TokioSync, NoVmDebug> = VmBuilder::new()
+            .host_function(1, |args: Vec<i16>| async move {
+                tokio::task::yield_now().await;
+                Some(args[0] - args[1])
+            })
+            .build()
+            .await;
+        vm.modules.host.register(2, |_| async { None });
+        vm.load(program).unwrap();
+        assert!(matches!(
+            vm.run().await,
+            Err(VMError::ModuleError(ModuleError::HostCallFailed))
+        ));
+        assert_eq!(vm.modules.test.messages, ["TEST_ONE_ARG: 38"]);
+
+        assert!(vm.modules.host.unregister(2));
+        vm.load(program).unwrap();
+        assert!(matches!(
+            vm.run().await,
+            Err(VMError::ModuleError(ModuleError::InvalidModuleOpcode))
+        ));
+
+        vm.modules.host.register(2, |_| async { Some(0) });
+        vm.load(program).unwrap();
+        assert!(matches!(
+            vm.run().await,
+            Err(VMError::Halt(HaltReason::HaltOp))
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+use super::MAX_PIXELS;
+use super::output::{OutputDriver, OutputError};
+
+// Minimal SPI transmit interface, so the driver can sit on top of any HAL
+pub trait SpiWrite: Send {
+    fn write(&mut self, data: &[u8]) -> Result<(), OutputError>;
+}
+
+const START_FRAME: usize = 4;
+// One extra clock edge is needed per two LEDs to push the data through
+const END_FRAME_MAX: usize = MAX_PIXELS.div_ceil(16);
+
+// Driver for clocked APA102/SK9822 strips. Expects 3-byte frames, which
+// should be encoded with ColorOrder::Bgr for standard strips.
+pub struct Apa102Driver<S: SpiWrite> {
+    spi: S,
+    // The strip's 5-bit per-LED current control, applied to every pixel
+    pub global_brightness: u8,
+    buf: [u8; START_FRAME + MAX_PIXELS * 4 + END_FRAME_MAX],
+}
+
+impl<S: SpiWrite> Apa102Driver<S> {
+    pub fn new(spi: S) -> Self {
+        Apa102Driver {
+            spi,
+            global_brightness: 31,
+            buf: [0; START_FRAME + MAX_PIXELS * 4 + END_FRAME_MAX],
+        }
+    }
+}
+
+impl<S: SpiWrite> OutputDriver for Apa102Driver<S> {
+    fn write(&mut self, frame: &[u8]) -> Result<(), OutputError> {
+        let num_pixels = frame.len() / 3;
+        let header = 0xe0 | self.global_brightness.min(31);
+
+        // SK9822 needs a zeroed reset frame, APA102 needs at least n/2 extra
+        // clocks; zeros satisfy both
+        let end = START_FRAME.max(num_pixels.div_ceil(16));
+        let buf = self
+            .buf
+            .get_mut(..START_FRAME + num_pixels * 4 + end)
+            .ok_or(OutputError::Failed)?;
+        buf.fill(0);
+        let pixel_frames = buf.chunks_exact_mut(4).skip(START_FRAME / 4);
+        for (out, pixel) in pixel_frames.zip(frame.chunks_exact(3)) {
+            if let [brightness, color @ ..] = out {
+                *brightness = header;
+                color.copy_from_slice(pixel);
+            }
+        }
+
+        self.spi.write(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    struct MockSpi(Arc<Mutex<Vec<u8>>>);
+
+    impl SpiWrite for MockSpi {
+        fn write(&mut self, data: &[u8]) -> Result<(), OutputError> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_apa102_frame() {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut driver = Apa102Driver::new(MockSpi(sent.clone()));
+        driver.global_brightness = 3;
+        driver.write(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(
+            *sent.lock().unwrap(),
+            [0, 0, 0, 0, 0xe3, 1, 2, 3, 0xe3, 4, 5, 6, 0, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use super::Rgb;
+
+// Colors are passed around scripts as a single stack value, packed as RGB565
+
+pub fn pack_rgb565(color: Rgb) -> i16 {
+    let [r, g, b] = color;
+    let packed = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+    packed as i16
+}
+
+pub fn unpack_rgb565(packed: i16) -> Rgb {
+    let packed = packed as u16;
+    let r5 = ((packed >> 11) & 0x1f) as u8;
+    let g6 = ((packed >> 5) & 0x3f) as u8;
+    let b5 = (packed & 0x1f) as u8;
+    // Replicate the high bits into the low bits so full intensity maps to 255
+    [
+        (r5 << 3) | (r5 >> 2),
+        (g6 << 2) | (g6 >> 4),
+        (b5 << 3) | (b5 >> 2),
+    ]
+}
+
+// Scales value by scale/256, where a scale of 255 leaves the value unchanged
+pub fn scale8(value: u8, scale: u8) -> u8 {
+    ((value as u16 * (scale as u16 + 1)) >> 8) as u8
+}
+
+// Mixes from a (amount 0) to b (amount 255)
+pub fn blend8(a: u8, b: u8, amount: u8) -> u8 {
+    scale8(a, 255 - amount) + scale8(b, amount)
+}
+
+pub fn blend(a: Rgb, b: Rgb, amount: u8) -> Rgb {
+    [
+        blend8(a[0], b[0], amount),
+        blend8(a[1], b[1], amount),
+        blend8(a[2], b[2], amount),
+    ]
+}
+
+pub fn fade_to_black(pixels: &mut [Rgb], amount: u8) {
+    for pixel in pixels {
+        for channel in pixel.iter_mut() {
+            *channel = scale8(*channel, 255 - amount);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use super::{MAX_PIXELS, Rgb};
+
+// Temporal dithering for the brightness scaling in show(). Scaling keeps
+// the top 8 bits of a 16-bit product, so dim colors band; carrying each
+// channel's dropped low bits into the next frame makes the output average
+// out to the unrounded value over a few frames.
+#[derive(Clone)]
+pub struct Dither {
+    remainders: [Rgb; MAX_PIXELS],
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dither {
+    pub const fn new() -> Self {
+        Dither {
+            remainders: [[0; 3]; MAX_PIXELS],
+        }
+    }
+
+    // Scales `pixels` by scale/256, as color::scale8 does
+    pub fn scale(&mut self, pixels: &mut [Rgb], scale: u8) {
+        for (pixel, remainders) in pixels.iter_mut().zip(&mut self.remainders) {
+            for (channel, remainder) in pixel.iter_mut().zip(remainders) {
+                let scaled = *channel as u16 * (scale as u16 + 1) + *remainder as u16;
+                *channel = (scaled >> 8) as u8;
+                *remainder = scaled as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dither() {
+        // Halving 3 and 255 gives 1.5 and 127.5, which scale8 always
+        // rounds down
+        let mut dither = Dither::new();
+        let mut totals = [0u16; 3];
+        for _ in 0..4 {
+            let mut pixels = [[3, 0, 255]];
+            dither.scale(&mut pixels, 127);
+            for (total, channel) in totals.iter_mut().zip(pixels[0]) {
+                *total += channel as u16;
+            }
+        }
+        assert_eq!(totals, [6, 0, 510]);
+    }
+}
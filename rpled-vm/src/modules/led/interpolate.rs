@@ -0,0 +1,107 @@
+use super::{MAX_PIXELS, Rgb, color};
+
+// Smooths scripts that draw slower than the strip refreshes (e.g. 10-15 FPS
+// on a slow MCU) by blending from the frame on the strip to the newest shown
+// frame over the refreshes in between. The blend is paced by the number of
+// refreshes between the last two show()s, so it lags the script by about a
+// frame.
+#[derive(Clone)]
+pub struct Interpolator {
+    from: [Rgb; MAX_PIXELS],
+    to: [Rgb; MAX_PIXELS],
+    // Refreshes expected before the next frame
+    period: u16,
+    elapsed: u16,
+}
+
+impl Default for Interpolator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpolator {
+    pub const fn new() -> Self {
+        Interpolator {
+            from: [[0; 3]; MAX_PIXELS],
+            to: [[0; 3]; MAX_PIXELS],
+            period: 1,
+            elapsed: 0,
+        }
+    }
+
+    fn progress(&self) -> u8 {
+        (self.elapsed.min(self.period) as u32 * 255 / self.period as u32) as u8
+    }
+
+    // Starts blending towards `frame`, from wherever the last blend got to
+    pub fn push_frame(&mut self, frame: &[Rgb]) {
+        let progress = self.progress();
+        for ((from, to), next) in self.from.iter_mut().zip(&mut self.to).zip(frame) {
+            *from = color::blend(*from, *to, progress);
+            *to = *next;
+        }
+        self.period = self.elapsed.max(1);
+        self.elapsed = 0;
+    }
+
+    // Writes the blended frame for the next refresh into `out`
+    pub fn compose(&mut self, out: &mut [Rgb]) {
+        self.elapsed = self.elapsed.saturating_add(1);
+        let progress = self.progress();
+        for ((out, from), to) in out.iter_mut().zip(&self.from).zip(&self.to) {
+            *out = color::blend(*from, *to, progress);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate() {
+        let mut interpolator = Interpolator::new();
+        let mut out = [[9; 3]];
+        // Nothing to pace the first frame by, so it's shown straight away
+        interpolator.push_frame(&[[0, 0, 0]]);
+        interpolator.compose(&mut out);
+        assert_eq!(out, [[0, 0, 0]]);
+        interpolator.compose(&mut out);
+        interpolator.compose(&mut out);
+
+        // Three refreshes between frames, so each is a third of the way
+        interpolator.push_frame(&[[240, 0, 0]]);
+        interpolator.compose(&mut out);
+        assert_eq!(out, [[80, 0, 0]]);
+        interpolator.compose(&mut out);
+        assert_eq!(out, [[160, 0, 0]]);
+
+        // An early frame starts from the partial blend
+        interpolator.push_frame(&[[0, 0, 0]]);
+        interpolator.compose(&mut out);
+        assert_eq!(out, [[80, 0, 0]]);
+        interpolator.compose(&mut out);
+        assert_eq!(out, [[0, 0, 0]]);
+    }
+
+    #[tokio::test]
+    async fn test_interpolate_flag() {
+        use crate::builder::ProgramBuilder;
+        use crate::program::{FLAGS_OFFSET, ProgramFlags};
+        use crate::sync::TokioSync;
+        use crate::vm::{make_vm, opcodes};
+
+        let mut buf = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Smooth").unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let mut program = builder.finish().to_vec();
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load(&program).unwrap();
+        assert!(vm.modules.led.interpolator.is_none());
+
+        program[FLAGS_OFFSET] = ProgramFlags::INTERPOLATE.bits();
+        vm.load(&program).unwrap();
+        assert!(vm.modules.led.interpolator.is_some());
+    }
+}
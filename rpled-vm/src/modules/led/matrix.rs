@@ -0,0 +1,60 @@
+use super::Rgb;
+
+// Maps 2D coordinates onto the strip. A width of 0 treats the strip as a
+// single row. Serpentine layouts reverse every odd row, matching the usual
+// zig-zag wiring of LED matrix panels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MatrixLayout {
+    pub width: u16,
+    pub serpentine: bool,
+}
+
+impl MatrixLayout {
+    pub fn index(&self, x: i16, y: i16, num_pixels: usize) -> Option<usize> {
+        let width = match self.width {
+            0 => num_pixels,
+            width => width as usize,
+        };
+        if x < 0 || y < 0 || x as usize >= width {
+            return None;
+        }
+        let (x, y) = (x as usize, y as usize);
+        let x = if self.serpentine && y % 2 == 1 {
+            width - 1 - x
+        } else {
+            x
+        };
+        let index = y * width + x;
+        (index < num_pixels).then_some(index)
+    }
+}
+
+// Sprites are stored in program memory as a width byte, a height byte and
+// then width * height RGB triples in row-major order.
+pub struct Sprite<'a> {
+    pub width: u8,
+    pub height: u8,
+    pub pixels: &'a [u8],
+}
+
+impl<'a> Sprite<'a> {
+    pub fn parse(memory: &'a [u8], addr: usize) -> Option<Self> {
+        let (width, height) = (*memory.get(addr)?, *memory.get(addr + 1)?);
+        let len = width as usize * height as usize * 3;
+        let pixels = memory.get(addr + 2..addr + 2 + len)?;
+        Some(Sprite {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn pixel(&self, x: u8, y: u8) -> Rgb {
+        let start = (y as usize * self.width as usize + x as usize) * 3;
+        // Black outside the sprite
+        match self.pixels.get(start..start + 3) {
+            Some(&[r, g, b]) => [r, g, b],
+            _ => [0; 3],
+        }
+    }
+}
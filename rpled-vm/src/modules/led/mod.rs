@@ -0,0 +1,639 @@
+use crate::modules::ModuleError;
+use crate::program::{MAX_STRIPS, ProgramError, STRIP_ENTRY_SIZE};
+use crate::vm::Result;
+use paste::paste;
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+pub mod apa102;
+pub mod color;
+pub mod dither;
+pub mod font;
+pub mod interpolate;
+pub mod matrix;
+pub mod order;
+pub mod output;
+pub mod pixel_map;
+pub mod power;
+#[cfg(feature = "rp2040-pio")]
+pub mod rp2040;
+pub mod transition;
+
+use dither::Dither;
+use interpolate::Interpolator;
+use matrix::{MatrixLayout, Sprite};
+use order::ColorOrder;
+use output::{BoxedDriver, BoxedListener, OutputError};
+use pixel_map::PixelMap;
+use power::PowerModel;
+use transition::{Transition, TransitionKind};
+
+pub const MAX_PIXELS: usize = 256;
+
+pub type Rgb = [u8; 3];
+
+// A run of the frame sent to its own strip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Strip {
+    pub start: usize,
+    pub len: usize,
+    pub color_order: ColorOrder,
+}
+
+pub struct LedModule {
+    // The framebuffer scripts draw into
+    pub pixels: [Rgb; MAX_PIXELS],
+    // White channel for RGBW strips, kept alongside the RGB framebuffer
+    pub white: [u8; MAX_PIXELS],
+    // The frame most recently shown, after any output processing
+    pub output: [Rgb; MAX_PIXELS],
+    pub output_white: [u8; MAX_PIXELS],
+    pub color_order: ColorOrder,
+    // Shown frames are encoded into `wire` and sent to the driver, if any
+    driver: Option<BoxedDriver>,
+    wire: [u8; MAX_PIXELS * 4],
+    // Frames skipped because the driver was still sending the previous one
+    pub dropped_frames: u32,
+    // The strips the frame is split between, from the program's header.
+    // Empty for a single strip, using color_order and driver.
+    pub strips: Vec<Strip>,
+    strip_drivers: [Option<BoxedDriver>; MAX_STRIPS],
+    // The strip scripts draw on with set_pixel and friends, chosen with
+    // output(); the whole frame if none
+    pub selected_strip: Option<usize>,
+    // When set (on hosts), show() takes as long as sending the frame to
+    // the strips would, so scripts are paced as they will be on hardware
+    pub simulate_transfer: bool,
+    // Host hook told about each shown frame
+    frame_listener: Option<BoxedListener>,
+    pub num_pixels: usize,
+    pub frame_count: u32,
+    pub layout: MatrixLayout,
+    // For irregular installations: where each logical pixel is on the
+    // strip, used in place of the layout
+    pub pixel_map: Option<PixelMap>,
+    pub transition: Option<Transition>,
+    // When set, frames go to the driver from refresh(), blended between
+    // shown frames, rather than from show()
+    pub interpolator: Option<Interpolator>,
+    // Applied to every shown frame, after any transition
+    pub brightness: u8,
+    // Set by scripts with dither(), to dither the brightness scaling
+    pub dither: Option<Dither>,
+    pub power_model: PowerModel,
+    // Shown frames are scaled down to stay under this draw, if set
+    pub power_budget_ma: Option<u32>,
+    // Estimated draw of the last shown frame
+    pub estimated_ma: u32,
+    // When set, a copy of the output is recorded on every show()
+    pub captured_frames: Option<Vec<Vec<Rgb>>>,
+}
+
+#[derive(Clone)]
+pub struct LedSnapshot {
+    pub pixels: [Rgb; MAX_PIXELS],
+    pub white: [u8; MAX_PIXELS],
+    pub output: [Rgb; MAX_PIXELS],
+    pub output_white: [u8; MAX_PIXELS],
+    pub selected_strip: Option<usize>,
+    pub layout: MatrixLayout,
+    pub pixel_map: Option<PixelMap>,
+    pub num_pixels: usize,
+    pub frame_count: u32,
+    pub transition: Option<Transition>,
+    pub interpolator: Option<Interpolator>,
+    pub brightness: u8,
+    pub dither: Option<Dither>,
+}
+
+impl super::ModuleInit for LedModule {
+    async fn init() -> Self {
+        LedModule {
+            pixels: [[0; 3]; MAX_PIXELS],
+            white: [0; MAX_PIXELS],
+            output: [[0; 3]; MAX_PIXELS],
+            output_white: [0; MAX_PIXELS],
+            color_order: ColorOrder::default(),
+            driver: None,
+            wire: [0; MAX_PIXELS * 4],
+            dropped_frames: 0,
+            strips: Vec::new(),
+            strip_drivers: [const { None }; MAX_STRIPS],
+            selected_strip: None,
+            simulate_transfer: false,
+            frame_listener: None,
+            num_pixels: MAX_PIXELS,
+            frame_count: 0,
+            layout: MatrixLayout::default(),
+            pixel_map: None,
+            transition: None,
+            interpolator: None,
+            brightness: u8::MAX,
+            dither: None,
+            power_model: PowerModel::default(),
+            power_budget_ma: None,
+            estimated_ma: 0,
+            captured_frames: None,
+        }
+    }
+
+    async fn reset(&mut self) -> Result<()> {
+        self.pixels.fill([0; 3]);
+        self.white.fill(0);
+        self.frame_count = 0;
+        self.dither = None;
+        if let Some(frames) = &mut self.captured_frames {
+            frames.clear();
+        }
+        Ok(())
+    }
+}
+
+impl LedModule {
+    pub fn set_num_pixels(&mut self, num_pixels: usize) {
+        self.num_pixels = num_pixels.min(MAX_PIXELS);
+    }
+
+    // num_pixels is at most MAX_PIXELS, so these are never cut short
+    pub fn frame(&self) -> &[Rgb] {
+        self.pixels.get(..self.num_pixels).unwrap_or_default()
+    }
+
+    fn frame_mut(&mut self) -> &mut [Rgb] {
+        self.pixels.get_mut(..self.num_pixels).unwrap_or_default()
+    }
+
+    pub fn output(&self) -> &[Rgb] {
+        self.output.get(..self.num_pixels).unwrap_or_default()
+    }
+
+    // Starts a transition away from the last shown frame, typically just
+    // before loading the next program. The incoming program's frames are
+    // blended with it over the next `frames` calls to show().
+    pub fn begin_transition(&mut self, kind: TransitionKind, frames: u16) {
+        self.transition = Some(Transition::new(kind, frames, self.output()));
+    }
+
+    pub fn set_driver(&mut self, driver: BoxedDriver) {
+        self.driver = Some(driver);
+    }
+
+    pub fn take_driver(&mut self) -> Option<BoxedDriver> {
+        self.driver.take()
+    }
+
+    pub fn set_frame_listener(&mut self, listener: BoxedListener) {
+        self.frame_listener = Some(listener);
+    }
+
+    pub fn take_frame_listener(&mut self) -> Option<BoxedListener> {
+        self.frame_listener.take()
+    }
+
+    // Encodes the last shown frame in the strip's wire order, returning the
+    // number of bytes written. `buf` must hold num_pixels * bytes_per_pixel.
+    pub fn encode_output(&self, buf: &mut [u8]) -> usize {
+        self.color_order
+            .encode_frame(self.output(), &self.output_white, buf)
+    }
+
+    // Turned on for programs with the INTERPOLATE flag
+    pub fn set_interpolation(&mut self, enabled: bool) {
+        self.interpolator = enabled.then(Interpolator::new);
+    }
+
+    // Sends the next blended frame, when interpolating. The firmware calls
+    // this at the strip's refresh rate; without interpolation show() sends
+    // frames itself, and this does nothing.
+    pub fn refresh(&mut self) -> Result<()> {
+        let Some(interpolator) = &mut self.interpolator else {
+            return Ok(());
+        };
+        interpolator.compose(self.output.get_mut(..self.num_pixels).unwrap_or_default());
+        self.send_output()
+    }
+
+    // Splits the frame between the strips in a program's strip table (see
+    // Program::strip_table), which must fit within num_pixels
+    pub fn set_strips(&mut self, table: &[u8]) -> Result<()> {
+        self.strips.clear();
+        self.selected_strip = None;
+        let mut start = 0;
+        for entry in table.chunks_exact(STRIP_ENTRY_SIZE) {
+            let &[len0, len1, order] = entry else {
+                continue;
+            };
+            let color_order = ColorOrder::from_u8(order).ok_or(ProgramError::InvalidStrips)?;
+            let len = u16::from_le_bytes([len0, len1]) as usize;
+            self.strips.push(Strip {
+                start,
+                len,
+                color_order,
+            });
+            start += len;
+        }
+        if start > self.num_pixels {
+            self.strips.clear();
+            return Err(ProgramError::TooManyPixels {
+                total: start,
+                max: self.num_pixels,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    // The driver for strip `strip` of a program's strip table
+    pub fn set_strip_driver(&mut self, strip: usize, driver: BoxedDriver) {
+        if let Some(slot) = self.strip_drivers.get_mut(strip) {
+            *slot = Some(driver);
+        }
+    }
+
+    // How long sending a frame takes, as WS2812s. Strips have their own
+    // drivers, so are sent at the same time.
+    pub fn transfer_us(&self) -> u32 {
+        if self.strips.is_empty() {
+            return output::ws2812_transfer_us(self.num_pixels, self.color_order);
+        }
+        self.strips
+            .iter()
+            .map(|strip| output::ws2812_transfer_us(strip.len, strip.color_order))
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn send_output(&mut self) -> Result<()> {
+        if self.strips.is_empty() {
+            let n = self.num_pixels;
+            return write_frame(
+                self.driver.as_mut(),
+                self.color_order,
+                self.output.get(..n).unwrap_or_default(),
+                self.output_white.get(..n).unwrap_or_default(),
+                &mut self.wire,
+                &mut self.dropped_frames,
+            );
+        }
+        for (strip, driver) in self.strips.iter().zip(&mut self.strip_drivers) {
+            let range = strip.start..strip.start + strip.len;
+            write_frame(
+                driver.as_mut(),
+                strip.color_order,
+                self.output.get(range.clone()).unwrap_or_default(),
+                self.output_white.get(range).unwrap_or_default(),
+                &mut self.wire,
+                &mut self.dropped_frames,
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn start_capture(&mut self) {
+        self.captured_frames = Some(Vec::new());
+    }
+
+    pub fn take_captured_frames(&mut self) -> Vec<Vec<Rgb>> {
+        self.captured_frames.take().unwrap_or_default()
+    }
+
+    pub fn snapshot(&self) -> LedSnapshot {
+        LedSnapshot {
+            pixels: self.pixels,
+            white: self.white,
+            output: self.output,
+            output_white: self.output_white,
+            selected_strip: self.selected_strip,
+            layout: self.layout,
+            pixel_map: self.pixel_map.clone(),
+            num_pixels: self.num_pixels,
+            frame_count: self.frame_count,
+            transition: self.transition.clone(),
+            interpolator: self.interpolator.clone(),
+            brightness: self.brightness,
+            dither: self.dither.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &LedSnapshot) {
+        self.pixels = snapshot.pixels;
+        self.white = snapshot.white;
+        self.output = snapshot.output;
+        self.output_white = snapshot.output_white;
+        self.selected_strip = snapshot.selected_strip;
+        self.layout = snapshot.layout;
+        self.pixel_map.clone_from(&snapshot.pixel_map);
+        self.num_pixels = snapshot.num_pixels;
+        self.frame_count = snapshot.frame_count;
+        self.transition = snapshot.transition.clone();
+        self.interpolator = snapshot.interpolator.clone();
+        self.brightness = snapshot.brightness;
+        self.dither = snapshot.dither.clone();
+    }
+
+    // The number of pixels scripts draw on: the selected strip's, if
+    // output() has chosen one, otherwise the whole frame's
+    fn drawable_len(&self) -> usize {
+        match self.selected_strip.and_then(|strip| self.strips.get(strip)) {
+            Some(strip) => strip.len,
+            None => self.num_pixels,
+        }
+    }
+
+    // Where a logical pixel is on the strip, if it's on it at all. Pixels
+    // of a selected strip are numbered from its start, and not mapped.
+    fn physical_index(&self, index: i16) -> Option<usize> {
+        let index = usize::try_from(index).ok()?;
+        if let Some(strip) = self.selected_strip.and_then(|strip| self.strips.get(strip)) {
+            return (index < strip.len).then_some(strip.start + index);
+        }
+        let index = match &self.pixel_map {
+            Some(map) => map.get(index)?.index as usize,
+            None => index,
+        };
+        (index < self.num_pixels).then_some(index)
+    }
+
+    // The coordinates of a logical pixel, from the pixel map if there is
+    // one, otherwise its row and column in the layout
+    fn position(&self, index: i16) -> Option<(i16, i16)> {
+        match &self.pixel_map {
+            Some(map) => {
+                let pixel = map.get(usize::try_from(index).ok()?)?;
+                Some((pixel.x, pixel.y))
+            }
+            None => {
+                let width = self.matrix_width().max(1);
+                (index >= 0).then(|| (index % width, index / width))
+            }
+        }
+    }
+
+    fn set_pixel(&mut self, index: i16, color: Rgb) {
+        // Writes outside the strip are clipped rather than treated as errors
+        if let Some(pixel) = self
+            .physical_index(index)
+            .and_then(|index| self.pixels.get_mut(index))
+        {
+            *pixel = color;
+        }
+    }
+
+    fn set_xy(&mut self, x: i16, y: i16, color: Rgb) {
+        let index = match &self.pixel_map {
+            Some(map) => map.at(x, y).filter(|index| *index < self.num_pixels),
+            None => self.layout.index(x, y, self.num_pixels),
+        };
+        if let Some(pixel) = index.and_then(|index| self.pixels.get_mut(index)) {
+            *pixel = color;
+        }
+    }
+
+    // Draws a sprite with its top-left corner at (x, y), clipping to the matrix
+    fn blit(&mut self, sprite: &Sprite, x: i16, y: i16) {
+        for sy in 0..sprite.height {
+            for sx in 0..sprite.width {
+                let color = sprite.pixel(sx, sy);
+                self.set_xy(
+                    x.saturating_add(sx as i16),
+                    y.saturating_add(sy as i16),
+                    color,
+                );
+            }
+        }
+    }
+
+    // Draws text with its top-left corner at (x, y). Only set glyph pixels are
+    // drawn, so the text overlays whatever is already in the framebuffer.
+    fn text(&mut self, text: &[u8], x: i16, y: i16, color: Rgb) {
+        for (i, c) in text.iter().enumerate() {
+            let char_x = x.saturating_add((i as i16).saturating_mul(font::ADVANCE));
+            for (col, bits) in font::glyph(*c).iter().enumerate() {
+                for row in 0..font::GLYPH_HEIGHT {
+                    if bits & (1 << row) != 0 {
+                        self.set_xy(char_x.saturating_add(col as i16), y.saturating_add(row), color);
+                    }
+                }
+            }
+        }
+    }
+
+    // Width of the matrix in pixels, as used for scrolling
+    fn matrix_width(&self) -> i16 {
+        match self.layout.width {
+            0 => self.num_pixels as i16,
+            width => width as i16,
+        }
+    }
+
+    fn show(&mut self) -> Result<()> {
+        let n = self.num_pixels;
+        let pixels = self.pixels.get(..n).unwrap_or_default();
+        let output = self.output.get_mut(..n).unwrap_or_default();
+        let white = self.white.get(..n).unwrap_or_default();
+        let output_white = self.output_white.get_mut(..n).unwrap_or_default();
+        match &mut self.transition {
+            Some(transition) => {
+                transition.compose(pixels, output);
+                if transition.is_finished() {
+                    self.transition = None;
+                }
+            }
+            None => output.copy_from_slice(pixels),
+        }
+
+        // Transitions only consider the RGB channels
+        output_white.copy_from_slice(white);
+        if self.brightness != u8::MAX {
+            match &mut self.dither {
+                Some(dither) => dither.scale(output, self.brightness),
+                None => color::fade_to_black(output, u8::MAX - self.brightness),
+            }
+            for w in output_white.iter_mut() {
+                *w = color::scale8(*w, self.brightness);
+            }
+        }
+        self.estimated_ma = match self.power_budget_ma {
+            Some(budget) => self.power_model.limit(output, output_white, budget),
+            None => self.power_model.estimate_ma(output, output_white),
+        };
+
+        self.frame_count = self.frame_count.wrapping_add(1);
+        if let Some(frames) = &mut self.captured_frames {
+            frames.push(output.to_vec());
+        }
+
+        if let Some(listener) = &mut self.frame_listener {
+            listener.on_frame(self.frame_count, output, output_white);
+        }
+
+        match &mut self.interpolator {
+            Some(interpolator) => {
+                interpolator.push_frame(output);
+                Ok(())
+            }
+            None => self.send_output(),
+        }
+    }
+}
+
+// Encodes `pixels` in the strip's wire order and starts sending them
+fn write_frame(
+    driver: Option<&mut BoxedDriver>,
+    color_order: ColorOrder,
+    pixels: &[Rgb],
+    white: &[u8],
+    wire: &mut [u8],
+    dropped_frames: &mut u32,
+) -> Result<()> {
+    let Some(driver) = driver else {
+        return Ok(());
+    };
+    let len = color_order.encode_frame(pixels, white, wire);
+    match driver.write(wire.get(..len).unwrap_or_default()) {
+        Ok(()) => Ok(()),
+        Err(OutputError::Busy) => {
+            *dropped_frames = dropped_frames.wrapping_add(1);
+            Ok(())
+        }
+        Err(OutputError::Failed) => Err(ModuleError::OutputFailed.into()),
+    }
+}
+
+define_module! {
+    led (vm) {
+        1 => async fn clear(&mut vm) -> Result<()> {
+            vm.modules.led.pixels.fill([0; 3]);
+            vm.modules.led.white.fill(0);
+            Ok(())
+        },
+        2 => async fn show(&mut vm) -> Result<()> {
+            vm.modules.led.show()?;
+            if vm.modules.led.simulate_transfer {
+                let mut us = vm.modules.led.transfer_us();
+                while us > 0 {
+                    let step = us.min(u16::MAX as u32);
+                    S::delay(step as u16).await;
+                    us -= step;
+                }
+            }
+            Ok(())
+        },
+        // The length of the selected strip, if output() has chosen one
+        3 => async fn get_num_pixels(&mut vm) -> Result<i16> {
+            Ok(vm.modules.led.drawable_len() as i16)
+        },
+        4 => async fn set_pixel(&mut vm, index: i16, r: u8, g: u8, b: u8) -> Result<()> {
+            vm.modules.led.set_pixel(index, [r, g, b]);
+            Ok(())
+        },
+        5 => async fn fill(&mut vm, start: i16, end: i16, r: u8, g: u8, b: u8) -> Result<()> {
+            let end = end.min(vm.modules.led.drawable_len() as i16 - 1);
+            for index in start.max(0)..=end {
+                vm.modules.led.set_pixel(index, [r, g, b]);
+            }
+            Ok(())
+        },
+        6 => async fn rgb(&mut vm, r: u8, g: u8, b: u8) -> Result<i16> {
+            Ok(super::color::pack_rgb565([r, g, b]))
+        },
+        7 => async fn blend(&mut vm, a: i16, b: i16, amount: u8) -> Result<i16> {
+            let blended = super::color::blend(
+                super::color::unpack_rgb565(a),
+                super::color::unpack_rgb565(b),
+                amount,
+            );
+            Ok(super::color::pack_rgb565(blended))
+        },
+        8 => async fn scale8(&mut vm, value: u8, scale: u8) -> Result<u8> {
+            Ok(super::color::scale8(value, scale))
+        },
+        9 => async fn fade_to_black(&mut vm, amount: u8) -> Result<()> {
+            super::color::fade_to_black(vm.modules.led.frame_mut(), amount);
+            Ok(())
+        },
+        10 => async fn set_pixel_color(&mut vm, index: i16, color: i16) -> Result<()> {
+            vm.modules.led.set_pixel(index, super::color::unpack_rgb565(color));
+            Ok(())
+        },
+        11 => async fn set_layout(&mut vm, width: i16, serpentine: bool) -> Result<()> {
+            vm.modules.led.layout = super::MatrixLayout {
+                width: width.max(0) as u16,
+                serpentine,
+            };
+            Ok(())
+        },
+        12 => async fn set_xy(&mut vm, x: i16, y: i16, r: u8, g: u8, b: u8) -> Result<()> {
+            vm.modules.led.set_xy(x, y, [r, g, b]);
+            Ok(())
+        },
+        13 => async fn blit(&mut vm, sprite: u16, x: i16, y: i16) -> Result<()> {
+            let sprite = super::Sprite::parse(&vm.memory, sprite as usize)
+                .ok_or(crate::modules::ModuleError::OutOfBounds)?;
+            vm.modules.led.blit(&sprite, x, y);
+            Ok(())
+        },
+        14 => async fn text(&mut vm, text: u16, len: u16, x: i16, y: i16, color: i16) -> Result<()> {
+            let text = vm.memory.get(text as usize..text as usize + len as usize)
+                .ok_or(crate::modules::ModuleError::OutOfBounds)?;
+            vm.modules.led.text(text, x, y, super::color::unpack_rgb565(color));
+            Ok(())
+        },
+        // Draws text scrolled `step` pixels leftwards from the right edge of the
+        // matrix, wrapping once it has fully left the left edge
+        15 => async fn scroll_text(&mut vm, text: u16, len: u16, y: i16, color: i16, step: i16) -> Result<()> {
+            let text = vm.memory.get(text as usize..text as usize + len as usize)
+                .ok_or(crate::modules::ModuleError::OutOfBounds)?;
+            let width = vm.modules.led.matrix_width();
+            let period = (width as i32 + super::font::text_width(text.len()) as i32).max(1);
+            let x = width as i32 - (step as i32).rem_euclid(period);
+            vm.modules.led.text(text, x as i16, y, super::color::unpack_rgb565(color));
+            Ok(())
+        },
+        16 => async fn text_width(&mut vm, len: u16) -> Result<i16> {
+            Ok(super::font::text_width(len as usize))
+        },
+        // Sets the white channel of an RGBW strip; ignored for RGB strips
+        17 => async fn set_w(&mut vm, index: i16, w: u8) -> Result<()> {
+            let led = &mut vm.modules.led;
+            if let Some(white) = led.physical_index(index).and_then(|index| led.white.get_mut(index)) {
+                *white = w;
+            }
+            Ok(())
+        },
+        // Turns dithering of dim colors on (non-zero) or off
+        18 => async fn dither(&mut vm, enabled: bool) -> Result<()> {
+            let led = &mut vm.modules.led;
+            if enabled != led.dither.is_some() {
+                led.dither = enabled.then(super::Dither::new);
+            }
+            Ok(())
+        },
+        // Maps pixels through the table at `map` (see pixel_map)
+        19 => async fn load_map(&mut vm, map: u16) -> Result<()> {
+            let map = super::PixelMap::parse(&vm.memory, map as usize)
+                .ok_or(crate::modules::ModuleError::OutOfBounds)?;
+            vm.modules.led.pixel_map = Some(map);
+            Ok(())
+        },
+        // Pushes the x then y coordinates of a pixel, e.g. to sample noise at
+        20 => async fn position(&mut vm, index: i16) -> Result<(i16, i16)> {
+            let position = vm.modules.led.position(index)
+                .ok_or(crate::modules::ModuleError::OutOfBounds)?;
+            Ok(position)
+        },
+        // Draws on strip n of the program's strip table, or the whole frame
+        // for -1
+        21 => async fn output(&mut vm, strip: i16) -> Result<()> {
+            let led = &mut vm.modules.led;
+            led.selected_strip = match usize::try_from(strip) {
+                Ok(strip) if strip < led.strips.len() => Some(strip),
+                Ok(_) => return Err(crate::modules::ModuleError::OutOfBounds.into()),
+                Err(_) => None,
+            };
+            Ok(())
+        },
+    }
+}
@@ -0,0 +1,80 @@
+use super::Rgb;
+
+// Channel order (and channel count) of the strip on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorOrder {
+    Rgb,
+    #[default]
+    Grb,
+    Brg,
+    Bgr,
+    Rgbw,
+    Grbw,
+}
+
+impl ColorOrder {
+    // As numbered in a program's strip table
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => ColorOrder::Rgb,
+            1 => ColorOrder::Grb,
+            2 => ColorOrder::Brg,
+            3 => ColorOrder::Bgr,
+            4 => ColorOrder::Rgbw,
+            5 => ColorOrder::Grbw,
+            _ => return None,
+        })
+    }
+
+    pub fn has_white(self) -> bool {
+        matches!(self, ColorOrder::Rgbw | ColorOrder::Grbw)
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        if self.has_white() { 4 } else { 3 }
+    }
+
+    // Writes one pixel in wire order, returning the number of bytes written.
+    // The white value is dropped for 3-channel strips.
+    pub fn encode(self, [r, g, b]: Rgb, w: u8, out: &mut [u8]) -> usize {
+        let bytes: &[u8] = match self {
+            ColorOrder::Rgb => &[r, g, b],
+            ColorOrder::Grb => &[g, r, b],
+            ColorOrder::Brg => &[b, r, g],
+            ColorOrder::Bgr => &[b, g, r],
+            ColorOrder::Rgbw => &[r, g, b, w],
+            ColorOrder::Grbw => &[g, r, b, w],
+        };
+        match out.get_mut(..bytes.len()) {
+            Some(out) => {
+                out.copy_from_slice(bytes);
+                bytes.len()
+            }
+            None => 0,
+        }
+    }
+
+    pub fn encode_frame(self, pixels: &[Rgb], white: &[u8], out: &mut [u8]) -> usize {
+        let mut len = 0;
+        for (pixel, w) in pixels.iter().zip(white) {
+            len += self.encode(*pixel, *w, out.get_mut(len..).unwrap_or_default());
+        }
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let mut out = [0u8; 4];
+        assert_eq!(ColorOrder::Grb.encode([1, 2, 3], 4, &mut out), 3);
+        assert_eq!(out[..3], [2, 1, 3]);
+        assert_eq!(ColorOrder::Brg.encode([1, 2, 3], 4, &mut out), 3);
+        assert_eq!(out[..3], [3, 1, 2]);
+        assert_eq!(ColorOrder::Grbw.encode([1, 2, 3], 4, &mut out), 4);
+        assert_eq!(out, [2, 1, 3, 4]);
+    }
+}
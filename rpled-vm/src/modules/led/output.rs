@@ -0,0 +1,175 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+
+use super::Rgb;
+use super::order::ColorOrder;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OutputError {
+    // The previous frame is still being sent
+    Busy,
+    Failed,
+}
+
+// A backend that sends encoded frames to a strip. write() should only start
+// the transfer (e.g. by kicking off DMA) and return, so that show() doesn't
+// block the VM while the strip is clocked out. Implementations that need the
+// data to outlive the call must copy it into their own buffer.
+pub trait OutputDriver: Send {
+    fn write(&mut self, frame: &[u8]) -> Result<(), OutputError>;
+}
+
+pub type BoxedDriver = Box<dyn OutputDriver>;
+
+// WS2812s take 1.25us per bit at 800kHz, then need the line held low for
+// the strip to latch the frame
+pub const WS2812_US_PER_BYTE: u32 = 10;
+pub const WS2812_LATCH_US: u32 = 50;
+
+// How long a WS2812 strip takes to clock out and latch a frame
+pub fn ws2812_transfer_us(num_pixels: usize, color_order: ColorOrder) -> u32 {
+    let bytes = num_pixels * color_order.bytes_per_pixel();
+    bytes as u32 * WS2812_US_PER_BYTE + WS2812_LATCH_US
+}
+
+// Told about every frame show() produces, after all output processing, so
+// host apps (simulator, recorder, Art-Net sender) get frames as they happen
+// rather than polling the framebuffer. Called from inside show(), so must
+// not block. Closures taking the same arguments work as listeners.
+pub trait FrameListener: Send {
+    fn on_frame(&mut self, frame_count: u32, frame: &[Rgb], white: &[u8]);
+}
+
+impl<F: FnMut(u32, &[Rgb], &[u8]) + Send> FrameListener for F {
+    fn on_frame(&mut self, frame_count: u32, frame: &[Rgb], white: &[u8]) {
+        self(frame_count, frame, white)
+    }
+}
+
+pub type BoxedListener = Box<dyn FrameListener>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::ModuleInit;
+    use crate::modules::led::LedModule;
+    use std::sync::{Arc, Mutex};
+    use std::vec::Vec;
+
+    struct RecordingDriver {
+        frames: Arc<Mutex<Vec<Vec<u8>>>>,
+        busy: bool,
+    }
+
+    impl OutputDriver for RecordingDriver {
+        fn write(&mut self, frame: &[u8]) -> Result<(), OutputError> {
+            if self.busy {
+                return Err(OutputError::Busy);
+            }
+            self.frames.lock().unwrap().push(frame.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_show_writes_to_driver() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let mut led = LedModule::init().await;
+        led.set_num_pixels(2);
+        led.color_order = ColorOrder::Grb;
+        led.set_driver(Box::new(RecordingDriver {
+            frames: frames.clone(),
+            busy: false,
+        }));
+        led.pixels[0] = [1, 2, 3];
+        led.show().unwrap();
+        assert_eq!(*frames.lock().unwrap(), [[2, 1, 3, 0, 0, 0]]);
+
+        led.set_driver(Box::new(RecordingDriver {
+            frames: frames.clone(),
+            busy: true,
+        }));
+        led.show().unwrap();
+        assert_eq!(led.dropped_frames, 1);
+        assert_eq!(frames.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_frame_listener() {
+        let shown = Arc::new(Mutex::new(Vec::new()));
+        let mut led = LedModule::init().await;
+        led.set_num_pixels(2);
+        led.brightness = 128;
+        led.set_frame_listener(Box::new({
+            let shown = shown.clone();
+            move |count: u32, frame: &[Rgb], _: &[u8]| {
+                shown.lock().unwrap().push((count, frame.to_vec()));
+            }
+        }));
+        led.pixels[1] = [200, 0, 0];
+        led.show().unwrap();
+        led.show().unwrap();
+        assert_eq!(
+            *shown.lock().unwrap(),
+            [
+                (1, vec![[0; 3], [100, 0, 0]]),
+                (2, vec![[0; 3], [100, 0, 0]])
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_strips() {
+        let (first, second) = (
+            Arc::new(Mutex::new(Vec::new())),
+            Arc::new(Mutex::new(Vec::new())),
+        );
+        let mut led = LedModule::init().await;
+        led.set_num_pixels(4);
+        assert!(led.set_strips(&[3, 0, 0, 2, 0, 0]).is_err());
+
+        // Two RGB pixels, then one BGR
+        led.set_strips(&[2, 0, 0, 1, 0, 3]).unwrap();
+        for (strip, frames) in [&first, &second].into_iter().enumerate() {
+            led.set_strip_driver(
+                strip,
+                Box::new(RecordingDriver {
+                    frames: frames.clone(),
+                    busy: false,
+                }),
+            );
+        }
+        led.pixels[..3].copy_from_slice(&[[1, 2, 3], [4, 5, 6], [7, 8, 9]]);
+        led.show().unwrap();
+        assert_eq!(*first.lock().unwrap(), [[1, 2, 3, 4, 5, 6]]);
+        assert_eq!(*second.lock().unwrap(), [[9, 8, 7]]);
+    }
+
+    #[tokio::test]
+    async fn test_simulate_transfer() {
+        use crate::builder::ProgramBuilder;
+        use crate::sync::TokioSync;
+        use crate::vm::opcodes;
+        use crate::vm_builder::VmBuilder;
+        use std::time::{Duration, Instant};
+
+        let mut buf = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Show").unwrap();
+        builder.module_call(opcodes::LED0, 2, 0).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let mut vm = VmBuilder::<256>::new()
+            .num_pixels(200)
+            .simulate_transfer()
+            .load::<TokioSync>(builder.finish())
+            .await
+            .unwrap();
+        assert_eq!(vm.modules.led.transfer_us(), 200 * 30 + 50);
+        vm.modules.led.set_strips(&[100, 0, 4, 50, 0, 0]).unwrap();
+        assert_eq!(vm.modules.led.transfer_us(), 100 * 40 + 50);
+
+        let start = Instant::now();
+        let _ = vm.run().await;
+        assert!(start.elapsed() >= Duration::from_micros(100 * 40 + 50));
+    }
+}
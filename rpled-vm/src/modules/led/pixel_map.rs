@@ -0,0 +1,97 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+// Maps the logical pixel indices scripts draw with onto physical positions
+// on the strip, with coordinates, for installations that aren't a line or
+// a grid (trees, sculptures). Tables are built from a CSV by the compiler,
+// and either placed in the program's data for the script to load, or set
+// by the host. Stored as a u16 count, then for each logical pixel its
+// physical index (u16) and x and y (i16s), all little-endian.
+pub const ENTRY_SIZE: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MappedPixel {
+    pub index: u16,
+    pub x: i16,
+    pub y: i16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PixelMap {
+    pub pixels: Vec<MappedPixel>,
+}
+
+impl PixelMap {
+    // Reads the table starting at `addr` in `memory`
+    pub fn parse(memory: &[u8], addr: usize) -> Option<Self> {
+        let count = u16::from_le_bytes([*memory.get(addr)?, *memory.get(addr + 1)?]) as usize;
+        let table = memory.get(addr + 2..addr + 2 + count * ENTRY_SIZE)?;
+        let pixels = table
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| match *entry {
+                [i0, i1, x0, x1, y0, y1] => MappedPixel {
+                    index: u16::from_le_bytes([i0, i1]),
+                    x: i16::from_le_bytes([x0, x1]),
+                    y: i16::from_le_bytes([y0, y1]),
+                },
+                _ => MappedPixel::default(),
+            })
+            .collect();
+        Some(PixelMap { pixels })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(2 + self.pixels.len() * ENTRY_SIZE);
+        out.extend_from_slice(&(self.pixels.len() as u16).to_le_bytes());
+        for pixel in &self.pixels {
+            out.extend_from_slice(&pixel.index.to_le_bytes());
+            out.extend_from_slice(&pixel.x.to_le_bytes());
+            out.extend_from_slice(&pixel.y.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn get(&self, logical: usize) -> Option<&MappedPixel> {
+        self.pixels.get(logical)
+    }
+
+    // The physical index of the pixel at (x, y), if there is one
+    pub fn at(&self, x: i16, y: i16) -> Option<usize> {
+        self.pixels
+            .iter()
+            .find(|pixel| pixel.x == x && pixel.y == y)
+            .map(|pixel| pixel.index as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pixel_map() {
+        let map = PixelMap {
+            pixels: vec![
+                MappedPixel {
+                    index: 2,
+                    x: 0,
+                    y: -1,
+                },
+                MappedPixel {
+                    index: 0,
+                    x: 5,
+                    y: 3,
+                },
+            ],
+        };
+        let mut memory = vec![0xff];
+        memory.extend(map.to_bytes());
+        assert_eq!(PixelMap::parse(&memory, 1), Some(map.clone()));
+        assert_eq!(PixelMap::parse(&memory[..memory.len() - 1], 1), None);
+
+        assert_eq!(map.get(1).map(|pixel| pixel.index), Some(0));
+        assert_eq!(map.at(0, -1), Some(2));
+        assert_eq!(map.at(1, 1), None);
+    }
+}
@@ -0,0 +1,102 @@
+use super::{Rgb, color};
+
+// Current draw model for a strip, in milliamps. The defaults are typical
+// figures for WS2812-style LEDs: ~20mA per channel at full and ~1mA idle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerModel {
+    pub channel_ma: u16,
+    pub idle_ma: u16,
+}
+
+impl Default for PowerModel {
+    fn default() -> Self {
+        PowerModel {
+            channel_ma: 20,
+            idle_ma: 1,
+        }
+    }
+}
+
+impl PowerModel {
+    // The draw of `pixels` and their white channels (empty for RGB strips),
+    // saturating at u32::MAX
+    pub fn estimate_ma(&self, pixels: &[Rgb], white: &[u8]) -> u32 {
+        let channel_total: u64 = pixels
+            .iter()
+            .flatten()
+            .chain(white)
+            .map(|&c| c as u64)
+            .sum();
+        let ma = channel_total * self.channel_ma as u64 / 255
+            + pixels.len() as u64 * self.idle_ma as u64;
+        u32::try_from(ma).unwrap_or(u32::MAX)
+    }
+
+    // Scales `pixels` and `white` down so that the estimated draw fits in
+    // `budget_ma`, returning the estimate after scaling
+    pub fn limit(&self, pixels: &mut [Rgb], white: &mut [u8], budget_ma: u32) -> u32 {
+        let estimate = self.estimate_ma(pixels, white);
+        let idle = (pixels.len() as u32).saturating_mul(self.idle_ma as u32);
+        if estimate <= budget_ma || budget_ma <= idle {
+            return estimate;
+        }
+        // Only the channel current scales with brightness
+        let scale = ((budget_ma - idle) as u64 * 255 / (estimate - idle) as u64) as u8;
+        color::fade_to_black(pixels, 255 - scale);
+        for w in white.iter_mut() {
+            *w = color::scale8(*w, scale);
+        }
+        self.estimate_ma(pixels, white)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit() {
+        let model = PowerModel::default();
+        let mut pixels = [[255; 3]; 10];
+        assert_eq!(model.estimate_ma(&pixels, &[]), 610);
+
+        let estimate = model.limit(&mut pixels, &mut [], 310);
+        assert!(estimate <= 310, "{estimate}");
+        assert!(estimate > 290, "{estimate}");
+
+        let mut dim = [[10, 0, 0]; 10];
+        assert_eq!(
+            model.limit(&mut dim, &mut [], 310),
+            model.estimate_ma(&[[10, 0, 0]; 10], &[])
+        );
+        assert_eq!(dim, [[10, 0, 0]; 10]);
+    }
+
+    #[test]
+    fn test_limit_white() {
+        let model = PowerModel::default();
+        let mut pixels = [[0; 3]; 10];
+        let mut white = [255; 10];
+        assert_eq!(model.estimate_ma(&pixels, &white), 210);
+
+        let estimate = model.limit(&mut pixels, &mut white, 110);
+        assert!(estimate <= 110, "{estimate}");
+        assert!(white[0] < 255 && white[0] > 100, "{}", white[0]);
+    }
+
+    #[test]
+    fn test_large_channel_current() {
+        let model = PowerModel {
+            channel_ma: u16::MAX,
+            idle_ma: u16::MAX,
+        };
+        let mut pixels = [[255; 3]; 256];
+        let mut white = [255; 256];
+        assert_eq!(
+            model.estimate_ma(&pixels, &white),
+            256 * 4 * u16::MAX as u32 + 256 * u16::MAX as u32
+        );
+        let budget = 256 * 2 * u16::MAX as u32;
+        assert!(model.limit(&mut pixels, &mut white, budget) <= budget);
+    }
+}
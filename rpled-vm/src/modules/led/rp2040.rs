@@ -0,0 +1,106 @@
+use rp2040_hal::dma::single_buffer::{Config, Transfer};
+use rp2040_hal::dma::{Byte, SingleChannel};
+use rp2040_hal::gpio::{Pin, PinId, PullType};
+use rp2040_hal::pio::{
+    Buffers, PIO, PIOBuilder, PIOExt, PinDir, Running, ShiftDirection, StateMachine,
+    StateMachineIndex, Tx, UninitStateMachine,
+};
+
+use super::output::{OutputDriver, OutputError};
+
+// The program below takes 10 PIO cycles per bit, for 800kbit/s
+const CYCLES_PER_BIT: u64 = 10;
+const BIT_RATE_HZ: u64 = 800_000;
+
+type Buffer = &'static mut [u8];
+type FifoTx<P, SM> = Tx<(P, SM), Byte>;
+
+enum State<P: PIOExt, SM: StateMachineIndex, CH: SingleChannel> {
+    Idle(CH, Buffer, FifoTx<P, SM>),
+    Sending(Transfer<CH, Buffer, FifoTx<P, SM>>),
+}
+
+// Driver for WS2812 strips on the RP2040: a PIO state machine generates the
+// signal, fed from `buffer` by a DMA channel, so write() only copies the
+// frame and starts the transfer. The buffer should be the size of the
+// strip's frames; shorter frames are padded with unlit pixels, and longer
+// ones fail.
+pub struct Rp2040PioDriver<P: PIOExt, SM: StateMachineIndex, CH: SingleChannel> {
+    state: Option<State<P, SM, CH>>,
+    _sm: StateMachine<(P, SM), Running>,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex, CH: SingleChannel> Rp2040PioDriver<P, SM, CH> {
+    pub fn new<I: PinId, R: PullType>(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        pin: Pin<I, P::PinFunction, R>,
+        dma: CH,
+        buffer: &'static mut [u8],
+        system_clock_hz: u32,
+    ) -> Result<Self, OutputError> {
+        // Each bit is high for 2 cycles, then high (1) or low (0) for 5, then
+        // low for 3, as in the Pico SDK's ws2812 example
+        let program = pio::pio_asm!(
+            ".side_set 1",
+            ".wrap_target",
+            "bitloop:",
+            "    out x, 1       side 0 [2]",
+            "    jmp !x do_zero side 1 [1]",
+            "do_one:",
+            "    jmp bitloop    side 1 [4]",
+            "do_zero:",
+            "    nop            side 0 [4]",
+            ".wrap",
+        );
+        let installed = pio
+            .install(&program.program)
+            .map_err(|_| OutputError::Failed)?;
+
+        // As a fixed point number with 8 fractional bits
+        let divisor = system_clock_hz as u64 * 256 / (BIT_RATE_HZ * CYCLES_PER_BIT);
+        let pin = pin.id().num;
+        // DMA writes bytes, which the bus repeats across the whole FIFO word,
+        // so the top 8 bits shifted out are the byte, most significant first
+        let (mut sm, _, tx) = PIOBuilder::from_installed_program(installed)
+            .side_set_pin_base(pin)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(true)
+            .pull_threshold(8)
+            .buffers(Buffers::OnlyTx)
+            .clock_divisor_fixed_point((divisor >> 8) as u16, divisor as u8)
+            .build(sm);
+        sm.set_pindirs([(pin, PinDir::Output)]);
+
+        Ok(Rp2040PioDriver {
+            state: Some(State::Idle(dma, buffer, tx.transfer_size(Byte))),
+            _sm: sm.start(),
+        })
+    }
+}
+
+impl<P: PIOExt, SM: StateMachineIndex, CH: SingleChannel + Send> OutputDriver
+    for Rp2040PioDriver<P, SM, CH>
+{
+    fn write(&mut self, frame: &[u8]) -> Result<(), OutputError> {
+        let (dma, buffer, tx) = match self.state.take() {
+            Some(State::Sending(transfer)) if !transfer.is_done() => {
+                self.state = Some(State::Sending(transfer));
+                return Err(OutputError::Busy);
+            }
+            Some(State::Sending(transfer)) => transfer.wait(),
+            Some(State::Idle(dma, buffer, tx)) => (dma, buffer, tx),
+            None => return Err(OutputError::Failed),
+        };
+        if frame.len() > buffer.len() {
+            self.state = Some(State::Idle(dma, buffer, tx));
+            return Err(OutputError::Failed);
+        }
+        buffer.fill(0);
+        for (out, byte) in buffer.iter_mut().zip(frame) {
+            *out = *byte;
+        }
+        self.state = Some(State::Sending(Config::new(dma, buffer, tx).start()));
+        Ok(())
+    }
+}
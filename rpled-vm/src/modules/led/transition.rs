@@ -0,0 +1,87 @@
+use super::{MAX_PIXELS, Rgb, color};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionKind {
+    CrossFade,
+    // Reveals the incoming frame from the start of the strip to the end
+    Wipe,
+}
+
+// An in-progress transition from a captured outgoing frame to whatever the
+// incoming program draws, lasting a fixed number of shown frames.
+#[derive(Clone)]
+pub struct Transition {
+    pub kind: TransitionKind,
+    pub frames: u16,
+    pub elapsed: u16,
+    from: [Rgb; MAX_PIXELS],
+}
+
+impl Transition {
+    pub fn new(kind: TransitionKind, frames: u16, from: &[Rgb]) -> Self {
+        let mut from_frame = [[0; 3]; MAX_PIXELS];
+        for (pixel, from) in from_frame.iter_mut().zip(from) {
+            *pixel = *from;
+        }
+        Transition {
+            kind,
+            frames: frames.max(1),
+            elapsed: 0,
+            from: from_frame,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.elapsed >= self.frames
+    }
+
+    // Writes the blended frame for the current step into `out`, then advances
+    pub fn compose(&mut self, incoming: &[Rgb], out: &mut [Rgb]) {
+        self.elapsed = self.elapsed.saturating_add(1).min(self.frames);
+        let progress = self.elapsed as u32 * 255 / self.frames as u32;
+        match self.kind {
+            TransitionKind::CrossFade => {
+                for ((out, from), to) in out.iter_mut().zip(&self.from).zip(incoming) {
+                    *out = color::blend(*from, *to, progress as u8);
+                }
+            }
+            TransitionKind::Wipe => {
+                let revealed = incoming.len() * self.elapsed as usize / self.frames as usize;
+                let pixels = out.iter_mut().zip(incoming).zip(&self.from);
+                for (i, ((out, to), from)) in pixels.enumerate() {
+                    *out = if i < revealed { *to } else { *from };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cross_fade() {
+        let mut transition = Transition::new(TransitionKind::CrossFade, 2, &[[200, 0, 0]]);
+        let mut out = [[0; 3]];
+        transition.compose(&[[0, 0, 200]], &mut out);
+        assert_eq!(out, [[100, 0, 100]]);
+        assert!(!transition.is_finished());
+        transition.compose(&[[0, 0, 200]], &mut out);
+        assert_eq!(out, [[0, 0, 200]]);
+        assert!(transition.is_finished());
+    }
+
+    #[test]
+    fn test_wipe() {
+        let from = [[1, 1, 1]; 4];
+        let incoming = [[9, 9, 9]; 4];
+        let mut transition = Transition::new(TransitionKind::Wipe, 4, &from);
+        let mut out = [[0; 3]; 4];
+        transition.compose(&incoming, &mut out);
+        assert_eq!(out, [[9, 9, 9], [1, 1, 1], [1, 1, 1], [1, 1, 1]]);
+        transition.compose(&incoming, &mut out);
+        transition.compose(&incoming, &mut out);
+        assert_eq!(out, [[9, 9, 9], [9, 9, 9], [9, 9, 9], [1, 1, 1]]);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::sync::Sync;
+use crate::vm::{Result, VM, VmDebug};
+
+// Conversions from the i16 values on the stack to the typed parameters of
+// module functions (see define_module!). A parameter takes SLOTS values,
+// popped in argument order, so the first parameter is on top of the stack.
+pub trait FromStack: Sized {
+    const SLOTS: usize;
+
+    // Takes SLOTS values from `values`, which always has enough
+    fn from_stack(values: &mut impl Iterator<Item = i16>) -> Self;
+}
+
+fn next(values: &mut impl Iterator<Item = i16>) -> i16 {
+    values.next().unwrap_or(0)
+}
+
+impl FromStack for i16 {
+    const SLOTS: usize = 1;
+
+    fn from_stack(values: &mut impl Iterator<Item = i16>) -> Self {
+        next(values)
+    }
+}
+
+// Addresses and lengths, which can use the full 16 bits
+impl FromStack for u16 {
+    const SLOTS: usize = 1;
+
+    fn from_stack(values: &mut impl Iterator<Item = i16>) -> Self {
+        next(values) as u16
+    }
+}
+
+// Clamped to 0..=255, as color channels and amounts are
+impl FromStack for u8 {
+    const SLOTS: usize = 1;
+
+    fn from_stack(values: &mut impl Iterator<Item = i16>) -> Self {
+        next(values).clamp(0, u8::MAX as i16) as u8
+    }
+}
+
+// Any non-zero value is true
+impl FromStack for bool {
+    const SLOTS: usize = 1;
+
+    fn from_stack(values: &mut impl Iterator<Item = i16>) -> Self {
+        next(values) != 0
+    }
+}
+
+// Two values, with the low half on top: push the high half first
+impl FromStack for i32 {
+    const SLOTS: usize = 2;
+
+    fn from_stack(values: &mut impl Iterator<Item = i16>) -> Self {
+        let low = next(values) as u16 as i32;
+        let high = next(values) as i32;
+        high << 16 | low
+    }
+}
+
+// 8.8 fixed point, for fractional parameters: 256 is 1.0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fixed(pub i16);
+
+impl Fixed {
+    pub const ONE: i16 = 256;
+
+    // Rounded towards negative infinity
+    pub fn to_int(self) -> i16 {
+        self.0 >> 8
+    }
+
+    pub fn fraction(self) -> u8 {
+        self.0 as u8
+    }
+
+    // `value` scaled by this, e.g. a brightness by a factor
+    pub fn scale(self, value: i16) -> i16 {
+        ((value as i32 * self.0 as i32) >> 8) as i16
+    }
+}
+
+impl FromStack for Fixed {
+    const SLOTS: usize = 1;
+
+    fn from_stack(values: &mut impl Iterator<Item = i16>) -> Self {
+        Fixed(next(values))
+    }
+}
+
+// Conversions from the values module functions return to the VALUES i16s
+// define_module! pushes for them. Tuples push in order, so the last value
+// ends up on top; () pushes nothing.
+pub trait IntoStack {
+    const VALUES: u8;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, vm: &mut VM<N, S, D>) -> Result<()>;
+}
+
+impl IntoStack for () {
+    const VALUES: u8 = 0;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, _vm: &mut VM<N, S, D>) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl IntoStack for i16 {
+    const VALUES: u8 = 1;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, vm: &mut VM<N, S, D>) -> Result<()> {
+        vm.stack_push(self)
+    }
+}
+
+impl IntoStack for u16 {
+    const VALUES: u8 = 1;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, vm: &mut VM<N, S, D>) -> Result<()> {
+        vm.stack_push(self as i16)
+    }
+}
+
+impl IntoStack for u8 {
+    const VALUES: u8 = 1;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, vm: &mut VM<N, S, D>) -> Result<()> {
+        vm.stack_push(self as i16)
+    }
+}
+
+// 1 or 0
+impl IntoStack for bool {
+    const VALUES: u8 = 1;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, vm: &mut VM<N, S, D>) -> Result<()> {
+        vm.stack_push(self as i16)
+    }
+}
+
+// The high half, then the low half, as i32 parameters take them
+impl IntoStack for i32 {
+    const VALUES: u8 = 2;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, vm: &mut VM<N, S, D>) -> Result<()> {
+        vm.stack_push((self >> 16) as i16)?;
+        vm.stack_push(self as i16)
+    }
+}
+
+impl IntoStack for Fixed {
+    const VALUES: u8 = 1;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, vm: &mut VM<N, S, D>) -> Result<()> {
+        vm.stack_push(self.0)
+    }
+}
+
+impl<A: IntoStack, B: IntoStack> IntoStack for (A, B) {
+    const VALUES: u8 = A::VALUES + B::VALUES;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, vm: &mut VM<N, S, D>) -> Result<()> {
+        self.0.push(vm)?;
+        self.1.push(vm)
+    }
+}
+
+impl<A: IntoStack, B: IntoStack, C: IntoStack> IntoStack for (A, B, C) {
+    const VALUES: u8 = A::VALUES + B::VALUES + C::VALUES;
+
+    fn push<const N: usize, S: Sync, D: VmDebug>(self, vm: &mut VM<N, S, D>) -> Result<()> {
+        self.0.push(vm)?;
+        self.1.push(vm)?;
+        self.2.push(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_stack() {
+        let mut values = [300, -1, 2, -5, 1, 0x1234, -2, -384].into_iter();
+        assert_eq!(u8::from_stack(&mut values), 255);
+        assert_eq!(u16::from_stack(&mut values), 0xffff);
+        assert!(bool::from_stack(&mut values));
+        assert_eq!(u8::from_stack(&mut values), 0);
+        assert_eq!(i32::from_stack(&mut values), 0x1234_0001);
+        assert_eq!(i32::from_stack(&mut values), -2 & 0xffff | -384 << 16);
+
+        let half = Fixed(Fixed::ONE / 2);
+        assert_eq!(half.scale(100), 50);
+        assert_eq!(Fixed(-384).to_int(), -2);
+        assert_eq!(Fixed(0x0180).fraction(), 0x80);
+    }
+
+    #[tokio::test]
+    async fn test_into_stack() {
+        use crate::sync::TokioSync;
+        use crate::vm::make_vm;
+
+        let mut vm = make_vm::<64, TokioSync>().await;
+        assert_eq!(<(i16, i32, bool)>::VALUES, 4);
+        (7i16, 0x1234_0001i32, true).push(&mut vm).unwrap();
+        ().push(&mut vm).unwrap();
+        // The last value ends up on top, and i32s read back as pushed
+        let mut values = [
+            vm.stack_pop::<i16>().unwrap(),
+            vm.stack_pop::<i16>().unwrap(),
+            vm.stack_pop::<i16>().unwrap(),
+        ]
+        .into_iter();
+        assert!(bool::from_stack(&mut values));
+        assert_eq!(i32::from_stack(&mut values), 0x1234_0001);
+        assert_eq!(vm.stack_pop::<i16>().unwrap(), 7);
+    }
+}
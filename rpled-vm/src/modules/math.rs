@@ -0,0 +1,56 @@
+use crate::vm::Result;
+use paste::paste;
+
+// Easing curves operate on 8-bit fixed point: inputs are clamped to 0..=255
+// (0.0..=1.0) and results are pushed back onto the stack on the same scale.
+pub const UNIT: i32 = 255;
+
+pub struct MathModule {}
+
+impl super::ModuleInit for MathModule {
+    async fn init() -> Self {
+        MathModule {}
+    }
+
+    async fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn unit(t: i16) -> i32 {
+    (t as i32).clamp(0, UNIT)
+}
+
+pub fn ease_in(t: i16) -> i16 {
+    let t = unit(t);
+    (t * t / UNIT) as i16
+}
+
+pub fn ease_out(t: i16) -> i16 {
+    let inv = UNIT - unit(t);
+    (UNIT - inv * inv / UNIT) as i16
+}
+
+pub fn ease_in_out(t: i16) -> i16 {
+    let t = unit(t);
+    if t < (UNIT + 1) / 2 {
+        (2 * t * t / UNIT) as i16
+    } else {
+        let inv = UNIT - t;
+        (UNIT - 2 * inv * inv / UNIT) as i16
+    }
+}
+
+define_module! {
+    math (vm) {
+        1 => async fn ease_in(&mut vm, t: i16) -> Result<i16> {
+            Ok(super::ease_in(t))
+        },
+        2 => async fn ease_out(&mut vm, t: i16) -> Result<i16> {
+            Ok(super::ease_out(t))
+        },
+        3 => async fn ease_in_out(&mut vm, t: i16) -> Result<i16> {
+            Ok(super::ease_in_out(t))
+        },
+    }
+}
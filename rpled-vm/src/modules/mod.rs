@@ -2,35 +2,100 @@ use crate::sync::Sync;
 use crate::vm::{Result, VM, VmDebug};
 use bitflags::bitflags;
 
+#[cfg(any(test, modules))]
 #[macro_use]
 mod define_module;
 
-#[cfg(test)]
+pub mod marshal;
+pub mod requests;
+
+#[cfg(any(test, feature = "fixtures"))]
 pub mod test;
 
 #[cfg(feature = "led")]
 pub mod led;
 
+#[cfg(feature = "math")]
+pub mod math;
+
+#[cfg(feature = "msg")]
+pub mod msg;
+
+#[cfg(feature = "dbg")]
+pub mod dbg;
+
+#[cfg(feature = "time")]
+pub mod time;
+
+#[cfg(feature = "rand")]
+pub mod rand;
+
+#[cfg(feature = "host")]
+pub mod host;
+
 #[derive(Debug)]
 pub enum ModuleError {
     InvalidModuleOpcode,
     IncorrectCallVariant,
+    OutOfBounds,
+    OutputFailed,
+    // Async module calls (see requests)
+    TooManyRequests,
+    UnknownRequest,
+    RequestNotReady,
+    // msg.recv on an empty channel
+    NoMessage,
+    // An invalid print template, or one with too many values
+    BadFormat,
+    // A host function returned None (see host)
+    HostCallFailed,
+}
+
+// A channel number (msg, test_emit), from 0 to 255. Others fail, rather
+// than wrapping round onto another channel.
+#[cfg(any(test, feature = "msg", feature = "fixtures"))]
+fn channel(channel: i16) -> Result<u8> {
+    u8::try_from(channel).map_err(|_| ModuleError::OutOfBounds.into())
 }
 
 pub const TEST_OPCODE_OFFSET: u8 = 60;
 pub const LED_OPCODE_OFFSET: u8 = 64;
+pub const MATH_OPCODE_OFFSET: u8 = 68;
+pub const MSG_OPCODE_OFFSET: u8 = 72;
+pub const DBG_OPCODE_OFFSET: u8 = 76;
+pub const TIME_OPCODE_OFFSET: u8 = 80;
+pub const RAND_OPCODE_OFFSET: u8 = 84;
+pub const HOST_OPCODE_OFFSET: u8 = 88;
 
 pub const ENABLED_MODULE_IDS: &[u8] = &[
-    #[cfg(test)]
+    #[cfg(any(test, feature = "fixtures"))]
     TEST_OPCODE_OFFSET,
     #[cfg(feature = "led")]
     LED_OPCODE_OFFSET,
+    #[cfg(feature = "math")]
+    MATH_OPCODE_OFFSET,
+    #[cfg(feature = "msg")]
+    MSG_OPCODE_OFFSET,
+    #[cfg(feature = "dbg")]
+    DBG_OPCODE_OFFSET,
+    #[cfg(feature = "time")]
+    TIME_OPCODE_OFFSET,
+    #[cfg(feature = "rand")]
+    RAND_OPCODE_OFFSET,
+    #[cfg(feature = "host")]
+    HOST_OPCODE_OFFSET,
 ];
 
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
     pub struct ModuleFlags: u8 {
         const LED = 0b00000001;
+        const MATH = 0b00000010;
+        const MSG = 0b00000100;
+        const DBG = 0b00001000;
+        const TIME = 0b00010000;
+        const RAND = 0b00100000;
+        const HOST = 0b01000000;
         const TEST = 0b10000000;
     }
 }
@@ -38,11 +103,73 @@ bitflags! {
 pub const fn offset_to_flag(offset: u8) -> Option<ModuleFlags> {
     match offset {
         LED_OPCODE_OFFSET => Some(ModuleFlags::LED),
+        MATH_OPCODE_OFFSET => Some(ModuleFlags::MATH),
+        MSG_OPCODE_OFFSET => Some(ModuleFlags::MSG),
+        DBG_OPCODE_OFFSET => Some(ModuleFlags::DBG),
+        TIME_OPCODE_OFFSET => Some(ModuleFlags::TIME),
+        RAND_OPCODE_OFFSET => Some(ModuleFlags::RAND),
+        HOST_OPCODE_OFFSET => Some(ModuleFlags::HOST),
         TEST_OPCODE_OFFSET => Some(ModuleFlags::TEST),
         _ => None,
     }
 }
 
+// Code analysis tables generated by define_module!
+struct ModuleTables {
+    results: &'static [(u8, u8)],
+    function_names: &'static [(u8, &'static str)],
+}
+
+// Module opcodes are allocated in aligned groups of 4, starting at the
+// module offset
+fn module_tables(module_offset: u8) -> Option<ModuleTables> {
+    match module_offset {
+        #[cfg(any(test, feature = "fixtures"))]
+        TEST_OPCODE_OFFSET => Some((test::RESULTS, test::FUNCTION_NAMES)),
+        #[cfg(feature = "led")]
+        LED_OPCODE_OFFSET => Some((led::RESULTS, led::FUNCTION_NAMES)),
+        #[cfg(feature = "math")]
+        MATH_OPCODE_OFFSET => Some((math::RESULTS, math::FUNCTION_NAMES)),
+        #[cfg(feature = "msg")]
+        MSG_OPCODE_OFFSET => Some((msg::RESULTS, msg::FUNCTION_NAMES)),
+        #[cfg(feature = "dbg")]
+        DBG_OPCODE_OFFSET => Some((dbg::RESULTS, dbg::FUNCTION_NAMES)),
+        #[cfg(feature = "time")]
+        TIME_OPCODE_OFFSET => Some((time::RESULTS, time::FUNCTION_NAMES)),
+        #[cfg(feature = "rand")]
+        RAND_OPCODE_OFFSET => Some((rand::RESULTS, rand::FUNCTION_NAMES)),
+        _ => None,
+    }
+    .map(|(results, function_names)| ModuleTables {
+        results,
+        function_names,
+    })
+}
+
+// Number of results a module function pushes, for code analysis
+pub fn call_results(module_offset: u8, func: u8) -> Option<u8> {
+    // Host functions are registered at run time, but all push one value
+    #[cfg(feature = "host")]
+    if module_offset == HOST_OPCODE_OFFSET {
+        return Some(host::RESULTS);
+    }
+    module_tables(module_offset)?
+        .results
+        .iter()
+        .find(|(f, _)| *f == func)
+        .map(|(_, n)| *n)
+}
+
+pub fn function_name(module_offset: u8, func: u8) -> Option<&'static str> {
+    module_tables(module_offset)?
+        .function_names
+        .iter()
+        .find(|(f, _)| *f == func)
+        .map(|(_, name)| *name)
+}
+
+// Evaluated at compile time, so it can't panic at run time
+#[allow(clippy::indexing_slicing, clippy::unwrap_used)]
 pub const ENABLED_MODULE_FLAGS: ModuleFlags = {
     let mut flags: u8 = 0;
     let mut i = 0;
@@ -53,31 +180,72 @@ pub const ENABLED_MODULE_FLAGS: ModuleFlags = {
     ModuleFlags::from_bits(flags).unwrap()
 };
 
+#[cfg(any(test, modules, feature = "host"))]
 trait ModuleInit {
     async fn init() -> Self
     where
         Self: Sized;
     async fn reset(&mut self) -> Result<()>;
+
+    // Called regularly by the VM's run loop, to progress work parked by
+    // async module calls. Must not block.
+    fn poll(&mut self) {}
 }
 
 #[allow(dead_code)]
 pub struct Modules {
-    #[cfg(test)]
+    #[cfg(any(test, feature = "fixtures"))]
     pub test: test::TestModule,
 
     #[cfg(feature = "led")]
     pub led: led::LedModule,
+
+    #[cfg(feature = "math")]
+    pub math: math::MathModule,
+
+    #[cfg(feature = "msg")]
+    pub msg: msg::MsgModule,
+
+    #[cfg(feature = "dbg")]
+    pub dbg: dbg::DbgModule,
+
+    #[cfg(feature = "time")]
+    pub time: time::TimeModule,
+
+    #[cfg(feature = "rand")]
+    pub rand: rand::RandModule,
+
+    #[cfg(feature = "host")]
+    pub host: host::HostModule,
 }
 
 #[allow(dead_code)]
 impl Modules {
     pub async fn init() -> Self {
         Self {
-            #[cfg(test)]
+            #[cfg(any(test, feature = "fixtures"))]
             test: test::TestModule::init().await,
 
             #[cfg(feature = "led")]
             led: led::LedModule::init().await,
+
+            #[cfg(feature = "math")]
+            math: math::MathModule::init().await,
+
+            #[cfg(feature = "msg")]
+            msg: msg::MsgModule::init().await,
+
+            #[cfg(feature = "dbg")]
+            dbg: dbg::DbgModule::init().await,
+
+            #[cfg(feature = "time")]
+            time: time::TimeModule::init().await,
+
+            #[cfg(feature = "rand")]
+            rand: rand::RandModule::init().await,
+
+            #[cfg(feature = "host")]
+            host: host::HostModule::init().await,
         }
     }
 
@@ -85,11 +253,55 @@ impl Modules {
         &mut self,
         _vm: &mut VM<N, S, D>,
     ) -> Result<()> {
-        #[cfg(test)]
+        #[cfg(any(test, feature = "fixtures"))]
         test::TestModule::reset(&mut self.test).await?;
 
         #[cfg(feature = "led")]
         led::LedModule::reset(&mut self.led).await?;
+
+        #[cfg(feature = "math")]
+        math::MathModule::reset(&mut self.math).await?;
+
+        #[cfg(feature = "msg")]
+        msg::MsgModule::reset(&mut self.msg).await?;
+
+        #[cfg(feature = "dbg")]
+        dbg::DbgModule::reset(&mut self.dbg).await?;
+
+        #[cfg(feature = "time")]
+        time::TimeModule::reset(&mut self.time).await?;
+
+        #[cfg(feature = "rand")]
+        rand::RandModule::reset(&mut self.rand).await?;
+
+        #[cfg(feature = "host")]
+        host::HostModule::reset(&mut self.host).await?;
         Ok(())
     }
+
+    pub fn poll(&mut self) {
+        #[cfg(any(test, feature = "fixtures"))]
+        test::TestModule::poll(&mut self.test);
+
+        #[cfg(feature = "led")]
+        led::LedModule::poll(&mut self.led);
+
+        #[cfg(feature = "math")]
+        math::MathModule::poll(&mut self.math);
+
+        #[cfg(feature = "msg")]
+        msg::MsgModule::poll(&mut self.msg);
+
+        #[cfg(feature = "dbg")]
+        dbg::DbgModule::poll(&mut self.dbg);
+
+        #[cfg(feature = "time")]
+        time::TimeModule::poll(&mut self.time);
+
+        #[cfg(feature = "rand")]
+        rand::RandModule::poll(&mut self.rand);
+
+        #[cfg(feature = "host")]
+        host::HostModule::poll(&mut self.host);
+    }
 }
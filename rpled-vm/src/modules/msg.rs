@@ -0,0 +1,111 @@
+use crate::vm::Result;
+use paste::paste;
+
+// Messages between VMs on the same controller, e.g. one per strip, through
+// the Sync layer's mailbox. Channels are FIFO queues of values; recv
+// doesn't wait, so scripts check pending() first (SLEEPing in between) or
+// wrap recv in a TRY.
+pub struct MsgModule {}
+
+impl super::ModuleInit for MsgModule {
+    async fn init() -> Self {
+        MsgModule {}
+    }
+
+    async fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+define_module! {
+    msg (vm) {
+        // Pushes 1 if the value was queued, 0 if the channel is full
+        1 => async fn send(&mut vm, channel: i16, value: i16) -> Result<bool> {
+            use crate::sync::Mailbox;
+            let channel = crate::modules::channel(channel)?;
+            Ok(vm.mailbox().send(channel, value))
+        },
+        2 => async fn recv(&mut vm, channel: i16) -> Result<i16> {
+            use crate::sync::Mailbox;
+            let channel = crate::modules::channel(channel)?;
+            let value = vm
+                .mailbox()
+                .recv(channel)
+                .ok_or(crate::modules::ModuleError::NoMessage)?;
+            Ok(value)
+        },
+        3 => async fn pending(&mut vm, channel: i16) -> Result<i16> {
+            use crate::sync::Mailbox;
+            let channel = crate::modules::channel(channel)?;
+            let pending = vm.mailbox().pending(channel);
+            Ok(pending as i16)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::ProgramBuilder;
+    use crate::sync::TokioSync;
+    use crate::vm::{HaltReason, VMError, make_vm, opcodes};
+
+    #[tokio::test]
+    async fn test_message_passing() {
+        // Channel 7 is used by this test alone, as the mailbox is shared
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Send").unwrap();
+        for value in [10, 20] {
+            builder.push(value).unwrap();
+            builder.push(7).unwrap();
+            builder.module_call(opcodes::MSG0, 1, 2).unwrap();
+            builder.op(opcodes::POP).unwrap();
+        }
+        builder.op(opcodes::HALT).unwrap();
+        let mut sender = make_vm::<256, TokioSync>().await;
+        sender.load(builder.finish()).unwrap();
+
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Recv").unwrap();
+        builder.push(7).unwrap();
+        builder.module_call(opcodes::MSG0, 3, 1).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        for _ in 0..3 {
+            builder.push(7).unwrap();
+            builder.module_call(opcodes::MSG0, 2, 1).unwrap();
+            builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        }
+        let mut receiver = make_vm::<256, TokioSync>().await;
+        receiver.load(builder.finish()).unwrap();
+
+        assert!(matches!(
+            sender.run().await,
+            Err(VMError::Halt(HaltReason::HaltOp))
+        ));
+        assert!(matches!(
+            receiver.run().await,
+            Err(VMError::ModuleError(super::super::ModuleError::NoMessage))
+        ));
+        assert_eq!(
+            receiver.modules.test.messages,
+            ["TEST_ONE_ARG: 2", "TEST_ONE_ARG: 10", "TEST_ONE_ARG: 20"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_channel_out_of_range() {
+        for channel in [256, -1] {
+            let mut buf = [0u8; 64];
+            let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Send").unwrap();
+            builder.push(1).unwrap();
+            builder.push(channel).unwrap();
+            builder.module_call(opcodes::MSG0, 1, 2).unwrap();
+            builder.op(opcodes::HALT).unwrap();
+            let mut vm = make_vm::<256, TokioSync>().await;
+            vm.load(builder.finish()).unwrap();
+            assert!(matches!(
+                vm.run().await,
+                Err(VMError::ModuleError(super::super::ModuleError::OutOfBounds))
+            ));
+        }
+    }
+}
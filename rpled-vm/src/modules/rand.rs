@@ -0,0 +1,104 @@
+use crate::vm::Result;
+use paste::paste;
+
+// Pseudo-random numbers from a xorshift generator. Every program starts
+// from the same seed, so runs are repeatable; scripts wanting different
+// patterns each time seed it from e.g. time.millis().
+pub const DEFAULT_SEED: u32 = 0x2545_f491;
+
+#[derive(Clone)]
+pub struct RandModule {
+    state: u32,
+}
+
+impl super::ModuleInit for RandModule {
+    async fn init() -> Self {
+        RandModule {
+            state: DEFAULT_SEED,
+        }
+    }
+
+    async fn reset(&mut self) -> Result<()> {
+        self.seed(DEFAULT_SEED);
+        Ok(())
+    }
+}
+
+impl RandModule {
+    // xorshift never leaves 0, so that gives the default seed
+    pub fn seed(&mut self, seed: u32) {
+        self.state = if seed == 0 { DEFAULT_SEED } else { seed };
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    // Between `low` and `high` inclusive, in either order
+    pub fn range(&mut self, low: i16, high: i16) -> i16 {
+        let (low, high) = (low.min(high) as i32, low.max(high) as i32);
+        let span = (high - low + 1) as u32;
+        (low + (self.next_u32() % span) as i32) as i16
+    }
+}
+
+define_module! {
+    rand (vm) {
+        // Any i16
+        1 => async fn int(&mut vm) -> Result<i16> {
+            Ok((vm.modules.rand.next_u32() >> 16) as i16)
+        },
+        2 => async fn range(&mut vm, low: i16, high: i16) -> Result<i16> {
+            Ok(vm.modules.rand.range(low, high))
+        },
+        3 => async fn seed(&mut vm, seed: i32) -> Result<()> {
+            vm.modules.rand.seed(seed as u32);
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::modules::ModuleInit;
+    use crate::sync::TokioSync;
+    use crate::vm::{make_vm, opcodes};
+
+    #[tokio::test]
+    async fn test_rand() {
+        let mut rand = RandModule::init().await;
+        let first: Vec<u32> = (0..4).map(|_| rand.next_u32()).collect();
+        rand.seed(0);
+        assert_eq!((0..4).map(|_| rand.next_u32()).collect::<Vec<_>>(), first);
+        for _ in 0..100 {
+            assert!((-3..=2).contains(&rand.range(2, -3)));
+        }
+        assert_eq!(rand.range(7, 7), 7);
+
+        // Two runs of the same program give the same numbers
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Rand").unwrap();
+        builder.module_call(opcodes::RAND0, 1, 0).unwrap();
+        builder.push(10).unwrap();
+        builder.push(1).unwrap();
+        builder.module_call(opcodes::RAND0, 2, 2).unwrap();
+        builder.module_call(opcodes::TEST0, 3, 2).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+        let mut vm = make_vm::<256, TokioSync>().await;
+        let mut runs = vec![];
+        for _ in 0..2 {
+            vm.load(program).unwrap();
+            let _ = vm.run().await;
+            runs.push(vm.modules.test.messages.pop().unwrap());
+        }
+        assert_eq!(runs[0], runs[1]);
+    }
+}
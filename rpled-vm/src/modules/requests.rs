@@ -0,0 +1,125 @@
+use super::ModuleError;
+use crate::vm::Result;
+
+// Async module calls: rather than awaiting slow work (a network fetch, say)
+// inside the call, which stalls the frame loop, a module parks a request
+// here and pushes its ticket straight away, so the script keeps running.
+// Whoever does the work (the module's poll(), or the host through
+// vm.modules) completes the request, and the script checks ready(ticket)
+// and collects the result with take(ticket). Tickets are slot indexes, and
+// are reused once taken.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Free,
+    Waiting(i16),
+    Done(i16),
+}
+
+pub struct Requests<const M: usize> {
+    slots: [Slot; M],
+}
+
+impl<const M: usize> Default for Requests<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const M: usize> Requests<M> {
+    pub const fn new() -> Self {
+        Requests {
+            slots: [Slot::Free; M],
+        }
+    }
+
+    fn slot(&self, ticket: i16) -> Result<Slot> {
+        match self.slots.get(ticket as usize) {
+            Some(Slot::Free) | None => Err(ModuleError::UnknownRequest.into()),
+            Some(slot) => Ok(*slot),
+        }
+    }
+
+    fn slot_mut(&mut self, ticket: i16) -> Result<&mut Slot> {
+        match self.slots.get_mut(ticket as usize) {
+            Some(Slot::Free) | None => Err(ModuleError::UnknownRequest.into()),
+            Some(slot) => Ok(slot),
+        }
+    }
+
+    // Returns the ticket for a new request
+    pub fn park(&mut self, request: i16) -> Result<i16> {
+        let (ticket, slot) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| **slot == Slot::Free)
+            .ok_or(ModuleError::TooManyRequests)?;
+        *slot = Slot::Waiting(request);
+        Ok(ticket as i16)
+    }
+
+    // (ticket, request) for each request not yet completed
+    pub fn waiting(&self) -> impl Iterator<Item = (i16, i16)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(ticket, slot)| match slot {
+                Slot::Waiting(request) => Some((ticket as i16, *request)),
+                _ => None,
+            })
+    }
+
+    pub fn complete(&mut self, ticket: i16, result: i16) -> Result<()> {
+        let slot = self.slot_mut(ticket)?;
+        match slot {
+            Slot::Waiting(_) => {
+                *slot = Slot::Done(result);
+                Ok(())
+            }
+            _ => Err(ModuleError::UnknownRequest.into()),
+        }
+    }
+
+    pub fn ready(&self, ticket: i16) -> Result<bool> {
+        Ok(matches!(self.slot(ticket)?, Slot::Done(_)))
+    }
+
+    // The result of a completed request, freeing its ticket
+    pub fn take(&mut self, ticket: i16) -> Result<i16> {
+        let slot = self.slot_mut(ticket)?;
+        match *slot {
+            Slot::Done(result) => {
+                *slot = Slot::Free;
+                Ok(result)
+            }
+            _ => Err(ModuleError::RequestNotReady.into()),
+        }
+    }
+
+    // Drops every request, e.g. when the program is reset
+    pub fn clear(&mut self) {
+        self.slots = [Slot::Free; M];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let mut requests: Requests<2> = Requests::new();
+        assert_eq!(requests.park(10).unwrap(), 0);
+        assert_eq!(requests.park(20).unwrap(), 1);
+        assert!(requests.park(30).is_err());
+        assert!(!requests.ready(1).unwrap());
+        assert!(requests.take(1).is_err());
+
+        requests.complete(1, 21).unwrap();
+        assert_eq!(requests.waiting().collect::<Vec<_>>(), [(0, 10)]);
+        assert!(requests.ready(1).unwrap());
+        assert_eq!(requests.take(1).unwrap(), 21);
+        assert!(requests.ready(1).is_err());
+        assert_eq!(requests.park(30).unwrap(), 1);
+    }
+}
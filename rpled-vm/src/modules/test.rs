@@ -1,3 +1,7 @@
+// Host only, so free to panic (see lib.rs)
+#![allow(clippy::unwrap_used)]
+
+use super::requests::Requests;
 use crate::vm::Result;
 use paste::paste;
 
@@ -8,19 +12,46 @@ use std::vec::Vec;
 
 pub struct TestModule {
     pub messages: Vec<String>,
+    // (channel, value) for each test_emit call, in order
+    pub emitted: Vec<(u8, i16)>,
+    // Fetches started by test_fetch, completed on the next poll
+    pub fetches: Requests<4>,
 }
 
 impl super::ModuleInit for TestModule {
     async fn init() -> Self {
         TestModule {
             messages: Vec::new(),
+            emitted: Vec::new(),
+            fetches: Requests::new(),
         }
     }
 
     async fn reset(&mut self) -> Result<()> {
         self.messages.clear();
+        self.emitted.clear();
+        self.fetches.clear();
         Ok(())
     }
+
+    fn poll(&mut self) {
+        let waiting: Vec<_> = self.fetches.waiting().collect();
+        for (ticket, request) in waiting {
+            self.messages.push(format!("TEST_FETCH DONE: {}", request));
+            self.fetches.complete(ticket, request * 2).unwrap();
+        }
+    }
+}
+
+impl TestModule {
+    // The values emitted on `channel`, in order
+    pub fn channel(&self, channel: u8) -> Vec<i16> {
+        self.emitted
+            .iter()
+            .filter(|(emitted, _)| *emitted == channel)
+            .map(|(_, value)| *value)
+            .collect()
+    }
 }
 
 define_module! {
@@ -46,11 +77,39 @@ define_module! {
             Ok(())
         },
         5 => async fn test_print(&mut vm, msg_ptr: u16, msg_len: u16) -> Result<()> {
-            let msg_bytes = vm.memory[msg_ptr as usize..(msg_ptr + msg_len) as usize].to_vec();
+            let msg_bytes = vm
+                .memory
+                .get(msg_ptr as usize..msg_ptr as usize + msg_len as usize)
+                .ok_or(crate::modules::ModuleError::OutOfBounds)?
+                .to_vec();
             let msg = String::from_utf8_lossy(&msg_bytes).to_string();
             std::println!("TEST_PRINT called with message: {} (*{}, {})", msg, msg_ptr, msg_len);
             vm.modules.test.messages.push(format!("TEST_PRINT: {:?}", msg));
             Ok(())
         },
+        // An example async call: a fetch that completes in the background
+        6 => async fn test_fetch(&mut vm, request: i16) -> Result<i16> {
+            vm.modules.test.messages.push(format!("TEST_FETCH: {}", request));
+            vm.modules.test.fetches.park(request)
+        },
+        7 => async fn test_ready(&mut vm, ticket: i16) -> Result<bool> {
+            vm.modules.test.fetches.ready(ticket)
+        },
+        8 => async fn test_take(&mut vm, ticket: i16) -> Result<i16> {
+            vm.modules.test.fetches.take(ticket)
+        },
+        9 => async fn test_printf(&mut vm, fmt_ptr: u16, fmt_len: u16) -> Result<()> {
+            let mut msg = String::new();
+            crate::format::format_from_vm(vm, fmt_ptr, fmt_len, &mut msg)?;
+            vm.modules.test.messages.push(format!("TEST_PRINTF: {}", msg));
+            Ok(())
+        },
+        // Records a value on a numbered channel, for fixtures to check
+        // without formatting it into a message
+        10 => async fn test_emit(&mut vm, channel: i16, value: i16) -> Result<()> {
+            let channel = crate::modules::channel(channel)?;
+            vm.modules.test.emitted.push((channel, value));
+            Ok(())
+        },
     }
 }
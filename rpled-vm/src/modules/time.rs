@@ -0,0 +1,77 @@
+use crate::vm::Result;
+use paste::paste;
+
+// Time since the program was loaded, from the Sync layer's clock, for
+// animations that should run at the same speed whatever the frame rate.
+#[derive(Clone)]
+pub struct TimeModule {
+    start_us: u64,
+}
+
+impl super::ModuleInit for TimeModule {
+    async fn init() -> Self {
+        TimeModule { start_us: 0 }
+    }
+
+    async fn reset(&mut self) -> Result<()> {
+        self.start_us = 0;
+        Ok(())
+    }
+}
+
+impl TimeModule {
+    // Called when a program is loaded, with the clock's time then
+    pub fn start(&mut self, now_us: u64) {
+        self.start_us = now_us;
+    }
+
+    pub fn millis(&self, now_us: u64) -> u64 {
+        now_us.saturating_sub(self.start_us) / 1000
+    }
+}
+
+define_module! {
+    time (vm) {
+        // Wraps every 65.536 seconds, so take differences with SUB rather
+        // than comparing
+        1 => async fn millis(&mut vm) -> Result<i16> {
+            Ok(vm.modules.time.millis(S::now_us()) as i16)
+        },
+        // The same count as an i32, which wraps after 24 days
+        2 => async fn millis32(&mut vm) -> Result<i32> {
+            Ok(vm.modules.time.millis(S::now_us()) as i32)
+        },
+        3 => async fn seconds(&mut vm) -> Result<i16> {
+            Ok((vm.modules.time.millis(S::now_us()) / 1000) as i16)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::modules::ModuleInit;
+    use crate::sync::TokioSync;
+    use crate::vm::{make_vm, opcodes};
+
+    #[tokio::test]
+    async fn test_millis() {
+        let mut time = TimeModule::init().await;
+        time.start(5_000_000);
+        assert_eq!(time.millis(5_000_999), 0);
+        assert_eq!(time.millis(75_536_000), 70_536);
+        assert_eq!(time.millis(0), 0);
+
+        // A program that reads the clock straight after loading
+        let mut buf = [0u8; 64];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Seconds").unwrap();
+        builder.module_call(opcodes::TIME0, 3, 0).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load(builder.finish()).unwrap();
+        let _ = vm.run().await;
+        assert_eq!(vm.modules.test.messages, ["TEST_ONE_ARG: 0"]);
+    }
+}
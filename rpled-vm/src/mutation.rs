@@ -0,0 +1,133 @@
+// Mutation testing of the interpreter: runs every single-opcode and
+// single-operand mutant of the fixture programs, checking that the VM fails
+// with an error, rather than panicking, hanging in an op, or writing
+// outside the heap and stack. Mutants are applied to the loaded code, so
+// they get past the loader's checks. The outcomes are printed, to track
+// how mutants fail over time (run with --nocapture).
+
+use std::collections::BTreeMap;
+use std::format;
+use std::string::{String, ToString};
+use std::time::Duration;
+use std::vec::Vec;
+
+use crate::disasm::Instructions;
+use crate::fixture_parse::parse_fixture_with_output;
+use crate::sync::TokioSync;
+use crate::vm::opcodes::{self, Operand};
+use crate::vm::{HaltReason, VMError};
+use crate::vm_builder::{Standard4K, VmBuilder};
+
+// Ops run per mutant before giving up on it
+const OP_BUDGET: usize = 200;
+
+// Longest a single op may take: a SLEEP of the maximum 65ms, with margin
+const OP_TIMEOUT: Duration = Duration::from_secs(1);
+
+// (offset in the code, replacement bytes)
+type Mutant = (usize, Vec<u8>);
+
+fn operand_values(kind: Operand) -> Vec<Vec<u8>> {
+    match kind.size() {
+        1 => [0u8, 1, 0x7f, 0x80, 0xff].map(|value| vec![value]).to_vec(),
+        _ => [0u16, 1, 0x7fff, 0x8000, 0xffff]
+            .map(|value| value.to_le_bytes().to_vec())
+            .to_vec(),
+    }
+}
+
+fn mutants(code: &[u8]) -> Vec<Mutant> {
+    let mut mutants = vec![];
+    for (offset, instruction) in Instructions::new(code) {
+        let Ok(instruction) = instruction else {
+            continue;
+        };
+        for &(opcode, _) in opcodes::NAMES {
+            if opcode != instruction.opcode {
+                mutants.push((offset, vec![opcode]));
+            }
+        }
+        mutants.push((offset, vec![0xff]));
+        let mut start = offset + 1;
+        for &kind in instruction.operands {
+            for value in operand_values(kind) {
+                mutants.push((start, value));
+            }
+            start += kind.size();
+        }
+    }
+    mutants
+}
+
+// Runs a mutant, returning how it ended
+async fn run_mutant(vm: &mut Standard4K<TokioSync>, mutant: &str) -> String {
+    let code = vm.memory[..vm.max_pc].to_vec();
+    for _ in 0..OP_BUDGET {
+        let step = tokio::time::timeout(OP_TIMEOUT, vm.step()).await;
+        assert_eq!(
+            vm.memory[..vm.max_pc],
+            code[..],
+            "{}: code overwritten",
+            mutant
+        );
+        // Jumps may leave pc past the code, as the next fetch then fails
+        assert!(
+            vm.pc < vm.memory.len(),
+            "{}: pc {} past memory",
+            mutant,
+            vm.pc
+        );
+        assert!(
+            vm.sp >= vm.heap_end && vm.sp <= vm.memory.len(),
+            "{}: sp {} outside the stack",
+            mutant,
+            vm.sp
+        );
+        match step.unwrap_or_else(|_| panic!("{}: op hung", mutant)) {
+            Ok(()) => {}
+            Err(VMError::Halt(HaltReason::HaltOp)) => return "halt".to_string(),
+            Err(err) => {
+                let name = format!("{:?}", err);
+                let end = name.find(['(', ' ']).unwrap_or(name.len());
+                return name[..end].to_string();
+            }
+        }
+    }
+    "out of budget".to_string()
+}
+
+#[tokio::test]
+async fn test_mutations() {
+    let mut outcomes: BTreeMap<String, usize> = BTreeMap::new();
+    let mut vm = VmBuilder::standard_4k().build::<TokioSync>().await;
+    let mut paths: Vec<_> = std::fs::read_dir("../testprogs")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().ends_with(".pxs.txt"))
+        .collect();
+    paths.sort();
+    for path in paths {
+        let fixture = std::fs::read_to_string(&path).unwrap();
+        let program = parse_fixture_with_output(&fixture).unwrap().program;
+        if vm.load(&program).is_err() {
+            continue;
+        }
+        let code = vm.code().to_vec();
+        for (offset, bytes) in mutants(&code) {
+            vm.load(&program).unwrap();
+            vm.modules.test.messages.clear();
+            let end = (offset + bytes.len()).min(vm.max_pc);
+            vm.memory[offset..end].copy_from_slice(&bytes[..end - offset]);
+            let mutant = format!("{} +{} = {:?}", path.display(), offset, bytes);
+            let outcome = run_mutant(&mut vm, &mutant).await;
+            *outcomes.entry(outcome).or_default() += 1;
+        }
+    }
+
+    let total: usize = outcomes.values().sum();
+    println!("{} mutants:", total);
+    for (outcome, count) in &outcomes {
+        println!("  {:<16} {:>6} ({}%)", outcome, count, count * 100 / total);
+    }
+    assert!(total > 0);
+}
@@ -0,0 +1,41 @@
+use crate::sync::Sync;
+use crate::vm::{Result, VM, VMError, VmDebug};
+
+// A bump allocator over the free memory between the heap and the stack, for
+// tables and strings that only live for a frame. ALLOC hands out zeroed
+// blocks as heap addresses, so LOAD/STORE reach them as usual; FREEALL
+// frees every block at once, and is emitted by the compiler at the end of
+// each frame.
+pub fn alloc<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    let size: u16 = vm.read_pc()?;
+    let start = vm.heap_end;
+    let end = start + size as usize;
+    // Leave room for the address being pushed
+    if end + size_of::<u16>() > vm.sp {
+        return Err(VMError::HeapOverflow);
+    }
+    vm.memory
+        .get_mut(start..end)
+        .ok_or(VMError::HeapOverflow)?
+        .fill(0);
+    vm.heap_end = end;
+    vm.stack_push((start - vm.heap_start) as u16)
+}
+
+pub fn free_all<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    vm.heap_end = vm.arena_start;
+    Ok(())
+}
+
+// Indirect heap access, for blocks whose address is only known at runtime
+pub fn loadi<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    let addr: u16 = vm.stack_pop()?;
+    let value: u16 = vm.read_heap(addr as usize)?;
+    vm.stack_push(value)
+}
+
+pub fn storei<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    let addr: u16 = vm.stack_pop()?;
+    let value: u16 = vm.stack_pop()?;
+    vm.write_heap(addr as usize, value)
+}
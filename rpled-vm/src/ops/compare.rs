@@ -6,7 +6,7 @@ macro_rules! compare_op {
         pub fn $name<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
             let b: i16 = vm.stack_pop()?;
             let a: i16 = vm.stack_pop()?;
-            let result = if a $op b { 1 } else { 0 };
+            let result: i16 = if a $op b { 1 } else { 0 };
             vm.stack_push(result)
         }
     };
@@ -1,10 +1,10 @@
 use crate::sync::Sync;
-use crate::vm::{HaltReason, Result, VM, VMError, VmDebug};
+use crate::vm::{HaltReason, Result, TryFrame, VM, VMError, VmDebug};
 
 #[inline]
 fn do_jmp<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>, addr: i16) -> Result<()> {
     let new_pc = vm.pc as isize + addr as isize;
-    if new_pc < 0 || new_pc as usize >= vm.memory.len() {
+    if new_pc < 0 || new_pc as usize >= vm.code().len() {
         return Err(crate::vm::VMError::InvalidJump);
     }
     vm.pc = new_pc as usize;
@@ -34,9 +34,36 @@ pub fn jnz<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<
     Ok(())
 }
 
+pub fn jmp8<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    let addr: i8 = vm.read_pc()?;
+    do_jmp(vm, addr as i16)
+}
+
+pub fn jz8<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    let addr: i8 = vm.read_pc()?;
+    let cond: i16 = vm.stack_pop()?;
+    if cond == 0 {
+        do_jmp(vm, addr as i16)?;
+    }
+    Ok(())
+}
+
+pub fn jnz8<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    let addr: i8 = vm.read_pc()?;
+    let cond: i16 = vm.stack_pop()?;
+    if cond != 0 {
+        do_jmp(vm, addr as i16)?;
+    }
+    Ok(())
+}
+
 fn do_call<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>, addr: i16) -> Result<()> {
-    let ret_addr = vm.pc;
-    vm.stack_push(ret_addr as u16)?;
+    let slot = vm
+        .call_stack
+        .get_mut(vm.call_depth)
+        .ok_or(VMError::StackOverflow)?;
+    *slot = vm.pc as u16;
+    vm.call_depth += 1;
     do_jmp(vm, addr)
 }
 
@@ -63,16 +90,60 @@ pub fn callnz<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Resu
 }
 
 pub fn ret<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
-    let ret_addr: u16 = vm.stack_pop()?;
-    vm.set_pc(ret_addr as usize)
+    vm.call_depth = vm
+        .call_depth
+        .checked_sub(1)
+        .ok_or(VMError::StackUnderflow)?;
+    let addr = vm
+        .call_stack
+        .get(vm.call_depth)
+        .ok_or(VMError::StackUnderflow)?;
+    vm.set_pc(*addr as usize)?;
+    // Returning from a function called by TRY: report success
+    let innermost_try = vm
+        .try_depth
+        .checked_sub(1)
+        .and_then(|i| vm.try_frames.get(i));
+    if innermost_try.is_some_and(|frame| frame.call_depth == vm.call_depth) {
+        vm.try_depth -= 1;
+        vm.stack_push(0i16)?;
+    }
+    Ok(())
+}
+
+// Calls a function like CALL, but if it fails the stack is unwound and
+// execution resumes after the TRY. Either way an error code is pushed: 0 if
+// the function returned, otherwise VMError::code().
+pub fn try_call<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    let addr: i16 = vm.read_pc()?;
+    let frame = vm
+        .try_frames
+        .get_mut(vm.try_depth)
+        .ok_or(VMError::StackOverflow)?;
+    *frame = TryFrame {
+        sp: vm.sp,
+        call_depth: vm.call_depth,
+        heap_end: vm.heap_end,
+        resume_pc: vm.pc,
+    };
+    vm.try_depth += 1;
+    do_call(vm, addr)
 }
 
 pub fn halt<const N: usize, S: Sync, D: VmDebug>(_vm: &mut VM<N, S, D>) -> Result<()> {
     Err(VMError::Halt(HaltReason::HaltOp))
 }
 
+pub fn halt_with<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    let code: i16 = vm.stack_pop()?;
+    Err(VMError::Halt(HaltReason::Exit(code)))
+}
+
 pub async fn sleep<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
     let duration_us: u16 = vm.stack_pop()?;
-    vm.delay(duration_us).await;
+    S::delay(duration_us).await;
+    // A sleeping script is usually waiting on something, so give async
+    // module calls a chance to complete
+    vm.modules.poll();
     Ok(())
 }
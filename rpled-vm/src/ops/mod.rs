@@ -1,3 +1,4 @@
+pub mod arena;
 pub mod bitwise;
 pub mod compare;
 pub mod control;
@@ -1,5 +1,4 @@
 use bytemuck::checked::pod_read_unaligned;
-use bytemuck::{cast_mut, from_bytes_mut};
 
 use crate::sync::Sync;
 use crate::vm::{Result, VM, VMError, VmDebug};
@@ -9,6 +8,23 @@ pub fn push<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result
     vm.stack_push(value)
 }
 
+pub fn push8<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    let value: i8 = vm.read_pc()?;
+    vm.stack_push(value as i16)
+}
+
+pub fn push1<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    vm.stack_push(1i16)
+}
+
+pub fn push2<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    vm.stack_push(2i16)
+}
+
+pub fn push3<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
+    vm.stack_push(3i16)
+}
+
 pub fn load<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
     let addr: u16 = vm.read_pc()?;
     let value: u16 = vm.read_heap(addr as usize)?;
@@ -40,38 +56,26 @@ pub fn popn<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result
 }
 
 pub fn dup<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
-    let value: u16 = pod_read_unaligned(&vm.memory[vm.sp..(vm.sp + 2)]);
+    let value: u16 = pod_read_unaligned(vm.stack_top_mut(2)?);
     vm.stack_push(value)
 }
 
+// Stack values are u16s, so SWAP and ROT rotate the top bytes two at a time
 pub fn swap<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
-    if vm.sp + 4 > vm.memory.len() {
-        return Err(VMError::StackUnderflow);
-    }
-    let bytes_arr: &mut [u8; 4] = from_bytes_mut(&mut vm.memory[vm.sp..(vm.sp + 4)]);
-    let halves: &mut [u16; 2] = cast_mut(bytes_arr);
-    halves.swap(0, 1);
+    vm.stack_top_mut(4)?.rotate_left(2);
     Ok(())
 }
 
 pub fn over<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
-    if vm.sp + 4 > vm.memory.len() {
-        return Err(VMError::StackUnderflow);
-    }
-    let value: u16 = pod_read_unaligned(&vm.memory[(vm.sp + 2)..(vm.sp + 4)]);
+    let [_, value]: [u16; 2] = pod_read_unaligned(vm.stack_top_mut(4)?);
     vm.stack_push(value)
 }
 
 pub fn rot<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
-    if vm.sp + 6 > vm.memory.len() {
-        return Err(VMError::StackUnderflow);
-    }
-    let bytes_arr: &mut [u8; 6] = from_bytes_mut(&mut vm.memory[vm.sp..(vm.sp + 6)]);
-    let thirds: &mut [u16; 3] = cast_mut(bytes_arr);
-    thirds.rotate_left(1);
+    vm.stack_top_mut(6)?.rotate_left(2);
     Ok(())
 }
 
 pub fn zero<const N: usize, S: Sync, D: VmDebug>(vm: &mut VM<N, S, D>) -> Result<()> {
-    vm.stack_push(0)
+    vm.stack_push(0i16)
 }
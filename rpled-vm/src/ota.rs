@@ -0,0 +1,171 @@
+use crate::storage::{ProgramStorage, ProgramStore, SlotInfo, SlotState, StorageError};
+
+type Result<T> = core::result::Result<T, StorageError>;
+
+// A/B program updates with automatic rollback. An uploaded program starts
+// on trial: it has to run `trial_frames` frames without a VMError before it
+// is confirmed and the previous program is dropped. If it fails, or the
+// device resets before it is confirmed, the previous program is booted.
+pub struct OtaUpdater<S: ProgramStorage> {
+    pub store: ProgramStore<S>,
+    pub trial_frames: u32,
+    // Slot being trialled, and the number of good frames it has shown
+    trial: Option<(usize, u32)>,
+}
+
+impl<S: ProgramStorage> OtaUpdater<S> {
+    pub fn new(store: ProgramStore<S>, trial_frames: u32) -> Self {
+        OtaUpdater {
+            store,
+            trial_frames,
+            trial: None,
+        }
+    }
+
+    pub async fn upload(&mut self, program: &[u8]) -> Result<usize> {
+        self.store.store(program).await
+    }
+
+    // Picks the slot to run, typically at startup or after an upload. A
+    // program found still on trial didn't survive its last boot, so it is
+    // discarded in favour of the previous one, if there is one. A first
+    // upload has nothing to roll back to, so its trial starts over.
+    pub async fn boot_slot(&mut self) -> Result<Option<usize>> {
+        loop {
+            let Some(newest) = self.store.newest(|info| info.state.has_program()).await? else {
+                self.trial = None;
+                return Ok(None);
+            };
+            match newest.state {
+                SlotState::Stored => {
+                    self.store.set_state(newest.slot, SlotState::Trial).await?;
+                    self.trial = Some((newest.slot, 0));
+                    return Ok(Some(newest.slot));
+                }
+                SlotState::Trial => {
+                    let is_confirmed = |info: &SlotInfo| info.state == SlotState::Confirmed;
+                    if self.store.newest(is_confirmed).await?.is_none() {
+                        self.trial = Some((newest.slot, 0));
+                        return Ok(Some(newest.slot));
+                    }
+                    self.store
+                        .set_state(newest.slot, SlotState::Deleted)
+                        .await?
+                }
+                _ => {
+                    self.trial = None;
+                    return Ok(Some(newest.slot));
+                }
+            }
+        }
+    }
+
+    // Called after each frame the running program shows
+    pub async fn frame_shown(&mut self) -> Result<()> {
+        let Some((slot, frames)) = &mut self.trial else {
+            return Ok(());
+        };
+        *frames += 1;
+        if *frames >= self.trial_frames {
+            let slot = *slot;
+            self.confirm(slot).await?;
+        }
+        Ok(())
+    }
+
+    // Called when the running program fails. Rolls back if it was on trial,
+    // returning the slot to run instead.
+    pub async fn program_failed(&mut self) -> Result<Option<usize>> {
+        match self.trial.take() {
+            Some((slot, _)) => {
+                self.store.set_state(slot, SlotState::Deleted).await?;
+                self.boot_slot().await
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn confirm(&mut self, slot: usize) -> Result<()> {
+        self.store.set_state(slot, SlotState::Confirmed).await?;
+        self.trial = None;
+        // Free the older program's slot for the next update
+        let is_old = |info: &SlotInfo| info.slot != slot && info.state == SlotState::Confirmed;
+        while let Some(old) = self.store.newest(is_old).await? {
+            self.store.set_state(old.slot, SlotState::Deleted).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    type Storage = MemoryStorage<2, 64>;
+
+    #[tokio::test]
+    async fn test_update_confirmed() {
+        let mut ota = OtaUpdater::new(ProgramStore::new(Storage::default()), 2);
+        let a = ota.upload(b"a").await.unwrap();
+        assert_eq!(ota.boot_slot().await.unwrap(), Some(a));
+        ota.frame_shown().await.unwrap();
+        ota.frame_shown().await.unwrap();
+
+        let b = ota.upload(b"b").await.unwrap();
+        assert_eq!(ota.boot_slot().await.unwrap(), Some(b));
+        ota.frame_shown().await.unwrap();
+        ota.frame_shown().await.unwrap();
+        assert_eq!(
+            ota.store.slot_info(b).await.unwrap().state,
+            SlotState::Confirmed
+        );
+        assert_eq!(
+            ota.store.slot_info(a).await.unwrap().state,
+            SlotState::Deleted
+        );
+
+        // With A dropped there's room for the next update
+        ota.upload(b"c").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rollback() {
+        let mut ota = OtaUpdater::new(ProgramStore::new(Storage::default()), 2);
+        let a = ota.upload(b"a").await.unwrap();
+        ota.boot_slot().await.unwrap();
+        ota.frame_shown().await.unwrap();
+        ota.frame_shown().await.unwrap();
+
+        // Fails during its trial
+        ota.upload(b"b").await.unwrap();
+        ota.boot_slot().await.unwrap();
+        assert_eq!(ota.program_failed().await.unwrap(), Some(a));
+
+        // Resets during its trial
+        ota.upload(b"c").await.unwrap();
+        ota.boot_slot().await.unwrap();
+        ota.frame_shown().await.unwrap();
+        let mut ota = OtaUpdater::new(ProgramStore::new(ota.store.storage), 2);
+        assert_eq!(ota.boot_slot().await.unwrap(), Some(a));
+        assert_eq!(ota.program_failed().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_reset_during_first_trial() {
+        let mut ota = OtaUpdater::new(ProgramStore::new(Storage::default()), 2);
+        let a = ota.upload(b"a").await.unwrap();
+        ota.boot_slot().await.unwrap();
+        ota.frame_shown().await.unwrap();
+
+        // There's nothing to roll back to, so A is trialled again
+        let mut ota = OtaUpdater::new(ProgramStore::new(ota.store.storage), 2);
+        assert_eq!(ota.boot_slot().await.unwrap(), Some(a));
+        ota.frame_shown().await.unwrap();
+        ota.frame_shown().await.unwrap();
+        assert_eq!(
+            ota.store.slot_info(a).await.unwrap().state,
+            SlotState::Confirmed
+        );
+    }
+}
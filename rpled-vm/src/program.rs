@@ -1,6 +1,8 @@
 use crate::modules::{self, ENABLED_MODULE_FLAGS};
 use crate::read::{MemoryReader, Read, ReadError};
+use bitflags::bitflags;
 use bytemuck::{Pod, PodCastError, Zeroable, try_from_bytes};
+use core::ops::Range;
 
 #[derive(Debug)]
 pub enum ProgramError {
@@ -11,6 +13,15 @@ pub enum ProgramError {
     UnknownModule(u8),
     InvalidName,
     MissingRequiredModules(modules::ModuleFlags),
+    UnknownFlags(u8),
+    CorruptBody,
+    // Compressed code has to be decompressed into memory to run
+    CannotExecuteInPlace,
+    Unsigned,
+    InvalidSignature,
+    InvalidStrips,
+    // The strips add up to more pixels than the controller drives
+    TooManyPixels { total: usize, max: usize },
 }
 
 type Result<T> = core::result::Result<T, ProgramError>;
@@ -29,36 +40,168 @@ impl From<ReadError> for ProgramError {
 
 #[derive(Copy, Clone, Pod, Zeroable)]
 #[repr(C, packed)]
-struct HeaderPrelude {
+pub(crate) struct HeaderPrelude {
+    pub magic: [u8; 3],
+    pub version: u8,
+    pub heap_size: u16,
+    pub flags: u8,
+    pub header_len: u8,
+    pub n_modules: u8,
+}
+pub(crate) const PRELUDE_SIZE: usize = core::mem::size_of::<HeaderPrelude>();
+pub(crate) const HEADER_LEN_OFFSET: u16 = 8; // This + header_len = total header length (3 + 1 + 2 + 1 + 1);
+pub(crate) const MAGIC: &[u8; 3] = b"PXS";
+pub(crate) const CURRENT_VERSION: u8 = 1;
+const SUPPORTED_VERSIONS: [u8; 2] = [0, CURRENT_VERSION];
+// Only version 1 headers and later have a flags byte
+pub const FLAGS_OFFSET: usize = 6;
+
+// Version 0 headers, from before program flags
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C, packed)]
+struct HeaderPreludeV0 {
     magic: [u8; 3],
     version: u8,
     heap_size: u16,
     header_len: u8,
     n_modules: u8,
 }
-const PRELUDE_SIZE: usize = core::mem::size_of::<HeaderPrelude>();
-const HEADER_LEN_OFFSET: u16 = 7; // This + header_len = total header length (3 + 1 + 2 + 1);
-const SUPPORTED_VERSIONS: [u8; 1] = [0];
+const VERSION_OFFSET: usize = 3;
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ProgramFlags: u8 {
+        // An ed25519 signature over everything before it is appended to the program
+        const SIGNED = 0b00000001;
+        // The code is LZ4 block compressed, and decompressed on load
+        const COMPRESSED = 0b00000010;
+        // The led module blends between shown frames (see led::interpolate)
+        const INTERPOLATE = 0b00000100;
+        // A strip table follows the module list (see strip_table)
+        const STRIPS = 0b00001000;
+    }
+}
+
+pub const SIGNATURE_LEN: usize = 64;
+
+// Programs that drive several strips list them in the header: a count,
+// then for each strip its length (u16) and color order (u8)
+pub const MAX_STRIPS: usize = 4;
+pub const STRIP_ENTRY_SIZE: usize = 3;
+
+// Checks the signature trailer of a signed program against `public_key`
+#[cfg(feature = "signatures")]
+pub fn verify_signature(program: &[u8], public_key: &[u8; 32]) -> Result<()> {
+    use ed25519_compact::{PublicKey, Signature};
+
+    if !program.flags()?.contains(ProgramFlags::SIGNED) {
+        return Err(ProgramError::Unsigned);
+    }
+    let (signed, signature) = program.split_at(program.program_end()?);
+    let signature = Signature::from_slice(signature).map_err(|_| ProgramError::InvalidSignature)?;
+    PublicKey::new(*public_key)
+        .verify(signed, &signature)
+        .map_err(|_| ProgramError::InvalidSignature)
+}
+
+// Builds with `require-signed` only accept programs signed by the key at
+// $RPLED_PUBLIC_KEY_PATH (32 raw bytes) when the firmware was built
+#[cfg(feature = "require-signed")]
+const PUBLIC_KEY: &[u8; 32] = include_bytes!(env!("RPLED_PUBLIC_KEY_PATH"));
 
 pub trait Program {
     fn validate_program(&self) -> Result<()>;
     fn required_modules(&self) -> Result<modules::ModuleFlags>;
     fn program_name(&self) -> Result<&str>;
     fn program_start(&self) -> Result<u16>;
+    fn version(&self) -> Result<u8>;
+    fn heap_size(&self) -> Result<u16>;
+    fn flags(&self) -> Result<ProgramFlags>;
+    // Offset of the end of the code, before any signature
+    fn program_end(&self) -> Result<usize>;
+    // The strip table's entries, empty for programs that drive a single strip
+    fn strip_table(&self) -> Result<&[u8]>;
+}
+
+// The prelude of either header version, with flags of 0 for version 0
+struct Prelude {
+    magic: [u8; 3],
+    version: u8,
+    heap_size: u16,
+    flags: u8,
+    header_len: u8,
+    n_modules: u8,
+    size: usize,
+}
+
+impl Prelude {
+    // header_len counts from the n_modules byte, the prelude's last
+    fn header_end(&self) -> usize {
+        self.size - 1 + self.header_len as usize
+    }
+}
+
+fn prelude(program: &[u8]) -> Result<Prelude> {
+    let version = *program.get(VERSION_OFFSET).ok_or(ProgramError::TooShort)?;
+    if version == 0 {
+        let size = core::mem::size_of::<HeaderPreludeV0>();
+        let bytes = program.get(..size).ok_or(ProgramError::TooShort)?;
+        let prelude: &HeaderPreludeV0 = try_from_bytes(bytes)?;
+        return Ok(Prelude {
+            magic: prelude.magic,
+            version,
+            heap_size: prelude.heap_size,
+            flags: 0,
+            header_len: prelude.header_len,
+            n_modules: prelude.n_modules,
+            size,
+        });
+    }
+    let bytes = program.get(..PRELUDE_SIZE).ok_or(ProgramError::TooShort)?;
+    let prelude: &HeaderPrelude = try_from_bytes(bytes)?;
+    Ok(Prelude {
+        magic: prelude.magic,
+        version,
+        heap_size: prelude.heap_size,
+        flags: prelude.flags,
+        header_len: prelude.header_len,
+        n_modules: prelude.n_modules,
+        size: PRELUDE_SIZE,
+    })
+}
+
+// Where the strip table's entries are, and so where the name starts
+fn strip_entries(program: &[u8]) -> Result<Range<usize>> {
+    let prelude = prelude(program)?;
+    let modules_end = prelude.size + prelude.n_modules as usize;
+    if !program.flags()?.contains(ProgramFlags::STRIPS) {
+        return Ok(modules_end..modules_end);
+    }
+    let count = *program.get(modules_end).ok_or(ProgramError::TooShort)? as usize;
+    let entries = modules_end + 1..modules_end + 1 + count * STRIP_ENTRY_SIZE;
+    let header_end = prelude.header_end();
+    if count > MAX_STRIPS || entries.end > header_end {
+        return Err(ProgramError::InvalidStrips);
+    }
+    Ok(entries)
 }
 
 impl Program for &[u8] {
     fn validate_program(&self) -> Result<()> {
-        if self.len() < PRELUDE_SIZE {
-            return Err(ProgramError::TooShort);
-        }
-        let prelude: &HeaderPrelude = try_from_bytes(&self[0..PRELUDE_SIZE])?;
-        if &prelude.magic != b"PXS" {
+        let prelude = prelude(self)?;
+        if &prelude.magic != MAGIC {
             return Err(ProgramError::InvalidMagic);
         }
         if !SUPPORTED_VERSIONS.contains(&prelude.version) {
             return Err(ProgramError::UnexpectedVersion(prelude.version));
         }
+        self.flags()?;
+        self.strip_table()?;
+        if self.program_start()? as usize > self.program_end()? {
+            return Err(ProgramError::TooShort);
+        }
+        #[cfg(feature = "require-signed")]
+        verify_signature(self, PUBLIC_KEY)?;
         let modules = self.required_modules()?;
         let not_enabled = modules.difference(ENABLED_MODULE_FLAGS);
         if !not_enabled.is_empty() {
@@ -68,8 +211,9 @@ impl Program for &[u8] {
     }
 
     fn required_modules(&self) -> Result<modules::ModuleFlags> {
+        let prelude = prelude(self)?;
         let mut read = MemoryReader::new(self);
-        let prelude: HeaderPrelude = read.read()?;
+        read.seek(prelude.size)?;
         let mut modules_enabled = modules::ModuleFlags::empty();
         for _ in 0..prelude.n_modules {
             let module_id: u8 = read.read()?;
@@ -81,18 +225,48 @@ impl Program for &[u8] {
     }
 
     fn program_name(&self) -> Result<&str> {
-        let prelude: &HeaderPrelude = try_from_bytes(&self[0..PRELUDE_SIZE])?;
-        let name_start = PRELUDE_SIZE + (prelude.n_modules as usize);
-        let name_end = prelude.header_len as usize + HEADER_LEN_OFFSET as usize;
-        let name_bytes = &self[name_start..name_end];
+        let prelude = prelude(self)?;
+        let name_start = strip_entries(self)?.end;
+        let name_end = prelude.header_end();
+        let name_bytes = self
+            .get(name_start..name_end)
+            .ok_or(ProgramError::InvalidName)?;
         let name_str = core::str::from_utf8(name_bytes).map_err(|_| ProgramError::InvalidName)?;
         Ok(name_str)
     }
 
     fn program_start(&self) -> Result<u16> {
-        let prelude: &HeaderPrelude = try_from_bytes(&self[0..PRELUDE_SIZE])?;
-        let program_start = prelude.header_len as u16 + HEADER_LEN_OFFSET;
-        Ok(program_start)
+        let prelude = prelude(self)?;
+        Ok(prelude.header_end() as u16)
+    }
+
+    fn version(&self) -> Result<u8> {
+        Ok(prelude(self)?.version)
+    }
+
+    fn heap_size(&self) -> Result<u16> {
+        let prelude = prelude(self)?;
+        Ok(prelude.heap_size)
+    }
+
+    fn flags(&self) -> Result<ProgramFlags> {
+        let prelude = prelude(self)?;
+        ProgramFlags::from_bits(prelude.flags).ok_or(ProgramError::UnknownFlags(prelude.flags))
+    }
+
+    fn program_end(&self) -> Result<usize> {
+        if self.flags()?.contains(ProgramFlags::SIGNED) {
+            self.len()
+                .checked_sub(SIGNATURE_LEN)
+                .ok_or(ProgramError::TooShort)
+        } else {
+            Ok(self.len())
+        }
+    }
+
+    fn strip_table(&self) -> Result<&[u8]> {
+        self.get(strip_entries(self)?)
+            .ok_or(ProgramError::InvalidStrips)
     }
 }
 
@@ -104,8 +278,9 @@ mod tests {
     fn test_header() {
         let program: &[u8] = &[
             b'P', b'X', b'S', // Magic
-            0x00, // Version
+            0x01, // Version
             0x10, 0x00, // Heap Size
+            0x00, // Flags
             10,   // Header Length (1 n_mod, 1 mod_id,  8 name)
             0x01, // Number of Modules
             60,   // Module ID (TEST)
@@ -119,10 +294,63 @@ mod tests {
             modules::ModuleFlags::TEST
         );
         assert_eq!(program.program_name().unwrap(), "TestProg");
+        assert_eq!(program.heap_size().unwrap(), 0x10);
         assert_eq!(program.program_start().unwrap(), program.len() as u16 - 2);
         assert_eq!(
             program[program.program_start().unwrap() as usize..],
             [0xff, 0xff]
         );
     }
+
+    #[test]
+    fn test_v0_header() {
+        let program: &[u8] = &[
+            b'P', b'X', b'S', // Magic
+            0x00, // Version
+            0x10, 0x00, // Heap Size
+            4,    // Header Length (1 n_mod, 1 mod_id, 2 name)
+            0x01, // Number of Modules
+            60,   // Module ID (TEST)
+            b'T', b'0', // Program Name
+            0xff, // Program Start (dummy data)
+        ];
+
+        program.validate_program().unwrap();
+        assert_eq!(program.version().unwrap(), 0);
+        assert_eq!(program.flags().unwrap(), ProgramFlags::empty());
+        assert_eq!(
+            program.required_modules().unwrap(),
+            modules::ModuleFlags::TEST
+        );
+        assert_eq!(program.program_name().unwrap(), "T0");
+        assert_eq!(program.heap_size().unwrap(), 0x10);
+        assert_eq!(program.program_start().unwrap(), program.len() as u16 - 1);
+    }
+
+    #[test]
+    fn test_strip_table() {
+        let mut buf = [0u8; 32];
+        let program = crate::builder::ProgramBuilder::with_strips(
+            &mut buf,
+            0,
+            &[],
+            &[(10, 0), (300, 1)],
+            "Two",
+        )
+        .unwrap()
+        .finish()
+        .to_vec();
+        let program = program.as_slice();
+        program.validate_program().unwrap();
+        assert!(program.flags().unwrap().contains(ProgramFlags::STRIPS));
+        assert_eq!(program.strip_table().unwrap(), [10, 0, 0, 44, 1, 1]);
+        assert_eq!(program.program_name().unwrap(), "Two");
+
+        let mut bad = program.to_vec();
+        bad[PRELUDE_SIZE] = MAX_STRIPS as u8 + 1;
+        assert!(matches!(
+            bad.as_slice().validate_program(),
+            Err(ProgramError::InvalidStrips)
+        ));
+    }
 }
@@ -44,11 +44,12 @@ where
         let size = Idx::from(size_of::<T>());
         let start = self.cursor;
         let end = start + size;
-        if end > Idx::from(self.memory.len()) {
-            return Err(ReadError::OutOfBounds);
-        }
+        let bytes = self
+            .memory
+            .get(start.into()..end.into())
+            .ok_or(ReadError::OutOfBounds)?;
         self.cursor += size;
-        Ok(pod_read_unaligned::<T>(&self.memory[start.into()..end.into()]))
+        Ok(pod_read_unaligned::<T>(bytes))
     }
 
     fn seek(&mut self, pos: Idx) -> Result<()> {
@@ -0,0 +1,317 @@
+use bytemuck::{Pod, Zeroable, bytes_of, pod_read_unaligned};
+use core::future::Future;
+use core::mem::size_of;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StorageError {
+    InvalidSlot,
+    OutOfBounds,
+    TooLarge,
+    Full,
+    NotFound,
+    // The backend failed to read, write or erase
+    Device,
+}
+
+type Result<T> = core::result::Result<T, StorageError>;
+
+// Raw slot-based persistent storage, typically flash. Like NOR flash, writes
+// may only clear bits: a slot must be erased (to 0xff) before bits can be
+// set again. Each slot holds at most one program.
+pub trait ProgramStorage {
+    fn slot_count(&self) -> usize;
+    fn slot_size(&self) -> usize;
+
+    fn read(
+        &mut self,
+        slot: usize,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> impl Future<Output = Result<()>>;
+    fn write(
+        &mut self,
+        slot: usize,
+        offset: usize,
+        data: &[u8],
+    ) -> impl Future<Output = Result<()>>;
+    fn erase(&mut self, slot: usize) -> impl Future<Output = Result<()>>;
+}
+
+// In-memory storage with flash write semantics, for hosts and tests
+pub struct MemoryStorage<const SLOTS: usize, const SIZE: usize> {
+    pub slots: [[u8; SIZE]; SLOTS],
+}
+
+impl<const SLOTS: usize, const SIZE: usize> Default for MemoryStorage<SLOTS, SIZE> {
+    fn default() -> Self {
+        MemoryStorage {
+            slots: [[0xff; SIZE]; SLOTS],
+        }
+    }
+}
+
+impl<const SLOTS: usize, const SIZE: usize> MemoryStorage<SLOTS, SIZE> {
+    fn range(&mut self, slot: usize, offset: usize, len: usize) -> Result<&mut [u8]> {
+        self.slots
+            .get_mut(slot)
+            .ok_or(StorageError::InvalidSlot)?
+            .get_mut(offset..offset + len)
+            .ok_or(StorageError::OutOfBounds)
+    }
+}
+
+impl<const SLOTS: usize, const SIZE: usize> ProgramStorage for MemoryStorage<SLOTS, SIZE> {
+    fn slot_count(&self) -> usize {
+        SLOTS
+    }
+
+    fn slot_size(&self) -> usize {
+        SIZE
+    }
+
+    async fn read(&mut self, slot: usize, offset: usize, buf: &mut [u8]) -> Result<()> {
+        buf.copy_from_slice(self.range(slot, offset, buf.len())?);
+        Ok(())
+    }
+
+    async fn write(&mut self, slot: usize, offset: usize, data: &[u8]) -> Result<()> {
+        for (byte, new) in self.range(slot, offset, data.len())?.iter_mut().zip(data) {
+            *byte &= new;
+        }
+        Ok(())
+    }
+
+    async fn erase(&mut self, slot: usize) -> Result<()> {
+        self.range(slot, 0, SIZE)?.fill(0xff);
+        Ok(())
+    }
+}
+
+const SLOT_MAGIC: [u8; 4] = *b"PXSL";
+
+// Slot states only ever clear bits, so that moving between them needs a
+// single write rather than an erase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SlotState {
+    Erased = 0xff,
+    // Interrupted while being written
+    Writing = 0xfe,
+    Stored = 0xfc,
+    // Booted at least once without being confirmed (see ota)
+    Trial = 0xf8,
+    Confirmed = 0xf0,
+    Deleted = 0x00,
+}
+
+impl SlotState {
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            0xff => SlotState::Erased,
+            0xfe => SlotState::Writing,
+            0xfc => SlotState::Stored,
+            0xf8 => SlotState::Trial,
+            0xf0 => SlotState::Confirmed,
+            _ => SlotState::Deleted,
+        }
+    }
+
+    pub fn has_program(self) -> bool {
+        matches!(
+            self,
+            SlotState::Stored | SlotState::Trial | SlotState::Confirmed
+        )
+    }
+}
+
+// Metadata at the start of each slot. The erase count is carried across
+// erases so that new programs can be written to the least-worn free slot.
+#[derive(Copy, Clone, Pod, Zeroable)]
+#[repr(C, packed)]
+struct SlotHeader {
+    magic: [u8; 4],
+    erase_count: u32,
+    sequence: u32,
+    len: u32,
+    state: u8,
+}
+
+const HEADER_SIZE: usize = size_of::<SlotHeader>();
+const STATE_OFFSET: usize = HEADER_SIZE - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotInfo {
+    pub slot: usize,
+    pub state: SlotState,
+    pub erase_count: u32,
+    // Increases with every program stored, so the newest can be found
+    pub sequence: u32,
+    pub len: usize,
+}
+
+// Stores whole programs in a ProgramStorage, one per slot
+pub struct ProgramStore<S: ProgramStorage> {
+    pub storage: S,
+}
+
+impl<S: ProgramStorage> ProgramStore<S> {
+    pub fn new(storage: S) -> Self {
+        ProgramStore { storage }
+    }
+
+    // 0 if the backend's slots are too small for even the header
+    pub fn max_program_len(&self) -> usize {
+        self.storage.slot_size().saturating_sub(HEADER_SIZE)
+    }
+
+    pub async fn slot_info(&mut self, slot: usize) -> Result<SlotInfo> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        self.storage.read(slot, 0, &mut bytes).await?;
+        let header: SlotHeader = pod_read_unaligned(&bytes);
+        if header.magic != SLOT_MAGIC {
+            // Never written, or a header write was interrupted
+            return Ok(SlotInfo {
+                slot,
+                state: SlotState::Erased,
+                erase_count: 0,
+                sequence: 0,
+                len: 0,
+            });
+        }
+        Ok(SlotInfo {
+            slot,
+            state: SlotState::from_byte(header.state),
+            erase_count: header.erase_count,
+            sequence: header.sequence,
+            len: header.len as usize,
+        })
+    }
+
+    // The stored program with the highest sequence number accepted by `filter`
+    pub async fn newest(&mut self, filter: impl Fn(&SlotInfo) -> bool) -> Result<Option<SlotInfo>> {
+        let mut newest: Option<SlotInfo> = None;
+        for slot in 0..self.storage.slot_count() {
+            let info = self.slot_info(slot).await?;
+            if filter(&info) && newest.is_none_or(|n| info.sequence > n.sequence) {
+                newest = Some(info);
+            }
+        }
+        Ok(newest)
+    }
+
+    // Writes `program` to the least-erased slot that doesn't hold a
+    // program, returning the slot used
+    pub async fn store(&mut self, program: &[u8]) -> Result<usize> {
+        if program.len() > self.max_program_len() {
+            return Err(StorageError::TooLarge);
+        }
+        let mut target: Option<SlotInfo> = None;
+        let mut sequence = 0;
+        for slot in 0..self.storage.slot_count() {
+            let info = self.slot_info(slot).await?;
+            sequence = sequence.max(info.sequence);
+            if !info.state.has_program() && target.is_none_or(|t| info.erase_count < t.erase_count)
+            {
+                target = Some(info);
+            }
+        }
+        let target = target.ok_or(StorageError::Full)?;
+
+        self.storage.erase(target.slot).await?;
+        let header = SlotHeader {
+            magic: SLOT_MAGIC,
+            erase_count: target.erase_count.wrapping_add(1),
+            sequence: sequence.wrapping_add(1),
+            len: program.len() as u32,
+            state: SlotState::Writing as u8,
+        };
+        self.storage
+            .write(target.slot, 0, bytes_of(&header))
+            .await?;
+        self.storage
+            .write(target.slot, HEADER_SIZE, program)
+            .await?;
+        self.set_state(target.slot, SlotState::Stored).await?;
+        Ok(target.slot)
+    }
+
+    // Reads the program in `slot` into `buf`, returning its length
+    pub async fn load(&mut self, slot: usize, buf: &mut [u8]) -> Result<usize> {
+        let info = self.slot_info(slot).await?;
+        if !info.state.has_program() {
+            return Err(StorageError::NotFound);
+        }
+        let buf = buf.get_mut(..info.len).ok_or(StorageError::TooLarge)?;
+        self.storage.read(slot, HEADER_SIZE, buf).await?;
+        Ok(info.len)
+    }
+
+    pub async fn delete(&mut self, slot: usize) -> Result<()> {
+        self.set_state(slot, SlotState::Deleted).await
+    }
+
+    // States may only move towards Deleted (see SlotState)
+    pub async fn set_state(&mut self, slot: usize, state: SlotState) -> Result<()> {
+        self.storage.write(slot, STATE_OFFSET, &[state as u8]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store_and_load() {
+        let mut store = ProgramStore::new(MemoryStorage::<3, 64>::default());
+        assert_eq!(
+            store.newest(|_| true).await.unwrap().unwrap().state,
+            SlotState::Erased
+        );
+
+        let first = store.store(b"first").await.unwrap();
+        let second = store.store(b"second").await.unwrap();
+        assert_ne!(first, second);
+
+        let stored = |info: &SlotInfo| info.state == SlotState::Stored;
+        let newest = store.newest(stored).await.unwrap().unwrap();
+        assert_eq!(newest.slot, second);
+        let mut buf = [0u8; 64];
+        let len = store.load(second, &mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"second");
+
+        store.delete(second).await.unwrap();
+        assert_eq!(store.newest(stored).await.unwrap().unwrap().slot, first);
+        assert_eq!(
+            store.load(second, &mut buf).await,
+            Err(StorageError::NotFound)
+        );
+        assert_eq!(store.store(&[0; 60]).await, Err(StorageError::TooLarge));
+
+        let mut store = ProgramStore::new(MemoryStorage::<1, 4>::default());
+        assert_eq!(store.max_program_len(), 0);
+        assert_eq!(store.store(b"x").await, Err(StorageError::TooLarge));
+    }
+
+    #[tokio::test]
+    async fn test_wear_levelling() {
+        let mut store = ProgramStore::new(MemoryStorage::<3, 64>::default());
+        let keep = store.store(b"keep").await.unwrap();
+        // Repeatedly replacing a program spreads writes over the free slots
+        let mut used = [0; 3];
+        for _ in 0..6 {
+            let slot = store.store(b"update").await.unwrap();
+            store.delete(slot).await.unwrap();
+            used[slot] += 1;
+        }
+        assert_eq!(used[keep], 0);
+        assert!(
+            used.iter()
+                .enumerate()
+                .all(|(slot, &n)| slot == keep || n == 3)
+        );
+
+        store.store(b"a").await.unwrap();
+        store.store(b"b").await.unwrap();
+        assert_eq!(store.store(b"c").await, Err(StorageError::Full));
+    }
+}
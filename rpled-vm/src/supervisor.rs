@@ -0,0 +1,185 @@
+use crate::sync::Sync;
+use crate::vm::{HaltReason, VM, VMError, VmDebug};
+
+// Keeps a program running, so a buggy effect can't leave an installation
+// dark. A program that halts cleanly (exit code 0) is restarted straight
+// away; one that fails is handled by the deployment's policy. Programs are
+// reloaded from scratch, which clears their memory, but modules keep their
+// state (e.g. the LEDs show the last frame until the program draws again).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy<'p> {
+    // Give up, returning the error
+    Stop,
+    // Reload the program straight away
+    Restart,
+    // Reload the program after a delay, which doubles from `initial_ms`
+    // with each failure in a row, up to `max_ms`
+    Backoff { initial_ms: u16, max_ms: u16 },
+    // Switch to another program, e.g. a plain fill that can't fail, also if
+    // the program can't be loaded. If that fails too, give up.
+    Fallback(&'p [u8]),
+}
+
+pub struct Supervisor<'p> {
+    program: &'p [u8],
+    policy: Policy<'p>,
+    // Failures since the program last halted cleanly
+    failures: u16,
+    restarts: u32,
+    on_fallback: bool,
+}
+
+impl<'p> Supervisor<'p> {
+    pub fn new(program: &'p [u8], policy: Policy<'p>) -> Self {
+        Supervisor {
+            program,
+            policy,
+            failures: 0,
+            restarts: 0,
+            on_fallback: false,
+        }
+    }
+
+    // Number of times a program has been reloaded, for status reports
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    pub fn on_fallback(&self) -> bool {
+        self.on_fallback
+    }
+
+    // The delay before the next reload under Backoff, after `failures`
+    // failures in a row
+    pub fn backoff_ms(initial_ms: u16, max_ms: u16, failures: u16) -> u16 {
+        let shift = failures.saturating_sub(1).min(15);
+        initial_ms.saturating_mul(1 << shift).min(max_ms)
+    }
+
+    // Loads the program and keeps it running. Returns the error that ended
+    // supervision: a Halt(Signal) if the VM was stopped from outside, the
+    // load error of a program that can't be loaded and has no fallback to
+    // switch to (reloading it would only fail again), otherwise the failure
+    // the policy gave up on.
+    pub async fn run<const N: usize, S: Sync, D: VmDebug>(
+        &mut self,
+        vm: &mut VM<N, S, D>,
+    ) -> VMError {
+        let mut program = self.program;
+        loop {
+            if let Err(err) = vm.load(program) {
+                match self.policy {
+                    Policy::Fallback(fallback) if !self.on_fallback => {
+                        self.failures = self.failures.saturating_add(1);
+                        self.on_fallback = true;
+                        program = fallback;
+                        self.restarts = self.restarts.saturating_add(1);
+                        continue;
+                    }
+                    _ => return err,
+                }
+            }
+            let Err(err) = vm.run().await;
+            if let VMError::Halt(HaltReason::Signal) = err {
+                return err;
+            }
+            let finished = matches!(&err, VMError::Halt(reason) if reason.exit_code() == Some(0));
+            if finished {
+                self.failures = 0;
+            } else {
+                self.failures = self.failures.saturating_add(1);
+                match self.policy {
+                    Policy::Stop => return err,
+                    Policy::Restart => {}
+                    Policy::Backoff { initial_ms, max_ms } => {
+                        let ms = Self::backoff_ms(initial_ms, max_ms, self.failures);
+                        for _ in 0..ms {
+                            vm.delay(1000).await;
+                        }
+                    }
+                    Policy::Fallback(fallback) => {
+                        if self.on_fallback {
+                            return err;
+                        }
+                        self.on_fallback = true;
+                        program = fallback;
+                    }
+                }
+            }
+            self.restarts = self.restarts.saturating_add(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::program::ProgramError;
+    use crate::sync::TokioSync;
+    use crate::vm::{make_vm, opcodes};
+
+    // Prints `value`, then runs `last`
+    fn program(buf: &mut [u8], value: i16, last: u8) -> &[u8] {
+        let mut builder = ProgramBuilder::new(buf, 0, &[], "Supervised").unwrap();
+        builder.push(value).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.op(last).unwrap();
+        builder.finish()
+    }
+
+    #[tokio::test]
+    async fn test_supervisor() {
+        let (mut primary, mut fallback) = ([0u8; 64], [0u8; 64]);
+        // Both fail, with a stack underflow
+        let primary = program(&mut primary, 1, opcodes::POP);
+        let fallback = program(&mut fallback, 2, opcodes::POP);
+        let mut vm = make_vm::<256, TokioSync>().await;
+
+        let mut supervisor = Supervisor::new(primary, Policy::Stop);
+        assert!(matches!(
+            supervisor.run(&mut vm).await,
+            VMError::StackUnderflow
+        ));
+        assert_eq!(supervisor.restarts(), 0);
+
+        vm.modules.test.messages.clear();
+        let mut supervisor = Supervisor::new(primary, Policy::Fallback(fallback));
+        assert!(matches!(
+            supervisor.run(&mut vm).await,
+            VMError::StackUnderflow
+        ));
+        assert!(supervisor.on_fallback());
+        assert_eq!(supervisor.restarts(), 1);
+        assert_eq!(
+            vm.modules.test.messages,
+            ["TEST_ONE_ARG: 1", "TEST_ONE_ARG: 2"]
+        );
+
+        // A program that can't be loaded is switched for the fallback
+        vm.modules.test.messages.clear();
+        let mut corrupt = primary.to_vec();
+        corrupt[0] ^= 0xff;
+        let mut supervisor = Supervisor::new(&corrupt, Policy::Fallback(fallback));
+        assert!(matches!(
+            supervisor.run(&mut vm).await,
+            VMError::StackUnderflow
+        ));
+        assert!(supervisor.on_fallback());
+        assert_eq!(supervisor.restarts(), 1);
+        assert_eq!(vm.modules.test.messages, ["TEST_ONE_ARG: 2"]);
+
+        // but otherwise isn't retried
+        let mut supervisor = Supervisor::new(&primary[..4], Policy::Restart);
+        assert!(matches!(
+            supervisor.run(&mut vm).await,
+            VMError::ProgramError(ProgramError::TooShort)
+        ));
+        assert_eq!(supervisor.restarts(), 0);
+
+        assert_eq!(Supervisor::backoff_ms(100, 1000, 1), 100);
+        assert_eq!(Supervisor::backoff_ms(100, 1000, 3), 400);
+        assert_eq!(Supervisor::backoff_ms(100, 1000, 5), 1000);
+        assert_eq!(Supervisor::backoff_ms(100, 1000, 40), 1000);
+    }
+}
@@ -1,8 +1,14 @@
 pub trait Sync {
     type Signal: Signal;
+    type Mailbox: Mailbox + 'static;
 
     fn create_signal() -> Self::Signal;
     fn delay(us: u16) -> impl Future<Output = ()>;
+    // A monotonic clock, in microseconds from any starting point (see the
+    // time module)
+    fn now_us() -> u64;
+    // The mailbox shared by every VM on the controller
+    fn mailbox() -> &'static Self::Mailbox;
 }
 
 pub trait Signal {
@@ -15,8 +21,17 @@ pub trait Signal {
     fn is_signaled(&self) -> bool;
 }
 
+// Numbered FIFO queues of values, for VMs driving different strips to
+// coordinate (see the msg module). Neither call blocks.
+pub trait Mailbox {
+    // Returns false if the channel is full or doesn't exist
+    fn send(&self, channel: u8, value: i16) -> bool;
+    fn recv(&self, channel: u8) -> Option<i16>;
+    fn pending(&self, channel: u8) -> usize;
+}
+
 #[cfg(feature = "tokio")]
 pub mod tokio_sync;
 
 #[cfg(feature = "tokio")]
-pub use self::tokio_sync::TokioSync;
+pub use self::tokio_sync::{TokioMailbox, TokioSync};
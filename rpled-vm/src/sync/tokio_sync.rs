@@ -1,6 +1,18 @@
+// Host only, so free to panic (see lib.rs)
+#![allow(clippy::unwrap_used)]
+
 use core::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::Notify;
 
+extern crate std;
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+const MAILBOX_CHANNELS: usize = 8;
+const MAILBOX_DEPTH: usize = 16;
+
 pub struct AsyncSignal {
     flag: AtomicBool,
     flag_changed: Notify,
@@ -58,10 +70,57 @@ impl super::Signal for AsyncSignal {
     }
 }
 
+pub struct TokioMailbox {
+    channels: Mutex<[VecDeque<i16>; MAILBOX_CHANNELS]>,
+}
+
+impl Default for TokioMailbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokioMailbox {
+    pub const fn new() -> Self {
+        Self {
+            channels: Mutex::new([const { VecDeque::new() }; MAILBOX_CHANNELS]),
+        }
+    }
+}
+
+impl super::Mailbox for TokioMailbox {
+    fn send(&self, channel: u8, value: i16) -> bool {
+        let mut channels = self.channels.lock().unwrap();
+        match channels.get_mut(channel as usize) {
+            Some(queue) if queue.len() < MAILBOX_DEPTH => {
+                queue.push_back(value);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn recv(&self, channel: u8) -> Option<i16> {
+        let mut channels = self.channels.lock().unwrap();
+        channels.get_mut(channel as usize)?.pop_front()
+    }
+
+    fn pending(&self, channel: u8) -> usize {
+        let channels = self.channels.lock().unwrap();
+        channels.get(channel as usize).map_or(0, VecDeque::len)
+    }
+}
+
+static MAILBOX: TokioMailbox = TokioMailbox::new();
+
+// now_us() counts from its first call
+static CLOCK_START: OnceLock<Instant> = OnceLock::new();
+
 pub struct TokioSync;
 
 impl super::Sync for TokioSync {
     type Signal = AsyncSignal;
+    type Mailbox = TokioMailbox;
 
     fn create_signal() -> Self::Signal {
         AsyncSignal::new()
@@ -70,4 +129,36 @@ impl super::Sync for TokioSync {
     fn delay(us: u16) -> impl Future<Output = ()> {
         tokio::time::sleep(core::time::Duration::from_micros(us as u64))
     }
+
+    fn now_us() -> u64 {
+        CLOCK_START.get_or_init(Instant::now).elapsed().as_micros() as u64
+    }
+
+    fn mailbox() -> &'static TokioMailbox {
+        &MAILBOX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::Mailbox;
+
+    #[test]
+    fn test_mailbox() {
+        let mailbox = TokioMailbox::new();
+        assert!(mailbox.send(1, 10));
+        assert!(mailbox.send(1, 20));
+        assert_eq!(mailbox.pending(1), 2);
+        assert_eq!(mailbox.recv(2), None);
+        assert_eq!(mailbox.recv(1), Some(10));
+        assert_eq!(mailbox.recv(1), Some(20));
+        assert_eq!(mailbox.recv(1), None);
+
+        for value in 0..MAILBOX_DEPTH as i16 {
+            assert!(mailbox.send(0, value));
+        }
+        assert!(!mailbox.send(0, -1));
+        assert!(!mailbox.send(MAILBOX_CHANNELS as u8, 1));
+    }
 }
@@ -1,15 +1,22 @@
+use core::fmt;
+use core::ops::Range;
+
 use bytemuck::{NoUninit, Pod, bytes_of, pod_read_unaligned};
-use paste::paste;
 
-use crate::modules::{self, Modules};
+use crate::lz4;
+use crate::modules::Modules;
 use crate::ops;
-use crate::program::{Program, ProgramError};
+use crate::program::{Program, ProgramError, ProgramFlags};
 use crate::sync::{Signal, Sync};
 
 #[derive(Debug)]
 pub enum VMError {
     ProgramError(ProgramError),
-    ProgramTooLarge,
+    // Bytes needed (code, or code and heap) and bytes available
+    ProgramTooLarge {
+        size: usize,
+        max: usize,
+    },
     PCOverflow(u16),
     InvalidOpcode(u8, usize),
     StackOverflow,
@@ -36,22 +43,75 @@ impl From<crate::modules::ModuleError> for VMError {
 
 pub type Result<T> = core::result::Result<T, VMError>;
 
+// The result of run(), which only ever returns an error. Stable builds use
+// an empty enum in place of `!`.
+#[cfg(nightly)]
+pub type Never = !;
+#[cfg(not(nightly))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Never {}
+
 const MIN_STACK_SIZE: usize = 8;
+// Code addresses (pc, jump targets and return addresses) are u16
+pub const MAX_CODE_SIZE: usize = u16::MAX as usize;
+const MAX_TRY_DEPTH: usize = 8;
+// Ops run between checks for a halt signal and polls of the modules
+pub const POLL_INTERVAL: u32 = 1024;
+// Return addresses are kept apart from the value stack, so a function that
+// leaves values behind can't corrupt them
+pub const MAX_CALL_DEPTH: usize = 64;
+
+impl VMError {
+    // Number identifying the kind of error, as seen by scripts (see TRY)
+    pub fn code(&self) -> u8 {
+        match self {
+            VMError::ProgramError(_) => 1,
+            VMError::ProgramTooLarge { .. } => 2,
+            VMError::PCOverflow(_) => 3,
+            VMError::InvalidOpcode(..) => 4,
+            VMError::StackOverflow => 5,
+            VMError::StackUnderflow => 6,
+            VMError::HeapOverflow => 7,
+            VMError::DivisionByZero => 8,
+            VMError::InvalidJump => 9,
+            VMError::Halt(_) => 10,
+            VMError::ModuleNotEnabled(_) => 11,
+            VMError::ModuleError(_) => 12,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum HaltReason {
     Signal,
     HaltOp,
     ProgramEnd,
+    // HALTWITH, with the code the program gave
+    Exit(i16),
+}
+
+impl HaltReason {
+    // The program's exit code, for hosts that need to tell an effect that
+    // finished (0, from HALT) from one that gave up. None if the program
+    // was stopped from outside.
+    pub fn exit_code(&self) -> Option<i16> {
+        match self {
+            HaltReason::HaltOp => Some(0),
+            HaltReason::Exit(code) => Some(*code),
+            HaltReason::Signal | HaltReason::ProgramEnd => None,
+        }
+    }
 }
 
 macro_rules! dispatch_op {
     (
-        $( $num:literal $defn:tt),+,
+        $( $num:literal $defn:tt $meta:tt),+,
     ) => {
-        // Generate the run_op method
-        pub async fn run_op(&mut self) -> Result<()> {
+        // Generate the dispatch method, which runs a single op
+        async fn dispatch(&mut self) -> Result<()> {
             let pc = self.pc;
+            #[cfg(debug_assertions)]
+            let sp = self.sp;
             let opcode: u8 = self.read_pc()?;
             match opcode {
                 $(
@@ -60,16 +120,14 @@ macro_rules! dispatch_op {
                 ,
                 _ => return Err(VMError::InvalidOpcode(opcode, pc)),
             }
+            #[cfg(debug_assertions)]
+            self.check_stack_effect(opcode, sp);
             Ok(())
         }
 
-        // Generate the static opcode names method
+        // The static opcode names, shared with the opcodes module
         pub fn opcode_names() -> &'static [(u8, &'static str)] {
-            &[
-                $(
-                    dispatch_op!(@name $defn, $num)
-                ),+
-            ]
+            opcodes::NAMES
         }
     };
 
@@ -92,134 +150,529 @@ macro_rules! dispatch_op {
     (@call {MOD $name:ident $method:ident $var:literal}, $vm:expr, $opcode:ident) => {
         {
             let mod_op = $vm.read_pc()?;
-            modules::$name::$method::<N, S, D>($vm, mod_op).await?
+            $vm.debug.will_call_module($opcode & !3, mod_op);
+            crate::modules::$name::$method::<N, S, D>($vm, mod_op).await?
         }
     };
 
     (@call {async $name:ident => $path:path}, $vm:expr, $opcod:ident) => {
         $path($vm).await?
     };
-
-    (@name {#[cfg($cfg:meta)]$rest:tt}, $opcode:literal) => {
-        #[cfg($cfg)]
-        dispatch_op!(@name $rest, $opcode)
-    };
-
-    (@name {$name:ident => $path:path}, $opcode:literal) => {
-        ($opcode, stringify!($name))
-    };
-
-    (@name {MOD $name:ident $method:ident $var:literal}, $opcode:literal) => {
-        paste!{
-            ($opcode, stringify!([<$name:upper $var>]))
-        }
-    };
-
-    (@name {async $name:ident => $path:path}, $opcode:literal) => {
-        ($opcode, stringify!($name))
-    };
-
 }
 
 pub trait VmDebug {
-    fn will_run_op(&self) -> impl core::future::Future<Output = ()> + Send;
-    fn did_run_op(&self) -> impl core::future::Future<Output = ()> + Send;
+    fn will_run_op(
+        &mut self,
+        pc: usize,
+        opcode: u8,
+    ) -> impl core::future::Future<Output = ()> + Send;
+    fn did_run_op(&mut self) -> impl core::future::Future<Output = ()> + Send;
+    // Called while running a module call op, with the module's base opcode
+    // and the function code
+    fn will_call_module(&mut self, _module: u8, _func: u8) {}
+    // Called before a write of `len` bytes at heap address `addr`
+    fn will_write_heap(&mut self, _addr: usize, _len: usize) {}
+    // Called when an op fails, before a TRY gets to catch the error
+    fn op_failed(&mut self, _err: &VMError) {}
 }
 
 pub struct NoVmDebug;
 
 impl VmDebug for NoVmDebug {
-    async fn will_run_op(&self) {}
-    async fn did_run_op(&self) {}
+    async fn will_run_op(&mut self, _pc: usize, _opcode: u8) {}
+    async fn did_run_op(&mut self) {}
+}
+
+// Where to resume if a function called by TRY fails
+#[derive(Clone, Copy, Default)]
+pub struct TryFrame {
+    // sp, call depth and arena end at the TRY, so that blocks allocated by
+    // the failed call are freed
+    pub sp: usize,
+    pub call_depth: usize,
+    pub heap_end: usize,
+    pub resume_pc: usize,
 }
 
 pub struct VM<const N: usize, S: Sync, D: VmDebug> {
     pub memory: [u8; N],
+    // The code, when executing in place (see load_xip)
+    pub xip_code: Option<&'static [u8]>,
     pub heap_start: usize,
     pub max_pc: usize,
     pub heap_end: usize,
+    // End of the program's static heap, where the ALLOC arena starts
+    pub arena_start: usize,
 
     pub halt_signal: S::Signal,
 
     pub pc: usize,
     pub sp: usize,
 
+    pub call_stack: [u16; MAX_CALL_DEPTH],
+    pub call_depth: usize,
+    pub try_frames: [TryFrame; MAX_TRY_DEPTH],
+    pub try_depth: usize,
+
     pub modules: Modules,
     pub debug: D,
 }
 
+// A copy of the VM's execution state, used to rewind a running program
+#[derive(Clone)]
+pub struct VmSnapshot<const N: usize> {
+    pub memory: [u8; N],
+    pub xip_code: Option<&'static [u8]>,
+    pub heap_start: usize,
+    pub max_pc: usize,
+    pub heap_end: usize,
+    pub arena_start: usize,
+    pub pc: usize,
+    pub sp: usize,
+    pub call_stack: [u16; MAX_CALL_DEPTH],
+    pub call_depth: usize,
+    pub try_frames: [TryFrame; MAX_TRY_DEPTH],
+    pub try_depth: usize,
+
+    #[cfg(feature = "led")]
+    pub led: crate::modules::led::LedSnapshot,
+    #[cfg(feature = "time")]
+    pub time: crate::modules::time::TimeModule,
+    #[cfg(feature = "rand")]
+    pub rand: crate::modules::rand::RandModule,
+}
+
+// Where a program's code, heap and stack sit in VM memory. Addresses above
+// the stack pointer are in use by the stack; `free` is what's left for it
+// to grow into. When executing in place, `program` is the code's address
+// range outside of VM memory, and the heap starts at 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub program: Range<usize>,
+    pub heap: Range<usize>,
+    pub stack: Range<usize>,
+    pub free: usize,
+    pub xip: bool,
+}
+
+// The program's code bytes, and whether they're compressed
+fn code_slice(program: &[u8]) -> Result<(&[u8], bool)> {
+    program.validate_program()?;
+    let program_start = program.program_start()? as usize;
+    let code = program
+        .get(program_start..program.program_end()?)
+        .ok_or(ProgramError::TooShort)?;
+    Ok((code, program.flags()?.contains(ProgramFlags::COMPRESSED)))
+}
+
+impl MemoryMap {
+    // The layout VM::load will give `program` in `memory_size` bytes of
+    // memory, or the reason it won't fit
+    pub fn plan(program: &[u8], memory_size: usize) -> Result<MemoryMap> {
+        let (code, compressed) = code_slice(program)?;
+        let program_len = if compressed {
+            lz4::decompressed_len(code).map_err(|_| ProgramError::CorruptBody)?
+        } else {
+            code.len()
+        };
+        Self::layout(program_len, program_len, false, memory_size)
+    }
+
+    // As plan(), for VM::load_xip. The code stays outside of memory, so the
+    // heap is the size the header asks for rather than the code's.
+    pub fn plan_xip(program: &[u8], memory_size: usize) -> Result<MemoryMap> {
+        let (code, compressed) = code_slice(program)?;
+        if compressed {
+            return Err(ProgramError::CannotExecuteInPlace.into());
+        }
+        Self::layout(code.len(), program.heap_size()? as usize, true, memory_size)
+    }
+
+    fn layout(
+        program_len: usize,
+        heap_size: usize,
+        xip: bool,
+        memory_size: usize,
+    ) -> Result<MemoryMap> {
+        if program_len > MAX_CODE_SIZE {
+            return Err(VMError::ProgramTooLarge {
+                size: program_len,
+                max: MAX_CODE_SIZE,
+            });
+        }
+        let heap_start = if xip { 0 } else { program_len };
+        let heap_end = heap_start + heap_size;
+        // Memory smaller than the minimum stack leaves no room for anything,
+        // even an XIP program without a heap
+        let max = memory_size.saturating_sub(MIN_STACK_SIZE);
+        if heap_end > max || memory_size < MIN_STACK_SIZE {
+            return Err(VMError::ProgramTooLarge {
+                size: heap_end,
+                max,
+            });
+        }
+        Ok(MemoryMap {
+            program: 0..program_len,
+            heap: heap_start..heap_end,
+            stack: heap_end..memory_size,
+            // The stack starts one byte below the top of memory
+            free: memory_size - 1 - heap_end,
+            xip,
+        })
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.xip {
+            writeln!(
+                f,
+                "{:<8} {:<9} {:>6} bytes",
+                "program",
+                "in place",
+                self.program.len()
+            )?;
+        }
+        let in_memory = if self.xip { 1 } else { 0 };
+        let ranges = [
+            ("program", &self.program),
+            ("heap", &self.heap),
+            ("stack", &self.stack),
+        ];
+        for (name, range) in ranges.iter().skip(in_memory) {
+            writeln!(
+                f,
+                "{:<8} {:04x}-{:04x} {:>6} bytes",
+                name,
+                range.start,
+                range.end,
+                range.len()
+            )?;
+        }
+        writeln!(f, "{:<18} {:>6} bytes", "free", self.free)
+    }
+}
+
 pub async fn make_vm<const N: usize, S: Sync>() -> VM<N, S, NoVmDebug> {
     VM::new(NoVmDebug).await
 }
 
+// The single source of truth for the opcode space. Invokes `$callback` with
+// the full opcode table so that several macros can generate code from it.
+// `cycles` is an approximate RP2040 cost for dispatching and executing the
+// op, excluding module function bodies and time spent sleeping. `stack` is
+// the number of values popped and pushed, as seen by the caller for calls;
+// it is omitted where it depends on the operands (see disasm), and checked
+// as ops run in debug builds (see check_stack_effect). `doc` is
+// pseudocode and a description for the opcode reference in the README,
+// which `cargo xtask op-docs` generates.
+macro_rules! with_op_table {
+    ($callback:ident) => {
+        $callback!(
+            1 {PUSH => ops::stack::push} [cycles: 30, operands: [I16], stack: [0, 1], doc: ["push(i16)", "Push constant value"]],
+            2 {LOAD => ops::stack::load} [cycles: 40, operands: [U16], stack: [0, 1], doc: ["push(heap[u16])", "Push value from the heap"]],
+            3 {STORE => ops::stack::store} [cycles: 40, operands: [U16], stack: [1, 0], doc: ["heap[u16] = pop()", "Store top of stack into the heap"]],
+            4 {POP => ops::stack::pop} [cycles: 20, operands: [], stack: [1, 0], doc: ["pop()", "Discard top value"]],
+            5 {POPN => ops::stack::popn} [cycles: 25, operands: [U8], doc: ["sp += u8", "Discard u8 bytes (u8 / 2 values)"]],
+            6 {DUP => ops::stack::dup} [cycles: 25, operands: [], stack: [1, 2], doc: ["push(s[0])", "Duplicate top of stack"]],
+            7 {SWAP => ops::stack::swap} [cycles: 30, operands: [], stack: [2, 2], doc: ["swap(s[0], s[1])", "Swap top two values"]],
+            8 {OVER => ops::stack::over} [cycles: 30, operands: [], stack: [2, 3], doc: ["push(s[1])", "Copy second value to top"]],
+            9 {ROT => ops::stack::rot} [cycles: 35, operands: [], stack: [3, 3], doc: ["(s[2], s[1], s[0]) -> (s[0], s[2], s[1])", "Rotate top three values, moving the top to the third"]],
+            10 {ZERO => ops::stack::zero} [cycles: 20, operands: [], stack: [0, 1], doc: ["push(0)", "Push zero"]],
+
+            11 {ADD => ops::math::add} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] + s[0])", "Addition"]],
+            12 {SUB => ops::math::sub} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] - s[0])", "Subtraction"]],
+            13 {MUL => ops::math::mul} [cycles: 40, operands: [], stack: [2, 1], doc: ["push(s[1] * s[0])", "Multiplication"]],
+            14 {DIV => ops::math::div} [cycles: 50, operands: [], stack: [2, 1], doc: ["push(s[1] / s[0])", "Division, rounding towards zero"]],
+            15 {MOD => ops::math::modulo} [cycles: 50, operands: [], stack: [2, 1], doc: ["push(s[1] % s[0])", "Remainder, with the sign of s[1]"]],
+
+            16 {EQ => ops::compare::eq} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] == s[0])", "Equality test"]],
+            17 {NE => ops::compare::ne} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] != s[0])", "Inequality test"]],
+            18 {LT => ops::compare::lt} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] < s[0])", "Less than"]],
+            19 {GT => ops::compare::gt} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] > s[0])", "Greater than"]],
+            20 {LE => ops::compare::le} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] <= s[0])", "Less or equal"]],
+            21 {GE => ops::compare::ge} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] >= s[0])", "Greater or equal"]],
+
+            22 {AND => ops::bitwise::and} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] & s[0])", "Bitwise AND"]],
+            23 {OR => ops::bitwise::or} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] | s[0])", "Bitwise OR"]],
+            24 {XOR => ops::bitwise::xor} [cycles: 35, operands: [], stack: [2, 1], doc: ["push(s[1] ^ s[0])", "Bitwise XOR"]],
+            25 {NOT => ops::bitwise::not} [cycles: 25, operands: [], stack: [1, 1], doc: ["push(!s[0])", "Bitwise NOT"]],
+
+            26 {INC => ops::math::inc} [cycles: 30, operands: [], stack: [1, 1], doc: ["push(s[0] + 1)", "Increment"]],
+            27 {DEC => ops::math::dec} [cycles: 30, operands: [], stack: [1, 1], doc: ["push(s[0] - 1)", "Decrement"]],
+            28 {NEG => ops::math::neg} [cycles: 30, operands: [], stack: [1, 1], doc: ["push(-s[0])", "Negate"]],
+            29 {ABS => ops::math::abs} [cycles: 30, operands: [], stack: [1, 1], doc: ["push(abs(s[0]))", "Absolute value"]],
+            30 {CLAMP => ops::math::clamp} [cycles: 45, operands: [], stack: [3, 1], doc: ["push(min(max(s[2], s[1]), s[0]))", "Clamp value between s[1] and s[0]"]],
+            31 {JMP => ops::control::jmp} [cycles: 30, operands: [I16], stack: [0, 0], doc: ["pc += i16", "Unconditional jump (relative)"]],
+            32 {JZ => ops::control::jz} [cycles: 40, operands: [I16], stack: [1, 0], doc: ["if (pop() == 0) pc += i16", "Jump if zero"]],
+            33 {JNZ => ops::control::jnz} [cycles: 40, operands: [I16], stack: [1, 0], doc: ["if (pop() != 0) pc += i16", "Jump if non-zero"]],
+            34 {CALL => ops::control::call} [cycles: 45, operands: [I16], stack: [0, 0], doc: ["calls.push(pc); pc += i16", "Call subroutine"]],
+            35 {CALLZ => ops::control::callz} [cycles: 50, operands: [I16], stack: [1, 0], doc: ["if (pop() == 0) call", "Conditional call if zero"]],
+            36 {CALLNZ => ops::control::callnz} [cycles: 50, operands: [I16], stack: [1, 0], doc: ["if (pop() != 0) call", "Conditional call if non-zero"]],
+            37 {RET => ops::control::ret} [cycles: 35, operands: [], stack: [0, 0], doc: ["pc = calls.pop()", "Return from subroutine"]],
+            38 {HALT => ops::control::halt} [cycles: 10, operands: [], stack: [0, 0], doc: ["stop", "Stop execution"]],
+            39 {async SLEEP => ops::control::sleep} [cycles: 40, operands: [], stack: [1, 0], doc: ["delay(pop())", "Sleep for s[0] microseconds"]],
+            40 {TRY => ops::control::try_call} [cycles: 60, operands: [I16], stack: [0, 1], doc: ["call; push(error code or 0)", "Call, catching errors: pushes 0 on return or the error code"]],
+
+            // Compact encodings of common ops. ZERO doubles as PUSH0.
+            41 {PUSH8 => ops::stack::push8} [cycles: 25, operands: [I8], stack: [0, 1], doc: ["push(i8)", "Push small constant"]],
+            42 {PUSH1 => ops::stack::push1} [cycles: 20, operands: [], stack: [0, 1], doc: ["push(1)", "Push one"]],
+            43 {PUSH2 => ops::stack::push2} [cycles: 20, operands: [], stack: [0, 1], doc: ["push(2)", "Push two"]],
+            44 {PUSH3 => ops::stack::push3} [cycles: 20, operands: [], stack: [0, 1], doc: ["push(3)", "Push three"]],
+            45 {JMP8 => ops::control::jmp8} [cycles: 25, operands: [I8], stack: [0, 0], doc: ["pc += i8", "Short unconditional jump"]],
+            46 {JZ8 => ops::control::jz8} [cycles: 35, operands: [I8], stack: [1, 0], doc: ["if (pop() == 0) pc += i8", "Short jump if zero"]],
+            47 {JNZ8 => ops::control::jnz8} [cycles: 35, operands: [I8], stack: [1, 0], doc: ["if (pop() != 0) pc += i8", "Short jump if non-zero"]],
+
+            48 {ALLOC => ops::arena::alloc} [cycles: 50, operands: [U16], stack: [0, 1], doc: ["push(arena.alloc(u16))", "Allocate u16 zeroed bytes from the arena, pushing their heap address"]],
+            49 {FREEALL => ops::arena::free_all} [cycles: 20, operands: [], stack: [0, 0], doc: ["arena.reset()", "Free every arena allocation"]],
+            50 {LOADI => ops::arena::loadi} [cycles: 40, operands: [], stack: [1, 1], doc: ["push(heap[pop()])", "Push value from the heap address in s[0]"]],
+            51 {STOREI => ops::arena::storei} [cycles: 40, operands: [], stack: [2, 0], doc: ["heap[s[0]] = s[1]; pop(2)", "Store s[1] at the heap address in s[0]"]],
+            52 {HALTWITH => ops::control::halt_with} [cycles: 15, operands: [], stack: [1, 0], doc: ["stop(pop())", "Stop execution with exit code s[0]"]],
+
+            60 {#[cfg(any(test, feature = "fixtures"))]{MOD test call0 0 }} [cycles: 60, doc: ["test[u8]()", "Test call with 0 args"]],
+            61 {#[cfg(any(test, feature = "fixtures"))]{MOD test call1 1 }} [cycles: 70, doc: ["test[u8](pop())", "Test call with 1 arg (s[0])"]],
+            62 {#[cfg(any(test, feature = "fixtures"))]{MOD test call2 2 }} [cycles: 75, doc: ["test[u8](pop(), pop())", "Test call with 2 args (s[0], s[1])"]],
+            63 {#[cfg(any(test, feature = "fixtures"))]{MOD test calln "N" }} [cycles: 90, doc: ["test[u8](pop(u8))", "Test call with as many args as the second operand"]],
+
+            64 {#[cfg(feature = "led")]{MOD led call0 0 }} [cycles: 60, doc: ["led[u8]()", "LED call with 0 args"]],
+            65 {#[cfg(feature = "led")]{MOD led call1 1 }} [cycles: 70, doc: ["led[u8](pop())", "LED call with 1 arg (s[0])"]],
+            66 {#[cfg(feature = "led")]{MOD led call2 2 }} [cycles: 75, doc: ["led[u8](pop(), pop())", "LED call with 2 args (s[0], s[1])"]],
+            67 {#[cfg(feature = "led")]{MOD led calln "N" }} [cycles: 90, doc: ["led[u8](pop(u8))", "LED call with as many args as the second operand"]],
+
+            68 {#[cfg(feature = "math")]{MOD math call0 0 }} [cycles: 60, doc: ["math[u8]()", "Math call with 0 args"]],
+            69 {#[cfg(feature = "math")]{MOD math call1 1 }} [cycles: 70, doc: ["math[u8](pop())", "Math call with 1 arg (s[0])"]],
+            70 {#[cfg(feature = "math")]{MOD math call2 2 }} [cycles: 75, doc: ["math[u8](pop(), pop())", "Math call with 2 args (s[0], s[1])"]],
+            71 {#[cfg(feature = "math")]{MOD math calln "N" }} [cycles: 90, doc: ["math[u8](pop(u8))", "Math call with as many args as the second operand"]],
+
+            72 {#[cfg(feature = "msg")]{MOD msg call0 0 }} [cycles: 60, doc: ["msg[u8]()", "Msg call with 0 args"]],
+            73 {#[cfg(feature = "msg")]{MOD msg call1 1 }} [cycles: 70, doc: ["msg[u8](pop())", "Msg call with 1 arg (s[0])"]],
+            74 {#[cfg(feature = "msg")]{MOD msg call2 2 }} [cycles: 75, doc: ["msg[u8](pop(), pop())", "Msg call with 2 args (s[0], s[1])"]],
+            75 {#[cfg(feature = "msg")]{MOD msg calln "N" }} [cycles: 90, doc: ["msg[u8](pop(u8))", "Msg call with as many args as the second operand"]],
+
+            76 {#[cfg(feature = "dbg")]{MOD dbg call0 0 }} [cycles: 60, doc: ["dbg[u8]()", "Dbg call with 0 args"]],
+            77 {#[cfg(feature = "dbg")]{MOD dbg call1 1 }} [cycles: 70, doc: ["dbg[u8](pop())", "Dbg call with 1 arg (s[0])"]],
+            78 {#[cfg(feature = "dbg")]{MOD dbg call2 2 }} [cycles: 75, doc: ["dbg[u8](pop(), pop())", "Dbg call with 2 args (s[0], s[1])"]],
+            79 {#[cfg(feature = "dbg")]{MOD dbg calln "N" }} [cycles: 90, doc: ["dbg[u8](pop(u8))", "Dbg call with as many args as the second operand"]],
+            80 {#[cfg(feature = "time")]{MOD time call0 0 }} [cycles: 60, doc: ["time[u8]()", "Time call with 0 args"]],
+            81 {#[cfg(feature = "time")]{MOD time call1 1 }} [cycles: 70, doc: ["time[u8](pop())", "Time call with 1 arg (s[0])"]],
+            82 {#[cfg(feature = "time")]{MOD time call2 2 }} [cycles: 75, doc: ["time[u8](pop(), pop())", "Time call with 2 args (s[0], s[1])"]],
+            83 {#[cfg(feature = "time")]{MOD time calln "N" }} [cycles: 90, doc: ["time[u8](pop(u8))", "Time call with as many args as the second operand"]],
+            84 {#[cfg(feature = "rand")]{MOD rand call0 0 }} [cycles: 60, doc: ["rand[u8]()", "Rand call with 0 args"]],
+            85 {#[cfg(feature = "rand")]{MOD rand call1 1 }} [cycles: 70, doc: ["rand[u8](pop())", "Rand call with 1 arg (s[0])"]],
+            86 {#[cfg(feature = "rand")]{MOD rand call2 2 }} [cycles: 75, doc: ["rand[u8](pop(), pop())", "Rand call with 2 args (s[0], s[1])"]],
+            87 {#[cfg(feature = "rand")]{MOD rand calln "N" }} [cycles: 90, doc: ["rand[u8](pop(u8))", "Rand call with as many args as the second operand"]],
+            88 {#[cfg(feature = "host")]{MOD host call0 0 }} [cycles: 60, doc: ["host[u8]()", "Host call with 0 args"]],
+            89 {#[cfg(feature = "host")]{MOD host call1 1 }} [cycles: 70, doc: ["host[u8](pop())", "Host call with 1 arg (s[0])"]],
+            90 {#[cfg(feature = "host")]{MOD host call2 2 }} [cycles: 75, doc: ["host[u8](pop(), pop())", "Host call with 2 args (s[0], s[1])"]],
+            91 {#[cfg(feature = "host")]{MOD host calln "N" }} [cycles: 90, doc: ["host[u8](pop(u8))", "Host call with as many args as the second operand"]],
+        );
+    };
+}
+
+macro_rules! define_opcodes {
+    (
+        $( $num:literal $defn:tt $meta:tt),+,
+    ) => {
+        $(
+            define_opcodes!(@const $defn, $num);
+        )+
+
+        pub const NAMES: &[(u8, &str)] = &[
+            $(
+                define_opcodes!(@name $defn, $num)
+            ),+
+        ];
+
+        pub const CYCLE_COSTS: &[(u8, u16)] = &[
+            $(
+                define_opcodes!(@cycles $defn, $num, $meta)
+            ),+
+        ];
+
+        pub const OPERANDS: &[(u8, &[Operand])] = &[
+            $(
+                define_opcodes!(@operands $defn, $num, $meta)
+            ),+
+        ];
+
+        pub const STACK_EFFECTS: &[(u8, Option<(u8, u8)>)] = &[
+            $(
+                define_opcodes!(@stack $defn, $num, $meta)
+            ),+
+        ];
+
+        pub const DOCS: &[(u8, (&str, &str))] = &[
+            $(
+                define_opcodes!(@doc $defn, $num, $meta)
+            ),+
+        ];
+    };
+
+    (@const {#[cfg($cfg:meta)]$rest:tt}, $opcode:literal) => {
+        #[cfg($cfg)]
+        define_opcodes!(@const $rest, $opcode);
+    };
+
+    (@const {$name:ident => $path:path}, $opcode:literal) => {
+        pub const $name: u8 = $opcode;
+    };
+
+    (@const {MOD $name:ident $method:ident $var:literal}, $opcode:literal) => {
+        ::paste::paste! {
+            pub const [<$name:upper $var>]: u8 = $opcode;
+        }
+    };
+
+    (@const {async $name:ident => $path:path}, $opcode:literal) => {
+        pub const $name: u8 = $opcode;
+    };
+
+    (@name {#[cfg($cfg:meta)]$rest:tt}, $opcode:literal) => {
+        #[cfg($cfg)]
+        define_opcodes!(@name $rest, $opcode)
+    };
+
+    (@name {$name:ident => $path:path}, $opcode:literal) => {
+        ($opcode, stringify!($name))
+    };
+
+    (@name {MOD $name:ident $method:ident $var:literal}, $opcode:literal) => {
+        ::paste::paste! {
+            ($opcode, stringify!([<$name:upper $var>]))
+        }
+    };
+
+    (@name {async $name:ident => $path:path}, $opcode:literal) => {
+        ($opcode, stringify!($name))
+    };
+
+    (@cycles {#[cfg($cfg:meta)]$rest:tt}, $opcode:literal, $meta:tt) => {
+        #[cfg($cfg)]
+        define_opcodes!(@cycles $rest, $opcode, $meta)
+    };
+
+    (@cycles $defn:tt, $opcode:literal, [cycles: $cycles:literal $(, $($rest:tt)*)?]) => {
+        ($opcode, $cycles)
+    };
+
+    (@operands {#[cfg($cfg:meta)]$rest:tt}, $opcode:literal, $meta:tt) => {
+        #[cfg($cfg)]
+        define_opcodes!(@operands $rest, $opcode, $meta)
+    };
+
+    // Module calls take a function code, plus an argument count for the N variant
+    (@operands {MOD $name:ident $method:ident "N"}, $opcode:literal, $meta:tt) => {
+        ($opcode, &[Operand::U8, Operand::U8])
+    };
+
+    (@operands {MOD $name:ident $method:ident $var:literal}, $opcode:literal, $meta:tt) => {
+        ($opcode, &[Operand::U8])
+    };
+
+    (@operands $defn:tt, $opcode:literal, [cycles: $cycles:literal, operands: [$($operand:ident),*] $(, $($rest:tt)*)?]) => {
+        ($opcode, &[$(Operand::$operand),*])
+    };
+
+    (@stack {#[cfg($cfg:meta)]$rest:tt}, $opcode:literal, $meta:tt) => {
+        #[cfg($cfg)]
+        define_opcodes!(@stack $rest, $opcode, $meta)
+    };
+
+    (@stack $defn:tt, $opcode:literal, [cycles: $cycles:literal, operands: $operands:tt, stack: [$pops:literal, $pushes:literal] $(, $($rest:tt)*)?]) => {
+        ($opcode, Some(($pops, $pushes)))
+    };
+
+    (@stack $defn:tt, $opcode:literal, $meta:tt) => {
+        ($opcode, None)
+    };
+
+    (@doc {#[cfg($cfg:meta)]$rest:tt}, $opcode:literal, $meta:tt) => {
+        #[cfg($cfg)]
+        define_opcodes!(@doc $rest, $opcode, $meta)
+    };
+
+    (@doc $defn:tt, $opcode:literal, [cycles: $cycles:literal, $(operands: $operands:tt,)? $(stack: $stack:tt,)? doc: [$pseudocode:literal, $description:literal]]) => {
+        ($opcode, ($pseudocode, $description))
+    };
+}
+
+// Opcode constants for every op, for code generators that emit bytecode
+pub mod opcodes {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Operand {
+        U8,
+        I8,
+        U16,
+        I16,
+    }
+
+    impl Operand {
+        pub const fn size(self) -> usize {
+            match self {
+                Operand::U8 | Operand::I8 => 1,
+                Operand::U16 | Operand::I16 => 2,
+            }
+        }
+    }
+
+    with_op_table!(define_opcodes);
+
+    pub fn name(opcode: u8) -> Option<&'static str> {
+        lookup(NAMES, opcode)
+    }
+
+    pub fn cycle_cost(opcode: u8) -> Option<u16> {
+        lookup(CYCLE_COSTS, opcode)
+    }
+
+    pub fn operands(opcode: u8) -> Option<&'static [Operand]> {
+        lookup(OPERANDS, opcode)
+    }
+
+    // (pops, pushes) for ops with a fixed stack effect
+    pub fn stack_effect(opcode: u8) -> Option<(u8, u8)> {
+        lookup(STACK_EFFECTS, opcode).flatten()
+    }
+
+    // (pseudocode, description), for the opcode reference
+    pub fn doc(opcode: u8) -> Option<(&'static str, &'static str)> {
+        lookup(DOCS, opcode)
+    }
+
+    pub fn by_name(name: &str) -> Option<u8> {
+        NAMES
+            .iter()
+            .find(|(_, op_name)| *op_name == name)
+            .map(|(code, _)| *code)
+    }
+
+    fn lookup<T: Copy>(table: &[(u8, T)], opcode: u8) -> Option<T> {
+        table
+            .iter()
+            .find(|(code, _)| *code == opcode)
+            .map(|(_, value)| *value)
+    }
+}
+
 impl<const N: usize, S: Sync, D: VmDebug> VM<N, S, D> {
-    // Generate run_op and opcode_names methods using the dispatch_op macro
-    dispatch_op!(
-        1 {PUSH => ops::stack::push},
-        2 {LOAD => ops::stack::load},
-        3 {STORE => ops::stack::store},
-        4 {POP => ops::stack::pop},
-        5 {POPN => ops::stack::popn},
-        6 {DUP => ops::stack::dup},
-        7 {SWAP => ops::stack::swap},
-        8 {OVER => ops::stack::over},
-        9 {ROT => ops::stack::rot},
-        10 {ZERO => ops::stack::zero},
-
-        11 {ADD => ops::math::add},
-        12 {SUB => ops::math::sub},
-        13 {MUL => ops::math::mul},
-        14 {DIV => ops::math::div},
-        15 {MOD => ops::math::modulo},
-
-        16 {EQ => ops::compare::eq},
-        17 {NE => ops::compare::ne},
-        18 {LT => ops::compare::lt},
-        19 {GT => ops::compare::gt},
-        20 {LE => ops::compare::le},
-        21 {GE => ops::compare::ge},
-
-        22 {AND => ops::bitwise::and},
-        23 {OR => ops::bitwise::or},
-        24 {XOR => ops::bitwise::xor},
-        25 {NOT => ops::bitwise::not},
-
-        26 {INC => ops::math::inc},
-        27 {DEC => ops::math::dec},
-        28 {NEG => ops::math::neg},
-        29 {ABS => ops::math::abs},
-        30 {CLAMP => ops::math::clamp},
-        31 {JMP => ops::control::jmp},
-        32 {JZ => ops::control::jz},
-        33 {JNZ => ops::control::jnz},
-        34 {CALL => ops::control::call},
-        35 {CALLZ => ops::control::callz},
-        36 {CALLNZ => ops::control::callnz},
-        37 {RET => ops::control::ret},
-        38 {HALT => ops::control::halt},
-        39 { async SLEEP => ops::control::sleep},
-
-        60 {#[cfg(test)]{MOD test call0 0 }},
-        61 {#[cfg(test)]{MOD test call1 1 }},
-        62 {#[cfg(test)]{MOD test call2 2 }},
-        63 {#[cfg(test)]{MOD test calln "N" }},
-
-        64 {#[cfg(feature = "led")]{MOD led call0 0 }},
-        65 {#[cfg(feature = "led")]{MOD led call1 1 }},
-        66 {#[cfg(feature = "led")]{MOD led call2 2 }},
-        67 {#[cfg(feature = "led")]{MOD led calln "N" }},
-
-    );
+    // Generate dispatch and opcode_names methods using the dispatch_op macro
+    with_op_table!(dispatch_op);
 
     pub async fn new(debug: D) -> Self {
         VM {
             memory: [0; N],
+            xip_code: None,
             heap_start: 0,
             heap_end: 0,
+            arena_start: 0,
             max_pc: 0,
             halt_signal: S::create_signal(),
             pc: 0,
             sp: N - 1,
+            call_stack: [0; MAX_CALL_DEPTH],
+            call_depth: 0,
+            try_frames: [TryFrame::default(); MAX_TRY_DEPTH],
+            try_depth: 0,
 
             modules: Modules::init().await,
             debug,
@@ -229,24 +682,152 @@ impl<const N: usize, S: Sync, D: VmDebug> VM<N, S, D> {
     pub fn load(&mut self, program: &[u8]) -> Result<()> {
         self.memory.fill(0);
 
-        program.validate_program()?;
-        let program_start = program.program_start()?;
-        let program_slice = &program[program_start as usize..];
-        let program_len = program_slice.len();
-        let heap_size = program_len;
-        if program_len + heap_size > N - MIN_STACK_SIZE {
-            return Err(VMError::ProgramTooLarge);
+        let map = MemoryMap::plan(program, N)?;
+        let (code, compressed) = code_slice(program)?;
+        // plan() has checked that the code fits
+        let Some(memory) = self.memory.get_mut(map.program.clone()) else {
+            return Err(VMError::ProgramTooLarge {
+                size: map.program.len(),
+                max: N,
+            });
+        };
+        if compressed {
+            lz4::decompress(code, memory).map_err(|_| ProgramError::CorruptBody)?;
+        } else {
+            memory.copy_from_slice(code);
         }
+        self.xip_code = None;
+        self.start(&map);
+        self.configure_modules(program)?;
+        Ok(())
+    }
 
-        self.memory[0..program_len].copy_from_slice(program_slice);
-        self.heap_start = program_len;
-        self.max_pc = core::cmp::min(self.heap_start, u16::MAX as usize);
-        self.heap_end = program_len + heap_size;
+    // Loads `program` to run from where it is, typically flash, rather than
+    // copying its code into memory, which leaves all of memory for the heap
+    // (sized by the program's header) and stack, and lets the code be larger
+    // than memory. Module functions that read data from program memory (e.g.
+    // text) only see the heap in this mode.
+    pub fn load_xip(&mut self, program: &'static [u8]) -> Result<()> {
+        let map = MemoryMap::plan_xip(program, N)?;
+        let (code, _) = code_slice(program)?;
+        self.memory.fill(0);
+        self.xip_code = Some(code);
+        self.start(&map);
+        self.configure_modules(program)?;
+        Ok(())
+    }
+
+    fn start(&mut self, map: &MemoryMap) {
+        self.heap_start = map.heap.start;
+        self.max_pc = map.program.end;
+        self.heap_end = map.heap.end;
+        self.arena_start = map.heap.end;
         self.pc = 0;
         self.sp = N - 1;
+        self.call_depth = 0;
+        self.try_depth = 0;
+    }
+
+    // Applies module settings from the program's header
+    fn configure_modules(&mut self, _program: &[u8]) -> Result<()> {
+        #[cfg(feature = "led")]
+        {
+            let led = &mut self.modules.led;
+            led.set_interpolation(_program.flags()?.contains(ProgramFlags::INTERPOLATE));
+            led.set_strips(_program.strip_table()?)?;
+        }
+        #[cfg(feature = "time")]
+        self.modules.time.start(S::now_us());
+        #[cfg(feature = "rand")]
+        self.modules.rand.seed(crate::modules::rand::DEFAULT_SEED);
         Ok(())
     }
 
+    // Debug builds check that each op that succeeds moves the stack pointer
+    // by its stack effect in the op table, catching handlers that push or
+    // pop the wrong size. TRY's effect happens when the function returns,
+    // and RET's depends on whether it returns to a TRY, so they're skipped.
+    #[cfg(debug_assertions)]
+    fn check_stack_effect(&self, opcode: u8, sp_before: usize) {
+        if opcode == opcodes::TRY || opcode == opcodes::RET {
+            return;
+        }
+        if let Some((pops, pushes)) = opcodes::stack_effect(opcode) {
+            let value_size = size_of::<i16>() as isize;
+            let expected = sp_before as isize + (pops as isize - pushes as isize) * value_size;
+            debug_assert_eq!(
+                self.sp as isize,
+                expected,
+                "{} moved the stack pointer from {:#x} to {:#x}",
+                opcodes::name(opcode).unwrap_or("?"),
+                sp_before,
+                self.sp
+            );
+        }
+    }
+
+    // The code being run, wherever it is
+    pub fn code(&self) -> &[u8] {
+        self.xip_code
+            .unwrap_or_else(|| self.memory.get(..self.max_pc).unwrap_or_default())
+    }
+
+    pub fn memory_map(&self) -> MemoryMap {
+        MemoryMap {
+            program: 0..self.max_pc,
+            heap: self.heap_start..self.heap_end,
+            stack: self.heap_end..N,
+            free: self.sp.saturating_sub(self.heap_end),
+            xip: self.xip_code.is_some(),
+        }
+    }
+
+    pub fn snapshot(&self) -> VmSnapshot<N> {
+        VmSnapshot {
+            memory: self.memory,
+            xip_code: self.xip_code,
+            heap_start: self.heap_start,
+            max_pc: self.max_pc,
+            heap_end: self.heap_end,
+            arena_start: self.arena_start,
+            pc: self.pc,
+            sp: self.sp,
+            call_stack: self.call_stack,
+            call_depth: self.call_depth,
+            try_frames: self.try_frames,
+            try_depth: self.try_depth,
+
+            #[cfg(feature = "led")]
+            led: self.modules.led.snapshot(),
+            #[cfg(feature = "time")]
+            time: self.modules.time.clone(),
+            #[cfg(feature = "rand")]
+            rand: self.modules.rand.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &VmSnapshot<N>) {
+        self.memory = snapshot.memory;
+        self.xip_code = snapshot.xip_code;
+        self.heap_start = snapshot.heap_start;
+        self.max_pc = snapshot.max_pc;
+        self.heap_end = snapshot.heap_end;
+        self.arena_start = snapshot.arena_start;
+        self.pc = snapshot.pc;
+        self.sp = snapshot.sp;
+        self.call_stack = snapshot.call_stack;
+        self.call_depth = snapshot.call_depth;
+        self.try_frames = snapshot.try_frames;
+        self.try_depth = snapshot.try_depth;
+
+        #[cfg(feature = "led")]
+        self.modules.led.restore(&snapshot.led);
+        #[cfg(feature = "time")]
+        self.modules.time.clone_from(&snapshot.time);
+        #[cfg(feature = "rand")]
+        self.modules.rand.clone_from(&snapshot.rand);
+    }
+
     pub fn signal_halt(&self) {
         self.halt_signal.signal();
     }
@@ -262,6 +843,31 @@ impl<const N: usize, S: Sync, D: VmDebug> VM<N, S, D> {
 
         self.pc = 0;
         self.sp = N - 1;
+        self.heap_end = self.arena_start;
+        self.call_depth = 0;
+        self.try_depth = 0;
+    }
+
+    pub async fn run_op(&mut self) -> Result<()> {
+        let result = self.dispatch().await;
+        if let Err(err) = &result {
+            self.debug.op_failed(err);
+        }
+        match result {
+            Err(err) if self.try_depth > 0 && !matches!(err, VMError::Halt(_)) => {
+                // Unwind to the innermost TRY, which sees the error code
+                self.try_depth -= 1;
+                let Some(frame) = self.try_frames.get(self.try_depth).copied() else {
+                    return Err(err);
+                };
+                self.sp = frame.sp;
+                self.call_depth = frame.call_depth;
+                self.heap_end = frame.heap_end;
+                self.pc = frame.resume_pc;
+                self.stack_push(err.code() as i16)
+            }
+            result => result,
+        }
     }
 
     pub fn set_pc(&mut self, pc: usize) -> Result<()> {
@@ -278,43 +884,51 @@ impl<const N: usize, S: Sync, D: VmDebug> VM<N, S, D> {
         let size = size_of::<T>();
         let start = self.pc;
         self.pc += size;
-        if self.pc > self.max_pc {
-            let pc_u16 = self.pc as u16;
-            self.pc = 0;
-            return Err(VMError::PCOverflow(pc_u16));
+        match self.code().get(start..self.pc) {
+            Some(bytes) if self.pc <= self.max_pc => Ok(pod_read_unaligned::<T>(bytes)),
+            _ => {
+                let pc_u16 = self.pc as u16;
+                self.pc = 0;
+                Err(VMError::PCOverflow(pc_u16))
+            }
         }
-        Ok(pod_read_unaligned::<T>(&self.memory[start..(self.pc)]))
     }
 
-    pub fn alloc_stack_space<T>(&mut self) -> Result<&mut [u8; size_of::<T>()]> {
-        let size = size_of::<T>();
+    pub fn alloc_stack_space(&mut self, size: usize) -> Result<&mut [u8]> {
         let new_sp = self.sp.checked_sub(size).ok_or(VMError::StackOverflow)?;
         if new_sp < self.heap_end {
             return Err(VMError::StackOverflow);
         }
+        let space = self
+            .memory
+            .get_mut(new_sp..new_sp + size)
+            .ok_or(VMError::StackOverflow)?;
         self.sp = new_sp;
-        let slice = &mut self.memory[self.sp..(self.sp + size)];
-        Ok(slice.try_into().unwrap())
+        Ok(space)
     }
 
-    pub fn stack_push<T: NoUninit>(&mut self, value: T) -> Result<()>
-    where
-        [(); size_of::<T>()]:,
-    {
+    pub fn stack_push<T: NoUninit>(&mut self, value: T) -> Result<()> {
         let bytes = bytes_of(&value);
-        let stack_slice = self.alloc_stack_space::<T>()?;
+        let stack_slice = self.alloc_stack_space(bytes.len())?;
         stack_slice.copy_from_slice(bytes);
         Ok(())
     }
 
     pub fn stack_pop_raw(&mut self, size: usize) -> Result<&[u8]> {
         let start = self.sp;
-        let end = start + size;
-        if end > N {
-            return Err(VMError::StackUnderflow);
-        }
+        let bytes = self
+            .memory
+            .get(start..start + size)
+            .ok_or(VMError::StackUnderflow)?;
         self.sp += size;
-        Ok(&self.memory[start..end])
+        Ok(bytes)
+    }
+
+    // The top `size` bytes of the stack, left in place
+    pub fn stack_top_mut(&mut self, size: usize) -> Result<&mut [u8]> {
+        self.memory
+            .get_mut(self.sp..self.sp + size)
+            .ok_or(VMError::StackUnderflow)
     }
 
     pub fn stack_pop<T: Pod>(&mut self) -> Result<T> {
@@ -329,7 +943,8 @@ impl<const N: usize, S: Sync, D: VmDebug> VM<N, S, D> {
         if end > self.heap_end {
             return Err(VMError::HeapOverflow);
         }
-        Ok(pod_read_unaligned::<T>(&self.memory[start..end]))
+        let bytes = self.memory.get(start..end).ok_or(VMError::HeapOverflow)?;
+        Ok(pod_read_unaligned::<T>(bytes))
     }
 
     pub fn write_heap<T: NoUninit>(&mut self, addr: usize, value: T) -> Result<()> {
@@ -339,7 +954,9 @@ impl<const N: usize, S: Sync, D: VmDebug> VM<N, S, D> {
         if end > self.heap_end {
             return Err(VMError::HeapOverflow);
         }
-        self.memory[start..end].copy_from_slice(bytes);
+        self.debug.will_write_heap(addr, size_of::<T>());
+        let heap = self.memory.get_mut(start..end).ok_or(VMError::HeapOverflow)?;
+        heap.copy_from_slice(bytes);
         Ok(())
     }
 
@@ -347,29 +964,51 @@ impl<const N: usize, S: Sync, D: VmDebug> VM<N, S, D> {
         S::delay(us).await;
     }
 
-    pub async fn run(&mut self) -> Result<!> {
-        self.halt_signal.reset();
+    pub fn mailbox(&self) -> &'static S::Mailbox {
+        S::mailbox()
+    }
 
-        let mut op_counter: u32 = 0;
+    pub async fn run(&mut self) -> Result<Never> {
+        self.halt_signal.reset();
         loop {
-            if op_counter.is_multiple_of(1024)
-                && self.halt_signal.is_signaled() {
-                    self.halt_signal.reset();
-                    return Err(VMError::Halt(HaltReason::Signal));
-                }
-            op_counter = op_counter.wrapping_add(1);
+            self.run_slice().await?;
+        }
+    }
 
-            self.debug.will_run_op().await;
-            self.run_op().await?;
-            self.debug.did_run_op().await;
+    // Runs POLL_INTERVAL ops, after checking for a halt signal and polling
+    // the modules. For hosts that interleave other work with running the VM
+    // (see VmHandle); run() is just these back to back.
+    pub async fn run_slice(&mut self) -> Result<()> {
+        if self.halt_signal.is_signaled() {
+            self.halt_signal.reset();
+            return Err(VMError::Halt(HaltReason::Signal));
         }
+        self.modules.poll();
+        for _ in 0..POLL_INTERVAL {
+            self.step().await?;
+        }
+        Ok(())
+    }
+
+    // Runs one op, calling the debug hooks around it
+    pub async fn step(&mut self) -> Result<()> {
+        let opcode = self.code().get(self.pc).copied().unwrap_or(0);
+        self.debug.will_run_op(self.pc, opcode).await;
+        self.run_op().await?;
+        self.debug.did_run_op().await;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::fixture_parse::parse_fixture_with_output;
+    use crate::coverage::Coverage;
+    use crate::sync::TokioSync;
+    use crate::vm_builder::VmBuilder;
+    #[cfg(feature = "led")]
+    use crate::fixture_parse::parse_fixture_with_frames;
+    use crate::fixture_parse::{ErrorLocation, error_kind, parse_fixture_with_output};
     use rstest::*;
     use std::path::PathBuf;
 
@@ -377,40 +1016,528 @@ mod tests {
     #[tokio::test]
     async fn test_fixtures(#[files("../testprogs/*.pxs.txt")] path: PathBuf) {
         let fixture_data = std::fs::read_to_string(&path).unwrap();
-        let parsed = parse_fixture_with_output(&fixture_data);
+        let parsed = parse_fixture_with_output(&fixture_data)
+            .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
 
         let mut actual_output = vec![];
+        let mut actual_error = None;
 
         println!("Fixture Contents:\n{:?}", parsed.program);
-        let mut vm: VM<4096, crate::sync::TokioSync, crate::vm::NoVmDebug> =
-            make_vm::<4096, crate::sync::TokioSync>().await;
+        // The trace gives the pc of the op that failed
+        let mut vm = VmBuilder::standard_4k().trace().build::<TokioSync>().await;
         match vm.load(&parsed.program) {
             Ok(()) => {
                 let run_result = vm.run().await;
+                if let Err(err) = &run_result {
+                    let pc = vm.debug.entries().last().map(|(pc, _)| pc as usize);
+                    actual_error = Some((error_kind(err), pc.map(ErrorLocation::Pc)));
+                }
 
                 vm.modules.test.messages.iter().for_each(|msg: &String| {
                     actual_output.push(msg.clone());
                 });
+                if let Some(expected) = &parsed.expected_channels {
+                    let mut actual = std::collections::BTreeMap::new();
+                    for (channel, value) in &vm.modules.test.emitted {
+                        actual.entry(*channel).or_insert_with(Vec::new).push(*value);
+                    }
+                    for channel in expected.keys() {
+                        actual.entry(*channel).or_default();
+                    }
+                    assert_eq!(&actual, expected, "Channels did not match for fixture {:?}", path);
+                }
                 let result_desc = match &run_result {
                     Ok(_) => panic!("VM should never return OK from run()"),
                     Err(VMError::Halt(HaltReason::HaltOp)) => "*HALT".to_string(),
+                    Err(VMError::Halt(HaltReason::Exit(code))) => format!("*EXIT {}", code),
                     Err(err) => format!("Error: {:?}", err),
                 };
                 actual_output.push(result_desc);
             }
             Err(err) => {
                 actual_output.push(format!("Load Error: {:?}", err));
+                actual_error = Some((error_kind(&err), Some(ErrorLocation::Load)));
+            }
+        }
+
+        if let Some(expected) = &parsed.expected_error {
+            let Some((kind, location)) = actual_error else {
+                panic!("Fixture {:?} didn't fail", path);
+            };
+            assert_eq!(kind, expected.kind, "Error did not match for fixture {:?}", path);
+            if expected.location.is_some() {
+                assert_eq!(
+                    location, expected.location,
+                    "Error location did not match for fixture {:?}",
+                    path
+                );
+            }
+        }
+        if let Some(expected_output) = &parsed.expected_output {
+            let actual = actual_output.join("\n");
+            assert_eq!(
+                actual.trim(),
+                expected_output.trim(),
+                "Output did not match for fixture {:?}",
+                path
+            );
+        }
+    }
+
+    // Runs every fixture with coverage tracking and reports code and
+    // opcodes that the suite never executes (see with --nocapture)
+    #[tokio::test]
+    async fn test_fixture_coverage() {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir("../testprogs")
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.to_string_lossy().ends_with(".pxs.txt"))
+            .collect();
+        paths.sort();
+
+        let mut opcodes_run = [false; 256];
+        for path in &paths {
+            let parsed = parse_fixture_with_output(&std::fs::read_to_string(path).unwrap())
+                .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+            let Ok(mut vm) = VmBuilder::standard_4k()
+                .debug(Coverage::<512>::new())
+                .load::<TokioSync>(&parsed.program)
+                .await
+            else {
+                continue;
+            };
+            let _ = vm.run().await;
+            for range in vm.debug.uncovered(vm.code()) {
+                println!(
+                    "{}: {:04x}..{:04x} not executed",
+                    path.display(),
+                    range.start,
+                    range.end
+                );
+            }
+            for &(opcode, _) in opcodes::NAMES {
+                opcodes_run[opcode as usize] |= vm.debug.opcode_executed(opcode);
+            }
+        }
+        let untested: Vec<&str> = opcodes::NAMES
+            .iter()
+            .filter(|(opcode, _)| !opcodes_run[*opcode as usize])
+            .map(|(_, name)| *name)
+            .collect();
+        println!("Opcodes not executed by any fixture: {}", untested.join(", "));
+        assert!(opcodes_run.iter().any(|&run| run));
+    }
+
+    // Truncated and corrupted programs have to fail with an error rather
+    // than a panic, which would take down a device
+    #[tokio::test]
+    async fn test_malformed_programs() {
+        let mut programs = vec![];
+        for entry in std::fs::read_dir("../testprogs").unwrap() {
+            let path = entry.unwrap().path();
+            if path.to_string_lossy().ends_with(".pxs.txt") {
+                let fixture = std::fs::read_to_string(&path).unwrap();
+                programs.push(parse_fixture_with_output(&fixture).unwrap().program);
+            }
+        }
+
+        let mut vm = make_vm::<256, TokioSync>().await;
+        for program in &programs {
+            let mut variants: Vec<Vec<u8>> = (0..program.len())
+                .map(|len| program[..len].to_vec())
+                .collect();
+            for i in 0..program.len() {
+                for value in [0xff, program[i].wrapping_add(1)] {
+                    let mut corrupted = program.clone();
+                    corrupted[i] = value;
+                    variants.push(corrupted);
+                }
+            }
+            for variant in &variants {
+                if vm.load(variant).is_ok() {
+                    for _ in 0..200 {
+                        if vm.run_op().await.is_err() {
+                            break;
+                        }
+                    }
+                }
             }
         }
+    }
 
-        let actual = actual_output.join("\n");
+    #[cfg(feature = "led")]
+    #[rstest]
+    #[tokio::test]
+    async fn test_frame_fixtures(#[files("../testprogs/frames/*.pxs.txt")] path: PathBuf) {
+        let fixture_data = std::fs::read_to_string(&path).unwrap();
+        let parsed = parse_fixture_with_frames(&fixture_data)
+            .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        let n_frames = parsed.expected_frames.len();
+        let n_pixels = parsed.expected_frames.first().map_or(0, |f| f.len());
+
+        let mut vm = VmBuilder::standard_4k()
+            .num_pixels(n_pixels)
+            .capture_frames()
+            .load::<TokioSync>(&parsed.program)
+            .await
+            .unwrap();
+
+        // Step the VM until it has shown the expected number of frames
+        while vm.modules.led.captured_frames.as_ref().unwrap().len() < n_frames {
+            if let Err(err) = vm.run_op().await {
+                panic!(
+                    "VM stopped with {:?} before producing {} frames",
+                    err, n_frames
+                );
+            }
+        }
+
+        let actual_frames = vm.modules.led.take_captured_frames();
+        for (i, (actual, expected)) in actual_frames
+            .iter()
+            .zip(&parsed.expected_frames)
+            .enumerate()
+        {
+            let within_tolerance = actual.len() == expected.len()
+                && actual
+                    .iter()
+                    .flatten()
+                    .zip(expected.iter().flatten())
+                    .all(|(a, e)| a.abs_diff(*e) <= parsed.tolerance);
+            assert!(
+                within_tolerance,
+                "Frame {} did not match for fixture {:?}\nactual:   {:02x?}\nexpected: {:02x?}",
+                i, path, actual, expected
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restore() {
+        let program = parse_fixture_with_output(
+            "HEADER(0)\nOP:PUSH 1i16\nOP:INC\nOP:DUP\nOP:TEST1 2\nOP:JMP -7i16\n=== OUTPUT ===",
+        )
+        .unwrap()
+        .program;
+        let mut vm = make_vm::<256, crate::sync::TokioSync>().await;
+        vm.load(&program).unwrap();
+        for _ in 0..4 {
+            vm.run_op().await.unwrap();
+        }
+        let snapshot = vm.snapshot();
+        for _ in 0..8 {
+            vm.run_op().await.unwrap();
+        }
+        vm.restore(&snapshot);
+        for _ in 0..4 {
+            vm.run_op().await.unwrap();
+        }
+        assert_eq!(
+            vm.modules.test.messages,
+            [
+                "TEST_ONE_ARG: 2",
+                "TEST_ONE_ARG: 3",
+                "TEST_ONE_ARG: 4",
+                "TEST_ONE_ARG: 3"
+            ]
+        );
+        assert_eq!(vm.pc, snapshot.pc);
+        assert_eq!(vm.sp, snapshot.sp);
+    }
+
+    #[cfg(feature = "rand")]
+    #[tokio::test]
+    async fn test_snapshot_restore_rand() {
+        // Prints random numbers forever
+        let mut buf = [0u8; 64];
+        let mut builder = crate::builder::ProgramBuilder::new(&mut buf, 0, &[], "Rand").unwrap();
+        builder.module_call(opcodes::RAND0, 1, 0).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.jump_to(opcodes::JMP, 0).unwrap();
+        let program = builder.finish();
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load(program).unwrap();
+
+        let snapshot = vm.snapshot();
+        for _ in 0..6 {
+            vm.run_op().await.unwrap();
+        }
+        let first = core::mem::take(&mut vm.modules.test.messages);
+        vm.restore(&snapshot);
+        for _ in 0..6 {
+            vm.run_op().await.unwrap();
+        }
+        assert_eq!(vm.modules.test.messages, first);
+        assert_ne!(first[0], first[1]);
+    }
+
+    #[cfg(feature = "led")]
+    #[tokio::test]
+    async fn test_snapshot_restore_led() {
+        fn set_pixel(
+            builder: &mut crate::builder::ProgramBuilder,
+            index: i16,
+            [r, g, b]: [i16; 3],
+        ) {
+            for value in [b, g, r, index] {
+                builder.push(value).unwrap();
+            }
+            builder.module_call(opcodes::LED0, 4, 4).unwrap();
+            builder.module_call(opcodes::LED0, 2, 0).unwrap();
+        }
+
+        // Shows pixel 0 red, then on a matrix, pixel 0 of strip 1 green,
+        // then pixel 1 blue
+        let mut buf = [0u8; 128];
+        let mut builder = crate::builder::ProgramBuilder::with_strips(
+            &mut buf,
+            0,
+            &[],
+            &[(2, 0), (2, 0)],
+            "Strips",
+        )
+        .unwrap();
+        set_pixel(&mut builder, 0, [255, 0, 0]);
+        let on_strip = builder.pc();
+        builder.push(1).unwrap();
+        builder.module_call(opcodes::LED0, 21, 1).unwrap();
+        builder.push(1).unwrap();
+        builder.push(2).unwrap();
+        builder.module_call(opcodes::LED0, 11, 2).unwrap();
+        set_pixel(&mut builder, 0, [0, 255, 0]);
+        let blue = builder.pc();
+        set_pixel(&mut builder, 1, [0, 0, 255]);
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load(program).unwrap();
+
+        while vm.pc != on_strip {
+            vm.run_op().await.unwrap();
+        }
+        let snapshot = vm.snapshot();
+        while vm.pc != blue {
+            vm.run_op().await.unwrap();
+        }
+        assert_eq!(
+            vm.modules.led.output()[..4],
+            [[255, 0, 0], [0; 3], [0, 255, 0], [0; 3]]
+        );
+        vm.restore(&snapshot);
+        assert_eq!(
+            vm.modules.led.output()[..4],
+            [[255, 0, 0], [0; 3], [0; 3], [0; 3]]
+        );
+        assert_eq!(
+            vm.modules.led.layout,
+            crate::modules::led::matrix::MatrixLayout::default()
+        );
+
+        // Drawn on the whole frame again, not strip 1
+        vm.pc = blue;
+        while vm.run_op().await.is_ok() {}
+        assert_eq!(
+            vm.modules.led.output()[..4],
+            [[255, 0, 0], [0, 0, 255], [0; 3], [0; 3]]
+        );
+    }
+
+    #[cfg(feature = "led")]
+    #[tokio::test]
+    async fn test_fill_strip() {
+        // Fills pixels 0 to 100 of the 2-pixel strip 0 red
+        let mut buf = [0u8; 64];
+        let mut builder = crate::builder::ProgramBuilder::with_strips(
+            &mut buf,
+            0,
+            &[],
+            &[(2, 0), (3, 0)],
+            "Fill",
+        )
+        .unwrap();
+        builder.push(0).unwrap();
+        builder.module_call(opcodes::LED0, 21, 1).unwrap();
+        for value in [0, 0, 255, 100, 0] {
+            builder.push(value).unwrap();
+        }
+        builder.module_call(opcodes::LED0, 5, 5).unwrap();
+        builder.module_call(opcodes::LED0, 2, 0).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load(builder.finish()).unwrap();
+        let _ = vm.run().await;
+        assert_eq!(
+            vm.modules.led.output()[..5],
+            [[255, 0, 0], [255, 0, 0], [0; 3], [0; 3], [0; 3]]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_map() {
+        let mut buf = [0u8; 64];
+        let mut builder = crate::builder::ProgramBuilder::new(&mut buf, 0, &[], "Map").unwrap();
+        builder.push(1).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+        let map = MemoryMap::plan(program, 256).unwrap();
+        assert_eq!(
+            map,
+            MemoryMap {
+                program: 0..2,
+                heap: 2..4,
+                stack: 4..256,
+                free: 251,
+                xip: false,
+            }
+        );
+        assert_eq!(
+            map.to_string(),
+            "program  0000-0002      2 bytes\n\
+             heap     0002-0004      2 bytes\n\
+             stack    0004-0100    252 bytes\n\
+             free                  251 bytes\n"
+        );
+
+        let mut vm = make_vm::<256, crate::sync::TokioSync>().await;
+        vm.load(program).unwrap();
+        assert_eq!(vm.memory_map(), map);
+        let _ = vm.run().await;
+        assert_eq!(vm.memory_map().free, 249);
+
+        assert!(MemoryMap::layout(0, 0, true, MIN_STACK_SIZE).is_ok());
+        for memory_size in [0, MIN_STACK_SIZE - 1] {
+            assert!(matches!(
+                MemoryMap::layout(0, 0, true, memory_size),
+                Err(VMError::ProgramTooLarge { size: 0, max: 0 })
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_load_xip() {
+        let mut buf = [0u8; 64];
+        let mut builder = crate::builder::ProgramBuilder::new(&mut buf, 2, &[], "Xip").unwrap();
+        builder.push(300).unwrap();
+        builder.op_u16(opcodes::STORE, 0).unwrap();
+        builder.op_u16(opcodes::LOAD, 0).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program: &'static [u8] = Box::leak(builder.finish().into());
+
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load_xip(program).unwrap();
+        let map = vm.memory_map();
+        assert_eq!(map, MemoryMap::plan_xip(program, 256).unwrap());
         assert_eq!(
-            actual.trim(),
-            parsed.expected_output.trim(),
-            "Output did not match for fixture {:?}",
-            path
+            map.to_string(),
+            "program  in place      12 bytes\n\
+             heap     0000-0002      2 bytes\n\
+             stack    0002-0100    254 bytes\n\
+             free                  253 bytes\n"
         );
+        assert_eq!((map.program, map.heap, map.free), (0..12, 0..2, 253));
+        assert!(matches!(
+            vm.run().await,
+            Err(VMError::Halt(HaltReason::HaltOp))
+        ));
+        assert_eq!(vm.modules.test.messages, ["TEST_ONE_ARG: 300"]);
+        assert_eq!(&vm.memory[..2], &300i16.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_load_xip_larger_than_memory() {
+        // Jumps over 500 bytes of padding, more than the VM's memory
+        let mut buf = [0u8; 600];
+        let mut builder = crate::builder::ProgramBuilder::new(&mut buf, 2, &[], "Big").unwrap();
+        builder.op_i16(opcodes::JMP, 500).unwrap();
+        builder.bytes(&[opcodes::HALT; 500]).unwrap();
+        builder.push(7).unwrap();
+        builder.module_call(opcodes::TEST0, 2, 1).unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program: &'static [u8] = Box::leak(builder.finish().into());
+
+        let mut vm = make_vm::<256, TokioSync>().await;
+        assert!(matches!(
+            vm.load(program),
+            Err(VMError::ProgramTooLarge { .. })
+        ));
+        vm.load_xip(program).unwrap();
+        assert_eq!(vm.memory_map().heap, 0..2);
+        assert!(matches!(
+            vm.run().await,
+            Err(VMError::Halt(HaltReason::HaltOp))
+        ));
+        assert_eq!(vm.modules.test.messages, ["TEST_ONE_ARG: 7"]);
+    }
+
+    #[tokio::test]
+    async fn test_program_too_large() {
+        let mut buf = vec![0u8; MAX_CODE_SIZE + 64];
+        let mut builder = crate::builder::ProgramBuilder::new(&mut buf, 0, &[], "Big").unwrap();
+        builder.bytes(&[opcodes::HALT; 2100]).unwrap();
+        let mut vm = make_vm::<4096, crate::sync::TokioSync>().await;
+        let err = vm.load(builder.finish()).unwrap_err();
+        assert!(matches!(
+            err,
+            VMError::ProgramTooLarge {
+                size: 4200,
+                max: 4088
+            }
+        ));
+
+        // Code beyond the u16 address space is rejected however much memory
+        // there is
+        let mut builder = crate::builder::ProgramBuilder::new(&mut buf, 0, &[], "Big").unwrap();
+        builder.bytes(&[opcodes::HALT; MAX_CODE_SIZE + 1]).unwrap();
+        let mut vm = Box::new(make_vm::<{ 2 * MAX_CODE_SIZE + 64 }, crate::sync::TokioSync>().await);
+        let err = vm.load(builder.finish()).unwrap_err();
+        assert!(matches!(
+            err,
+            VMError::ProgramTooLarge {
+                size: 65536,
+                max: MAX_CODE_SIZE
+            }
+        ));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "ADD moved the stack pointer from 0xff to 0xfb")]
+    async fn test_stack_effect_check() {
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.sp = 0xfb;
+        vm.check_stack_effect(opcodes::TRY, 0xff);
+        vm.check_stack_effect(opcodes::ADD, 0xff);
     }
+
+    #[tokio::test]
+    async fn test_call_stack() {
+        // The function leaves a value on the stack, which doesn't affect
+        // where it returns to
+        let program = parse_fixture_with_output(
+            "HEADER(0)\nOP:CALL 1i16\nOP:HALT\nOP:PUSH1\nOP:RET\n=== OUTPUT ===",
+        )
+        .unwrap()
+        .program;
+        let mut vm = make_vm::<256, TokioSync>().await;
+        vm.load(&program).unwrap();
+        assert!(matches!(
+            vm.run().await,
+            Err(VMError::Halt(HaltReason::HaltOp))
+        ));
+        assert_eq!(vm.stack_pop::<i16>().unwrap(), 1);
+        assert_eq!(vm.call_depth, 0);
+
+        // Unbounded recursion runs out of call stack
+        let program =
+            parse_fixture_with_output("HEADER(0)\nOP:CALL -3i16\n=== OUTPUT ===")
+                .unwrap()
+                .program;
+        vm.load(&program).unwrap();
+        assert!(matches!(vm.run().await, Err(VMError::StackOverflow)));
+        assert_eq!(vm.call_depth, MAX_CALL_DEPTH);
+        assert_eq!(vm.sp, 255);
+    }
+
     //     let mut vm: VM<256> = VM::new();
     //     let program = [0x01, 0x02, 0x03, 0x04];
     //     vm.load(&program);
@@ -0,0 +1,232 @@
+#[cfg(feature = "host")]
+extern crate alloc;
+
+use crate::crash::TraceTail;
+#[cfg(feature = "dbg")]
+use crate::modules::dbg::BoxedSink;
+#[cfg(feature = "host")]
+use crate::modules::host::{self, HostFn};
+#[cfg(feature = "led")]
+use crate::modules::led::output::{BoxedDriver, BoxedListener};
+use crate::sync::Sync;
+use crate::vm::{NoVmDebug, Result, VM, VmDebug};
+#[cfg(feature = "host")]
+use alloc::vec::Vec;
+
+// VM memory sizes for the supported boards
+pub const TINY_2K: usize = 2 * 1024;
+pub const STANDARD_4K: usize = 4 * 1024;
+pub const LARGE_16K: usize = 16 * 1024;
+
+pub type Tiny2K<S, D = NoVmDebug> = VM<TINY_2K, S, D>;
+pub type Standard4K<S, D = NoVmDebug> = VM<STANDARD_4K, S, D>;
+pub type Large16K<S, D = NoVmDebug> = VM<LARGE_16K, S, D>;
+
+// Sets up a VM: memory size (from a preset, or any N with new()), debug
+// hook and module configuration. The Sync implementation is picked at
+// build(), e.g.
+//   VmBuilder::standard_4k().trace().build::<TokioSync>().await
+pub struct VmBuilder<const N: usize, D: VmDebug = NoVmDebug> {
+    debug: D,
+    #[cfg(feature = "led")]
+    num_pixels: Option<usize>,
+    #[cfg(feature = "led")]
+    driver: Option<BoxedDriver>,
+    #[cfg(feature = "led")]
+    capture_frames: bool,
+    #[cfg(feature = "led")]
+    frame_listener: Option<BoxedListener>,
+    #[cfg(feature = "led")]
+    simulate_transfer: bool,
+    #[cfg(feature = "dbg")]
+    dbg_sink: Option<BoxedSink>,
+    #[cfg(feature = "host")]
+    host_functions: Vec<(u8, HostFn)>,
+}
+
+impl<const N: usize> Default for VmBuilder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> VmBuilder<N> {
+    pub fn new() -> Self {
+        VmBuilder {
+            debug: NoVmDebug,
+            #[cfg(feature = "led")]
+            num_pixels: None,
+            #[cfg(feature = "led")]
+            driver: None,
+            #[cfg(feature = "led")]
+            capture_frames: false,
+            #[cfg(feature = "led")]
+            frame_listener: None,
+            #[cfg(feature = "led")]
+            simulate_transfer: false,
+            #[cfg(feature = "dbg")]
+            dbg_sink: None,
+            #[cfg(feature = "host")]
+            host_functions: Vec::new(),
+        }
+    }
+}
+
+impl VmBuilder<TINY_2K> {
+    pub fn tiny_2k() -> Self {
+        Self::new()
+    }
+}
+
+impl VmBuilder<STANDARD_4K> {
+    pub fn standard_4k() -> Self {
+        Self::new()
+    }
+}
+
+impl VmBuilder<LARGE_16K> {
+    pub fn large_16k() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, D: VmDebug> VmBuilder<N, D> {
+    pub fn debug<D2: VmDebug>(self, debug: D2) -> VmBuilder<N, D2> {
+        VmBuilder {
+            debug,
+            #[cfg(feature = "led")]
+            num_pixels: self.num_pixels,
+            #[cfg(feature = "led")]
+            driver: self.driver,
+            #[cfg(feature = "led")]
+            capture_frames: self.capture_frames,
+            #[cfg(feature = "led")]
+            frame_listener: self.frame_listener,
+            #[cfg(feature = "led")]
+            simulate_transfer: self.simulate_transfer,
+            #[cfg(feature = "dbg")]
+            dbg_sink: self.dbg_sink,
+            #[cfg(feature = "host")]
+            host_functions: self.host_functions,
+        }
+    }
+
+    // Keeps a trace of the last ops run, for crash reports
+    pub fn trace(self) -> VmBuilder<N, TraceTail> {
+        self.debug(TraceTail::new())
+    }
+
+    #[cfg(feature = "led")]
+    pub fn num_pixels(mut self, num_pixels: usize) -> Self {
+        self.num_pixels = Some(num_pixels);
+        self
+    }
+
+    #[cfg(feature = "led")]
+    pub fn driver(mut self, driver: BoxedDriver) -> Self {
+        self.driver = Some(driver);
+        self
+    }
+
+    // Records every shown frame (see LedModule::take_captured_frames)
+    #[cfg(feature = "led")]
+    pub fn capture_frames(mut self) -> Self {
+        self.capture_frames = true;
+        self
+    }
+
+    // Called with every shown frame (see FrameListener)
+    #[cfg(feature = "led")]
+    pub fn on_frame(mut self, listener: BoxedListener) -> Self {
+        self.frame_listener = Some(listener);
+        self
+    }
+
+    // Makes show() take as long as sending the frame to WS2812s would, for
+    // hosts simulating a device
+    #[cfg(feature = "led")]
+    pub fn simulate_transfer(mut self) -> Self {
+        self.simulate_transfer = true;
+        self
+    }
+
+    // Where dbg module messages go
+    #[cfg(feature = "dbg")]
+    pub fn dbg_sink(mut self, sink: BoxedSink) -> Self {
+        self.dbg_sink = Some(sink);
+        self
+    }
+
+    // Registers `function` as host module function `code` (see host)
+    #[cfg(feature = "host")]
+    pub fn host_function<F, Fut>(mut self, code: u8, function: F) -> Self
+    where
+        F: Fn(Vec<i16>) -> Fut + Send + 'static,
+        Fut: Future<Output = Option<i16>> + Send + 'static,
+    {
+        self.host_functions.push((code, host::boxed(function)));
+        self
+    }
+
+    pub async fn build<S: Sync>(self) -> VM<N, S, D> {
+        #[allow(unused_mut)]
+        let mut vm = VM::new(self.debug).await;
+        #[cfg(feature = "led")]
+        {
+            if let Some(num_pixels) = self.num_pixels {
+                vm.modules.led.set_num_pixels(num_pixels);
+            }
+            if let Some(driver) = self.driver {
+                vm.modules.led.set_driver(driver);
+            }
+            if self.capture_frames {
+                vm.modules.led.start_capture();
+            }
+            if let Some(listener) = self.frame_listener {
+                vm.modules.led.set_frame_listener(listener);
+            }
+            vm.modules.led.simulate_transfer = self.simulate_transfer;
+        }
+        #[cfg(feature = "dbg")]
+        if let Some(sink) = self.dbg_sink {
+            vm.modules.dbg.set_sink(sink);
+        }
+        #[cfg(feature = "host")]
+        for (code, function) in self.host_functions {
+            vm.modules.host.register_boxed(code, function);
+        }
+        vm
+    }
+
+    // Builds the VM and loads `program` into it
+    pub async fn load<S: Sync>(self, program: &[u8]) -> Result<VM<N, S, D>> {
+        let mut vm = self.build().await;
+        vm.load(program)?;
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::ProgramBuilder;
+    use crate::sync::TokioSync;
+    use crate::vm::opcodes;
+
+    #[tokio::test]
+    async fn test_vm_builder() {
+        let mut buf = [0u8; 32];
+        let mut builder = ProgramBuilder::new(&mut buf, 0, &[], "Build").unwrap();
+        builder.op(opcodes::HALT).unwrap();
+        let program = builder.finish();
+
+        let mut vm: Tiny2K<TokioSync, TraceTail> =
+            VmBuilder::tiny_2k().trace().load(program).await.unwrap();
+        assert_eq!(vm.memory.len(), 2048);
+        let _ = vm.run().await;
+        assert_eq!(vm.debug.entries().collect::<Vec<_>>(), [(0, opcodes::HALT)]);
+
+        let vm = VmBuilder::large_16k().build::<TokioSync>().await;
+        assert_eq!(vm.memory_map().stack.end, LARGE_16K);
+    }
+}
@@ -0,0 +1,7 @@
+fn main() {
+    // There's no main() on bare metal, so make the probe function the entry
+    // point, which keeps it (and everything it uses) through section GC
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        println!("cargo:rustc-link-arg-bins=--entry=rpled_probe_run");
+    }
+}
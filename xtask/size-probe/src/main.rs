@@ -0,0 +1,170 @@
+// Links in the VM the way firmware would, so that the size checks in xtask
+// measure just the code a firmware image would contain. Never run.
+#![cfg_attr(target_os = "none", no_std, no_main)]
+
+extern crate alloc;
+
+use core::future::{Future, ready};
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use rpled_vm::sync::{Mailbox, Signal, Sync};
+use rpled_vm::vm_builder::{Standard4K, VmBuilder};
+
+struct ProbeSignal(AtomicBool);
+
+impl Signal for ProbeSignal {
+    fn signal(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    fn wait_signal(&self) -> impl Future<Output = ()> {
+        ready(())
+    }
+
+    fn wait_reset(&self) -> impl Future<Output = ()> {
+        ready(())
+    }
+
+    fn is_signaled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct NoMailbox;
+
+impl Mailbox for NoMailbox {
+    fn send(&self, _channel: u8, _value: i16) -> bool {
+        false
+    }
+
+    fn recv(&self, _channel: u8) -> Option<i16> {
+        None
+    }
+
+    fn pending(&self, _channel: u8) -> usize {
+        0
+    }
+}
+
+static MAILBOX: NoMailbox = NoMailbox;
+
+struct ProbeSync;
+
+impl Sync for ProbeSync {
+    type Signal = ProbeSignal;
+    type Mailbox = NoMailbox;
+
+    fn create_signal() -> ProbeSignal {
+        ProbeSignal(AtomicBool::new(false))
+    }
+
+    fn delay(_us: u16) -> impl Future<Output = ()> {
+        ready(())
+    }
+
+    fn now_us() -> u64 {
+        0
+    }
+
+    fn mailbox() -> &'static NoMailbox {
+        &MAILBOX
+    }
+}
+
+// A WS2812 driver on PIO0 and DMA channel 0, driving GPIO 2
+#[cfg(feature = "rp2040-pio")]
+fn pio_driver() -> Option<rpled_vm::modules::led::output::BoxedDriver> {
+    use alloc::boxed::Box;
+    use rp2040_hal::dma::DMAExt;
+    use rp2040_hal::gpio::Pins;
+    use rp2040_hal::pac::Peripherals;
+    use rp2040_hal::pio::PIOExt;
+    use rp2040_hal::sio::Sio;
+    use rpled_vm::modules::led::MAX_PIXELS;
+    use rpled_vm::modules::led::rp2040::Rp2040PioDriver;
+
+    // Safety: the probe is never run, so nothing else uses the peripherals
+    let mut pac = unsafe { Peripherals::steal() };
+    let (mut pio, sm0, _, _, _) = pac.PIO0.split(&mut pac.RESETS);
+    let sio = Sio::new(pac.SIO);
+    let pins = Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut pac.RESETS,
+    );
+    let dma = pac.DMA.split(&mut pac.RESETS);
+    let buffer = Box::leak(Box::new([0; 3 * MAX_PIXELS]));
+    let driver = Rp2040PioDriver::new(
+        &mut pio,
+        sm0,
+        pins.gpio2.into_function(),
+        dma.ch0,
+        buffer,
+        125_000_000,
+    );
+    Some(Box::new(driver.ok()?))
+}
+
+// Loads and runs a program up to its first await, returning the error code
+// if it fails straight away
+#[unsafe(no_mangle)]
+pub extern "C" fn rpled_probe_run(program: &[u8; 256]) -> u8 {
+    let run = async {
+        let builder = VmBuilder::standard_4k();
+        #[cfg(feature = "rp2040-pio")]
+        let builder = match pio_driver() {
+            Some(driver) => builder.driver(driver),
+            None => builder,
+        };
+        let mut vm: Standard4K<ProbeSync> = builder.build().await;
+        vm.load(program)?;
+        vm.run().await
+    };
+    match pin!(run).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(Err(err)) => err.code(),
+        _ => 0,
+    }
+}
+
+// Bare-metal builds are rooted at rpled_probe_run instead (see build.rs)
+#[cfg(not(target_os = "none"))]
+fn main() {
+    std::hint::black_box(rpled_probe_run(std::hint::black_box(&[0; 256])));
+}
+
+#[cfg(target_os = "none")]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+// The led module allocates (frame capture, boxed drivers); firmware brings
+// its own heap, so any allocator will do for measuring, as long as the
+// optimiser can't tell that it always fails and drop the code after each
+// allocation as unreachable
+#[cfg(target_os = "none")]
+mod allocator {
+    use core::alloc::{GlobalAlloc, Layout};
+
+    struct NoAlloc;
+
+    unsafe impl GlobalAlloc for NoAlloc {
+        unsafe fn alloc(&self, _layout: Layout) -> *mut u8 {
+            core::hint::black_box(core::ptr::null_mut())
+        }
+
+        unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: NoAlloc = NoAlloc;
+}
@@ -0,0 +1,518 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode};
+
+use rpled_vm::modules::TEST_OPCODE_OFFSET;
+use rpled_vm::vm::opcodes;
+
+mod symbols;
+
+const USAGE: &str = "\
+Usage: cargo xtask <command> [args]
+
+Commands:
+  check-embedded [--target <triple>] [--bless]
+                       Build rpled-vm for a no_std target with each feature set, and
+                       check its code size against xtask/sizes.txt (--bless records
+                       the current sizes instead)
+  size-report [--target <triple>]
+                       Print rpled-vm's code size per feature set and per opcode
+                       handler, failing if a feature set is over its budget in
+                       xtask/size-budgets.txt
+  op-docs [--check]    Regenerate the opcode reference in README.md from the op table
+                       (--check fails if it's out of date instead)
+  ffi-header [--check] Regenerate rpled-ffi's C header, rpled-ffi/include/rpled.h
+                       (--check fails if it's out of date instead)";
+
+// The smallest target we support (RP2040 class)
+const EMBEDDED_TARGET: &str = "thumbv6m-none-eabi";
+
+// rpled-vm feature sets that firmware builds use, as features of the size
+// probe. The test module and the host-only features (tokio, std) must stay
+// out of all of them.
+const FEATURE_SETS: &[(&str, &[&str])] = &[
+    ("bare", &[]),
+    ("led", &["led"]),
+    ("math", &["math"]),
+    ("modules", &["led", "math", "msg", "dbg"]),
+    ("embassy", &["led", "math", "msg", "dbg", "embassy"]),
+    ("rp2040", &["led", "rp2040-pio"]),
+];
+
+const PROBE: &str = "rpled-size-probe";
+const PROBE_FUNCTION: &str = "rpled_probe_run";
+
+// Recorded sizes, one `<target> <feature set> <bytes>` per line
+const SIZES_FILE: &str = "xtask/sizes.txt";
+
+// Size limits, one `<feature set> <bytes>` per line, for EMBEDDED_TARGET
+const BUDGETS_FILE: &str = "xtask/size-budgets.txt";
+
+// Growth over the recorded size (in percent) that fails the check
+const SIZE_TOLERANCE: u64 = 1;
+
+const README: &str = "README.md";
+const OP_DOCS_START: &str =
+    "<!-- op-docs: generated by `cargo xtask op-docs` from the op table in rpled-vm/src/vm.rs -->";
+const OP_DOCS_END: &str = "<!-- /op-docs -->";
+
+const FFI_SOURCE: &str = "rpled-ffi/src/lib.rs";
+const FFI_HEADER: &str = "rpled-ffi/include/rpled.h";
+const FFI_HEADER_COMMENT: &str = "\
+/* The C interface to the rpled VM (see rpled-ffi/src/lib.rs).
+ * Generated by `cargo xtask ffi-header`: don't edit.
+ *
+ * Create a VM with rpled_vm_create(), load a compiled program with
+ * rpled_vm_load() and call rpled_vm_run_for() regularly, reading the
+ * shown frame with rpled_vm_get_frame(). Functions returning int32_t give
+ * RPLED_OK, RPLED_INVALID_ARGUMENT or the VM's error code. */";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["check-embedded", ref options @ ..] => check_embedded(options),
+        ["size-report"] => size_report(EMBEDDED_TARGET),
+        ["size-report", "--target", target] => size_report(target),
+        ["op-docs"] => op_docs(false),
+        ["op-docs", "--check"] => op_docs(true),
+        ["ffi-header"] => ffi_header(false),
+        ["ffi-header", "--check"] => ffi_header(true),
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+fn cargo() -> Command {
+    let mut command = Command::new(std::env::var("CARGO").unwrap_or("cargo".to_string()));
+    command.current_dir(workspace_root());
+    command
+}
+
+// Builds the size probe (and so rpled-vm) for `target`, returning the path
+// of the binary. Warnings fail the build, as code that's unused with some
+// features should be left out of those builds.
+fn build_probe(target: &str, features: &[&str]) -> Result<PathBuf, String> {
+    let rustflags = std::env::var("RUSTFLAGS").unwrap_or_default();
+    let status = cargo()
+        .args(["build", "--release", "--package", PROBE])
+        .args(["--target", target])
+        .args(["--features", &features.join(",")])
+        .env("RUSTFLAGS", format!("{} -D warnings", rustflags).trim())
+        .status()
+        .map_err(|err| format!("Failed to run cargo: {}", err))?;
+    if !status.success() {
+        return Err("Build failed".to_string());
+    }
+    Ok(workspace_root()
+        .join("target")
+        .join(target)
+        .join("release")
+        .join(PROBE))
+}
+
+// Includes generic std/core code instantiated for VM types
+fn is_vm_code(name: &str) -> bool {
+    name.contains("rpled_vm::") || name.contains("rpled_size_probe::") || name == PROBE_FUNCTION
+}
+
+fn is_std_code(name: &str) -> bool {
+    name.starts_with("std::") || name.contains("<std::")
+}
+
+// Whitespace separated lines of names and a final number, skipping blank
+// lines and comments
+fn read_table(file: &str) -> Result<Vec<(Vec<String>, u64)>, String> {
+    let path = workspace_root().join(file);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(format!("Failed to read {}: {}", path.display(), err)),
+    };
+    text.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let mut fields: Vec<String> = line.split_whitespace().map(String::from).collect();
+            let size = fields.pop().and_then(|size| size.parse().ok());
+            size.map(|size| (fields, size))
+                .ok_or(format!("Invalid line in {}: {}", file, line))
+        })
+        .collect()
+}
+
+fn read_sizes() -> Result<BTreeMap<(String, String), u64>, String> {
+    read_table(SIZES_FILE)?
+        .into_iter()
+        .map(|(fields, size)| match &fields[..] {
+            [target, set] => Ok(((target.clone(), set.clone()), size)),
+            _ => Err(format!("Invalid entry in {}: {:?}", SIZES_FILE, fields)),
+        })
+        .collect()
+}
+
+fn read_budgets() -> Result<BTreeMap<String, u64>, String> {
+    read_table(BUDGETS_FILE)?
+        .into_iter()
+        .map(|(fields, size)| match &fields[..] {
+            [set] => Ok((set.clone(), size)),
+            _ => Err(format!("Invalid entry in {}: {:?}", BUDGETS_FILE, fields)),
+        })
+        .collect()
+}
+
+fn write_sizes(sizes: &BTreeMap<(String, String), u64>) -> Result<(), String> {
+    let mut text = "# Code size (.text) of the size probe, and so of what rpled-vm adds to a\n\
+                    # firmware image, per target and feature set, in bytes.\n\
+                    # Update with `cargo xtask check-embedded --bless`.\n"
+        .to_string();
+    for ((target, set), size) in sizes {
+        text += &format!("{} {} {}\n", target, set, size);
+    }
+    let path = workspace_root().join(SIZES_FILE);
+    std::fs::write(&path, text)
+        .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+}
+
+fn check_embedded(options: &[&str]) -> Result<(), String> {
+    let mut target = EMBEDDED_TARGET;
+    let mut bless = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "--target" => target = options.next().ok_or("--target needs a value")?,
+            "--bless" => bless = true,
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let mut sizes = read_sizes()?;
+    let mut failures = Vec::new();
+    for (set, features) in FEATURE_SETS {
+        println!("== {} ({})", set, features.join(","));
+        let probe = match build_probe(target, features) {
+            Ok(probe) => probe,
+            Err(err) => {
+                failures.push(format!("{}: {}", set, err));
+                continue;
+            }
+        };
+        let functions = symbols::functions(&probe)?;
+        let vm_functions: Vec<_> = functions
+            .iter()
+            .filter(|(_, name)| is_vm_code(name))
+            .collect();
+        // Host targets link std anyway, so this only means something for
+        // no_std ones
+        if target.contains("-none")
+            && let Some((_, name)) = functions.iter().find(|(_, name)| is_std_code(name))
+        {
+            failures.push(format!("{}: std leaked in ({})", set, name));
+        }
+        if let Some((_, name)) = vm_functions
+            .iter()
+            .find(|(_, name)| name.contains("rpled_vm::modules::test::"))
+        {
+            failures.push(format!("{}: test module leaked in ({})", set, name));
+        }
+
+        let size = symbols::text_size(&probe)?;
+        let key = (target.to_string(), set.to_string());
+        match sizes.get(&key) {
+            _ if bless => println!("{} bytes", size),
+            Some(&recorded) => {
+                println!("{} bytes (recorded {})", size, recorded);
+                if size * 100 > recorded * (100 + SIZE_TOLERANCE) {
+                    failures.push(format!(
+                        "{}: code size grew from {} to {} bytes",
+                        set, recorded, size
+                    ));
+                }
+            }
+            None => {
+                println!("{} bytes (nothing recorded)", size);
+                failures.push(format!(
+                    "{}: no size recorded for {} in {}, run with --bless",
+                    set, target, SIZES_FILE
+                ));
+            }
+        }
+        sizes.insert(key, size);
+    }
+
+    if bless {
+        write_sizes(&sizes)?;
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "check-embedded failed:\n  {}",
+            failures.join("\n  ")
+        ))
+    }
+}
+
+// (opcode name, handler path) for each op in the op table in vm.rs. Module
+// ops are left out, as their functions are reported on their own.
+fn op_handlers() -> Result<Vec<(String, String)>, String> {
+    let path = workspace_root().join("rpled-vm/src/vm.rs");
+    let text = std::fs::read_to_string(&path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    let table = text
+        .split_once("macro_rules! with_op_table")
+        .ok_or("No op table in vm.rs")?
+        .1;
+    Ok(table
+        .lines()
+        .take_while(|line| line.trim() != "}")
+        .filter_map(|line| {
+            // 1 {PUSH => ops::stack::push} [...], or {async SLEEP => ...}
+            let defn = line.split_once('{')?.1.split_once('}')?.0;
+            let (name, handler) = defn.split_once("=>")?;
+            let name = name.split_whitespace().last()?;
+            Some((name.to_string(), format!("rpled_vm::{}", handler.trim())))
+        })
+        .collect())
+}
+
+// Total size of a function and its closures (the bodies of async fns), in
+// each of its instantiations. Legacy names end at the path or a
+// `::{{closure}}`, v0 ones go on with `::<generic args>` or `::{closure#0}`.
+fn function_size(functions: &[(u64, String)], path: &str) -> u64 {
+    functions
+        .iter()
+        .filter(|(_, name)| {
+            name.strip_prefix(path).is_some_and(|rest| {
+                rest.is_empty() || rest.starts_with("::{") || rest.starts_with("::<")
+            })
+        })
+        .map(|(size, _)| size)
+        .sum()
+}
+
+fn size_report(target: &str) -> Result<(), String> {
+    let budgets = read_budgets()?;
+    let mut over = Vec::new();
+    let mut functions = Vec::new();
+    println!("Code size on {}:", target);
+    for (set, features) in FEATURE_SETS {
+        let probe = build_probe(target, features).map_err(|err| format!("{}: {}", set, err))?;
+        functions = symbols::functions(&probe)?;
+        let size: u64 = functions
+            .iter()
+            .filter(|(_, name)| is_vm_code(name))
+            .map(|(size, _)| size)
+            .sum();
+        match budgets.get(*set) {
+            Some(&budget) => {
+                println!(
+                    "  {:<10} {:>7} bytes, {}% of {}",
+                    set,
+                    size,
+                    size * 100 / budget,
+                    budget
+                );
+                if size > budget {
+                    over.push(format!("{}: {} bytes, budget {}", set, size, budget));
+                }
+            }
+            None => println!("  {:<10} {:>7} bytes, no budget", set, size),
+        }
+    }
+
+    // The last feature set has every module enabled
+    println!();
+    println!("Opcode handlers (inlined ones are counted in dispatch):");
+    let mut handlers: Vec<_> = op_handlers()?
+        .into_iter()
+        .map(|(name, handler)| (function_size(&functions, &handler), name))
+        .collect();
+    handlers.push((
+        function_size(&functions, "rpled_vm::vm::VM<_,S,D>::dispatch"),
+        "(dispatch)".to_string(),
+    ));
+    handlers.sort_by(|a, b| b.cmp(a));
+    for (size, name) in handlers {
+        match size {
+            0 => println!("  {:<12} inlined", name),
+            size => println!("  {:<12} {:>7}", name, size),
+        }
+    }
+
+    println!();
+    println!("Module functions:");
+    let mut module_functions: BTreeMap<String, u64> = BTreeMap::new();
+    for (size, name) in &functions {
+        if let Some(rest) = name.strip_prefix("rpled_vm::modules::")
+            && let Some((module, function)) = rest.split_once("::impls::")
+        {
+            let function = function.split("::").next().unwrap_or(function);
+            *module_functions
+                .entry(format!("{}::{}", module, function))
+                .or_default() += size;
+        }
+    }
+    let mut module_functions: Vec<_> = module_functions.into_iter().collect();
+    module_functions.sort_by_key(|(_, size)| Reverse(*size));
+    for (name, size) in module_functions {
+        println!("  {:<24} {:>7}", name, size);
+    }
+
+    if over.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Over budget:\n  {}", over.join("\n  ")))
+    }
+}
+
+// The opcode reference table, one row per op, with a heading row before
+// each module's calls
+fn op_docs_table() -> String {
+    let mut table = "\
+|  # | Op | Stack | Cycles | Pseudocode | Description |
+| -: | -- | :---: | -----: | ---------- | ----------- |
+"
+    .to_string();
+    for &(opcode, name) in opcodes::NAMES {
+        // The test module is only in test and fixture builds, not the
+        // instruction set
+        if (TEST_OPCODE_OFFSET..TEST_OPCODE_OFFSET + 4).contains(&opcode) {
+            continue;
+        }
+        if opcode >= TEST_OPCODE_OFFSET && (opcode - TEST_OPCODE_OFFSET).is_multiple_of(4) {
+            table += &format!(
+                "|    | **{} module** | | | | |\n",
+                name.trim_end_matches('0')
+            );
+        }
+        let operands = opcodes::operands(opcode).unwrap_or_default();
+        let spec = std::iter::once(name.to_string())
+            .chain(
+                operands
+                    .iter()
+                    .map(|operand| format!("{:?}", operand).to_lowercase()),
+            )
+            .collect::<Vec<_>>()
+            .join(" ");
+        let stack = match opcodes::stack_effect(opcode) {
+            Some((pops, pushes)) => format!("{} → {}", pops, pushes),
+            None => String::new(),
+        };
+        let cycles = opcodes::cycle_cost(opcode).unwrap_or_default();
+        let (pseudocode, description) = opcodes::doc(opcode).unwrap_or_default();
+        table += &format!(
+            "| {:>2} | {} | {} | {} | `{}` | {} |\n",
+            opcode,
+            spec,
+            stack,
+            cycles,
+            pseudocode.replace('|', "\\|"),
+            description
+        );
+    }
+    table
+}
+
+// The README with its opcode reference regenerated
+fn readme_with_op_docs() -> Result<(PathBuf, String, String), String> {
+    let path = workspace_root().join(README);
+    let text = std::fs::read_to_string(&path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    let (before, rest) = text
+        .split_once(OP_DOCS_START)
+        .ok_or(format!("No '{}' marker in {}", OP_DOCS_START, README))?;
+    let (_, after) = rest
+        .split_once(OP_DOCS_END)
+        .ok_or(format!("No '{}' marker in {}", OP_DOCS_END, README))?;
+    let updated = format!(
+        "{}{}\n{}{}{}",
+        before,
+        OP_DOCS_START,
+        op_docs_table(),
+        OP_DOCS_END,
+        after
+    );
+    Ok((path, text, updated))
+}
+
+fn op_docs(check: bool) -> Result<(), String> {
+    let (path, text, updated) = readme_with_op_docs()?;
+    if text == updated {
+        return Ok(());
+    }
+    if check {
+        return Err(format!(
+            "The opcode reference in {} is out of date, run `cargo xtask op-docs`",
+            README
+        ));
+    }
+    std::fs::write(&path, updated)
+        .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+}
+
+// rpled-ffi's header, as cbindgen generates it
+fn ffi_header_text() -> Result<String, String> {
+    let config = cbindgen::Config {
+        usize_is_size_t: true,
+        ..Default::default()
+    };
+    let bindings = cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(workspace_root().join(FFI_SOURCE))
+        .with_language(cbindgen::Language::C)
+        .with_include_guard("RPLED_H")
+        .with_header(FFI_HEADER_COMMENT)
+        .with_no_includes()
+        .with_sys_include("stdint.h")
+        .with_sys_include("stddef.h")
+        .with_cpp_compat(true)
+        .generate()
+        .map_err(|err| format!("Failed to generate the header: {}", err))?;
+    let mut header = vec![];
+    bindings.write(&mut header);
+    String::from_utf8(header).map_err(|err| format!("Header isn't UTF-8: {}", err))
+}
+
+fn ffi_header(check: bool) -> Result<(), String> {
+    let path = workspace_root().join(FFI_HEADER);
+    let header = ffi_header_text()?;
+    if std::fs::read_to_string(&path).is_ok_and(|current| current == header) {
+        return Ok(());
+    }
+    if check {
+        return Err(format!(
+            "{} is out of date, run `cargo xtask ffi-header`",
+            FFI_HEADER
+        ));
+    }
+    std::fs::write(&path, header)
+        .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_op_docs_current() {
+        op_docs(true).unwrap();
+    }
+
+    #[test]
+    fn test_ffi_header_current() {
+        ffi_header(true).unwrap();
+    }
+}
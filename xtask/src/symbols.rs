@@ -0,0 +1,127 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+// An LLVM tool (llvm-nm, llvm-size) from the llvm-tools component, which
+// matches rustc's LLVM version, falling back to the one on PATH. `env`
+// (LLVM_NM, LLVM_SIZE) overrides both.
+fn llvm_tool(name: &str, env: &str) -> PathBuf {
+    if let Ok(tool) = std::env::var(env) {
+        return PathBuf::from(tool);
+    }
+    let sysroot = Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .output()
+        .ok()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+    let tools = sysroot.and_then(|sysroot| {
+        std::fs::read_dir(Path::new(&sysroot).join("lib/rustlib"))
+            .ok()?
+            .filter_map(|entry| Some(entry.ok()?.path().join("bin").join(name)))
+            .find(|path| path.exists())
+    });
+    tools.unwrap_or(PathBuf::from(name))
+}
+
+fn run_tool(name: &str, env: &str, args: &[&str], binary: &Path) -> Result<Output, String> {
+    let tool = llvm_tool(name, env);
+    let output = Command::new(&tool)
+        .args(args)
+        .arg(binary)
+        .output()
+        .map_err(|err| {
+            format!(
+                "Failed to run {}: {} (install llvm-tools, or set {})",
+                tool.display(),
+                err,
+                env
+            )
+        })?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            tool.display(),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(output)
+}
+
+// Size of a linked binary's .text section, i.e. all the code that made it
+// through section GC
+pub fn text_size(binary: &Path) -> Result<u64, String> {
+    let output = run_tool("llvm-size", "LLVM_SIZE", &["-A"], binary)?;
+    // <section> <size> <address>
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| {
+            let mut fields = line.split_whitespace();
+            (fields.next()? == ".text").then(|| fields.next()?.parse().ok())?
+        })
+        .ok_or(format!("No .text section in {}", binary.display()))
+}
+
+// (size, demangled name) of each function in a binary
+pub fn functions(binary: &Path) -> Result<Vec<(u64, String)>, String> {
+    let output = run_tool(
+        "llvm-nm",
+        "LLVM_NM",
+        &["--print-size", "--defined-only"],
+        binary,
+    )?;
+    // <address> <size> <type> <symbol>
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let (_, size, kind, symbol) = (
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+                fields.next()?,
+            );
+            let size = u64::from_str_radix(size, 16).ok()?;
+            matches!(kind, "t" | "T").then(|| (size, demangle(symbol)))
+        })
+        .collect())
+}
+
+// Either of Rust's manglings, without the hash
+pub fn demangle(symbol: &str) -> String {
+    format!("{:#}", rustc_demangle::demangle(symbol))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demangle() {
+        assert_eq!(
+            demangle("_ZN8rpled_vm3ops7control4call17h64b595279321c12dE"),
+            "rpled_vm::ops::control::call"
+        );
+        assert_eq!(
+            demangle("_ZN8rpled_vm2vm19VM$LT$_$C$S$C$D$GT$4load17h61b4e8a2f9ddd6beE"),
+            "rpled_vm::vm::VM<_,S,D>::load"
+        );
+        assert_eq!(
+            demangle(
+                "_ZN8rpled_vm7modules3led5impls3rgb28_$u7b$$u7b$closure$u7d$$u7d$17h48bd0988df49dd6aE"
+            ),
+            "rpled_vm::modules::led::impls::rgb::{{closure}}"
+        );
+        assert_eq!(
+            demangle(
+                "_RINvNtNtCseIjn8kz1iIY_8rpled_vm3ops4math3addKj1000_NtCsbQLbqbCMyTP_16rpled_size_probe9ProbeSyncNtNtB6_2vm9NoVmDebugEBP_"
+            ),
+            "rpled_vm::ops::math::add::<4096, rpled_size_probe::ProbeSync, rpled_vm::vm::NoVmDebug>"
+        );
+        assert_eq!(
+            demangle(
+                "_RNCNvMs5_NtCseIjn8kz1iIY_8rpled_vm2vmINtB7_2VMKj1000_NtCsbQLbqbCMyTP_16rpled_size_probe9ProbeSyncNtB7_9NoVmDebugE8dispatch0BR_"
+            ),
+            "<rpled_vm::vm::VM<4096, rpled_size_probe::ProbeSync, rpled_vm::vm::NoVmDebug>>::dispatch::{closure#0}"
+        );
+        assert_eq!(demangle("rpled_probe_run"), "rpled_probe_run");
+    }
+}